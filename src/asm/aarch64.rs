@@ -0,0 +1,1639 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use anyhow::{anyhow, Error};
+
+use crate::asm::aarch64::inst::{Cond, Inst};
+use crate::asm::arch::{Arch, Asm, Label, Rel, RelocKind, Traps};
+use crate::asm::exec::Rt;
+use crate::asm::host::HostFn;
+use crate::interpreter::EvalError;
+use crate::parser::ast::Op;
+
+pub mod inst;
+
+extern "C" {
+    fn pow(x: f64, y: f64) -> f64;
+    fn fmod(x: f64, y: f64) -> f64;
+}
+
+/// Scratch register reserved for constants and call targets (IP0 in the procedure call
+/// standard).
+const SCRATCH: IntReg = IntReg::X16;
+
+/// Integer and floating point argument registers of the procedure call standard.
+const ARGS: u8 = 8;
+
+/// A64 code generator. Every value pushed on the stack takes a 16-byte slot, so `sp` stays
+/// aligned as the architecture requires.
+///
+/// Unlike x86 `idiv`, `sdiv` does not trap: in code without a frame, division by zero yields 0,
+/// and a negative power of zero yields 0 as well.
+#[derive(Default)]
+pub struct AArch64 {
+    asm: Asm,
+    /// Set by `frame`; x19 then holds the status pointer, x20 the arguments and x29 the frame.
+    traps: Option<Traps>,
+}
+
+impl AArch64 {
+    fn emit(&mut self, inst: Inst) {
+        self.asm.put(&inst.encode().to_le_bytes());
+    }
+
+    /// Emits a branch whose offset is patched once `label` is bound.
+    fn branch(&mut self, inst: Inst, label: Label) {
+        let rel = match inst {
+            Inst::B { .. } | Inst::Bl { .. } => Rel::Imm26,
+            _ => Rel::Imm19,
+        };
+        self.asm.put_branch(inst.encode(), label, rel);
+    }
+
+    /// Emits a branch to the exit reporting `err`, if the code has a frame.
+    fn trap(&mut self, inst: Inst, err: EvalError) {
+        if let Some(traps) = &mut self.traps {
+            let exit = traps.exit(&mut self.asm, err);
+            self.branch(inst, exit);
+        }
+    }
+
+    /// `rd = rn / rm`, leaving code with a frame early where the interpreter fails.
+    fn sdiv(&mut self, rd: IntReg, rn: IntReg, rm: IntReg) {
+        if self.traps.is_some() {
+            let valid = self.asm.label();
+            self.trap(Inst::Cbz { rt: rm, offset: 0 }, EvalError::DivisionByZero);
+            self.emit(Inst::CmnImm { rn: rm, imm: 1 });
+            self.branch(
+                Inst::BCond {
+                    cond: Cond::NE,
+                    offset: 0,
+                },
+                valid,
+            );
+            self.mov_imm(SCRATCH, i64::MIN as u64);
+            self.emit(Inst::Cmp { rn, rm: SCRATCH });
+            let overflow = Inst::BCond {
+                cond: Cond::EQ,
+                offset: 0,
+            };
+            self.trap(overflow, EvalError::Overflow);
+            self.asm.bind(valid);
+        }
+        self.emit(Inst::Sdiv { rd, rn, rm });
+    }
+
+    /// Moves `sp` by the room of `slots` arguments with `inst`, an `add` or `sub` of `sp`,
+    /// keeping it aligned to 16 bytes.
+    fn adjust_sp(&mut self, slots: usize, inst: Inst) {
+        let mut bytes = (8 * slots + 15) & !15;
+        while bytes != 0 {
+            // The immediate has 12 bits.
+            let imm = bytes.min(0xff0);
+            self.emit(match inst {
+                Inst::AddImm { rd, rn, .. } => Inst::AddImm {
+                    rd,
+                    rn,
+                    imm: imm as u16,
+                },
+                Inst::SubImm { rd, rn, .. } => Inst::SubImm {
+                    rd,
+                    rn,
+                    imm: imm as u16,
+                },
+                inst => panic!("{} doesn't move sp", inst),
+            });
+            bytes -= imm;
+        }
+    }
+
+    /// Loads a 64-bit constant with the fewest `movz`/`movn` + `movk` instructions.
+    fn mov_imm(&mut self, rd: IntReg, val: u64) {
+        let halves = |val: u64| (0..4).map(move |hw| (hw as u8, (val >> (16 * hw)) as u16));
+        let ones = halves(val).filter(|(_, imm)| *imm == 0xffff).count();
+        let zeros = halves(val).filter(|(_, imm)| *imm == 0).count();
+
+        let (skip, first) = if ones > zeros {
+            (0xffff, !val)
+        } else {
+            (0, val)
+        };
+        let (hw, imm) = halves(first).find(|(_, imm)| *imm != 0).unwrap_or((0, 0));
+        if ones > zeros {
+            self.emit(Inst::Movn { rd, imm, hw });
+        } else {
+            self.emit(Inst::Movz { rd, imm, hw });
+        }
+        for (k, imm) in halves(val).filter(|(k, imm)| *k != hw && *imm != skip) {
+            self.emit(Inst::Movk { rd, imm, hw: k });
+        }
+    }
+
+    /// Keeps `INT_ACC` if it compares to `op` as `cond` says, and `op` otherwise.
+    fn select(&mut self, op: IntReg, cond: Cond) {
+        let acc = Self::INT_ACC;
+        self.emit(Inst::Cmp { rn: acc, rm: op });
+        self.emit(Inst::Csel {
+            rd: acc,
+            rn: acc,
+            rm: op,
+            cond,
+        });
+    }
+
+    /// Calls `symbol` at an absolute address through the scratch register, or through the
+    /// linker if the code `links_calls`, keeping a frame record so the stack can be unwound.
+    /// Clobbers all caller-saved registers.
+    fn call_addr(&mut self, symbol: &str, addr: usize) {
+        let (fp, lr) = (IntReg::X29, IntReg::X30);
+        self.emit(Inst::StpPre {
+            rt: fp,
+            rt2: lr,
+            rn: IntReg::SP,
+            imm: -16,
+        });
+        self.emit(Inst::AddImm {
+            rd: fp,
+            rn: IntReg::SP,
+            imm: 0,
+        });
+        if self.asm.links_calls() {
+            self.asm.relocate(self.asm.len(), symbol, RelocKind::Call26);
+            self.emit(Inst::Bl { offset: 0 });
+        } else {
+            self.call_abs(symbol, addr);
+        }
+        self.emit(Inst::LdpPost {
+            rt: fp,
+            rt2: lr,
+            rn: IntReg::SP,
+            imm: 16,
+        });
+    }
+
+    /// Calls `symbol` at `addr`, loaded into the scratch register.
+    fn call_abs(&mut self, symbol: &str, addr: usize) {
+        // All four halves are always loaded, so that any address can be relocated in.
+        let kinds = [
+            RelocKind::MovwG0,
+            RelocKind::MovwG1,
+            RelocKind::MovwG2,
+            RelocKind::MovwG3,
+        ];
+        for hw in (0..4).rev() {
+            let (rd, imm) = (SCRATCH, (addr as u64 >> (16 * hw)) as u16);
+            self.asm.relocate(self.asm.len(), symbol, kinds[hw as usize]);
+            if hw == 3 {
+                self.emit(Inst::Movz { rd, imm, hw });
+            } else {
+                self.emit(Inst::Movk { rd, imm, hw });
+            }
+        }
+        self.emit(Inst::Blr { rn: SCRATCH });
+    }
+}
+
+/// Condition holding after `cmp` or `fcmp` when `cmp` does.
+fn cond(cmp: Op) -> Cond {
+    match cmp {
+        Op::Lt => Cond::LT,
+        Op::Le => Cond::LE,
+        Op::Gt => Cond::GT,
+        Op::Ge => Cond::GE,
+        Op::Eq => Cond::EQ,
+        Op::Ne => Cond::NE,
+        op => panic!("{} is not a comparison", op),
+    }
+}
+
+impl Arch for AArch64 {
+    type IntReg = IntReg;
+    type FloatReg = FloatReg;
+
+    const NAME: &'static str = "aarch64";
+
+    const ELF_MACHINE: u16 = 183;
+
+    const INT_RET: Self::IntReg = IntReg::X0;
+    const FLOAT_RET: Self::FloatReg = FloatReg::D0;
+
+    const INT_ACC: Self::IntReg = IntReg::X0;
+    const FLOAT_ACC: Self::FloatReg = FloatReg::D0;
+
+    const INT_TMP: Self::IntReg = IntReg::X1;
+    const FLOAT_TMP: Self::FloatReg = FloatReg::D1;
+
+    fn movi(&mut self, from: Self::IntReg, to: Self::IntReg) {
+        if from != to {
+            self.emit(Inst::Mov { rd: to, rm: from });
+        }
+    }
+
+    fn movf(&mut self, from: Self::FloatReg, to: Self::FloatReg) {
+        if from != to {
+            self.emit(Inst::Fmov { rd: to, rn: from });
+        }
+    }
+
+    fn storei(&mut self, reg: Self::IntReg, val: i64) {
+        self.mov_imm(reg, val as u64);
+    }
+
+    fn storef(&mut self, reg: Self::FloatReg, val: f64) {
+        let bits = val.to_bits();
+        if bits == 0 {
+            self.emit(Inst::FmovFromInt {
+                rd: reg,
+                rn: IntReg::XZR,
+            });
+        } else {
+            self.mov_imm(SCRATCH, bits);
+            self.emit(Inst::FmovFromInt {
+                rd: reg,
+                rn: SCRATCH,
+            });
+        }
+    }
+
+    fn castf(&mut self, from: Self::IntReg, to: Self::FloatReg) {
+        self.emit(Inst::Scvtf { rd: to, rn: from });
+    }
+
+    fn negi(&mut self, reg: Self::IntReg) {
+        self.emit(Inst::Neg { rd: reg, rm: reg });
+    }
+
+    fn negf(&mut self, reg: Self::FloatReg) {
+        self.emit(Inst::Fneg { rd: reg, rn: reg });
+    }
+
+    fn sqrtf(&mut self, reg: Self::FloatReg) {
+        self.emit(Inst::Fsqrt { rd: reg, rn: reg });
+    }
+
+    fn absi(&mut self, reg: Self::IntReg) {
+        self.emit(Inst::CmpImm { rn: reg, imm: 0 });
+        self.emit(Inst::Csneg {
+            rd: reg,
+            rn: reg,
+            rm: reg,
+            cond: Cond::GE,
+        });
+    }
+
+    fn mini(&mut self, op: Self::IntReg) {
+        self.select(op, Cond::LT);
+    }
+
+    fn maxi(&mut self, op: Self::IntReg) {
+        self.select(op, Cond::GT);
+    }
+
+    fn addi(&mut self, op: Self::IntReg) {
+        let acc = Self::INT_ACC;
+        self.emit(Inst::Add {
+            rd: acc,
+            rn: acc,
+            rm: op,
+        });
+    }
+
+    fn addf(&mut self, op: Self::FloatReg) {
+        let acc = Self::FLOAT_ACC;
+        self.emit(Inst::Fadd {
+            rd: acc,
+            rn: acc,
+            rm: op,
+        });
+    }
+
+    fn subi(&mut self, op: Self::IntReg) {
+        let acc = Self::INT_ACC;
+        self.emit(Inst::Sub {
+            rd: acc,
+            rn: acc,
+            rm: op,
+        });
+    }
+
+    fn subf(&mut self, op: Self::FloatReg) {
+        let acc = Self::FLOAT_ACC;
+        self.emit(Inst::Fsub {
+            rd: acc,
+            rn: acc,
+            rm: op,
+        });
+    }
+
+    fn muli(&mut self, op: Self::IntReg) {
+        let acc = Self::INT_ACC;
+        self.emit(Inst::Mul {
+            rd: acc,
+            rn: acc,
+            rm: op,
+        });
+    }
+
+    fn mulf(&mut self, op: Self::FloatReg) {
+        let acc = Self::FLOAT_ACC;
+        self.emit(Inst::Fmul {
+            rd: acc,
+            rn: acc,
+            rm: op,
+        });
+    }
+
+    fn modi(&mut self, op: Self::IntReg) {
+        // acc - (acc / op) * op, with the quotient truncated toward zero.
+        let acc = Self::INT_ACC;
+        self.sdiv(SCRATCH, acc, op);
+        self.emit(Inst::Msub {
+            rd: acc,
+            rn: SCRATCH,
+            rm: op,
+            ra: acc,
+        });
+    }
+
+    fn modf(&mut self, op: Self::FloatReg) {
+        self.movf(op, FloatReg::D1);
+        self.call_addr("fmod", fmod as *const () as usize);
+    }
+
+    fn divi(&mut self, op: Self::IntReg) {
+        let acc = Self::INT_ACC;
+        self.sdiv(acc, acc, op);
+    }
+
+    fn divf(&mut self, op: Self::FloatReg) {
+        let acc = Self::FLOAT_ACC;
+        self.emit(Inst::Fdiv {
+            rd: acc,
+            rn: acc,
+            rm: op,
+        });
+    }
+
+    fn powi(&mut self, op: Self::IntReg) {
+        let (base, exp, result) = (IntReg::X0, IntReg::X1, IntReg::X2);
+        self.movi(op, exp);
+
+        let (pos, repeat, skip, done) = (
+            self.asm.label(),
+            self.asm.label(),
+            self.asm.label(),
+            self.asm.label(),
+        );
+
+        self.emit(Inst::CmpImm { rn: exp, imm: 0 });
+        self.branch(
+            Inst::BCond {
+                cond: Cond::GE,
+                offset: 0,
+            },
+            pos,
+        );
+        // A negative exponent follows truncating division: base^-n == (1 / base)^n.
+        self.emit(Inst::Neg { rd: exp, rm: exp });
+        self.trap(Inst::Cbz { rt: base, offset: 0 }, EvalError::DivisionByZero);
+        self.mov_imm(SCRATCH, 1);
+        self.emit(Inst::Sdiv {
+            rd: base,
+            rn: SCRATCH,
+            rm: base,
+        });
+
+        self.asm.bind(pos);
+        self.mov_imm(result, 1);
+        self.asm.bind(repeat);
+        self.branch(Inst::Cbz { rt: exp, offset: 0 }, done);
+        self.emit(Inst::TstLsb { rn: exp });
+        self.branch(
+            Inst::BCond {
+                cond: Cond::EQ,
+                offset: 0,
+            },
+            skip,
+        );
+        self.emit(Inst::Mul {
+            rd: result,
+            rn: result,
+            rm: base,
+        });
+        self.asm.bind(skip);
+        self.emit(Inst::Mul {
+            rd: base,
+            rn: base,
+            rm: base,
+        });
+        self.emit(Inst::LsrImm {
+            rd: exp,
+            rn: exp,
+            shift: 1,
+        });
+        self.branch(Inst::B { offset: 0 }, repeat);
+        self.asm.bind(done);
+        self.movi(result, Self::INT_ACC);
+    }
+
+    fn powf(&mut self, op: Self::FloatReg) {
+        self.movf(op, FloatReg::D1);
+        self.call_addr("pow", pow as *const () as usize);
+    }
+
+    fn popi(&mut self, reg: Self::IntReg) {
+        self.emit(Inst::LdrPost {
+            rt: reg,
+            rn: IntReg::SP,
+            imm: 16,
+        });
+    }
+
+    fn popf(&mut self, reg: Self::FloatReg) {
+        self.emit(Inst::LdrPostF {
+            rt: reg,
+            rn: IntReg::SP,
+            imm: 16,
+        });
+    }
+
+    fn pushli(&mut self, val: i64) {
+        self.mov_imm(SCRATCH, val as u64);
+        self.pushi(SCRATCH);
+    }
+
+    fn pushlf(&mut self, val: f64) {
+        self.mov_imm(SCRATCH, val.to_bits());
+        self.pushi(SCRATCH);
+    }
+
+    fn pushi(&mut self, reg: Self::IntReg) {
+        self.emit(Inst::StrPre {
+            rt: reg,
+            rn: IntReg::SP,
+            imm: -16,
+        });
+    }
+
+    fn pushf(&mut self, reg: Self::FloatReg) {
+        self.emit(Inst::StrPreF {
+            rt: reg,
+            rn: IntReg::SP,
+            imm: -16,
+        });
+    }
+
+    fn call(&mut self, fun: &HostFn) {
+        self.pushi(Self::INT_TMP);
+        self.pushf(Self::FLOAT_TMP);
+
+        let count = fun.args().len();
+        let (mut ints, mut floats) = (0, 0);
+        for (i, rt) in fun.args().iter().enumerate() {
+            let imm = (32 + 16 * (count - 1 - i)) as u16;
+            if *rt == Rt::Int {
+                assert!(ints < ARGS, "Too many integer arguments");
+                self.emit(Inst::Ldr {
+                    rt: IntReg::from_code(ints, false),
+                    rn: IntReg::SP,
+                    imm,
+                });
+                ints += 1;
+            } else {
+                assert!(floats < ARGS, "Too many float arguments");
+                self.emit(Inst::LdrF {
+                    rt: FloatReg::from_code(floats),
+                    rn: IntReg::SP,
+                    imm,
+                });
+                floats += 1;
+            }
+        }
+
+        self.call_addr(fun.name(), fun.addr());
+
+        self.popf(Self::FLOAT_TMP);
+        self.popi(Self::INT_TMP);
+        if count != 0 {
+            self.emit(Inst::AddImm {
+                rd: IntReg::SP,
+                rn: IntReg::SP,
+                imm: 16 * count as u16,
+            });
+        }
+    }
+
+    fn cmpi(&mut self, op: Self::IntReg, cmp: Op) {
+        self.emit(Inst::Cmp {
+            rn: Self::INT_ACC,
+            rm: op,
+        });
+        self.emit(Inst::Cset {
+            rd: Self::INT_ACC,
+            cond: cond(cmp),
+        });
+    }
+
+    fn cmpf(&mut self, op: Self::FloatReg, cmp: Op) {
+        // `lt` and `le` hold for an unordered result, `>` and `>=` with the operands swapped
+        // don't.
+        let (rn, rm, cmp) = match cmp {
+            Op::Lt => (op, Self::FLOAT_ACC, Op::Gt),
+            Op::Le => (op, Self::FLOAT_ACC, Op::Ge),
+            cmp => (Self::FLOAT_ACC, op, cmp),
+        };
+        self.emit(Inst::Fcmp { rn, rm });
+        self.emit(Inst::Cset {
+            rd: Self::INT_ACC,
+            cond: cond(cmp),
+        });
+    }
+
+    fn jump(&mut self, label: Label) {
+        self.branch(Inst::B { offset: 0 }, label);
+    }
+
+    fn jump_zero(&mut self, reg: Self::IntReg, label: Label) {
+        self.branch(Inst::Cbz { rt: reg, offset: 0 }, label);
+    }
+
+    fn reserve(&mut self, slots: usize) {
+        self.adjust_sp(slots, Inst::SubImm {
+            rd: IntReg::SP,
+            rn: IntReg::SP,
+            imm: 0,
+        });
+    }
+
+    fn setargi(&mut self, reg: Self::IntReg, slot: usize) {
+        self.emit(Inst::Str {
+            rt: reg,
+            rn: IntReg::SP,
+            imm: 8 * slot as u16,
+        });
+    }
+
+    fn setargf(&mut self, reg: Self::FloatReg, slot: usize) {
+        self.emit(Inst::StrF {
+            rt: reg,
+            rn: IntReg::SP,
+            imm: 8 * slot as u16,
+        });
+    }
+
+    fn apply(&mut self, target: Label, slots: usize) {
+        let status = IntReg::X19;
+        let budget = |rt| Inst::Ldr {
+            rt,
+            rn: status,
+            imm: 8,
+        };
+        self.emit(budget(SCRATCH));
+        self.trap(
+            Inst::Cbz {
+                rt: SCRATCH,
+                offset: 0,
+            },
+            EvalError::StackOverflow,
+        );
+        self.emit(Inst::SubImm {
+            rd: SCRATCH,
+            rn: SCRATCH,
+            imm: 1,
+        });
+        self.emit(Inst::Str {
+            rt: SCRATCH,
+            rn: status,
+            imm: 8,
+        });
+
+        self.emit(Inst::Mov {
+            rd: IntReg::X0,
+            rm: status,
+        });
+        self.emit(Inst::AddImm {
+            rd: IntReg::X1,
+            rn: IntReg::SP,
+            imm: 0,
+        });
+        self.branch(Inst::Bl { offset: 0 }, target);
+
+        self.emit(budget(SCRATCH));
+        self.emit(Inst::AddImm {
+            rd: SCRATCH,
+            rn: SCRATCH,
+            imm: 1,
+        });
+        self.emit(Inst::Str {
+            rt: SCRATCH,
+            rn: status,
+            imm: 8,
+        });
+        self.adjust_sp(slots, Inst::AddImm {
+            rd: IntReg::SP,
+            rn: IntReg::SP,
+            imm: 0,
+        });
+        self.emit(Inst::Ldr {
+            rt: SCRATCH,
+            rn: status,
+            imm: 0,
+        });
+        let unwind = self
+            .traps
+            .as_mut()
+            .expect("Calls require a frame")
+            .unwind(&mut self.asm);
+        self.branch(
+            Inst::Cbnz {
+                rt: SCRATCH,
+                offset: 0,
+            },
+            unwind,
+        );
+    }
+
+    fn frame(&mut self) {
+        let (fp, lr, status, args) = (IntReg::X29, IntReg::X30, IntReg::X19, IntReg::X20);
+        self.emit(Inst::StpPre {
+            rt: fp,
+            rt2: lr,
+            rn: IntReg::SP,
+            imm: -16,
+        });
+        self.emit(Inst::AddImm {
+            rd: fp,
+            rn: IntReg::SP,
+            imm: 0,
+        });
+        self.emit(Inst::StpPre {
+            rt: status,
+            rt2: args,
+            rn: IntReg::SP,
+            imm: -16,
+        });
+        self.emit(Inst::Mov {
+            rd: status,
+            rm: IntReg::X0,
+        });
+        self.emit(Inst::Mov {
+            rd: args,
+            rm: IntReg::X1,
+        });
+        self.traps = Some(Traps::default());
+    }
+
+    fn loadi(&mut self, reg: Self::IntReg, slot: usize) {
+        self.emit(Inst::Ldr {
+            rt: reg,
+            rn: IntReg::X20,
+            imm: 8 * slot as u16,
+        });
+    }
+
+    fn loadf(&mut self, reg: Self::FloatReg, slot: usize) {
+        self.emit(Inst::LdrF {
+            rt: reg,
+            rn: IntReg::X20,
+            imm: 8 * slot as u16,
+        });
+    }
+
+    fn ret(&mut self) {
+        let traps = match self.traps.take() {
+            Some(traps) => traps,
+            None => return self.emit(Inst::Ret),
+        };
+
+        // Error exits may leave spilled operands behind, the frame drops them.
+        let (fp, lr, status, args) = (IntReg::X29, IntReg::X30, IntReg::X19, IntReg::X20);
+        let exit = traps.propagated().unwrap_or_else(|| self.asm.label());
+        self.asm.bind(exit);
+        self.emit(Inst::SubImm {
+            rd: IntReg::SP,
+            rn: fp,
+            imm: 16,
+        });
+        self.emit(Inst::LdpPost {
+            rt: status,
+            rt2: args,
+            rn: IntReg::SP,
+            imm: 16,
+        });
+        self.emit(Inst::LdpPost {
+            rt: fp,
+            rt2: lr,
+            rn: IntReg::SP,
+            imm: 16,
+        });
+        self.emit(Inst::Ret);
+        for (err, label) in traps.into_exits() {
+            let code = err.code().expect("Only errors with a code are trapped");
+            self.asm.bind(label);
+            self.mov_imm(SCRATCH, code);
+            self.emit(Inst::Str {
+                rt: SCRATCH,
+                rn: status,
+                imm: 0,
+            });
+            self.branch(Inst::B { offset: 0 }, exit);
+        }
+    }
+
+    fn asm(&mut self) -> &mut Asm {
+        &mut self.asm
+    }
+
+    fn library(symbol: &str) -> Option<usize> {
+        match symbol {
+            "pow" => Some(pow as *const () as usize),
+            "fmod" => Some(fmod as *const () as usize),
+            _ => None,
+        }
+    }
+
+    fn disassemble(code: &[u8]) -> Result<Vec<(Range<usize>, String)>, Error> {
+        code.chunks(4)
+            .enumerate()
+            .map(|(i, word)| {
+                let at = 4 * i;
+                if word.len() < 4 {
+                    return Err(anyhow!("Truncated instruction at offset {:#x}", at));
+                }
+                let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                let inst = Inst::decode(word).ok_or_else(|| {
+                    anyhow!("Unknown instruction {:#010x} at offset {:#x}", word, at)
+                })?;
+                Ok((at..at + 4, inst.to_string()))
+            })
+            .collect()
+    }
+}
+
+impl From<AArch64> for Asm {
+    fn from(arch: AArch64) -> Asm {
+        arch.asm
+    }
+}
+
+/// General purpose register. `SP` and `XZR` share number 31; which one an operand means depends
+/// on the instruction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IntReg {
+    X0,
+    X1,
+    X2,
+    X3,
+    X4,
+    X5,
+    X6,
+    X7,
+    X8,
+    X9,
+    X10,
+    X11,
+    X12,
+    X13,
+    X14,
+    X15,
+    X16,
+    X17,
+    X18,
+    X19,
+    X20,
+    X21,
+    X22,
+    X23,
+    X24,
+    X25,
+    X26,
+    X27,
+    X28,
+    X29,
+    X30,
+    SP,
+    XZR,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FloatReg {
+    D0,
+    D1,
+    D2,
+    D3,
+    D4,
+    D5,
+    D6,
+    D7,
+    D8,
+    D9,
+    D10,
+    D11,
+    D12,
+    D13,
+    D14,
+    D15,
+    D16,
+    D17,
+    D18,
+    D19,
+    D20,
+    D21,
+    D22,
+    D23,
+    D24,
+    D25,
+    D26,
+    D27,
+    D28,
+    D29,
+    D30,
+    D31,
+}
+
+impl IntReg {
+    const ALL: [IntReg; 31] = [
+        IntReg::X0,
+        IntReg::X1,
+        IntReg::X2,
+        IntReg::X3,
+        IntReg::X4,
+        IntReg::X5,
+        IntReg::X6,
+        IntReg::X7,
+        IntReg::X8,
+        IntReg::X9,
+        IntReg::X10,
+        IntReg::X11,
+        IntReg::X12,
+        IntReg::X13,
+        IntReg::X14,
+        IntReg::X15,
+        IntReg::X16,
+        IntReg::X17,
+        IntReg::X18,
+        IntReg::X19,
+        IntReg::X20,
+        IntReg::X21,
+        IntReg::X22,
+        IntReg::X23,
+        IntReg::X24,
+        IntReg::X25,
+        IntReg::X26,
+        IntReg::X27,
+        IntReg::X28,
+        IntReg::X29,
+        IntReg::X30,
+    ];
+
+    /// Register number of the instruction fields.
+    pub fn code(&self) -> u8 {
+        match self {
+            IntReg::SP | IntReg::XZR => 31,
+            reg => *reg as u8,
+        }
+    }
+
+    /// Register with the given number, where 31 is `SP` if `sp` is set and `XZR` otherwise.
+    pub fn from_code(code: u8, sp: bool) -> IntReg {
+        match code {
+            31 if sp => IntReg::SP,
+            31 => IntReg::XZR,
+            code => Self::ALL[code as usize],
+        }
+    }
+}
+
+impl FloatReg {
+    const ALL: [FloatReg; 32] = [
+        FloatReg::D0,
+        FloatReg::D1,
+        FloatReg::D2,
+        FloatReg::D3,
+        FloatReg::D4,
+        FloatReg::D5,
+        FloatReg::D6,
+        FloatReg::D7,
+        FloatReg::D8,
+        FloatReg::D9,
+        FloatReg::D10,
+        FloatReg::D11,
+        FloatReg::D12,
+        FloatReg::D13,
+        FloatReg::D14,
+        FloatReg::D15,
+        FloatReg::D16,
+        FloatReg::D17,
+        FloatReg::D18,
+        FloatReg::D19,
+        FloatReg::D20,
+        FloatReg::D21,
+        FloatReg::D22,
+        FloatReg::D23,
+        FloatReg::D24,
+        FloatReg::D25,
+        FloatReg::D26,
+        FloatReg::D27,
+        FloatReg::D28,
+        FloatReg::D29,
+        FloatReg::D30,
+        FloatReg::D31,
+    ];
+
+    /// Register number of the instruction fields.
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_code(code: u8) -> FloatReg {
+        Self::ALL[code as usize]
+    }
+}
+
+impl Display for IntReg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl Display for FloatReg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "d{}", self.code())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+    use std::collections::HashMap;
+    use std::thread;
+
+    use crate::asm::aarch64::inst::{Cond, Inst};
+    use crate::asm::aarch64::{fmod, pow, AArch64, FloatReg, IntReg};
+    use crate::asm::arch::{Arch, Asm};
+    use crate::asm::exec::{self, AsmCode, Rt};
+    use crate::asm::host::{HostFn, HostFns};
+    use crate::interpreter::{Env, EvalError, Execution, MAX_DEPTH};
+    use crate::parser::ast::{parse_exp, parse_program, Builtin, Exp, Val};
+    use crate::parser::lexer::Lexer;
+    use crate::parser::program::Instance;
+
+    fn parse(input: &str) -> Exp {
+        let mut lexer = Lexer::new(input);
+        lexer.advance().unwrap();
+        parse_exp(&mut lexer).unwrap()
+    }
+
+    fn compile(exp: &Exp) -> Vec<u32> {
+        assemble(exp, false)
+    }
+
+    fn assemble(exp: &Exp, framed: bool) -> Vec<u32> {
+        assemble_with(exp, framed, &[])
+    }
+
+    /// Assembles `exp` followed by the functions of the instances it calls.
+    fn assemble_with(exp: &Exp, framed: bool, funs: &[Instance]) -> Vec<u32> {
+        let mut arch = AArch64::default();
+        if framed {
+            arch.frame();
+        }
+        exp.to_asm::<AArch64>(&mut arch, AArch64::INT_RET, AArch64::FLOAT_RET);
+        arch.ret();
+        for (i, fun) in funs.iter().enumerate() {
+            exec::instance(fun, i, &mut arch);
+        }
+        let mut asm = Asm::from(arch);
+        asm.finalize().unwrap();
+        asm.buffer()
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect()
+    }
+
+    /// Size of the emulated stack, enough for calls nested `MAX_DEPTH` deep.
+    const STACK: usize = 1 << 20;
+
+    /// Runs decoded A64 code on a minimal model of the machine. Calls are dispatched by address
+    /// to the host functions compiled into the code.
+    struct Machine {
+        x: [u64; 31],
+        d: [f64; 32],
+        sp: usize,
+        stack: Vec<u8>,
+        flags: (bool, bool, bool),
+        hosts: HashMap<u64, HostFn>,
+    }
+
+    impl Machine {
+        /// The status word and the call depth budget are at the bottom of the stack, `x0`
+        /// points to them. Returning to the address in `x30` ends the run.
+        fn new(hosts: &[HostFn]) -> Machine {
+            let mut machine = Machine {
+                x: [0; 31],
+                d: [0.0; 32],
+                sp: STACK,
+                stack: vec![0; STACK],
+                flags: (false, false, false),
+                hosts: hosts
+                    .iter()
+                    .map(|fun| (fun.addr() as u64, fun.clone()))
+                    .collect(),
+            };
+            machine.store(8, MAX_DEPTH as u64);
+            machine.x[30] = u64::MAX;
+            machine
+        }
+
+        fn get(&self, reg: IntReg) -> u64 {
+            match reg {
+                IntReg::XZR => 0,
+                IntReg::SP => self.sp as u64,
+                reg => self.x[reg.code() as usize],
+            }
+        }
+
+        fn set(&mut self, reg: IntReg, val: u64) {
+            match reg {
+                IntReg::XZR => {}
+                IntReg::SP => self.sp = val as usize,
+                reg => self.x[reg.code() as usize] = val,
+            }
+        }
+
+        fn load(&self, addr: u64) -> u64 {
+            let addr = addr as usize;
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&self.stack[addr..addr + 8]);
+            u64::from_le_bytes(bytes)
+        }
+
+        fn store(&mut self, addr: u64, val: u64) {
+            let addr = addr as usize;
+            self.stack[addr..addr + 8].copy_from_slice(&val.to_le_bytes());
+        }
+
+        /// Passes `args` right above the status word and the budget.
+        fn args(&mut self, args: &[Val]) {
+            for (i, arg) in args.iter().enumerate() {
+                let bits = match arg {
+                    Val::Int(val) => *val as u64,
+                    Val::Float(val) => val.to_bits(),
+                };
+                self.store(16 + 8 * i as u64, bits);
+            }
+            self.x[1] = 16;
+        }
+
+        fn holds(&self, cond: Cond) -> bool {
+            let (n, z, v) = self.flags;
+            match cond {
+                Cond::EQ => z,
+                Cond::NE => !z,
+                Cond::GE => n == v,
+                Cond::LT => n != v,
+                Cond::GT => !z && n == v,
+                Cond::LE => z || n != v,
+            }
+        }
+
+        fn call(&mut self, addr: u64) {
+            let (x, y) = (self.d[0], self.d[1]);
+            if addr == pow as *const () as u64 {
+                self.d[0] = x.powf(y);
+            } else if addr == fmod as *const () as u64 {
+                self.d[0] = x % y;
+            } else {
+                let fun = self.hosts[&addr].clone();
+                let (mut ints, mut floats) = (0, 0);
+                let args: Vec<Val> = fun
+                    .args()
+                    .iter()
+                    .map(|rt| match rt {
+                        Rt::Int => {
+                            ints += 1;
+                            Val::Int(self.x[ints - 1] as i64)
+                        }
+                        Rt::Float => {
+                            floats += 1;
+                            Val::Float(self.d[floats - 1])
+                        }
+                    })
+                    .collect();
+                match fun.invoke(&args) {
+                    Val::Int(val) => self.x[0] = val as u64,
+                    Val::Float(val) => self.d[0] = val,
+                }
+            }
+        }
+
+        fn run(&mut self, code: &[u32], rt: Rt) -> Val {
+            let mut pc = 0;
+            loop {
+                let inst = Inst::decode(code[pc]).expect("Undecodable instruction");
+                let mut next = pc as i64 + 1;
+                match inst {
+                    Inst::Add { rd, rn, rm } => {
+                        self.set(rd, self.get(rn).wrapping_add(self.get(rm)))
+                    }
+                    Inst::Sub { rd, rn, rm } => {
+                        self.set(rd, self.get(rn).wrapping_sub(self.get(rm)))
+                    }
+                    Inst::Mul { rd, rn, rm } => {
+                        self.set(rd, self.get(rn).wrapping_mul(self.get(rm)))
+                    }
+                    Inst::Msub { rd, rn, rm, ra } => {
+                        let product = self.get(rn).wrapping_mul(self.get(rm));
+                        self.set(rd, self.get(ra).wrapping_sub(product))
+                    }
+                    Inst::Sdiv { rd, rn, rm } => {
+                        let (n, m) = (self.get(rn) as i64, self.get(rm) as i64);
+                        self.set(rd, if m == 0 { 0 } else { n.wrapping_div(m) as u64 })
+                    }
+                    Inst::Mov { rd, rm } => self.set(rd, self.get(rm)),
+                    Inst::Neg { rd, rm } => self.set(rd, self.get(rm).wrapping_neg()),
+                    Inst::Movz { rd, imm, hw } => self.set(rd, (imm as u64) << (16 * hw)),
+                    Inst::Movn { rd, imm, hw } => self.set(rd, !((imm as u64) << (16 * hw))),
+                    Inst::Movk { rd, imm, hw } => {
+                        let mask = 0xffffu64 << (16 * hw);
+                        self.set(rd, self.get(rd) & !mask | (imm as u64) << (16 * hw))
+                    }
+                    Inst::AddImm { rd, rn, imm } => self.set(rd, self.get(rn) + imm as u64),
+                    Inst::SubImm { rd, rn, imm } => self.set(rd, self.get(rn) - imm as u64),
+                    Inst::CmpImm { rn, imm } => {
+                        let (result, v) = (self.get(rn) as i64).overflowing_sub(imm as i64);
+                        self.flags = (result < 0, result == 0, v);
+                    }
+                    Inst::CmnImm { rn, imm } => {
+                        let (result, v) = (self.get(rn) as i64).overflowing_add(imm as i64);
+                        self.flags = (result < 0, result == 0, v);
+                    }
+                    Inst::Cmp { rn, rm } => {
+                        let (l, r) = (self.get(rn) as i64, self.get(rm) as i64);
+                        let (result, v) = l.overflowing_sub(r);
+                        self.flags = (result < 0, result == 0, v);
+                    }
+                    Inst::Cset { rd, cond } => self.set(rd, self.holds(cond) as u64),
+                    Inst::Csel { rd, rn, rm, cond } => {
+                        let val = if self.holds(cond) { rn } else { rm };
+                        self.set(rd, self.get(val))
+                    }
+                    Inst::Csneg { rd, rn, rm, cond } => {
+                        let val = if self.holds(cond) {
+                            self.get(rn)
+                        } else {
+                            self.get(rm).wrapping_neg()
+                        };
+                        self.set(rd, val)
+                    }
+                    Inst::TstLsb { rn } => self.flags = (false, self.get(rn) & 1 == 0, false),
+                    Inst::LsrImm { rd, rn, shift } => self.set(rd, self.get(rn) >> shift),
+                    Inst::Fadd { rd, rn, rm } => {
+                        self.d[rd.code() as usize] =
+                            self.d[rn.code() as usize] + self.d[rm.code() as usize]
+                    }
+                    Inst::Fsub { rd, rn, rm } => {
+                        self.d[rd.code() as usize] =
+                            self.d[rn.code() as usize] - self.d[rm.code() as usize]
+                    }
+                    Inst::Fmul { rd, rn, rm } => {
+                        self.d[rd.code() as usize] =
+                            self.d[rn.code() as usize] * self.d[rm.code() as usize]
+                    }
+                    Inst::Fdiv { rd, rn, rm } => {
+                        self.d[rd.code() as usize] =
+                            self.d[rn.code() as usize] / self.d[rm.code() as usize]
+                    }
+                    Inst::Fmov { rd, rn } => {
+                        self.d[rd.code() as usize] = self.d[rn.code() as usize]
+                    }
+                    Inst::Fneg { rd, rn } => {
+                        self.d[rd.code() as usize] = -self.d[rn.code() as usize]
+                    }
+                    Inst::Fsqrt { rd, rn } => {
+                        self.d[rd.code() as usize] = self.d[rn.code() as usize].sqrt()
+                    }
+                    Inst::Fcmp { rn, rm } => {
+                        let (l, r) = (self.d[rn.code() as usize], self.d[rm.code() as usize]);
+                        // An unordered result sets `v`, so neither `ge` nor `gt` hold.
+                        self.flags = match l.partial_cmp(&r) {
+                            None => (false, false, true),
+                            Some(Ordering::Less) => (true, false, false),
+                            Some(Ordering::Equal) => (false, true, false),
+                            Some(Ordering::Greater) => (false, false, false),
+                        };
+                    }
+                    Inst::FmovFromInt { rd, rn } => {
+                        self.d[rd.code() as usize] = f64::from_bits(self.get(rn))
+                    }
+                    Inst::Scvtf { rd, rn } => {
+                        self.d[rd.code() as usize] = self.get(rn) as i64 as f64
+                    }
+                    Inst::StrPre { rt, rn, imm } => {
+                        let addr = self.get(rn).wrapping_add(imm as u64);
+                        self.store(addr, self.get(rt));
+                        self.set(rn, addr);
+                    }
+                    Inst::LdrPost { rt, rn, imm } => {
+                        let addr = self.get(rn);
+                        self.set(rt, self.load(addr));
+                        self.set(rn, addr.wrapping_add(imm as u64));
+                    }
+                    Inst::StrPreF { rt, rn, imm } => {
+                        let addr = self.get(rn).wrapping_add(imm as u64);
+                        self.store(addr, self.d[rt.code() as usize].to_bits());
+                        self.set(rn, addr);
+                    }
+                    Inst::LdrPostF { rt, rn, imm } => {
+                        let addr = self.get(rn);
+                        self.d[rt.code() as usize] = f64::from_bits(self.load(addr));
+                        self.set(rn, addr.wrapping_add(imm as u64));
+                    }
+                    Inst::Ldr { rt, rn, imm } => self.set(rt, self.load(self.get(rn) + imm as u64)),
+                    Inst::Str { rt, rn, imm } => {
+                        self.store(self.get(rn) + imm as u64, self.get(rt))
+                    }
+                    Inst::LdrF { rt, rn, imm } => {
+                        self.d[rt.code() as usize] =
+                            f64::from_bits(self.load(self.get(rn) + imm as u64))
+                    }
+                    Inst::StrF { rt, rn, imm } => self.store(
+                        self.get(rn) + imm as u64,
+                        self.d[rt.code() as usize].to_bits(),
+                    ),
+                    Inst::StpPre { rt, rt2, rn, imm } => {
+                        let addr = self.get(rn).wrapping_add(imm as u64);
+                        self.store(addr, self.get(rt));
+                        self.store(addr + 8, self.get(rt2));
+                        self.set(rn, addr);
+                    }
+                    Inst::LdpPost { rt, rt2, rn, imm } => {
+                        let addr = self.get(rn);
+                        self.set(rt, self.load(addr));
+                        self.set(rt2, self.load(addr + 8));
+                        self.set(rn, addr.wrapping_add(imm as u64));
+                    }
+                    Inst::B { offset } => next = pc as i64 + offset as i64 / 4,
+                    Inst::BCond { cond, offset } => {
+                        if self.holds(cond) {
+                            next = pc as i64 + offset as i64 / 4;
+                        }
+                    }
+                    Inst::Cbz { rt, offset } => {
+                        if self.get(rt) == 0 {
+                            next = pc as i64 + offset as i64 / 4;
+                        }
+                    }
+                    Inst::Cbnz { rt, offset } => {
+                        if self.get(rt) != 0 {
+                            next = pc as i64 + offset as i64 / 4;
+                        }
+                    }
+                    Inst::Blr { rn } => {
+                        assert_eq!(self.sp % 16, 0, "Misaligned stack at call");
+                        self.call(self.get(rn));
+                    }
+                    Inst::Bl { offset } => {
+                        assert_eq!(self.sp % 16, 0, "Misaligned stack at call");
+                        self.x[30] = 4 * (pc as u64 + 1);
+                        next = pc as i64 + offset as i64 / 4;
+                    }
+                    Inst::Ret if self.x[30] == u64::MAX => break,
+                    Inst::Ret => next = self.x[30] as i64 / 4,
+                }
+                pc = next as usize;
+            }
+
+            assert_eq!(self.sp, STACK, "Unbalanced stack");
+            match rt {
+                Rt::Int => Val::Int(self.x[0] as i64),
+                Rt::Float => Val::Float(self.d[0]),
+            }
+        }
+    }
+
+    fn emulate(exp: &Exp, hosts: &[HostFn]) -> Val {
+        Machine::new(hosts).run(&compile(exp), exp.result_type())
+    }
+
+    fn compare(input: &str) {
+        let exp = parse(input);
+        match (emulate(&exp, &[]), exp.exec(&Env::new())) {
+            (Val::Float(actual), Val::Float(expected)) if expected.is_nan() => {
+                assert!(actual.is_nan(), "{}: {} != NaN", input, actual)
+            }
+            (actual, expected) => assert_eq!(actual, expected, "{}", input),
+        }
+    }
+
+    #[test]
+    fn test_golden() {
+        assert_eq!(
+            compile(&parse("1 + 2")),
+            vec![0xd280_0020, 0xd280_0041, 0x8b01_0000, 0xd65f_03c0]
+        );
+        // movn x0, #0 is -1; the float goes through x16.
+        assert_eq!(
+            compile(&parse("-1 * 2.5")),
+            vec![
+                0x92800000,
+                0x9e620000,
+                0xd2e8_0090,
+                0x9e67_0201,
+                0x1e61_0800,
+                0xd65f_03c0
+            ]
+        );
+        assert_eq!(
+            compile(&parse("7 % 3")),
+            vec![
+                0xd280_00e0,
+                0xd280_0061,
+                0x9ac1_0c10,
+                0x9b01_8200,
+                0xd65f_03c0
+            ]
+        );
+        assert_eq!(
+            compile(&parse("(1 + 2) / (3 + 4)")),
+            vec![
+                0xd280_0020, // movz x0, #1
+                0xd280_0041, // movz x1, #2
+                0x8b01_0000, // add x0, x0, x1
+                0xf81f_0fe0, // str x0, [sp, #-16]!
+                0xd280_0060, // movz x0, #3
+                0xd280_0081, // movz x1, #4
+                0x8b01_0000, // add x0, x0, x1
+                0xaa00_03e1, // mov x1, x0
+                0xf841_07e0, // ldr x0, [sp], #16
+                0x9ac1_0c00, // sdiv x0, x0, x1
+                0xd65f_03c0, // ret
+            ]
+        );
+    }
+
+    #[test]
+    fn test_checked() {
+        let min = "(-9223372036854775807 - 1)";
+        for input in [
+            "7 / 0".to_owned(),
+            "7 % (3 - 3)".to_owned(),
+            "(1 - 1) ^ -2".to_owned(),
+            format!("{} / -1", min),
+            format!("(1 + 2) * ((3 + 4) * ({} % (0 - 1)))", min),
+            "2.5 * ((1 + 2) * (3 / 0)) + 1.5".to_owned(),
+            "7 / 2 + 7 % -1".to_owned(),
+            format!("{} / 1 + -1 ^ -3", min),
+            "7.0 / 0".to_owned(),
+        ]
+        .iter()
+        {
+            let exp = parse(input);
+            // The status word lives at the bottom of the emulated stack.
+            let mut machine = Machine::new(&[]);
+            let val = machine.run(&assemble(&exp, true), exp.result_type());
+            let status = EvalError::from_code(machine.load(0));
+            let actual = status.map_or(Ok(val), Err);
+            assert_eq!(actual, exp.try_exec(&Env::new()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_pow_loop() {
+        let code = compile(&parse("3 ^ 5"));
+        let text: Vec<String> = code
+            .iter()
+            .map(|word| Inst::decode(*word).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            text,
+            vec![
+                "movz x0, #0x3, lsl #0",
+                "movz x1, #0x5, lsl #0",
+                "cmp x1, #0",
+                "b.ge #16",
+                "neg x1, x1",
+                "movz x16, #0x1, lsl #0",
+                "sdiv x0, x16, x0",
+                "movz x2, #0x1, lsl #0",
+                "cbz x1, #28",
+                "tst x1, #0x1",
+                "b.eq #8",
+                "mul x2, x2, x0",
+                "mul x0, x0, x0",
+                "lsr x1, x1, #1",
+                "b #-24",
+                "mov x0, x2",
+                "ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        let values: [i64; 10] = [
+            0,
+            1,
+            -1,
+            0xffff,
+            0x1_0000,
+            -0x1_0000,
+            0x1234_5678_9abc_def0,
+            i64::MAX,
+            i64::MIN,
+            -0x1234_0000_0000,
+        ];
+        for val in values.iter() {
+            let exp = Exp::Val(Val::Int(*val));
+            assert_eq!(emulate(&exp, &[]), Val::Int(*val), "{}", val);
+            let exp = Exp::Val(Val::Float(*val as f64));
+            assert_eq!(emulate(&exp, &[]), Val::Float(*val as f64), "{}", val);
+        }
+        assert_eq!(compile(&Exp::Val(Val::Int(-1))).len(), 2);
+        assert_eq!(compile(&Exp::Val(Val::Int(i64::MIN))).len(), 2);
+    }
+
+    #[test]
+    fn test_emulated() {
+        compare("(2 + 2) * 10 ^ 2");
+        compare("(1 + 2) * (3 + 4)");
+        compare("(100 / (7 - 2)) % ((3 + 4) * (1 + 1))");
+        compare("(1 + 2) / (3 * 1.5) - (10 % 4) * (2 - 0.25)");
+        compare("(1.5 + 2) % ((3 + 4) / (2 - 0.5))");
+        compare("((((1 - 2) - 3) - 4) - 5) * (6 - (7 - (8 - 9.5)))");
+        compare("2.5 ^ 3 - 2 ^ 0.5");
+        compare("-(1 + 2) * -(3.5 - 4) - -(7 % 4)");
+        compare("-2 ^ 2 + -(-9223372036854775807 - 1)");
+        compare("-(0.0 / 0) + -(1 - 0.5)");
+
+        let operands: [i64; 8] = [0, 1, -1, 2, -7, 13, i64::MAX, i64::MIN + 1];
+        for l in operands.iter() {
+            for r in operands.iter() {
+                compare(&format!("{} + {}", l, r));
+                compare(&format!("{} - {}", l, r));
+                compare(&format!("{} * {}", l, r));
+                if *r != 0 {
+                    compare(&format!("{} / {}", l, r));
+                    compare(&format!("{} % {}", l, r));
+                }
+                if *r >= 0 && *r < 70 || *l != 0 {
+                    compare(&format!("({}) ^ {}", l, r % 70));
+                }
+            }
+        }
+    }
+
+    extern "C" fn scale(a: i64, b: f64, c: i64) -> f64 {
+        (a + c) as f64 * b
+    }
+
+    extern "C" fn sub(a: i64, b: i64) -> i64 {
+        a - b
+    }
+
+    #[test]
+    fn test_host_call() {
+        let mut fns = HostFns::new();
+        fns.bind("scale", scale as extern "C" fn(i64, f64, i64) -> f64);
+        fns.bind("sub", sub as extern "C" fn(i64, i64) -> i64);
+        let hosts = [
+            fns.get("scale").unwrap().clone(),
+            fns.get("sub").unwrap().clone(),
+        ];
+
+        let inner = fns
+            .call("sub", vec![parse("10 * 3"), Exp::Val(Val::Int(4))])
+            .unwrap();
+        let exp = fns
+            .call(
+                "scale",
+                vec![inner, parse("0.5 + 1"), Exp::Val(Val::Int(-2))],
+            )
+            .unwrap();
+        let exp = Exp::Exp {
+            op: crate::parser::ast::Op::Sub,
+            left: Box::new(parse("(1.5 + 2) * 3")),
+            right: Box::new(exp),
+        };
+        assert_eq!(emulate(&exp, &hosts), exp.exec(&Env::new()));
+
+        let words = compile(&exp);
+        assert!(words.contains(&0xd63f_0200), "blr x16");
+        assert!(words.contains(&0xa9bf_7bfd), "stp x29, x30, [sp, #-16]!");
+    }
+
+    #[test]
+    fn test_builtins() {
+        compare("sqrt(2) * 3 - sqrt(0.0 - 1)");
+        assert!(compile(&parse("sqrt(2.25)")).contains(&0x1e61_c000), "fsqrt d0, d0");
+        // Integer abs, min and max don't call out.
+        compare("min(abs(-3), 2) * max(-7, abs(-9223372036854775807 - 1)) + max(4, min(9, 6))");
+
+        let exp = parse("min(abs(-3), 2) + sin(1) * max(1.5, x)");
+        let exp = exp.bind(&[("x", Rt::Float)]).unwrap();
+        let hosts = [
+            Builtin::Sin.host(&[Rt::Int]),
+            Builtin::Max.host(&[Rt::Float, Rt::Float]),
+        ];
+        for x in [0.5, 2.5].iter() {
+            let mut machine = Machine::new(&hosts);
+            machine.args(&[Val::Float(*x)]);
+            let val = machine.run(&assemble(&exp, true), exp.result_type());
+            let env = Env::new().with("x", Val::Float(*x));
+            assert_eq!(val, exp.exec(&env), "{}", x);
+        }
+    }
+
+    #[test]
+    fn test_params() {
+        let mut fns = HostFns::new();
+        fns.bind("sub", sub as extern "C" fn(i64, i64) -> i64);
+        let hosts = [fns.get("sub").unwrap().clone()];
+        let params = [("x", Rt::Int), ("y", Rt::Float)];
+        let call = fns
+            .call("sub", vec![parse("x * 3"), parse("x")])
+            .unwrap();
+        let exps = vec![
+            parse("x * 2 + y"),
+            parse("(x + 1) * (y - x) / (x % 3 + y)"),
+            parse("y ^ x"),
+            Exp::Exp {
+                op: crate::parser::ast::Op::Add,
+                left: Box::new(call),
+                right: Box::new(parse("(y * y) * (x * x)")),
+            },
+        ];
+        for exp in exps {
+            let exp = exp.bind(&params).unwrap();
+            let code = assemble(&exp, true);
+            for (x, y) in [(0, 0.5), (7, -1.5), (-3, 2.25), (40, 1e9)].iter() {
+                let mut machine = Machine::new(&hosts);
+                machine.args(&[Val::Int(*x), Val::Float(*y)]);
+                let val = machine.run(&code, exp.result_type());
+                let status = EvalError::from_code(machine.load(0));
+                let env = Env::new().with("x", Val::Int(*x)).with("y", Val::Float(*y));
+                assert_eq!(status.map_or(Ok(val), Err), exp.try_exec(&env), "{}", exp);
+            }
+        }
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let operands = ["0", "1", "-1", "2.5", "-0.0", "(0.0 / 0)"];
+        for l in operands.iter() {
+            for r in operands.iter() {
+                for op in ["<", "<=", ">", ">=", "==", "!="].iter() {
+                    compare(&format!("{} {} {}", l, op, r));
+                }
+            }
+        }
+        compare("if 1 < 2 then 3 else 4.5");
+        compare("if 0.0 / 0 then 1 else 2");
+        compare("if 0.0 then 1 else 2");
+        compare("(if 2 > 1 then 2 ^ 3 else 0) * (if 1 == 1.0 then 1.5 else 0)");
+    }
+
+    #[test]
+    fn test_program() {
+        let inputs = [
+            "sq(x) = x * x; sq(x) + sq(y)",
+            "fact(n) = if n <= 1 then 1 else n * fact(n - 1); fact(x) / fact(x - 1)",
+            "fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(x) * y",
+            "f(a, b) = if a <= 0 then b else f(a - 1, b / 2); f(x, y) + f(y, x)",
+            "g(n) = 10 / n; h(n) = g(n) + g(n - 1); h(x)",
+            "down(n) = if n == 0 then 0 else down(n - 1); down(x * 1000)",
+        ];
+        // The interpreter recurses as deep as the calls do.
+        let handle = thread::Builder::new().stack_size(64 << 20).spawn(move || {
+            for input in inputs.iter() {
+                let program = parse_program(input).unwrap();
+                let bound = program.bind(&[("x", Rt::Int), ("y", Rt::Float)]).unwrap();
+                let code = assemble_with(&bound.exp, true, &bound.funs);
+                for (x, y) in [(0, 0.5), (1, -1.5), (7, 2.25)].iter() {
+                    let mut machine = Machine::new(&[]);
+                    machine.args(&[Val::Int(*x), Val::Float(*y)]);
+                    let val = machine.run(&code, bound.exp.result_type());
+                    let status = EvalError::from_code(machine.load(0));
+                    let env = Env::new().with("x", Val::Int(*x)).with("y", Val::Float(*y));
+                    let expected = bound.try_exec(&env);
+                    assert_eq!(status.map_or(Ok(val), Err), expected, "{} at {}", input, x);
+                    assert_eq!(machine.load(8), MAX_DEPTH as u64, "{}", input);
+                }
+            }
+        });
+        handle.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_registers() {
+        for code in 0..31 {
+            assert_eq!(IntReg::from_code(code, false).code(), code);
+            assert_eq!(FloatReg::from_code(code).code(), code);
+        }
+        assert_eq!(IntReg::from_code(31, true), IntReg::SP);
+        assert_eq!(IntReg::from_code(31, false), IntReg::XZR);
+        assert_eq!(IntReg::X29.to_string(), "x29");
+        assert_eq!(FloatReg::D31.to_string(), "d31");
+    }
+}
@@ -0,0 +1,1077 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::asm::aarch64::{FloatReg, IntReg};
+
+/// Condition of `b.cond` and `cset`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Cond {
+    EQ = 0x0,
+    NE = 0x1,
+    GE = 0xa,
+    LT = 0xb,
+    GT = 0xc,
+    LE = 0xd,
+}
+
+impl Cond {
+    const ALL: [Cond; 6] = [Cond::EQ, Cond::NE, Cond::GE, Cond::LT, Cond::GT, Cond::LE];
+
+    fn from_code(code: u32) -> Option<Cond> {
+        Self::ALL.iter().copied().find(|cond| *cond as u32 == code)
+    }
+}
+
+impl Display for Cond {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// A64 instruction of the subset the backend emits. Branch offsets are in bytes, relative to
+/// the branch, and memory offsets in bytes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Inst {
+    Add {
+        rd: IntReg,
+        rn: IntReg,
+        rm: IntReg,
+    },
+    Sub {
+        rd: IntReg,
+        rn: IntReg,
+        rm: IntReg,
+    },
+    Mul {
+        rd: IntReg,
+        rn: IntReg,
+        rm: IntReg,
+    },
+    Msub {
+        rd: IntReg,
+        rn: IntReg,
+        rm: IntReg,
+        ra: IntReg,
+    },
+    Sdiv {
+        rd: IntReg,
+        rn: IntReg,
+        rm: IntReg,
+    },
+    Mov {
+        rd: IntReg,
+        rm: IntReg,
+    },
+    Neg {
+        rd: IntReg,
+        rm: IntReg,
+    },
+    Movz {
+        rd: IntReg,
+        imm: u16,
+        hw: u8,
+    },
+    Movk {
+        rd: IntReg,
+        imm: u16,
+        hw: u8,
+    },
+    Movn {
+        rd: IntReg,
+        imm: u16,
+        hw: u8,
+    },
+    AddImm {
+        rd: IntReg,
+        rn: IntReg,
+        imm: u16,
+    },
+    SubImm {
+        rd: IntReg,
+        rn: IntReg,
+        imm: u16,
+    },
+    CmpImm {
+        rn: IntReg,
+        imm: u16,
+    },
+    /// `cmn rn, #imm`: compares with `-imm`.
+    CmnImm {
+        rn: IntReg,
+        imm: u16,
+    },
+    Cmp {
+        rn: IntReg,
+        rm: IntReg,
+    },
+    /// `cset rd, cond`: sets `rd` to 1 if `cond` holds and to 0 otherwise.
+    Cset {
+        rd: IntReg,
+        cond: Cond,
+    },
+    /// `csel rd, rn, rm, cond`: `rn` if `cond` holds, `rm` otherwise.
+    Csel {
+        rd: IntReg,
+        rn: IntReg,
+        rm: IntReg,
+        cond: Cond,
+    },
+    /// `csneg rd, rn, rm, cond`: `rn` if `cond` holds, `-rm` otherwise.
+    Csneg {
+        rd: IntReg,
+        rn: IntReg,
+        rm: IntReg,
+        cond: Cond,
+    },
+    /// `tst rn, #1`
+    TstLsb {
+        rn: IntReg,
+    },
+    LsrImm {
+        rd: IntReg,
+        rn: IntReg,
+        shift: u8,
+    },
+    Fadd {
+        rd: FloatReg,
+        rn: FloatReg,
+        rm: FloatReg,
+    },
+    Fsub {
+        rd: FloatReg,
+        rn: FloatReg,
+        rm: FloatReg,
+    },
+    Fmul {
+        rd: FloatReg,
+        rn: FloatReg,
+        rm: FloatReg,
+    },
+    Fdiv {
+        rd: FloatReg,
+        rn: FloatReg,
+        rm: FloatReg,
+    },
+    Fmov {
+        rd: FloatReg,
+        rn: FloatReg,
+    },
+    Fneg {
+        rd: FloatReg,
+        rn: FloatReg,
+    },
+    Fsqrt {
+        rd: FloatReg,
+        rn: FloatReg,
+    },
+    /// `fcmp dn, dm`: an unordered result only sets C and V, so `b.ne` is the only condition
+    /// used here that holds for NaN.
+    Fcmp {
+        rn: FloatReg,
+        rm: FloatReg,
+    },
+    /// `fmov dd, xn`: moves the bits of a general purpose register.
+    FmovFromInt {
+        rd: FloatReg,
+        rn: IntReg,
+    },
+    Scvtf {
+        rd: FloatReg,
+        rn: IntReg,
+    },
+    /// `str xt, [rn, #imm]!`
+    StrPre {
+        rt: IntReg,
+        rn: IntReg,
+        imm: i16,
+    },
+    /// `ldr xt, [rn], #imm`
+    LdrPost {
+        rt: IntReg,
+        rn: IntReg,
+        imm: i16,
+    },
+    /// `str dt, [rn, #imm]!`
+    StrPreF {
+        rt: FloatReg,
+        rn: IntReg,
+        imm: i16,
+    },
+    /// `ldr dt, [rn], #imm`
+    LdrPostF {
+        rt: FloatReg,
+        rn: IntReg,
+        imm: i16,
+    },
+    /// `ldr xt, [rn, #imm]`
+    Ldr {
+        rt: IntReg,
+        rn: IntReg,
+        imm: u16,
+    },
+    /// `str xt, [rn, #imm]`
+    Str {
+        rt: IntReg,
+        rn: IntReg,
+        imm: u16,
+    },
+    /// `ldr dt, [rn, #imm]`
+    LdrF {
+        rt: FloatReg,
+        rn: IntReg,
+        imm: u16,
+    },
+    /// `str dt, [rn, #imm]`
+    StrF {
+        rt: FloatReg,
+        rn: IntReg,
+        imm: u16,
+    },
+    /// `stp xt, xt2, [rn, #imm]!`
+    StpPre {
+        rt: IntReg,
+        rt2: IntReg,
+        rn: IntReg,
+        imm: i16,
+    },
+    /// `ldp xt, xt2, [rn], #imm`
+    LdpPost {
+        rt: IntReg,
+        rt2: IntReg,
+        rn: IntReg,
+        imm: i16,
+    },
+    B {
+        offset: i32,
+    },
+    BCond {
+        cond: Cond,
+        offset: i32,
+    },
+    Cbz {
+        rt: IntReg,
+        offset: i32,
+    },
+    Cbnz {
+        rt: IntReg,
+        offset: i32,
+    },
+    Bl {
+        offset: i32,
+    },
+    Blr {
+        rn: IntReg,
+    },
+    Ret,
+}
+
+fn x(code: u32) -> IntReg {
+    IntReg::from_code(code as u8 & 31, false)
+}
+
+fn xsp(code: u32) -> IntReg {
+    IntReg::from_code(code as u8 & 31, true)
+}
+
+fn d(code: u32) -> FloatReg {
+    FloatReg::from_code(code as u8 & 31)
+}
+
+/// Sign extends the low `bits` bits of `val`.
+fn signed(val: u32, bits: u32) -> i32 {
+    ((val << (32 - bits)) as i32) >> (32 - bits)
+}
+
+impl Inst {
+    pub fn encode(&self) -> u32 {
+        fn rrr(base: u32, rd: u8, rn: u8, rm: u8) -> u32 {
+            base | (rm as u32) << 16 | (rn as u32) << 5 | rd as u32
+        }
+
+        match *self {
+            Inst::Add { rd, rn, rm } => rrr(0x8b00_0000, rd.code(), rn.code(), rm.code()),
+            Inst::Sub { rd, rn, rm } => rrr(0xcb00_0000, rd.code(), rn.code(), rm.code()),
+            Inst::Mul { rd, rn, rm } => rrr(0x9b00_7c00, rd.code(), rn.code(), rm.code()),
+            Inst::Msub { rd, rn, rm, ra } => {
+                rrr(0x9b00_8000, rd.code(), rn.code(), rm.code()) | (ra.code() as u32) << 10
+            }
+            Inst::Sdiv { rd, rn, rm } => rrr(0x9ac0_0c00, rd.code(), rn.code(), rm.code()),
+            Inst::Mov { rd, rm } => rrr(0xaa00_03e0, rd.code(), 0, rm.code()),
+            Inst::Neg { rd, rm } => rrr(0xcb00_03e0, rd.code(), 0, rm.code()),
+            Inst::Movz { rd, imm, hw } => 0xd280_0000 | mov_wide(rd, imm, hw),
+            Inst::Movk { rd, imm, hw } => 0xf280_0000 | mov_wide(rd, imm, hw),
+            Inst::Movn { rd, imm, hw } => 0x9280_0000 | mov_wide(rd, imm, hw),
+            Inst::AddImm { rd, rn, imm } => {
+                rrr(0x9100_0000, rd.code(), rn.code(), 0) | (imm as u32 & 0xfff) << 10
+            }
+            Inst::SubImm { rd, rn, imm } => {
+                rrr(0xd100_0000, rd.code(), rn.code(), 0) | (imm as u32 & 0xfff) << 10
+            }
+            Inst::CmpImm { rn, imm } => {
+                rrr(0xf100_001f, 0, rn.code(), 0) | (imm as u32 & 0xfff) << 10
+            }
+            Inst::CmnImm { rn, imm } => {
+                rrr(0xb100_001f, 0, rn.code(), 0) | (imm as u32 & 0xfff) << 10
+            }
+            Inst::Cmp { rn, rm } => rrr(0xeb00_001f, 0, rn.code(), rm.code()),
+            Inst::Cset { rd, cond } => {
+                rrr(0x9a9f_07e0, rd.code(), 0, 0) | (cond as u32 ^ 1) << 12
+            }
+            Inst::Csel { rd, rn, rm, cond } => {
+                rrr(0x9a80_0000, rd.code(), rn.code(), rm.code()) | (cond as u32) << 12
+            }
+            Inst::Csneg { rd, rn, rm, cond } => {
+                rrr(0xda80_0400, rd.code(), rn.code(), rm.code()) | (cond as u32) << 12
+            }
+            Inst::TstLsb { rn } => rrr(0xf240_001f, 0, rn.code(), 0),
+            Inst::LsrImm { rd, rn, shift } => {
+                rrr(0xd340_fc00, rd.code(), rn.code(), 0) | (shift as u32 & 63) << 16
+            }
+            Inst::Fadd { rd, rn, rm } => rrr(0x1e60_2800, rd.code(), rn.code(), rm.code()),
+            Inst::Fsub { rd, rn, rm } => rrr(0x1e60_3800, rd.code(), rn.code(), rm.code()),
+            Inst::Fmul { rd, rn, rm } => rrr(0x1e60_0800, rd.code(), rn.code(), rm.code()),
+            Inst::Fdiv { rd, rn, rm } => rrr(0x1e60_1800, rd.code(), rn.code(), rm.code()),
+            Inst::Fmov { rd, rn } => rrr(0x1e60_4000, rd.code(), rn.code(), 0),
+            Inst::Fneg { rd, rn } => rrr(0x1e61_4000, rd.code(), rn.code(), 0),
+            Inst::Fsqrt { rd, rn } => rrr(0x1e61_c000, rd.code(), rn.code(), 0),
+            Inst::Fcmp { rn, rm } => rrr(0x1e60_2000, 0, rn.code(), rm.code()),
+            Inst::FmovFromInt { rd, rn } => rrr(0x9e67_0000, rd.code(), rn.code(), 0),
+            Inst::Scvtf { rd, rn } => rrr(0x9e62_0000, rd.code(), rn.code(), 0),
+            Inst::StrPre { rt, rn, imm } => 0xf800_0c00 | index9(rt.code(), rn, imm),
+            Inst::LdrPost { rt, rn, imm } => 0xf840_0400 | index9(rt.code(), rn, imm),
+            Inst::StrPreF { rt, rn, imm } => 0xfc00_0c00 | index9(rt.code(), rn, imm),
+            Inst::LdrPostF { rt, rn, imm } => 0xfc40_0400 | index9(rt.code(), rn, imm),
+            Inst::Ldr { rt, rn, imm } => 0xf940_0000 | offset12(rt.code(), rn, imm),
+            Inst::Str { rt, rn, imm } => 0xf900_0000 | offset12(rt.code(), rn, imm),
+            Inst::LdrF { rt, rn, imm } => 0xfd40_0000 | offset12(rt.code(), rn, imm),
+            Inst::StrF { rt, rn, imm } => 0xfd00_0000 | offset12(rt.code(), rn, imm),
+            Inst::StpPre { rt, rt2, rn, imm } => 0xa980_0000 | pair(rt, rt2, rn, imm),
+            Inst::LdpPost { rt, rt2, rn, imm } => 0xa8c0_0000 | pair(rt, rt2, rn, imm),
+            Inst::B { offset } => 0x1400_0000 | (offset as u32 >> 2) & 0x3ff_ffff,
+            Inst::BCond { cond, offset } => {
+                0x5400_0000 | ((offset as u32 >> 2) & 0x7_ffff) << 5 | cond as u32
+            }
+            Inst::Cbz { rt, offset } => {
+                0xb400_0000 | ((offset as u32 >> 2) & 0x7_ffff) << 5 | rt.code() as u32
+            }
+            Inst::Cbnz { rt, offset } => {
+                0xb500_0000 | ((offset as u32 >> 2) & 0x7_ffff) << 5 | rt.code() as u32
+            }
+            Inst::Bl { offset } => 0x9400_0000 | (offset as u32 >> 2) & 0x3ff_ffff,
+            Inst::Blr { rn } => rrr(0xd63f_0000, 0, rn.code(), 0),
+            Inst::Ret => 0xd65f_03c0,
+        }
+    }
+
+    /// Decodes an instruction of the emitted subset.
+    pub fn decode(w: u32) -> Option<Inst> {
+        let (rd, rn, rm, ra) = (w & 31, (w >> 5) & 31, (w >> 16) & 31, (w >> 10) & 31);
+        let imm12 = ((w >> 10) & 0xfff) as u16;
+        let imm9 = signed(w >> 12, 9) as i16;
+        let hw = ((w >> 21) & 3) as u8;
+        let imm16 = ((w >> 5) & 0xffff) as u16;
+        let imm19 = signed(w >> 5, 19) * 4;
+
+        Some(match w {
+            _ if w & 0xffe0_ffe0 == 0xaa00_03e0 => Inst::Mov {
+                rd: x(rd),
+                rm: x(rm),
+            },
+            _ if w & 0xffe0_ffe0 == 0xcb00_03e0 => Inst::Neg {
+                rd: x(rd),
+                rm: x(rm),
+            },
+            _ if w & 0xffe0_fc00 == 0x8b00_0000 => Inst::Add {
+                rd: x(rd),
+                rn: x(rn),
+                rm: x(rm),
+            },
+            _ if w & 0xffe0_fc00 == 0xcb00_0000 => Inst::Sub {
+                rd: x(rd),
+                rn: x(rn),
+                rm: x(rm),
+            },
+            _ if w & 0xffe0_fc00 == 0x9b00_7c00 => Inst::Mul {
+                rd: x(rd),
+                rn: x(rn),
+                rm: x(rm),
+            },
+            _ if w & 0xffe0_8000 == 0x9b00_8000 => Inst::Msub {
+                rd: x(rd),
+                rn: x(rn),
+                rm: x(rm),
+                ra: x(ra),
+            },
+            _ if w & 0xffe0_fc00 == 0x9ac0_0c00 => Inst::Sdiv {
+                rd: x(rd),
+                rn: x(rn),
+                rm: x(rm),
+            },
+            _ if w & 0xff80_0000 == 0xd280_0000 => Inst::Movz {
+                rd: x(rd),
+                imm: imm16,
+                hw,
+            },
+            _ if w & 0xff80_0000 == 0xf280_0000 => Inst::Movk {
+                rd: x(rd),
+                imm: imm16,
+                hw,
+            },
+            _ if w & 0xff80_0000 == 0x9280_0000 => Inst::Movn {
+                rd: x(rd),
+                imm: imm16,
+                hw,
+            },
+            _ if w & 0xffc0_0000 == 0x9100_0000 => Inst::AddImm {
+                rd: xsp(rd),
+                rn: xsp(rn),
+                imm: imm12,
+            },
+            _ if w & 0xffc0_001f == 0xf100_001f => Inst::CmpImm {
+                rn: xsp(rn),
+                imm: imm12,
+            },
+            _ if w & 0xffc0_001f == 0xb100_001f => Inst::CmnImm {
+                rn: xsp(rn),
+                imm: imm12,
+            },
+            _ if w & 0xffe0_fc1f == 0xeb00_001f => Inst::Cmp {
+                rn: x(rn),
+                rm: x(rm),
+            },
+            _ if w & 0xffc0_0000 == 0xd100_0000 => Inst::SubImm {
+                rd: xsp(rd),
+                rn: xsp(rn),
+                imm: imm12,
+            },
+            _ if w & 0xffff_fc1f == 0xf240_001f => Inst::TstLsb { rn: x(rn) },
+            _ if w & 0xffc0_fc00 == 0xd340_fc00 => Inst::LsrImm {
+                rd: x(rd),
+                rn: x(rn),
+                shift: ((w >> 16) & 63) as u8,
+            },
+            _ if w & 0xffe0_fc00 == 0x1e60_2800 => Inst::Fadd {
+                rd: d(rd),
+                rn: d(rn),
+                rm: d(rm),
+            },
+            _ if w & 0xffe0_fc00 == 0x1e60_3800 => Inst::Fsub {
+                rd: d(rd),
+                rn: d(rn),
+                rm: d(rm),
+            },
+            _ if w & 0xffe0_fc00 == 0x1e60_0800 => Inst::Fmul {
+                rd: d(rd),
+                rn: d(rn),
+                rm: d(rm),
+            },
+            _ if w & 0xffe0_fc00 == 0x1e60_1800 => Inst::Fdiv {
+                rd: d(rd),
+                rn: d(rn),
+                rm: d(rm),
+            },
+            _ if w & 0xffff_fc00 == 0x1e60_4000 => Inst::Fmov {
+                rd: d(rd),
+                rn: d(rn),
+            },
+            _ if w & 0xffff_fc00 == 0x1e61_4000 => Inst::Fneg {
+                rd: d(rd),
+                rn: d(rn),
+            },
+            _ if w & 0xffff_fc00 == 0x1e61_c000 => Inst::Fsqrt {
+                rd: d(rd),
+                rn: d(rn),
+            },
+            _ if w & 0xffff_fc00 == 0x9e67_0000 => Inst::FmovFromInt {
+                rd: d(rd),
+                rn: x(rn),
+            },
+            _ if w & 0xffff_fc00 == 0x9e62_0000 => Inst::Scvtf {
+                rd: d(rd),
+                rn: x(rn),
+            },
+            _ if w & 0xffe0_0c00 == 0xf800_0c00 => Inst::StrPre {
+                rt: x(rd),
+                rn: xsp(rn),
+                imm: imm9,
+            },
+            _ if w & 0xffe0_0c00 == 0xf840_0400 => Inst::LdrPost {
+                rt: x(rd),
+                rn: xsp(rn),
+                imm: imm9,
+            },
+            _ if w & 0xffe0_0c00 == 0xfc00_0c00 => Inst::StrPreF {
+                rt: d(rd),
+                rn: xsp(rn),
+                imm: imm9,
+            },
+            _ if w & 0xffe0_0c00 == 0xfc40_0400 => Inst::LdrPostF {
+                rt: d(rd),
+                rn: xsp(rn),
+                imm: imm9,
+            },
+            _ if w & 0xffc0_0000 == 0xf940_0000 => Inst::Ldr {
+                rt: x(rd),
+                rn: xsp(rn),
+                imm: imm12 * 8,
+            },
+            _ if w & 0xffc0_0000 == 0xf900_0000 => Inst::Str {
+                rt: x(rd),
+                rn: xsp(rn),
+                imm: imm12 * 8,
+            },
+            _ if w & 0xffc0_0000 == 0xfd40_0000 => Inst::LdrF {
+                rt: d(rd),
+                rn: xsp(rn),
+                imm: imm12 * 8,
+            },
+            _ if w & 0xffc0_0000 == 0xfd00_0000 => Inst::StrF {
+                rt: d(rd),
+                rn: xsp(rn),
+                imm: imm12 * 8,
+            },
+            _ if w & 0xffc0_0000 == 0xa980_0000 => Inst::StpPre {
+                rt: x(rd),
+                rt2: x(ra),
+                rn: xsp(rn),
+                imm: signed(w >> 15, 7) as i16 * 8,
+            },
+            _ if w & 0xffc0_0000 == 0xa8c0_0000 => Inst::LdpPost {
+                rt: x(rd),
+                rt2: x(ra),
+                rn: xsp(rn),
+                imm: signed(w >> 15, 7) as i16 * 8,
+            },
+            _ if w & 0xfc00_0000 == 0x1400_0000 => Inst::B {
+                offset: signed(w, 26) * 4,
+            },
+            _ if w & 0xff00_0010 == 0x5400_0000 => Inst::BCond {
+                cond: Cond::from_code(w & 15)?,
+                offset: imm19,
+            },
+            _ if w & 0xff00_0000 == 0xb400_0000 => Inst::Cbz {
+                rt: x(rd),
+                offset: imm19,
+            },
+            _ if w & 0xff00_0000 == 0xb500_0000 => Inst::Cbnz {
+                rt: x(rd),
+                offset: imm19,
+            },
+            _ if w & 0xfc00_0000 == 0x9400_0000 => Inst::Bl {
+                offset: signed(w, 26) * 4,
+            },
+            _ if w & 0xffff_0fe0 == 0x9a9f_07e0 => Inst::Cset {
+                rd: x(rd),
+                cond: Cond::from_code((w >> 12) & 15 ^ 1)?,
+            },
+            _ if w & 0xffe0_0c00 == 0x9a80_0000 => Inst::Csel {
+                rd: x(rd),
+                rn: x(rn),
+                rm: x(rm),
+                cond: Cond::from_code((w >> 12) & 15)?,
+            },
+            _ if w & 0xffe0_0c00 == 0xda80_0400 => Inst::Csneg {
+                rd: x(rd),
+                rn: x(rn),
+                rm: x(rm),
+                cond: Cond::from_code((w >> 12) & 15)?,
+            },
+            _ if w & 0xffe0_fc1f == 0x1e60_2000 => Inst::Fcmp {
+                rn: d(rn),
+                rm: d(rm),
+            },
+            _ if w & 0xffff_fc1f == 0xd63f_0000 => Inst::Blr { rn: x(rn) },
+            0xd65f_03c0 => Inst::Ret,
+            _ => return None,
+        })
+    }
+}
+
+fn mov_wide(rd: IntReg, imm: u16, hw: u8) -> u32 {
+    (hw as u32 & 3) << 21 | (imm as u32) << 5 | rd.code() as u32
+}
+
+fn index9(rt: u8, rn: IntReg, imm: i16) -> u32 {
+    (imm as u32 & 0x1ff) << 12 | (rn.code() as u32) << 5 | rt as u32
+}
+
+fn offset12(rt: u8, rn: IntReg, imm: u16) -> u32 {
+    ((imm as u32 / 8) & 0xfff) << 10 | (rn.code() as u32) << 5 | rt as u32
+}
+
+fn pair(rt: IntReg, rt2: IntReg, rn: IntReg, imm: i16) -> u32 {
+    ((imm / 8) as u32 & 0x7f) << 15
+        | (rt2.code() as u32) << 10
+        | (rn.code() as u32) << 5
+        | rt.code() as u32
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Add { rd, rn, rm } => write!(f, "add {}, {}, {}", rd, rn, rm),
+            Inst::Sub { rd, rn, rm } => write!(f, "sub {}, {}, {}", rd, rn, rm),
+            Inst::Mul { rd, rn, rm } => write!(f, "mul {}, {}, {}", rd, rn, rm),
+            Inst::Msub { rd, rn, rm, ra } => write!(f, "msub {}, {}, {}, {}", rd, rn, rm, ra),
+            Inst::Sdiv { rd, rn, rm } => write!(f, "sdiv {}, {}, {}", rd, rn, rm),
+            Inst::Mov { rd, rm } => write!(f, "mov {}, {}", rd, rm),
+            Inst::Neg { rd, rm } => write!(f, "neg {}, {}", rd, rm),
+            Inst::Movz { rd, imm, hw } => write!(f, "movz {}, #{:#x}, lsl #{}", rd, imm, hw * 16),
+            Inst::Movk { rd, imm, hw } => write!(f, "movk {}, #{:#x}, lsl #{}", rd, imm, hw * 16),
+            Inst::Movn { rd, imm, hw } => write!(f, "movn {}, #{:#x}, lsl #{}", rd, imm, hw * 16),
+            Inst::AddImm { rd, rn, imm } => write!(f, "add {}, {}, #{}", rd, rn, imm),
+            Inst::SubImm { rd, rn, imm } => write!(f, "sub {}, {}, #{}", rd, rn, imm),
+            Inst::CmpImm { rn, imm } => write!(f, "cmp {}, #{}", rn, imm),
+            Inst::CmnImm { rn, imm } => write!(f, "cmn {}, #{}", rn, imm),
+            Inst::Cmp { rn, rm } => write!(f, "cmp {}, {}", rn, rm),
+            Inst::Cset { rd, cond } => write!(f, "cset {}, {}", rd, cond),
+            Inst::Csel { rd, rn, rm, cond } => write!(f, "csel {}, {}, {}, {}", rd, rn, rm, cond),
+            Inst::Csneg { rd, rn, rm, cond } => {
+                write!(f, "csneg {}, {}, {}, {}", rd, rn, rm, cond)
+            }
+            Inst::TstLsb { rn } => write!(f, "tst {}, #0x1", rn),
+            Inst::LsrImm { rd, rn, shift } => write!(f, "lsr {}, {}, #{}", rd, rn, shift),
+            Inst::Fadd { rd, rn, rm } => write!(f, "fadd {}, {}, {}", rd, rn, rm),
+            Inst::Fsub { rd, rn, rm } => write!(f, "fsub {}, {}, {}", rd, rn, rm),
+            Inst::Fmul { rd, rn, rm } => write!(f, "fmul {}, {}, {}", rd, rn, rm),
+            Inst::Fdiv { rd, rn, rm } => write!(f, "fdiv {}, {}, {}", rd, rn, rm),
+            Inst::Fmov { rd, rn } => write!(f, "fmov {}, {}", rd, rn),
+            Inst::Fneg { rd, rn } => write!(f, "fneg {}, {}", rd, rn),
+            Inst::Fsqrt { rd, rn } => write!(f, "fsqrt {}, {}", rd, rn),
+            Inst::Fcmp { rn, rm } => write!(f, "fcmp {}, {}", rn, rm),
+            Inst::FmovFromInt { rd, rn } => write!(f, "fmov {}, {}", rd, rn),
+            Inst::Scvtf { rd, rn } => write!(f, "scvtf {}, {}", rd, rn),
+            Inst::StrPre { rt, rn, imm } => write!(f, "str {}, [{}, #{}]!", rt, rn, imm),
+            Inst::LdrPost { rt, rn, imm } => write!(f, "ldr {}, [{}], #{}", rt, rn, imm),
+            Inst::StrPreF { rt, rn, imm } => write!(f, "str {}, [{}, #{}]!", rt, rn, imm),
+            Inst::LdrPostF { rt, rn, imm } => write!(f, "ldr {}, [{}], #{}", rt, rn, imm),
+            Inst::Ldr { rt, rn, imm } => write!(f, "ldr {}, [{}, #{}]", rt, rn, imm),
+            Inst::Str { rt, rn, imm } => write!(f, "str {}, [{}, #{}]", rt, rn, imm),
+            Inst::LdrF { rt, rn, imm } => write!(f, "ldr {}, [{}, #{}]", rt, rn, imm),
+            Inst::StrF { rt, rn, imm } => write!(f, "str {}, [{}, #{}]", rt, rn, imm),
+            Inst::StpPre { rt, rt2, rn, imm } => {
+                write!(f, "stp {}, {}, [{}, #{}]!", rt, rt2, rn, imm)
+            }
+            Inst::LdpPost { rt, rt2, rn, imm } => {
+                write!(f, "ldp {}, {}, [{}], #{}", rt, rt2, rn, imm)
+            }
+            Inst::B { offset } => write!(f, "b #{}", offset),
+            Inst::BCond { cond, offset } => write!(f, "b.{} #{}", cond, offset),
+            Inst::Cbz { rt, offset } => write!(f, "cbz {}, #{}", rt, offset),
+            Inst::Cbnz { rt, offset } => write!(f, "cbnz {}, #{}", rt, offset),
+            Inst::Bl { offset } => write!(f, "bl #{}", offset),
+            Inst::Blr { rn } => write!(f, "blr {}", rn),
+            Inst::Ret => write!(f, "ret"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asm::aarch64::inst::{Cond, Inst};
+    use crate::asm::aarch64::{FloatReg, IntReg};
+
+    /// Encodings checked against `llvm-mc --triple=aarch64 -show-encoding`.
+    fn golden() -> Vec<(Inst, u32, &'static str)> {
+        use FloatReg::*;
+        use IntReg::*;
+
+        vec![
+            (
+                Inst::Add {
+                    rd: X0,
+                    rn: X0,
+                    rm: X1,
+                },
+                0x8b01_0000,
+                "add x0, x0, x1",
+            ),
+            (
+                Inst::Sub {
+                    rd: X3,
+                    rn: X4,
+                    rm: X30,
+                },
+                0xcb1e_0083,
+                "sub x3, x4, x30",
+            ),
+            (
+                Inst::Mul {
+                    rd: X2,
+                    rn: X2,
+                    rm: X0,
+                },
+                0x9b00_7c42,
+                "mul x2, x2, x0",
+            ),
+            (
+                Inst::Msub {
+                    rd: X0,
+                    rn: X16,
+                    rm: X1,
+                    ra: X0,
+                },
+                0x9b01_8200,
+                "msub x0, x16, x1, x0",
+            ),
+            (
+                Inst::Sdiv {
+                    rd: X16,
+                    rn: X0,
+                    rm: X1,
+                },
+                0x9ac1_0c10,
+                "sdiv x16, x0, x1",
+            ),
+            (Inst::Mov { rd: X0, rm: X2 }, 0xaa02_03e0, "mov x0, x2"),
+            (Inst::Neg { rd: X1, rm: X1 }, 0xcb01_03e1, "neg x1, x1"),
+            (
+                Inst::Movz {
+                    rd: X0,
+                    imm: 1,
+                    hw: 0,
+                },
+                0xd280_0020,
+                "movz x0, #0x1, lsl #0",
+            ),
+            (
+                Inst::Movk {
+                    rd: X16,
+                    imm: 0xbeef,
+                    hw: 3,
+                },
+                0xf2f7_ddf0,
+                "movk x16, #0xbeef, lsl #48",
+            ),
+            (
+                Inst::Movn {
+                    rd: X0,
+                    imm: 0,
+                    hw: 0,
+                },
+                0x9280_0000,
+                "movn x0, #0x0, lsl #0",
+            ),
+            (
+                Inst::AddImm {
+                    rd: SP,
+                    rn: SP,
+                    imm: 48,
+                },
+                0x9100_c3ff,
+                "add sp, sp, #48",
+            ),
+            (
+                Inst::AddImm {
+                    rd: X29,
+                    rn: SP,
+                    imm: 0,
+                },
+                0x9100_03fd,
+                "add x29, sp, #0",
+            ),
+            (
+                Inst::SubImm {
+                    rd: SP,
+                    rn: SP,
+                    imm: 16,
+                },
+                0xd100_43ff,
+                "sub sp, sp, #16",
+            ),
+            (Inst::CmpImm { rn: X1, imm: 0 }, 0xf100_003f, "cmp x1, #0"),
+            (Inst::CmnImm { rn: X1, imm: 1 }, 0xb100_043f, "cmn x1, #1"),
+            (Inst::Cmp { rn: X0, rm: X16 }, 0xeb10_001f, "cmp x0, x16"),
+            (Inst::TstLsb { rn: X1 }, 0xf240_003f, "tst x1, #0x1"),
+            (
+                Inst::LsrImm {
+                    rd: X1,
+                    rn: X1,
+                    shift: 1,
+                },
+                0xd341_fc21,
+                "lsr x1, x1, #1",
+            ),
+            (
+                Inst::Fadd {
+                    rd: D0,
+                    rn: D0,
+                    rm: D1,
+                },
+                0x1e61_2800,
+                "fadd d0, d0, d1",
+            ),
+            (
+                Inst::Fsub {
+                    rd: D0,
+                    rn: D0,
+                    rm: D1,
+                },
+                0x1e61_3800,
+                "fsub d0, d0, d1",
+            ),
+            (
+                Inst::Fmul {
+                    rd: D0,
+                    rn: D0,
+                    rm: D1,
+                },
+                0x1e61_0800,
+                "fmul d0, d0, d1",
+            ),
+            (
+                Inst::Fdiv {
+                    rd: D31,
+                    rn: D2,
+                    rm: D17,
+                },
+                0x1e71_185f,
+                "fdiv d31, d2, d17",
+            ),
+            (Inst::Fmov { rd: D1, rn: D0 }, 0x1e60_4001, "fmov d1, d0"),
+            (Inst::Fneg { rd: D0, rn: D3 }, 0x1e61_4060, "fneg d0, d3"),
+            (Inst::Fsqrt { rd: D2, rn: D2 }, 0x1e61_c042, "fsqrt d2, d2"),
+            (
+                Inst::FmovFromInt { rd: D0, rn: X16 },
+                0x9e67_0200,
+                "fmov d0, x16",
+            ),
+            (
+                Inst::FmovFromInt { rd: D0, rn: XZR },
+                0x9e67_03e0,
+                "fmov d0, xzr",
+            ),
+            (Inst::Scvtf { rd: D0, rn: X1 }, 0x9e62_0020, "scvtf d0, x1"),
+            (
+                Inst::StrPre {
+                    rt: X1,
+                    rn: SP,
+                    imm: -16,
+                },
+                0xf81f_0fe1,
+                "str x1, [sp, #-16]!",
+            ),
+            (
+                Inst::LdrPost {
+                    rt: X1,
+                    rn: SP,
+                    imm: 16,
+                },
+                0xf841_07e1,
+                "ldr x1, [sp], #16",
+            ),
+            (
+                Inst::StrPreF {
+                    rt: D1,
+                    rn: SP,
+                    imm: -16,
+                },
+                0xfc1f_0fe1,
+                "str d1, [sp, #-16]!",
+            ),
+            (
+                Inst::LdrPostF {
+                    rt: D1,
+                    rn: SP,
+                    imm: 16,
+                },
+                0xfc41_07e1,
+                "ldr d1, [sp], #16",
+            ),
+            (
+                Inst::Ldr {
+                    rt: X3,
+                    rn: SP,
+                    imm: 40,
+                },
+                0xf940_17e3,
+                "ldr x3, [sp, #40]",
+            ),
+            (
+                Inst::Str {
+                    rt: X0,
+                    rn: X29,
+                    imm: 16,
+                },
+                0xf900_0ba0,
+                "str x0, [x29, #16]",
+            ),
+            (
+                Inst::LdrF {
+                    rt: D2,
+                    rn: SP,
+                    imm: 8,
+                },
+                0xfd40_07e2,
+                "ldr d2, [sp, #8]",
+            ),
+            (
+                Inst::StrF {
+                    rt: D0,
+                    rn: X29,
+                    imm: 24,
+                },
+                0xfd00_0fa0,
+                "str d0, [x29, #24]",
+            ),
+            (
+                Inst::StpPre {
+                    rt: X29,
+                    rt2: X30,
+                    rn: SP,
+                    imm: -16,
+                },
+                0xa9bf_7bfd,
+                "stp x29, x30, [sp, #-16]!",
+            ),
+            (
+                Inst::LdpPost {
+                    rt: X29,
+                    rt2: X30,
+                    rn: SP,
+                    imm: 16,
+                },
+                0xa8c1_7bfd,
+                "ldp x29, x30, [sp], #16",
+            ),
+            (Inst::B { offset: -16 }, 0x17ff_fffc, "b #-16"),
+            (
+                Inst::BCond {
+                    cond: Cond::GE,
+                    offset: 8,
+                },
+                0x5400_004a,
+                "b.ge #8",
+            ),
+            (
+                Inst::BCond {
+                    cond: Cond::EQ,
+                    offset: -8,
+                },
+                0x54ff_ffc0,
+                "b.eq #-8",
+            ),
+            (Inst::Cbz { rt: X1, offset: -4 }, 0xb4ff_ffe1, "cbz x1, #-4"),
+            (
+                Inst::Cbnz {
+                    rt: X16,
+                    offset: 8,
+                },
+                0xb500_0050,
+                "cbnz x16, #8",
+            ),
+            (Inst::Bl { offset: 8 }, 0x9400_0002, "bl #8"),
+            (Inst::Bl { offset: -4 }, 0x97ff_ffff, "bl #-4"),
+            (
+                Inst::Cset {
+                    rd: X0,
+                    cond: Cond::EQ,
+                },
+                0x9a9f_17e0,
+                "cset x0, eq",
+            ),
+            (
+                Inst::Cset {
+                    rd: X0,
+                    cond: Cond::LT,
+                },
+                0x9a9f_a7e0,
+                "cset x0, lt",
+            ),
+            (
+                Inst::Csel {
+                    rd: X0,
+                    rn: X0,
+                    rm: X1,
+                    cond: Cond::LT,
+                },
+                0x9a81_b000,
+                "csel x0, x0, x1, lt",
+            ),
+            (
+                Inst::Csneg {
+                    rd: X0,
+                    rn: X0,
+                    rm: X0,
+                    cond: Cond::GE,
+                },
+                0xda80_a400,
+                "csneg x0, x0, x0, ge",
+            ),
+            (Inst::Fcmp { rn: D0, rm: D1 }, 0x1e61_2000, "fcmp d0, d1"),
+            (Inst::Blr { rn: X16 }, 0xd63f_0200, "blr x16"),
+            (Inst::Ret, 0xd65f_03c0, "ret"),
+        ]
+    }
+
+    #[test]
+    fn test_encode() {
+        for (inst, word, text) in golden() {
+            assert_eq!(inst.encode(), word, "{}", text);
+            assert_eq!(inst.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (inst, word, text) in golden() {
+            assert_eq!(Inst::decode(word), Some(inst), "{}", text);
+        }
+
+        for code in 0..31 {
+            let (x, y) = (
+                IntReg::from_code(code, false),
+                IntReg::from_code(30 - code, false),
+            );
+            let (d, e) = (FloatReg::from_code(code), FloatReg::from_code(31 - code));
+            for inst in [
+                Inst::Add {
+                    rd: x,
+                    rn: y,
+                    rm: x,
+                },
+                Inst::Msub {
+                    rd: x,
+                    rn: y,
+                    rm: x,
+                    ra: y,
+                },
+                Inst::Movk {
+                    rd: x,
+                    imm: code as u16 * 1000,
+                    hw: code % 4,
+                },
+                Inst::Fsub {
+                    rd: d,
+                    rn: e,
+                    rm: d,
+                },
+                Inst::Scvtf { rd: e, rn: x },
+                Inst::Ldr {
+                    rt: x,
+                    rn: IntReg::SP,
+                    imm: code as u16 * 8,
+                },
+                Inst::StrPreF {
+                    rt: d,
+                    rn: IntReg::SP,
+                    imm: -(code as i16) * 8,
+                },
+                Inst::Cbz {
+                    rt: x,
+                    offset: (code as i32 - 15) * 4096,
+                },
+                Inst::B {
+                    offset: (code as i32 - 15) * (1 << 22),
+                },
+            ]
+            .iter()
+            {
+                assert_eq!(Inst::decode(inst.encode()), Some(*inst), "{}", inst);
+            }
+        }
+
+        assert_eq!(Inst::decode(0), None);
+        assert_eq!(Inst::decode(0xd503_201f), None);
+    }
+}
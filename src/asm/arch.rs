@@ -1,22 +1,242 @@
-use std::{io, ptr, slice};
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::slice;
+use std::sync::Arc;
 
-use anyhow::Error;
-#[cfg(any(unix))]
-use libc::{mmap, munmap, MAP_ANONYMOUS, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
+use anyhow::{anyhow, Error};
+
+#[cfg(unix)]
+use crate::asm::arena::{Arena, Slot};
+use crate::asm::host::HostFn;
+use crate::interpreter::EvalError;
+use crate::parser::ast::{Exp, Op};
 
 pub trait Bytecode {
     fn encode(&self) -> Vec<u8>;
 }
 
+/// Position in the code buffer that jumps and RIP-relative operands can refer to before it is
+/// known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rel {
+    /// x86 byte displacement, relative to the end of the field.
+    Rel8,
+    /// x86 dword displacement, relative to the end of the field.
+    Rel32,
+    /// AArch64 `b`/`bl` offset in instructions, relative to the branch. Bits 0..26.
+    Imm26,
+    /// AArch64 `b.cond`/`cbz` offset in instructions, relative to the branch. Bits 5..24.
+    Imm19,
+}
+
+impl Rel {
+    fn size(self) -> usize {
+        match self {
+            Rel::Rel8 => 1,
+            Rel::Rel32 | Rel::Imm26 | Rel::Imm19 => 4,
+        }
+    }
+}
+
+/// Displacement field waiting for its label.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Fixup {
+    at: usize,
+    label: Label,
+    rel: Rel,
+}
+
+/// How a symbol address is encoded in the code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocKind {
+    /// Eight byte address, the immediate of x86 `movabs`.
+    Abs64,
+    /// AArch64 `movz`/`movk` immediate holding bits 48..64 of the address.
+    MovwG3,
+    /// AArch64 `movk` immediate holding bits 32..48 of the address.
+    MovwG2,
+    /// AArch64 `movk` immediate holding bits 16..32 of the address.
+    MovwG1,
+    /// AArch64 `movk` immediate holding bits 0..16 of the address.
+    MovwG0,
+    /// x86 `call rel32` displacement, relative to the end of the field. Only a linker resolves
+    /// it, through the PLT when the symbol is in a shared library.
+    Plt32,
+    /// AArch64 `bl` offset in instructions, relative to the branch. Only a linker resolves it.
+    Call26,
+}
+
+/// Address of a host symbol baked into the code, so the code can be linked elsewhere.
 #[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reloc {
+    pub at: usize,
+    pub symbol: String,
+    pub kind: RelocKind,
+}
+
+impl Reloc {
+    /// Writes `addr` into the field of `code`.
+    pub fn apply(&self, code: &mut [u8], addr: usize) {
+        let at = self.at;
+        let hw = match self.kind {
+            RelocKind::Abs64 => {
+                code[at..at + 8].copy_from_slice(&(addr as u64).to_le_bytes());
+                return;
+            }
+            RelocKind::MovwG3 => 3,
+            RelocKind::MovwG2 => 2,
+            RelocKind::MovwG1 => 1,
+            RelocKind::MovwG0 => 0,
+            RelocKind::Plt32 | RelocKind::Call26 => {
+                panic!("Relative calls are resolved by the linker")
+            }
+        };
+        let mut insn = u32::from_le_bytes([code[at], code[at + 1], code[at + 2], code[at + 3]]);
+        let imm = (addr as u64 >> (16 * hw)) as u32 & 0xffff;
+        insn = insn & !(0xffff << 5) | imm << 5;
+        code[at..at + 4].copy_from_slice(&insn.to_le_bytes());
+    }
+}
+
+/// Error exits of code with a frame, one per `EvalError` that can occur. The labels are created
+/// when a guard first jumps to them and bound by `ret`, which stores the error code and returns.
+/// An error of a called function is passed on by the exit that returns with the status as the
+/// callee left it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Traps {
+    exits: Vec<(EvalError, Label)>,
+    unwind: Option<Label>,
+}
+
+impl Traps {
+    pub fn exit(&mut self, asm: &mut Asm, err: EvalError) -> Label {
+        if let Some((_, label)) = self.exits.iter().find(|(e, _)| *e == err) {
+            return *label;
+        }
+        let label = asm.label();
+        self.exits.push((err, label));
+        label
+    }
+
+    /// The exit that returns without touching the status.
+    pub fn unwind(&mut self, asm: &mut Asm) -> Label {
+        *self.unwind.get_or_insert_with(|| asm.label())
+    }
+
+    /// The exit that returns without touching the status, if a call needs it.
+    pub fn propagated(&self) -> Option<Label> {
+        self.unwind
+    }
+
+    pub fn into_exits(self) -> Vec<(EvalError, Label)> {
+        self.exits
+    }
+}
+
+/// AST nodes the code was emitted for, recorded while a listing is requested. Nodes are only
+/// told apart by their address, resolved against the compiled expression by `Listing::new`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Origins {
+    nodes: Vec<usize>,
+    active: Vec<usize>,
+    /// Offsets where the innermost active node changes.
+    marks: Vec<(usize, Option<usize>)>,
+}
+
+impl Origins {
+    /// Index of the innermost node being compiled when the byte at `offset` was emitted.
+    pub fn at(&self, offset: usize) -> Option<usize> {
+        let end = self.marks.partition_point(|(at, _)| *at <= offset);
+        end.checked_sub(1).and_then(|i| self.marks[i].1)
+    }
+
+    /// Address of the node entered `index`-th.
+    pub fn node(&self, index: usize) -> usize {
+        self.nodes[index]
+    }
+
+    /// How many nodes were entered.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Asm {
     bytes: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<Fixup>,
+    rebound: Option<Label>,
+    relocs: Vec<Reloc>,
+    origins: Option<Origins>,
+    /// Entry points of the functions of a program, by instance.
+    entries: Vec<Label>,
+    /// Set by `link_calls`.
+    linked: bool,
+}
+
+impl Default for Asm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Asm {
     pub fn new() -> Asm {
         Asm {
             bytes: Vec::with_capacity(64),
+            labels: vec![],
+            fixups: vec![],
+            rebound: None,
+            relocs: vec![],
+            origins: None,
+            entries: vec![],
+            linked: false,
+        }
+    }
+
+    /// Starts recording which AST node every instruction is emitted for.
+    pub fn record_origins(&mut self) {
+        self.origins.get_or_insert_with(Origins::default);
+    }
+
+    pub fn take_origins(&mut self) -> Option<Origins> {
+        self.origins.take()
+    }
+
+    /// Makes host calls relative to the code, so that a linker can place the code next to the
+    /// functions it calls. Such code only runs once it is linked.
+    pub fn link_calls(&mut self) {
+        self.linked = true;
+    }
+
+    pub fn links_calls(&self) -> bool {
+        self.linked
+    }
+
+    /// Code emitted from now on belongs to `node`, until the matching `leave`.
+    pub fn enter(&mut self, node: &Exp) {
+        let at = self.bytes.len();
+        if let Some(origins) = &mut self.origins {
+            origins.nodes.push(node as *const Exp as usize);
+            origins.active.push(origins.nodes.len() - 1);
+            origins.marks.push((at, origins.active.last().copied()));
+        }
+    }
+
+    pub fn leave(&mut self) {
+        let at = self.bytes.len();
+        if let Some(origins) = &mut self.origins {
+            origins.active.pop();
+            origins.marks.push((at, origins.active.last().copied()));
         }
     }
 
@@ -28,71 +248,213 @@ impl Asm {
         &self.bytes
     }
 
-    #[cfg(any(unix))]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Creates a new unbound label.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Label of the entry point of the function compiled for instance `index` of a program.
+    pub fn entry(&mut self, index: usize) -> Label {
+        while self.entries.len() <= index {
+            let label = self.label();
+            self.entries.push(label);
+        }
+        self.entries[index]
+    }
+
+    /// Binds the label to the current end of the buffer.
+    pub fn bind(&mut self, label: Label) {
+        let pos = &mut self.labels[label.0];
+        if pos.is_some() {
+            self.rebound.get_or_insert(label);
+        }
+        *pos = Some(self.bytes.len());
+    }
+
+    /// Offset of a bound label.
+    pub fn offset(&self, label: Label) -> Option<usize> {
+        self.labels[label.0]
+    }
+
+    /// Emits a displacement to `label`, counted from the end of the emitted field.
+    pub fn put_rel(&mut self, label: Label, rel: Rel) {
+        self.fixups.push(Fixup {
+            at: self.bytes.len(),
+            label,
+            rel,
+        });
+        self.bytes.resize(self.bytes.len() + rel.size(), 0);
+    }
+
+    /// Emits a fixed width instruction whose offset field refers to `label`.
+    pub fn put_branch(&mut self, insn: u32, label: Label, rel: Rel) {
+        self.fixups.push(Fixup {
+            at: self.bytes.len(),
+            label,
+            rel,
+        });
+        self.put(&insn.to_le_bytes());
+    }
+
+    /// Records that the field at `at` holds the address of `symbol`.
+    pub fn relocate(&mut self, at: usize, symbol: &str, kind: RelocKind) {
+        self.relocs.push(Reloc {
+            at,
+            symbol: symbol.to_string(),
+            kind,
+        });
+    }
+
+    pub fn relocs(&self) -> &[Reloc] {
+        &self.relocs
+    }
+
+    /// Patches every pending displacement.
+    pub fn finalize(&mut self) -> Result<(), Error> {
+        self.bytes = self.resolve()?;
+        self.fixups.clear();
+        Ok(())
+    }
+
+    fn resolve(&self) -> Result<Vec<u8>, Error> {
+        if let Some(label) = self.rebound {
+            return Err(anyhow!("Label {} is bound twice", label.0));
+        }
+
+        let mut bytes = self.bytes.clone();
+        for fixup in &self.fixups {
+            let target = self.labels[fixup.label.0]
+                .ok_or_else(|| anyhow!("Unresolved label {}", fixup.label.0))?;
+            let end = fixup.at + fixup.rel.size();
+            let disp = target as i64 - end as i64;
+            let out_of_range = |disp: i64| {
+                anyhow!(
+                    "Label {} is out of {:?} range: {} bytes at offset {}",
+                    fixup.label.0,
+                    fixup.rel,
+                    disp,
+                    fixup.at
+                )
+            };
+            match fixup.rel {
+                Rel::Rel8 => {
+                    let disp = i8::try_from(disp).map_err(|_| out_of_range(disp))?;
+                    bytes[fixup.at] = disp as u8;
+                }
+                Rel::Rel32 => {
+                    let disp = i32::try_from(disp).map_err(|_| out_of_range(disp))?;
+                    bytes[fixup.at..end].copy_from_slice(&disp.to_le_bytes());
+                }
+                Rel::Imm26 | Rel::Imm19 => {
+                    let disp = target as i64 - fixup.at as i64;
+                    let (bits, shift) = if fixup.rel == Rel::Imm26 { (26, 0) } else { (19, 5) };
+                    let insns = disp / 4;
+                    if disp % 4 != 0 || insns < -(1 << (bits - 1)) || insns >= 1 << (bits - 1) {
+                        return Err(out_of_range(disp));
+                    }
+                    let mask = (1u32 << bits) - 1;
+                    let mut insn = u32::from_le_bytes([
+                        bytes[fixup.at],
+                        bytes[fixup.at + 1],
+                        bytes[fixup.at + 2],
+                        bytes[fixup.at + 3],
+                    ]);
+                    insn = insn & !(mask << shift) | (insns as u32 & mask) << shift;
+                    bytes[fixup.at..end].copy_from_slice(&insn.to_le_bytes());
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Copies the finalized code into the shared arena.
+    #[cfg(unix)]
     pub fn prepare<T>(&self) -> Result<Elf<T>, Error> {
+        self.prepare_in(Arena::global())
+    }
+
+    /// Copies the finalized code into `arena`. The code is executable but never writable.
+    #[cfg(unix)]
+    pub fn prepare_in<T>(&self, arena: &Arena) -> Result<Elf<T>, Error> {
         if self.bytes.is_empty() {
             return Err(Error::msg("Empty buffer"));
         }
-
-        let ptr = unsafe {
-            mmap(
-                ptr::null_mut(),
-                self.bytes.len(),
-                PROT_EXEC | PROT_READ | PROT_WRITE,
-                MAP_ANONYMOUS | MAP_PRIVATE,
-                -1,
-                0,
-            )
-        };
-        if ptr == ptr::null_mut() {
-            Err(io::Error::last_os_error().into())
-        } else {
-            unsafe { ptr::copy(self.bytes.as_ptr(), ptr as *mut u8, self.bytes.len()) }
-            Ok(Elf {
-                func: ptr as *mut T,
-                size: self.bytes.len(),
-            })
-        }
+        let bytes = self.resolve()?;
+        let slot = arena.alloc(&bytes)?;
+
+        Ok(Elf {
+            slot: Arc::new(slot),
+            size: bytes.len(),
+            _t: PhantomData,
+        })
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Compiled code, kept alive by its arena slot.
+///
+/// The code is never written once it is prepared, so an `Elf` can be sent to and called from
+/// any thread. Clones share the code, which is released when the last of them is dropped.
+#[derive(Debug)]
 pub struct Elf<T> {
-    func: *mut T,
+    slot: Arc<Slot>,
     size: usize,
+    _t: PhantomData<T>,
+}
+
+impl<T> Clone for Elf<T> {
+    fn clone(&self) -> Self {
+        Elf {
+            slot: self.slot.clone(),
+            size: self.size,
+            _t: PhantomData,
+        }
+    }
 }
 
 impl<T> Elf<T> {
+    /// # Safety
+    ///
+    /// `T` must be a function pointer type matching the signature of the compiled code, and
+    /// the pointer must not be called after the last clone of the `Elf` is dropped.
     pub unsafe fn func(&self) -> T
     where
         T: Copy,
     {
-        *(&self.func as *const *mut T as *const T)
+        let ptr = self.slot.ptr();
+        *(&ptr as *const *const u8 as *const T)
     }
 
     pub fn len(&self) -> usize {
         self.size
     }
 
-    pub fn bytecode(&self) -> Vec<u8> {
-        Vec::from(unsafe { slice::from_raw_parts(self.func as *const u8, self.size) })
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
     }
-}
 
-#[cfg(any(unix))]
-impl<T> Drop for Elf<T> {
-    fn drop(&mut self) {
-        unsafe {
-            let result = munmap(self.func as *mut _, self.size);
-            debug_assert!(result >= 0);
-        }
+    pub fn bytecode(&self) -> Vec<u8> {
+        Vec::from(unsafe { slice::from_raw_parts(self.slot.ptr(), self.size) })
     }
 }
 
-
 pub trait Arch {
-    type IntReg: PartialEq + Eq;
-    type FloatReg: PartialEq + Eq;
+    type IntReg: PartialEq + Eq + Copy;
+    type FloatReg: PartialEq + Eq + Copy;
+
+    /// Name of the backend, as in target triples.
+    const NAME: &'static str;
+
+    /// `e_machine` of ELF files holding code for this architecture.
+    const ELF_MACHINE: u16;
 
     const INT_RET: Self::IntReg;
     const FLOAT_RET: Self::FloatReg;
@@ -111,6 +473,22 @@ pub trait Arch {
 
     fn castf(&mut self, from: Self::IntReg, to: Self::FloatReg);
 
+    /// Negates `reg` in place, wrapping `i64::MIN` to itself.
+    fn negi(&mut self, reg: Self::IntReg);
+    /// Flips the sign of `reg` in place, NaNs and zeros included.
+    fn negf(&mut self, reg: Self::FloatReg);
+
+    /// Replaces `reg` with its correctly rounded square root.
+    fn sqrtf(&mut self, reg: Self::FloatReg);
+
+    /// Replaces `reg` with its absolute value, wrapping `i64::MIN` to itself.
+    fn absi(&mut self, reg: Self::IntReg);
+
+    /// Keeps the smaller of `INT_ACC` and `op` in `INT_ACC`.
+    fn mini(&mut self, op: Self::IntReg);
+    /// Keeps the larger of `INT_ACC` and `op` in `INT_ACC`.
+    fn maxi(&mut self, op: Self::IntReg);
+
     fn addi(&mut self, op: Self::IntReg);
     fn addf(&mut self, op: Self::FloatReg);
 
@@ -138,9 +516,126 @@ pub trait Arch {
     fn pushi(&mut self, reg: Self::IntReg);
     fn pushf(&mut self, reg: Self::FloatReg);
 
+    /// Calls a host function. The arguments are popped from the stack, where the last one is on
+    /// top, and the result is left in `INT_ACC` or `FLOAT_ACC`. `INT_TMP` and `FLOAT_TMP` survive
+    /// the call.
+    fn call(&mut self, fun: &HostFn);
+
+    /// Compares `INT_ACC` with `op` and sets `INT_ACC` to 1 if the comparison `cmp` holds and
+    /// to 0 otherwise.
+    fn cmpi(&mut self, op: Self::IntReg, cmp: Op);
+    /// Compares `FLOAT_ACC` with `op` and sets `INT_ACC` to 1 if the comparison `cmp` holds and
+    /// to 0 otherwise. Only `!=` holds when either is NaN.
+    fn cmpf(&mut self, op: Self::FloatReg, cmp: Op);
+
+    fn jump(&mut self, label: Label);
+    /// Jumps to `label` if `reg` is zero.
+    fn jump_zero(&mut self, reg: Self::IntReg, label: Label);
+
+    /// Makes room on the stack for `slots` arguments of a function of the program.
+    fn reserve(&mut self, slots: usize);
+    /// Stores an argument in `slot` of the room made by `reserve`. Whatever was pushed since
+    /// has to be popped first.
+    fn setargi(&mut self, reg: Self::IntReg, slot: usize);
+    fn setargf(&mut self, reg: Self::FloatReg, slot: usize);
+
+    /// Calls the function of the program at `target` with the `slots` arguments set up by
+    /// `reserve`, and drops them. The result is left in `INT_ACC` or `FLOAT_ACC`; no other
+    /// register but those of the frame survives. Each call takes one from the depth budget for
+    /// its duration, and past the budget `EvalError::StackOverflow` is reported instead. An
+    /// error in the callee leaves early with its status. Requires a frame.
+    fn apply(&mut self, target: Label, slots: usize);
+
+    /// Sets up a frame for code that reports errors, reads parameters or calls functions of the
+    /// program. The function then takes a pointer to a status word, followed by the depth budget
+    /// for calls, and a pointer to its arguments, 8 bytes each. A failing integer division stores
+    /// `EvalError::code` in the status word and returns early. Must come before any other code;
+    /// without a frame division by zero and `i64::MIN / -1` behave as the hardware does, and
+    /// parameters can't be read.
+    fn frame(&mut self);
+
+    /// Loads the integer argument in `slot`. Requires a frame.
+    fn loadi(&mut self, reg: Self::IntReg, slot: usize);
+    /// Loads the float argument in `slot`. Requires a frame.
+    fn loadf(&mut self, reg: Self::FloatReg, slot: usize);
+
     fn ret(&mut self);
+
+    fn asm(&mut self) -> &mut Asm;
+
+    /// Address of a library function the backend calls on its own, such as `pow`.
+    fn library(symbol: &str) -> Option<usize>;
+
+    /// Decodes code of this architecture into instructions in the backend's assembly syntax.
+    fn disassemble(code: &[u8]) -> Result<Vec<(Range<usize>, String)>, Error>;
 }
 
-pub trait DebugMod {
-    fn debug_mod(&mut self, debug_mod: bool);
-}
\ No newline at end of file
+#[cfg(all(test, unix))]
+mod test {
+    use std::ptr;
+
+    use libc::{_exit, fork, waitpid, SIGBUS, SIGSEGV, WIFSIGNALED, WTERMSIG};
+
+    use crate::asm::arch::{Asm, Elf};
+    use crate::asm::arena::Arena;
+
+    /// `mov eax, 42; ret`
+    fn answer(arena: &Arena) -> Elf<extern "C" fn() -> i64> {
+        let mut asm = Asm::new();
+        asm.put(&[0xb8, 42, 0, 0, 0, 0xc3]);
+        asm.prepare_in(arena).unwrap()
+    }
+
+    #[test]
+    fn test_prepare() {
+        let elf = answer(Arena::global());
+        assert_eq!(elf.len(), 6);
+        assert_eq!(elf.bytecode(), vec![0xb8, 42, 0, 0, 0, 0xc3]);
+
+        // Bigger than a chunk.
+        let mut asm = Asm::new();
+        asm.put(&vec![0x90; 100_000]);
+        asm.put(&[0xc3]);
+        let elf: Elf<extern "C" fn()> = asm.prepare().unwrap();
+        let fun = unsafe { elf.func() };
+        fun();
+
+        assert!(Asm::new().prepare::<extern "C" fn()>().is_err());
+    }
+
+    /// Writes to `ptr` in a child so that the fault doesn't take the test runner down.
+    fn write_faults(ptr: *const u8) -> bool {
+        let pid = unsafe { fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            unsafe {
+                ptr::write_volatile(ptr as *mut u8, 0xc3);
+                _exit(0);
+            }
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { waitpid(pid, &mut status, 0) }, pid);
+        WIFSIGNALED(status) && matches!(WTERMSIG(status), SIGSEGV | SIGBUS)
+    }
+
+    #[test]
+    fn test_write_faults() {
+        let arena = Arena::new();
+        let elf = answer(&arena);
+        assert_eq!(unsafe { elf.func() }(), 42);
+        assert!(write_faults(elf.slot.ptr()), "Write to executable code succeeded");
+        assert_eq!(unsafe { elf.func() }(), 42);
+
+        // Released and reused code is only ever written through the arena's writable view.
+        let ptr = elf.slot.ptr();
+        drop(elf);
+        assert!(write_faults(ptr), "Write to released code succeeded");
+        let mut asm = Asm::new();
+        asm.put(&[0xb8, 7, 0, 0, 0, 0xc3]);
+        let elf: Elf<extern "C" fn() -> i64> = asm.prepare_in(&arena).unwrap();
+        assert_eq!(elf.slot.ptr(), ptr);
+        assert_eq!(unsafe { elf.func() }(), 7);
+        assert!(write_faults(elf.slot.ptr()), "Write to reused code succeeded");
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::{io, ptr};
+
+use anyhow::Error;
+use libc::{
+    c_int, c_void, close, ftruncate, mmap, munmap, off_t, sysconf, _SC_PAGESIZE, MAP_FAILED,
+    MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE,
+};
+
+/// Size of a regular chunk. Larger functions get a chunk of their own.
+const CHUNK: usize = 64 * 1024;
+
+/// Alignment of every function, which keeps loop heads and call targets on fetch boundaries.
+const ALIGN: usize = 16;
+
+/// Fill of unused code, which traps when run: `int3` on x86, `udf #0` on AArch64.
+#[cfg(target_arch = "aarch64")]
+const TRAP: u8 = 0x00;
+#[cfg(not(target_arch = "aarch64"))]
+const TRAP: u8 = 0xcc;
+
+/// Allocator that packs compiled functions into shared executable chunks.
+///
+/// Each chunk is a shared memory object mapped twice: once read-write, where code is copied
+/// in, and once read-execute, where it is called. No mapping is ever writable and executable
+/// at once, yet functions can sit 16 bytes apart and many of them share a page. Unused and
+/// released code is filled with traps, and a chunk is unmapped as soon as its last slot is
+/// released.
+///
+/// A `Slot` keeps its chunk alive, so compiled code stays valid after the arena is dropped.
+#[derive(Clone, Default)]
+pub struct Arena {
+    chunks: Arc<Mutex<Vec<Weak<Chunk>>>>,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena::default()
+    }
+
+    /// Arena behind `Asm::prepare`.
+    pub fn global() -> &'static Arena {
+        static GLOBAL: OnceLock<Arena> = OnceLock::new();
+        GLOBAL.get_or_init(Arena::new)
+    }
+
+    /// Copies `code` into a free slot, mapping a new chunk when none fits.
+    pub fn alloc(&self, code: &[u8]) -> Result<Slot, Error> {
+        let len = code.len().max(1).next_multiple_of(ALIGN);
+        let (chunk, offset) = {
+            let mut chunks = self.chunks.lock().unwrap();
+            chunks.retain(|chunk| chunk.strong_count() > 0);
+            let found = chunks.iter().find_map(|chunk| {
+                let chunk = chunk.upgrade()?;
+                let offset = chunk.take(len)?;
+                Some((chunk, offset))
+            });
+            match found {
+                Some(found) => found,
+                None => {
+                    let chunk = Arc::new(Chunk::new(page_align(len).max(CHUNK))?);
+                    let offset = chunk.take(len).expect("Fresh chunk fits");
+                    chunks.push(Arc::downgrade(&chunk));
+                    (chunk, offset)
+                }
+            }
+        };
+
+        // The slot owns its bytes, and nothing calls into them before it is returned.
+        let slot = Slot { chunk, offset, len };
+        unsafe {
+            let data = slot.chunk.data.add(offset);
+            ptr::copy_nonoverlapping(code.as_ptr(), data, code.len());
+        }
+        flush(slot.ptr(), code.len());
+        Ok(slot)
+    }
+
+    /// Number of chunks currently mapped.
+    pub fn chunks(&self) -> usize {
+        let chunks = self.chunks.lock().unwrap();
+        chunks.iter().filter(|chunk| chunk.strong_count() > 0).count()
+    }
+}
+
+impl Debug for Arena {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Arena({} chunks)", self.chunks())
+    }
+}
+
+/// Bytes of an arena holding one function. They are returned to their chunk on drop.
+pub struct Slot {
+    chunk: Arc<Chunk>,
+    offset: usize,
+    len: usize,
+}
+
+impl Slot {
+    /// Start of the slot in the executable mapping.
+    pub fn ptr(&self) -> *const u8 {
+        unsafe { self.chunk.code.add(self.offset) }
+    }
+
+    /// Allocated length, a multiple of 16 bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        // Stale function pointers trap until the bytes are handed out again.
+        unsafe { ptr::write_bytes(self.chunk.data.add(self.offset), TRAP, self.len) }
+        flush(self.ptr(), self.len);
+        self.chunk.release(self.offset, self.len);
+    }
+}
+
+impl Debug for Slot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Slot({:p}, {})", self.ptr(), self.len)
+    }
+}
+
+struct Chunk {
+    /// Read-write view, only written through by the owner of a slot.
+    data: *mut u8,
+    /// Read-execute view of the same memory.
+    code: *const u8,
+    size: usize,
+    /// Free regions, offset to length. Adjacent regions are always merged.
+    free: Mutex<BTreeMap<usize, usize>>,
+}
+
+// Slots are only written through `Arena::alloc` and `Slot::drop`, by their single owner.
+unsafe impl Send for Chunk {}
+unsafe impl Sync for Chunk {}
+
+impl Chunk {
+    fn new(size: usize) -> Result<Chunk, Error> {
+        let fd = shared()?;
+        let views = unsafe {
+            if ftruncate(fd, size as off_t) != 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                map(fd, size, PROT_READ | PROT_WRITE).and_then(|data| {
+                    match map(fd, size, PROT_READ | PROT_EXEC) {
+                        Ok(code) => Ok((data, code)),
+                        Err(err) => {
+                            munmap(data, size);
+                            Err(err)
+                        }
+                    }
+                })
+            }
+        };
+        // The mappings keep the memory alive.
+        unsafe { close(fd) };
+        let (data, code) = views?;
+
+        unsafe { ptr::write_bytes(data as *mut u8, TRAP, size) }
+        let mut free = BTreeMap::new();
+        free.insert(0, size);
+        Ok(Chunk {
+            data: data as *mut u8,
+            code: code as *const u8,
+            size,
+            free: Mutex::new(free),
+        })
+    }
+
+    /// First fit.
+    fn take(&self, len: usize) -> Option<usize> {
+        let mut free = self.free.lock().unwrap();
+        let (&offset, &size) = free.iter().find(|(_, size)| **size >= len)?;
+        free.remove(&offset);
+        if size > len {
+            free.insert(offset + len, size - len);
+        }
+        Some(offset)
+    }
+
+    fn release(&self, mut offset: usize, mut len: usize) {
+        let mut free = self.free.lock().unwrap();
+        if let Some(next) = free.remove(&(offset + len)) {
+            len += next;
+        }
+        if let Some((&prev, &size)) = free.range(..offset).next_back() {
+            if prev + size == offset {
+                free.remove(&prev);
+                offset = prev;
+                len += size;
+            }
+        }
+        free.insert(offset, len);
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.data as *mut c_void, self.size);
+            munmap(self.code as *mut c_void, self.size);
+        }
+    }
+}
+
+unsafe fn map(fd: c_int, size: usize, prot: c_int) -> io::Result<*mut c_void> {
+    let view = mmap(ptr::null_mut(), size, prot, MAP_SHARED, fd, 0);
+    if view == MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(view)
+}
+
+/// Anonymous shared memory object, gone once its descriptor and mappings are.
+#[cfg(target_os = "linux")]
+fn shared() -> io::Result<c_int> {
+    let name = b"neb-arena\0";
+    let fd = unsafe { libc::memfd_create(name.as_ptr() as _, libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared() -> io::Result<c_int> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let name = format!("/neb-arena-{}-{}\0", std::process::id(), count);
+    unsafe {
+        let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+        let fd = libc::shm_open(name.as_ptr() as _, flags, 0o600);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::shm_unlink(name.as_ptr() as _);
+        Ok(fd)
+    }
+}
+
+/// Makes freshly written code visible to instruction fetch. x86 keeps the caches coherent,
+/// AArch64 needs the written lines cleaned and the instruction cache invalidated.
+#[cfg(target_arch = "aarch64")]
+fn flush(start: *const u8, len: usize) {
+    extern "C" {
+        fn __clear_cache(start: *mut libc::c_char, end: *mut libc::c_char);
+    }
+    unsafe { __clear_cache(start as _, start.add(len) as _) }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn flush(_start: *const u8, _len: usize) {}
+
+/// Rounds `len` up to a whole number of pages.
+fn page_align(len: usize) -> usize {
+    len.div_ceil(page()) * page()
+}
+
+fn page() -> usize {
+    unsafe { sysconf(_SC_PAGESIZE) as usize }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::asm::arch::{Asm, Elf};
+    use crate::asm::arena::{page, Arena, ALIGN, CHUNK, TRAP};
+
+    /// `mov eax, val; ret`
+    fn constant(arena: &Arena, val: u8) -> Elf<extern "C" fn() -> i64> {
+        let mut asm = Asm::new();
+        asm.put(&[0xb8, val, 0, 0, 0, 0xc3]);
+        asm.prepare_in(arena).unwrap()
+    }
+
+    fn call(elf: &Elf<extern "C" fn() -> i64>) -> i64 {
+        let fun = unsafe { elf.func() };
+        fun()
+    }
+
+    #[test]
+    fn test_packing() {
+        let arena = Arena::new();
+        let count = CHUNK / ALIGN;
+        let elfs: Vec<_> = (0..count).map(|val| constant(&arena, val as u8)).collect();
+        assert_eq!(arena.chunks(), 1);
+
+        let mut addrs: Vec<_> = elfs
+            .iter()
+            .map(|elf| unsafe { elf.func() } as usize)
+            .collect();
+        addrs.sort_unstable();
+        assert!(addrs.windows(2).all(|pair| pair[1] - pair[0] == ALIGN));
+
+        // Small functions share their pages.
+        let pages: HashSet<_> = addrs.iter().map(|addr| addr / page()).collect();
+        assert_eq!(pages.len(), CHUNK / page());
+        assert!(page() / ALIGN > 1);
+
+        for (val, elf) in elfs.iter().enumerate() {
+            assert_eq!(call(elf), val as u8 as i64);
+        }
+        let _more = constant(&arena, 0);
+        assert_eq!(arena.chunks(), 2);
+    }
+
+    #[test]
+    fn test_reuse() {
+        let arena = Arena::new();
+        let a = constant(&arena, 1);
+        let b = constant(&arena, 2);
+        let c = constant(&arena, 3);
+        let addr = |elf: &Elf<extern "C" fn() -> i64>| unsafe { elf.func() } as usize;
+        let (b_addr, c_addr) = (addr(&b), addr(&c));
+
+        drop(b);
+        let d = constant(&arena, 4);
+        assert_eq!(addr(&d), b_addr);
+        assert_eq!(call(&d), 4);
+
+        // Freed neighbours merge, so a function spanning two pages fits where they were.
+        drop(c);
+        drop(d);
+        let mut asm = Asm::new();
+        asm.put(&vec![0x90; page()]);
+        asm.put(&[0xb8, 5, 0, 0, 0, 0xc3]);
+        let e: Elf<extern "C" fn() -> i64> = asm.prepare_in(&arena).unwrap();
+        assert_eq!(addr(&e), b_addr);
+        assert!(addr(&e) + e.len() > c_addr);
+        assert_eq!(call(&e), 5);
+        assert_eq!(call(&a), 1);
+        assert_eq!(arena.chunks(), 1);
+    }
+
+    #[test]
+    fn test_release() {
+        let arena = Arena::new();
+        let a = constant(&arena, 1);
+        let b = constant(&arena, 2);
+        let ptr = unsafe { b.func() } as *const u8;
+        drop(b);
+
+        // The neighbour keeps running while the released bytes trap.
+        let released = unsafe { std::slice::from_raw_parts(ptr, ALIGN) };
+        assert!(released.iter().all(|&byte| byte == TRAP));
+        assert_eq!(call(&a), 1);
+    }
+
+    #[test]
+    fn test_growth() {
+        let arena = Arena::new();
+        let mut asm = Asm::new();
+        asm.put(&vec![0x90; CHUNK - 16]);
+        asm.put(&[0xb8, 7, 0, 0, 0, 0xc3]);
+        let big: Elf<extern "C" fn() -> i64> = asm.prepare_in(&arena).unwrap();
+        let small = constant(&arena, 8);
+        assert_eq!(arena.chunks(), 2);
+        assert_eq!(call(&big), 7);
+        assert_eq!(call(&small), 8);
+    }
+
+    #[test]
+    fn test_unmap() {
+        let arena = Arena::new();
+        // Fills its chunk, so the small one can't share it.
+        let mut asm = Asm::new();
+        asm.put(&vec![0x90; 2 * CHUNK - 1]);
+        asm.put(&[0xc3]);
+        let big: Elf<extern "C" fn()> = asm.prepare_in(&arena).unwrap();
+        let small = constant(&arena, 1);
+        assert_eq!(arena.chunks(), 2);
+
+        drop(big);
+        assert_eq!(arena.chunks(), 1);
+        drop(small);
+        assert_eq!(arena.chunks(), 0);
+        assert_eq!(call(&constant(&arena, 2)), 2);
+    }
+
+    #[test]
+    fn test_outlives_arena() {
+        let arena = Arena::new();
+        let elf = constant(&arena, 42);
+        drop(arena);
+        assert_eq!(call(&elf), 42);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::asm::exec::Rt;
+use crate::asm::host::HostFn;
+use crate::parser::ast::Builtin;
+
+// The float functions carry the names of their C library counterparts, so object files calling
+// them link against libm. The integer ones are only called by the interpreter, compiled code
+// computes them inline.
+
+extern "C" fn sqrt(x: f64) -> f64 {
+    x.sqrt()
+}
+
+extern "C" fn sin(x: f64) -> f64 {
+    x.sin()
+}
+
+extern "C" fn cos(x: f64) -> f64 {
+    x.cos()
+}
+
+extern "C" fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+extern "C" fn log(x: f64) -> f64 {
+    x.ln()
+}
+
+extern "C" fn fabs(x: f64) -> f64 {
+    x.abs()
+}
+
+extern "C" fn fmin(x: f64, y: f64) -> f64 {
+    x.min(y)
+}
+
+extern "C" fn fmax(x: f64, y: f64) -> f64 {
+    x.max(y)
+}
+
+/// Wraps `i64::MIN` to itself, like negation.
+extern "C" fn neb_abs(x: i64) -> i64 {
+    x.wrapping_abs()
+}
+
+extern "C" fn neb_min(x: i64, y: i64) -> i64 {
+    x.min(y)
+}
+
+extern "C" fn neb_max(x: i64, y: i64) -> i64 {
+    x.max(y)
+}
+
+impl Builtin {
+    /// Type of the result for arguments of types `args`: integral when `abs`, `min` or `max`
+    /// only get integers.
+    pub fn ret(self, args: &[Rt]) -> Rt {
+        match self {
+            Builtin::Abs | Builtin::Min | Builtin::Max if args.iter().all(|rt| *rt == Rt::Int) => {
+                Rt::Int
+            }
+            _ => Rt::Float,
+        }
+    }
+
+    /// The host function computing the builtin for arguments of types `args`. Integer
+    /// arguments of a float function are converted by the caller. The interpreter and compiled
+    /// code both go through it for float results, so they agree on every one; only `sqrt` is
+    /// compiled to an instruction, which rounds the same way.
+    pub fn host(self, args: &[Rt]) -> HostFn {
+        let int = self.ret(args) == Rt::Int;
+        type Unary<T> = extern "C" fn(T) -> T;
+        type Binary<T> = extern "C" fn(T, T) -> T;
+        match self {
+            Builtin::Sqrt => HostFn::new("sqrt", sqrt as Unary<f64>),
+            Builtin::Sin => HostFn::new("sin", sin as Unary<f64>),
+            Builtin::Cos => HostFn::new("cos", cos as Unary<f64>),
+            Builtin::Exp => HostFn::new("exp", exp as Unary<f64>),
+            Builtin::Ln => HostFn::new("log", log as Unary<f64>),
+            Builtin::Abs if int => HostFn::new("neb_abs", neb_abs as Unary<i64>),
+            Builtin::Abs => HostFn::new("fabs", fabs as Unary<f64>),
+            Builtin::Min if int => HostFn::new("neb_min", neb_min as Binary<i64>),
+            Builtin::Min => HostFn::new("fmin", fmin as Binary<f64>),
+            Builtin::Max if int => HostFn::new("neb_max", neb_max as Binary<i64>),
+            Builtin::Max => HostFn::new("fmax", fmax as Binary<f64>),
+        }
+    }
+}
+
+/// Address of the host function a builtin is compiled to a call of, by symbol.
+pub fn library(symbol: &str) -> Option<usize> {
+    Builtin::ALL
+        .iter()
+        .flat_map(|fun| {
+            [Rt::Int, Rt::Float]
+                .iter()
+                .map(move |rt| fun.host(&vec![*rt; fun.arity()]))
+        })
+        .find(|host| host.name() == symbol)
+        .map(|host| host.addr())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asm::builtin::library;
+    use crate::asm::exec::Rt;
+    use crate::parser::ast::{Builtin, Val};
+
+    #[test]
+    fn test_host() {
+        let call = |fun: Builtin, args: &[Val]| {
+            let rts: Vec<Rt> = args
+                .iter()
+                .map(|arg| if arg.is_int() { Rt::Int } else { Rt::Float })
+                .collect();
+            let host = fun.host(&rts);
+            assert_eq!(host.ret(), fun.ret(&rts), "{}", fun);
+            assert_eq!(host.args().len(), fun.arity(), "{}", fun);
+            host.invoke(args)
+        };
+        assert_eq!(call(Builtin::Sqrt, &[Val::Int(16)]), Val::Float(4.0));
+        assert_eq!(call(Builtin::Abs, &[Val::Int(-3)]), Val::Int(3));
+        assert_eq!(
+            call(Builtin::Abs, &[Val::Int(i64::MIN)]),
+            Val::Int(i64::MIN)
+        );
+        assert_eq!(call(Builtin::Abs, &[Val::Float(-0.5)]), Val::Float(0.5));
+        assert_eq!(call(Builtin::Ln, &[Val::Int(1)]), Val::Float(0.0));
+        assert_eq!(call(Builtin::Exp, &[Val::Float(0.0)]), Val::Float(1.0));
+        assert_eq!(call(Builtin::Cos, &[Val::Int(0)]), Val::Float(1.0));
+        assert_eq!(call(Builtin::Sin, &[Val::Float(0.0)]), Val::Float(0.0));
+        assert_eq!(
+            call(Builtin::Min, &[Val::Int(2), Val::Int(-5)]),
+            Val::Int(-5)
+        );
+        assert_eq!(
+            call(Builtin::Max, &[Val::Int(2), Val::Int(-5)]),
+            Val::Int(2)
+        );
+        // One float argument makes the result a float; NaN loses against a number.
+        assert_eq!(
+            call(Builtin::Max, &[Val::Int(2), Val::Float(2.5)]),
+            Val::Float(2.5)
+        );
+        assert_eq!(
+            call(Builtin::Min, &[Val::Float(f64::NAN), Val::Int(1)]),
+            Val::Float(1.0)
+        );
+
+        for fun in Builtin::ALL.iter() {
+            let host = fun.host(&[Rt::Float, Rt::Float][..fun.arity()]);
+            assert_eq!(library(host.name()), Some(host.addr()));
+        }
+        assert_eq!(
+            library("neb_min"),
+            Some(Builtin::Min.host(&[Rt::Int, Rt::Int]).addr())
+        );
+        assert_eq!(library("pow"), None);
+    }
+}
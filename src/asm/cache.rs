@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Error};
+
+use crate::asm::arch::{Arch, Asm, Reloc, RelocKind};
+use crate::asm::builtin;
+use crate::asm::exec::{AsmCode, Rt};
+use crate::asm::Fun;
+use crate::parser::ast::{Exp, Val};
+
+const MAGIC: &[u8; 4] = b"NEBC";
+
+/// Bumped whenever the entry layout or the calling convention of the code changes.
+const FORMAT: u32 = 3;
+
+/// Directory of compiled functions, one file per expression and backend.
+///
+/// An entry holds the code, its result type, the backend it was compiled by and the normalised
+/// expression, protected by a checksum. The addresses of the host functions the code calls are
+/// filled in again on load, since they change from one process to the next. Entries that are
+/// corrupt, stale or were compiled from a different expression are never executed.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Opens the cache in `dir`, creating the directory if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Cache, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Cache { dir })
+    }
+
+    /// Loads the function for `exp` taking `params`, compiling and storing it when there's no
+    /// valid entry.
+    pub fn get<A>(&self, exp: &Exp, params: &[(&str, Rt)]) -> Result<Fun<A>, Error>
+    where
+        A: Arch + Default + Into<Asm>,
+    {
+        match self.load(exp, params) {
+            Ok(Some(fun)) => Ok(fun),
+            Ok(None) | Err(_) => self.store(exp, params),
+        }
+    }
+
+    /// Loads the function for `exp` taking `params`. A missing entry is `None`, an invalid one
+    /// an error.
+    pub fn load<A: Arch>(&self, exp: &Exp, params: &[(&str, Rt)]) -> Result<Option<Fun<A>>, Error> {
+        let exp = &exp.clone().bind(params)?;
+        let key = key(exp, params);
+        let bytes = match fs::read(self.path::<A>(&key)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut entry = Entry::decode(&bytes)?;
+        if entry.backend != backend::<A>() {
+            return Err(anyhow!("Entry was compiled by {}", entry.backend));
+        }
+        if entry.key != key {
+            return Err(anyhow!("Entry was compiled from a different expression"));
+        }
+        if entry.rt != exp.result_type() {
+            return Err(anyhow!("Entry has the wrong result type"));
+        }
+
+        let mut symbols = HashMap::new();
+        host_symbols(exp, &mut symbols);
+        for reloc in &entry.relocs {
+            let addr = symbols
+                .get(reloc.symbol.as_str())
+                .copied()
+                .or_else(|| A::library(&reloc.symbol))
+                .or_else(|| builtin::library(&reloc.symbol))
+                .ok_or_else(|| anyhow!("Unknown symbol '{}'", reloc.symbol))?;
+            reloc.apply(&mut entry.code, addr);
+        }
+
+        let mut asm = Asm::new();
+        asm.put(&entry.code);
+        Fun::prepare(&asm, entry.rt, params).map(Some)
+    }
+
+    /// Compiles `exp` into a function taking `params` and writes the entry for it.
+    pub fn store<A>(&self, exp: &Exp, params: &[(&str, Rt)]) -> Result<Fun<A>, Error>
+    where
+        A: Arch + Default + Into<Asm>,
+    {
+        let (fun, asm) = Fun::compile(exp.clone(), params, A::default())?;
+        let key = key(&exp.clone().bind(params)?, params);
+        let entry = Entry {
+            backend: backend::<A>(),
+            key,
+            rt: fun.result_type(),
+            relocs: asm.relocs().to_vec(),
+            code: fun.bytecode(),
+        };
+
+        // Written under a temporary name first, so a reader never sees half an entry.
+        let path = self.path::<A>(&entry.key);
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, entry.encode())?;
+        fs::rename(&tmp, &path)?;
+        Ok(fun)
+    }
+
+    fn path<A: Arch>(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{:016x}.{}", fnv1a(key.as_bytes()), A::NAME))
+    }
+}
+
+/// Identifies code generated by this backend of this version of the crate.
+fn backend<A: Arch>() -> String {
+    format!("{}/{}/{}", A::NAME, env!("CARGO_PKG_VERSION"), FORMAT)
+}
+
+/// Normalised form of `exp` bound to `params`. Unlike `Display`, it tells integers and floats
+/// apart and includes the parameters and the signatures of host functions.
+fn key(exp: &Exp, params: &[(&str, Rt)]) -> String {
+    let mut key = String::new();
+    for (name, rt) in params {
+        write!(key, "{}:{:?},", name, rt).unwrap();
+    }
+    key.push('|');
+    write_key(exp, &mut key);
+    key
+}
+
+fn write_key(exp: &Exp, out: &mut String) {
+    match exp {
+        Exp::Val(Val::Int(val)) => write!(out, "i{}", val).unwrap(),
+        Exp::Val(Val::Float(val)) => write!(out, "f{:x}", val.to_bits()).unwrap(),
+        Exp::Var(var) => write!(out, "v{}", var.slot).unwrap(),
+        Exp::Unary { op, exp } => {
+            write!(out, "(u{} ", op).unwrap();
+            write_key(exp, out);
+            out.push(')');
+        }
+        Exp::Exp { op, left, right } => {
+            write!(out, "({} ", op).unwrap();
+            write_key(left, out);
+            out.push(' ');
+            write_key(right, out);
+            out.push(')');
+        }
+        Exp::Call { fun, args } => {
+            write!(out, "{}:{:?}->{:?}(", fun.name(), fun.args(), fun.ret()).unwrap();
+            for arg in args {
+                write_key(arg, out);
+                out.push(' ');
+            }
+            out.push(')');
+        }
+        Exp::Builtin { fun, args } => {
+            write!(out, "{}(", fun).unwrap();
+            for arg in args {
+                write_key(arg, out);
+                out.push(' ');
+            }
+            out.push(')');
+        }
+        Exp::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            out.push_str("(if ");
+            write_key(cond, out);
+            out.push(' ');
+            write_key(then, out);
+            out.push(' ');
+            write_key(otherwise, out);
+            out.push(')');
+        }
+        Exp::Apply {
+            name,
+            args,
+            rt,
+            instance,
+        } => {
+            write!(out, "{}#{}->{:?}(", name, instance, rt).unwrap();
+            for arg in args {
+                write_key(arg, out);
+                out.push(' ');
+            }
+            out.push(')');
+        }
+        Exp::Error(_) => out.push('!'),
+    }
+}
+
+fn host_symbols<'a>(exp: &'a Exp, symbols: &mut HashMap<&'a str, usize>) {
+    match exp {
+        Exp::Val(_) | Exp::Var(_) | Exp::Error(_) => {}
+        Exp::Unary { exp, .. } => host_symbols(exp, symbols),
+        Exp::Exp { left, right, .. } => {
+            host_symbols(left, symbols);
+            host_symbols(right, symbols);
+        }
+        Exp::Call { fun, args } => {
+            symbols.insert(fun.name(), fun.addr());
+            for arg in args {
+                host_symbols(arg, symbols);
+            }
+        }
+        // Resolved with `builtin::library`.
+        Exp::Builtin { args, .. } | Exp::Apply { args, .. } => {
+            for arg in args {
+                host_symbols(arg, symbols);
+            }
+        }
+        Exp::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            host_symbols(cond, symbols);
+            host_symbols(then, symbols);
+            host_symbols(otherwise, symbols);
+        }
+    }
+}
+
+/// 64-bit FNV-1a.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    backend: String,
+    key: String,
+    rt: Rt,
+    relocs: Vec<Reloc>,
+    code: Vec<u8>,
+}
+
+/// Relocations of code that runs without a linker.
+const KINDS: [RelocKind; 5] = [
+    RelocKind::Abs64,
+    RelocKind::MovwG3,
+    RelocKind::MovwG2,
+    RelocKind::MovwG1,
+    RelocKind::MovwG0,
+];
+
+impl Entry {
+    /// Magic, format, fields, then the checksum of all of that.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT.to_le_bytes());
+        put_bytes(&mut out, self.backend.as_bytes());
+        put_bytes(&mut out, self.key.as_bytes());
+        out.push((self.rt == Rt::Float) as u8);
+        out.extend_from_slice(&(self.relocs.len() as u32).to_le_bytes());
+        for reloc in &self.relocs {
+            out.extend_from_slice(&(reloc.at as u32).to_le_bytes());
+            out.push(KINDS.iter().position(|kind| *kind == reloc.kind).unwrap() as u8);
+            put_bytes(&mut out, reloc.symbol.as_bytes());
+        }
+        put_bytes(&mut out, &self.code);
+        let sum = fnv1a(&out);
+        out.extend_from_slice(&sum.to_le_bytes());
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Entry, Error> {
+        if bytes.len() < MAGIC.len() + 8 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(anyhow!("Not a cache entry"));
+        }
+        let (body, sum) = bytes.split_at(bytes.len() - 8);
+        if fnv1a(body) != u64::from_le_bytes(sum.try_into().unwrap()) {
+            return Err(anyhow!("Checksum mismatch"));
+        }
+
+        let mut r = Reader {
+            bytes: body,
+            pos: MAGIC.len(),
+        };
+        let format = r.u32()?;
+        if format != FORMAT {
+            return Err(anyhow!("Unsupported entry format {}", format));
+        }
+        let backend = r.string()?;
+        let key = r.string()?;
+        let rt = match r.byte()? {
+            0 => Rt::Int,
+            1 => Rt::Float,
+            rt => return Err(anyhow!("Invalid result type {}", rt)),
+        };
+        let mut relocs = vec![];
+        for _ in 0..r.u32()? {
+            let at = r.u32()? as usize;
+            let kind = r.byte()?;
+            let kind = *KINDS
+                .get(kind as usize)
+                .ok_or_else(|| anyhow!("Invalid relocation kind {}", kind))?;
+            let symbol = r.string()?;
+            relocs.push(Reloc { at, symbol, kind });
+        }
+        let code = r.bytes()?.to_vec();
+        if r.pos != body.len() {
+            return Err(anyhow!("Trailing bytes in cache entry"));
+        }
+        if code.is_empty() {
+            return Err(anyhow!("Empty code"));
+        }
+        let size = |kind| if kind == RelocKind::Abs64 { 8 } else { 4 };
+        if relocs
+            .iter()
+            .any(|reloc| reloc.at + size(reloc.kind) > code.len())
+        {
+            return Err(anyhow!("Relocation out of bounds"));
+        }
+
+        Ok(Entry {
+            backend,
+            key,
+            rt,
+            relocs,
+            code,
+        })
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| anyhow!("Truncated cache entry"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| anyhow!("Invalid string"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::asm::aarch64::AArch64;
+    use crate::asm::arch::Arch;
+    use crate::asm::cache::{backend, key, Cache, Entry};
+    use crate::asm::exec::Rt;
+    use crate::asm::host::HostFns;
+    use crate::asm::x86_64::X8664;
+    use crate::asm::Fun;
+    use crate::parser::ast::{parse_exp, Exp, Val};
+    use crate::parser::lexer::Lexer;
+
+    fn parse(input: &str) -> Exp {
+        let mut lexer = Lexer::new(input);
+        lexer.advance().unwrap();
+        parse_exp(&mut lexer).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("neb-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// The only file in the cache.
+    fn entry_path(cache: &Cache) -> PathBuf {
+        let mut paths: Vec<_> = fs::read_dir(&cache.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(paths.len(), 1);
+        paths.pop().unwrap()
+    }
+
+    extern "C" fn add(a: i64, b: i64) -> i64 {
+        a + b
+    }
+
+    extern "C" fn sub(a: i64, b: i64) -> i64 {
+        a - b
+    }
+
+    extern "C" fn max(a: f64, b: f64) -> f64 {
+        a.max(b)
+    }
+
+    #[test]
+    fn test_key() {
+        assert_ne!(key(&parse("1"), &[]), key(&parse("1.0"), &[]));
+        assert_eq!(key(&parse("1 + 2 * 3"), &[]), key(&parse("1+(2*3)"), &[]));
+        assert_ne!(key(&parse("(1 + 2) * 3"), &[]), key(&parse("1 + 2 * 3"), &[]));
+        assert_ne!(key(&parse("min(1, 2)"), &[]), key(&parse("max(1, 2)"), &[]));
+        assert_ne!(key(&parse("1 < 2"), &[]), key(&parse("1 <= 2"), &[]));
+        assert_ne!(
+            key(&parse("if 1 then 2 else 3"), &[]),
+            key(&parse("if 1 then 3 else 2"), &[])
+        );
+
+        let mut ints = HostFns::new();
+        ints.bind("f", add as extern "C" fn(i64, i64) -> i64);
+        let mut floats = HostFns::new();
+        floats.bind("f", max as extern "C" fn(f64, f64) -> f64);
+        let args = || vec![parse("1"), parse("2")];
+        assert_ne!(
+            key(&ints.call("f", args()).unwrap(), &[]),
+            key(&floats.call("f", args()).unwrap(), &[])
+        );
+
+        // The parameters are part of the key.
+        let bind = |input: &str, params: &[(&str, Rt)]| key(&parse(input).bind(params).unwrap(), params);
+        let (x, y) = (("x", Rt::Int), ("y", Rt::Int));
+        assert_eq!(bind("x - y", &[x, y]), bind("x - y", &[x, y]));
+        assert_ne!(bind("x - y", &[x, y]), bind("x - y", &[y, x]));
+        assert_ne!(bind("x - y", &[x, y]), bind("x - y", &[x, ("y", Rt::Float)]));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = temp_dir("roundtrip");
+        let cache = Cache::new(&dir).unwrap();
+        // Builtins are linked without being bound anywhere.
+        let inputs = [
+            "6 * 7",
+            "1.5 ^ 2.5",
+            "7.5 % 2",
+            "(1 + 2) * 3 - 4 / 5",
+            "max(abs(-6), 2) * sqrt(49)",
+            "min(1, 2.5) + cos(0)",
+        ];
+        for input in &inputs {
+            let exp = parse(input);
+            assert!(cache.load::<X8664>(&exp, &[]).unwrap().is_none());
+            let stored: Fun<X8664> = cache.get(&exp, &[]).unwrap();
+            let loaded = cache.load::<X8664>(&exp, &[]).unwrap().unwrap();
+            assert_eq!(loaded.call(&[]), stored.call(&[]), "{}", input);
+            assert_eq!(loaded.result_type(), stored.result_type());
+        }
+
+        let exp = parse("x * 2 + y");
+        let params = [("x", Rt::Int), ("y", Rt::Float)];
+        let args = [Val::Int(20), Val::Float(2.5)];
+        assert!(cache.load::<X8664>(&exp, &params).unwrap().is_none());
+        let stored: Fun<X8664> = cache.get(&exp, &params).unwrap();
+        let loaded = cache.load::<X8664>(&exp, &params).unwrap().unwrap();
+        assert_eq!(loaded.call(&args), Val::Float(42.5));
+        assert_eq!(loaded.params(), stored.params());
+        assert!(cache.load::<X8664>(&exp, &params[..1]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relocation() {
+        let dir = temp_dir("relocation");
+        let cache = Cache::new(&dir).unwrap();
+        let mut host = HostFns::new();
+        host.bind("f", add as extern "C" fn(i64, i64) -> i64);
+        let exp = host.call("f", vec![parse("10"), parse("3")]).unwrap();
+        assert_eq!(cache.get::<X8664>(&exp, &[]).unwrap().call(&[]), Val::Int(13));
+
+        // Same name and signature, somewhere else: the entry is linked against the new address.
+        host.bind("f", sub as extern "C" fn(i64, i64) -> i64);
+        let exp = host.call("f", vec![parse("10"), parse("3")]).unwrap();
+        let fun = cache.load::<X8664>(&exp, &[]).unwrap().unwrap();
+        assert_eq!(fun.call(&[]), Val::Int(7));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let dir = temp_dir("corrupt");
+        let cache = Cache::new(&dir).unwrap();
+        let exp = parse("6 * 7");
+        cache.get::<X8664>(&exp, &[]).unwrap();
+        let path = entry_path(&cache);
+        let bytes = fs::read(&path).unwrap();
+
+        // Every flipped byte is caught.
+        for i in 0..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0x40;
+            fs::write(&path, &corrupt).unwrap();
+            assert!(cache.load::<X8664>(&exp, &[]).is_err(), "byte {}", i);
+        }
+        for len in 0..bytes.len() {
+            fs::write(&path, &bytes[..len]).unwrap();
+            assert!(cache.load::<X8664>(&exp, &[]).is_err(), "length {}", len);
+        }
+
+        // `get` replaces the entry.
+        assert_eq!(cache.get::<X8664>(&exp, &[]).unwrap().call(&[]), Val::Int(42));
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale() {
+        let dir = temp_dir("stale");
+        let cache = Cache::new(&dir).unwrap();
+        let exp = parse("6 * 7");
+        cache.get::<X8664>(&exp, &[]).unwrap();
+        let path = entry_path(&cache);
+        let bytes = fs::read(&path).unwrap();
+        let entry = Entry::decode(&bytes).unwrap();
+        assert_eq!(entry.backend, backend::<X8664>());
+
+        let mut old = entry.clone();
+        old.backend = "x86_64/0.0.0/0".to_owned();
+        fs::write(&path, old.encode()).unwrap();
+        let err = cache.load::<X8664>(&exp, &[]).err().unwrap();
+        assert!(err.to_string().contains("compiled by"), "{}", err);
+
+        // An entry under the name of another expression.
+        let mut other = entry.clone();
+        other.key = key(&parse("6 * 8"), &[]);
+        fs::write(&path, other.encode()).unwrap();
+        assert!(cache.load::<X8664>(&exp, &[]).is_err());
+
+        let mut float = entry.clone();
+        float.rt = Rt::Float;
+        fs::write(&path, float.encode()).unwrap();
+        assert!(cache.load::<X8664>(&exp, &[]).is_err());
+
+        // Backends keep separate entries, and code for another machine is never loaded.
+        fs::write(&path, &bytes).unwrap();
+        assert!(cache.load::<AArch64>(&exp, &[]).unwrap().is_none());
+        fs::write(path.with_extension(AArch64::NAME), &bytes).unwrap();
+        assert!(cache.load::<AArch64>(&exp, &[]).is_err());
+        let native = cfg!(target_arch = "aarch64");
+        assert_eq!(cache.get::<AArch64>(&exp, &[]).is_ok(), native);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
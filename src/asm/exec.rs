@@ -1,10 +1,10 @@
-use anyhow::Result;
+use crate::asm::arch::Arch;
+use crate::asm::host::{HostFn, HostType};
+use crate::parser::ast::{Builtin, Exp, Op, UnOp, Val};
+use crate::parser::program::Instance;
 
-use crate::asm::arch::{Asm, Arch};
-use crate::parser::ast::{Exp, Op, Val};
 
-
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum Rt {
     Int,
     Float,
@@ -35,6 +35,9 @@ impl AsmCode for Exp {
     fn result_type(&self) -> Rt {
         match self {
             Exp::Val(val) => val.result_type(),
+            Exp::Var(var) => var.rt,
+            Exp::Unary { exp, .. } => exp.result_type(),
+            Exp::Exp { op, .. } if op.is_comparison() => Rt::Int,
             Exp::Exp { op: _, left, right } => {
                 if left.result_type() == Rt::Float || right.result_type() == Rt::Float {
                     Rt::Float
@@ -42,38 +45,38 @@ impl AsmCode for Exp {
                     Rt::Int
                 }
             }
+            Exp::Call { fun, .. } => fun.ret(),
+            Exp::Builtin { fun, args } => fun.ret(&arg_types(args)),
+            Exp::If {
+                then, otherwise, ..
+            } => then.result_type().max(otherwise.result_type()),
+            Exp::Apply { rt, .. } => *rt,
+            Exp::Error(_) => Rt::Int,
         }
     }
 
     fn to_asm<A: Arch>(&self, asm: &mut A, int: A::IntReg, float: A::FloatReg) {
+        asm.asm().enter(self);
         match self {
             Exp::Val(val) => val.to_asm::<A>(asm, int, float),
-            Exp::Exp { op, left, right } => {
-                let left_rt = left.result_type();
-                let right_rt = right.result_type();
-
-                let int_result = left_rt == Rt::Int && right_rt == Rt::Int;
-
-                let need_cast = if left_rt != right_rt { true } else { false };
-
-                if left_rt == Rt::Int {
-                    left.to_asm::<A>(asm, A::INT_ACC, A::FLOAT_ACC);
-                    if need_cast {
-                        A::castf(asm, A::INT_ACC, A::FLOAT_ACC);
-                    }
-                } else {
-                    left.to_asm::<A>(asm, A::INT_ACC, A::FLOAT_ACC);
-                }
-
-                if right_rt == Rt::Int {
-                    right.to_asm::<A>(asm, A::INT_TMP, A::FLOAT_TMP);
-                    if need_cast {
-                        A::castf(asm, A::INT_TMP, A::FLOAT_TMP);
-                    }
-                } else {
-                    right.to_asm::<A>(asm, A::INT_TMP, A::FLOAT_TMP);
+            Exp::Var(var) => match var.rt {
+                Rt::Int => A::loadi(asm, int, var.slot),
+                Rt::Float => A::loadf(asm, float, var.slot),
+            },
+            Exp::Unary { op, exp } => {
+                exp.to_asm::<A>(asm, int, float);
+                match (op, exp.result_type()) {
+                    (UnOp::Plus, _) => {}
+                    (UnOp::Neg, Rt::Int) => A::negi(asm, int),
+                    (UnOp::Neg, Rt::Float) => A::negf(asm, float),
                 }
+            }
+            Exp::Exp { op, left, right } => {
+                // Comparisons convert their operands like the other operators and give an
+                // integer whatever their type.
+                let int_result = left.result_type().max(right.result_type()) == Rt::Int;
 
+                operands(left, right, asm, int_result);
                 match op {
                     Op::Add => {
                         if int_result {
@@ -117,18 +120,199 @@ impl AsmCode for Exp {
                             A::powf(asm, A::FLOAT_TMP);
                         }
                     }
+                    Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::Eq | Op::Ne => {
+                        if int_result {
+                            A::cmpi(asm, A::INT_TMP, *op);
+                        } else {
+                            A::cmpf(asm, A::FLOAT_TMP, *op);
+                        }
+                    }
                 }
 
-                if int_result {
-                    if A::INT_ACC != int {
-                        A::movi(asm, A::INT_ACC, int);
+                move_acc(asm, self.result_type() == Rt::Int, int, float);
+            }
+            Exp::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let int_result = self.result_type() == Rt::Int;
+                let (other, done) = (asm.asm().label(), asm.asm().label());
+                cond.to_asm::<A>(asm, A::INT_ACC, A::FLOAT_ACC);
+                if cond.result_type() == Rt::Float {
+                    A::storef(asm, A::FLOAT_TMP, 0.0);
+                    A::cmpf(asm, A::FLOAT_TMP, Op::Ne);
+                }
+                A::jump_zero(asm, A::INT_ACC, other);
+                operand(then, asm, int_result, int, float);
+                A::jump(asm, done);
+                asm.asm().bind(other);
+                operand(otherwise, asm, int_result, int, float);
+                asm.asm().bind(done);
+            }
+            Exp::Apply {
+                args, rt, instance, ..
+            } => {
+                A::reserve(asm, args.len());
+                for (slot, arg) in args.iter().enumerate() {
+                    arg.to_asm::<A>(asm, A::INT_ACC, A::FLOAT_ACC);
+                    match arg.result_type() {
+                        Rt::Int => A::setargi(asm, A::INT_ACC, slot),
+                        Rt::Float => A::setargf(asm, A::FLOAT_ACC, slot),
                     }
+                }
+                let target = asm.asm().entry(*instance);
+                A::apply(asm, target, args.len());
+                move_acc(asm, *rt == Rt::Int, int, float);
+            }
+            Exp::Call { fun, args } => call(fun, args, asm, int, float),
+            Exp::Builtin {
+                fun: Builtin::Sqrt,
+                args,
+            } => {
+                operand(&args[0], asm, false, int, float);
+                A::sqrtf(asm, float);
+            }
+            // Integer `abs`, `min` and `max` are a few instructions, so object files don't need
+            // a library for them.
+            Exp::Builtin {
+                fun: Builtin::Abs,
+                args,
+            } if self.result_type() == Rt::Int => {
+                args[0].to_asm::<A>(asm, int, float);
+                A::absi(asm, int);
+            }
+            Exp::Builtin {
+                fun: fun @ (Builtin::Min | Builtin::Max),
+                args,
+            } if self.result_type() == Rt::Int => {
+                operands(&args[0], &args[1], asm, true);
+                if *fun == Builtin::Min {
+                    A::mini(asm, A::INT_TMP);
                 } else {
-                    if A::FLOAT_ACC != float {
-                        A::movf(asm, A::FLOAT_ACC, float);
-                    }
+                    A::maxi(asm, A::INT_TMP);
+                }
+                move_acc(asm, true, int, float);
+            }
+            Exp::Builtin { fun, args } => call(&fun.host(&arg_types(args)), args, asm, int, float),
+            Exp::Error(_) => unreachable!("expressions with errors aren't bound"),
+        }
+        asm.asm().leave();
+    }
+}
+
+impl Exp {
+    fn is_leaf(&self) -> bool {
+        matches!(self, Exp::Val(_) | Exp::Var(_))
+    }
+
+    /// Whether the code needs a frame (see `Arch::frame`): it reads parameters, calls functions
+    /// of the program, or evaluation can fail because an integer division, remainder or power is
+    /// computed.
+    pub(crate) fn needs_frame(&self) -> bool {
+        match self {
+            Exp::Val(_) => false,
+            Exp::Var(_) => true,
+            Exp::Unary { exp, .. } => exp.needs_frame(),
+            Exp::Exp { op, left, right } => {
+                let divides = matches!(op, Op::Div | Op::Mod | Op::Pow);
+                divides && self.result_type() == Rt::Int || left.needs_frame() || right.needs_frame()
+            }
+            Exp::Call { args, .. } | Exp::Builtin { args, .. } => {
+                args.iter().any(Exp::needs_frame)
+            }
+            Exp::If {
+                cond,
+                then,
+                otherwise,
+            } => cond.needs_frame() || then.needs_frame() || otherwise.needs_frame(),
+            Exp::Apply { .. } => true,
+            Exp::Error(_) => false,
+        }
+    }
+}
+
+/// Emits the function of instance `index` of a program at its entry label. It always has a
+/// frame, through which it gets its arguments and the status of its caller.
+pub(crate) fn instance<A: Arch>(fun: &Instance, index: usize, asm: &mut A) {
+    let entry = asm.asm().entry(index);
+    asm.asm().bind(entry);
+    A::frame(asm);
+    operand(&fun.body, asm, fun.rt == Rt::Int, A::INT_RET, A::FLOAT_RET);
+    A::ret(asm);
+}
+
+fn arg_types(args: &[Exp]) -> Vec<Rt> {
+    args.iter().map(Exp::result_type).collect()
+}
+
+/// Calls `fun` with `args`, converting integer arguments of float parameters.
+fn call<A: Arch>(fun: &HostFn, args: &[Exp], asm: &mut A, int: A::IntReg, float: A::FloatReg) {
+    for (arg, rt) in args.iter().zip(fun.args()) {
+        let int_arg = *rt == Rt::Int;
+        match arg {
+            Exp::Val(Val::Int(val)) if int_arg => A::pushli(asm, *val),
+            Exp::Val(val) => A::pushlf(asm, f64::from_val(*val)),
+            _ => {
+                operand(arg, asm, int_arg, A::INT_ACC, A::FLOAT_ACC);
+                if int_arg {
+                    A::pushi(asm, A::INT_ACC);
+                } else {
+                    A::pushf(asm, A::FLOAT_ACC);
                 }
             }
         }
     }
+    A::call(asm, fun);
+    move_acc(asm, fun.ret() == Rt::Int, int, float);
+}
+
+/// Evaluates the operands of a binary operation into the accumulator and the temporary register.
+fn operands<A: Arch>(left: &Exp, right: &Exp, asm: &mut A, int_result: bool) {
+    // Nothing is live in registers while a subtree is evaluated, so a leaf operand can be loaded
+    // straight into the temporary register. Only when both sides are trees the left result has
+    // to be spilled while the right one is computed.
+    if right.is_leaf() {
+        operand(left, asm, int_result, A::INT_ACC, A::FLOAT_ACC);
+        operand(right, asm, int_result, A::INT_TMP, A::FLOAT_TMP);
+    } else if left.is_leaf() {
+        operand(right, asm, int_result, A::INT_ACC, A::FLOAT_ACC);
+        move_acc(asm, int_result, A::INT_TMP, A::FLOAT_TMP);
+        operand(left, asm, int_result, A::INT_ACC, A::FLOAT_ACC);
+    } else {
+        operand(left, asm, int_result, A::INT_ACC, A::FLOAT_ACC);
+        if int_result {
+            A::pushi(asm, A::INT_ACC);
+        } else {
+            A::pushf(asm, A::FLOAT_ACC);
+        }
+        operand(right, asm, int_result, A::INT_ACC, A::FLOAT_ACC);
+        move_acc(asm, int_result, A::INT_TMP, A::FLOAT_TMP);
+        if int_result {
+            A::popi(asm, A::INT_ACC);
+        } else {
+            A::popf(asm, A::FLOAT_ACC);
+        }
+    }
+}
+
+/// Evaluates `exp` into `int` or `float`, converting an integer operand when the operation is
+/// performed on floats.
+fn operand<A: Arch>(exp: &Exp, asm: &mut A, int_result: bool, int: A::IntReg, float: A::FloatReg) {
+    if int_result || exp.result_type() == Rt::Float {
+        exp.to_asm::<A>(asm, int, float);
+    } else {
+        exp.to_asm::<A>(asm, int, float);
+        A::castf(asm, int, float);
+    }
+}
+
+fn move_acc<A: Arch>(asm: &mut A, int_result: bool, int: A::IntReg, float: A::FloatReg) {
+    if int_result {
+        if A::INT_ACC != int {
+            A::movi(asm, A::INT_ACC, int);
+        }
+    } else if A::FLOAT_ACC != float {
+        A::movf(asm, A::FLOAT_ACC, float);
+    }
 }
@@ -0,0 +1,222 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+
+use crate::asm::exec::{AsmCode, Rt};
+use crate::parser::ast::{Exp, Val};
+
+mod sealed {
+    /// Keeps `HostType` and `HostFunction` to the implementations in this module. Compiled code
+    /// calls the address a `HostFunction` gives with arguments in the registers its types
+    /// select, which is only sound for real `extern "C"` functions of `i64`s and `f64`s.
+    pub trait Sealed {}
+}
+
+/// Value type that can cross the boundary between compiled code and a host function. Sealed:
+/// only `i64` and `f64` implement it.
+pub trait HostType: sealed::Sealed + Copy + 'static {
+    const RT: Rt;
+
+    fn from_val(val: Val) -> Self;
+    fn into_val(self) -> Val;
+}
+
+impl sealed::Sealed for i64 {}
+
+impl HostType for i64 {
+    const RT: Rt = Rt::Int;
+
+    fn from_val(val: Val) -> Self {
+        match val {
+            Val::Int(val) => val,
+            Val::Float(val) => val as i64,
+        }
+    }
+
+    fn into_val(self) -> Val {
+        Val::Int(self)
+    }
+}
+
+impl sealed::Sealed for f64 {}
+
+impl HostType for f64 {
+    const RT: Rt = Rt::Float;
+
+    fn from_val(val: Val) -> Self {
+        match val {
+            Val::Int(val) => val as f64,
+            Val::Float(val) => val,
+        }
+    }
+
+    fn into_val(self) -> Val {
+        Val::Float(self)
+    }
+}
+
+/// `extern "C"` function that can be bound to a name and called from compiled expressions.
+///
+/// Implemented for functions of up to six `i64`/`f64` arguments, so every argument is passed in
+/// a register under the System V calling convention. Sealed, since compiled code calls `addr`
+/// as such a function.
+pub trait HostFunction: sealed::Sealed + Copy + Send + Sync + 'static {
+    fn addr(self) -> usize;
+    fn args() -> Vec<Rt>;
+    fn ret() -> Rt;
+    fn invoke(self, args: &[Val]) -> Val;
+}
+
+macro_rules! host_function {
+    ($($arg:ident),*) => {
+        impl<R: HostType, $($arg: HostType),*> sealed::Sealed for extern "C" fn($($arg),*) -> R {}
+
+        impl<R: HostType, $($arg: HostType),*> HostFunction for extern "C" fn($($arg),*) -> R {
+            fn addr(self) -> usize {
+                self as usize
+            }
+
+            fn args() -> Vec<Rt> {
+                vec![$($arg::RT),*]
+            }
+
+            fn ret() -> Rt {
+                R::RT
+            }
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn invoke(self, args: &[Val]) -> Val {
+                let mut args = args.iter();
+                $(let $arg = $arg::from_val(*args.next().expect("Arity is checked on call"));)*
+                self($($arg),*).into_val()
+            }
+        }
+    };
+}
+
+host_function!();
+host_function!(A);
+host_function!(A, B);
+host_function!(A, B, C);
+host_function!(A, B, C, D);
+host_function!(A, B, C, D, E);
+host_function!(A, B, C, D, E, F);
+
+type Invoke = dyn Fn(&[Val]) -> Val + Send + Sync;
+
+/// Host function bound to a name.
+#[derive(Clone)]
+pub struct HostFn {
+    name: String,
+    addr: usize,
+    args: Vec<Rt>,
+    ret: Rt,
+    invoke: Arc<Invoke>,
+}
+
+impl HostFn {
+    pub fn new<F: HostFunction>(name: &str, fun: F) -> HostFn {
+        HostFn {
+            name: name.to_owned(),
+            addr: fun.addr(),
+            args: F::args(),
+            ret: F::ret(),
+            invoke: Arc::new(move |args| fun.invoke(args)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn args(&self) -> &[Rt] {
+        &self.args
+    }
+
+    pub fn ret(&self) -> Rt {
+        self.ret
+    }
+
+    pub fn invoke(&self, args: &[Val]) -> Val {
+        (self.invoke)(args)
+    }
+}
+
+impl Debug for HostFn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{:#x}", self.name, self.addr)
+    }
+}
+
+impl PartialEq for HostFn {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.addr == other.addr
+    }
+}
+
+impl PartialOrd for HostFn {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some((&self.name, self.addr).cmp(&(&other.name, other.addr)))
+    }
+}
+
+/// Registry of host functions available to expressions.
+#[derive(Clone, Debug, Default)]
+pub struct HostFns {
+    funcs: HashMap<String, HostFn>,
+}
+
+impl HostFns {
+    pub fn new() -> HostFns {
+        HostFns::default()
+    }
+
+    pub fn bind<F: HostFunction>(&mut self, name: &str, fun: F) {
+        self.funcs.insert(name.to_owned(), HostFn::new(name, fun));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&HostFn> {
+        self.funcs.get(name)
+    }
+
+    /// Builds a call of the function bound to `name`. Integer arguments are converted for
+    /// float parameters, the opposite is rejected.
+    pub fn call(&self, name: &str, args: Vec<Exp>) -> Result<Exp, Error> {
+        let fun = self
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown function '{}'", name))?;
+
+        if fun.args().len() != args.len() {
+            return Err(anyhow!(
+                "Function '{}' takes {} arguments but {} were supplied",
+                name,
+                fun.args().len(),
+                args.len()
+            ));
+        }
+
+        for (i, (arg, rt)) in args.iter().zip(fun.args()).enumerate() {
+            if *rt == Rt::Int && arg.result_type() == Rt::Float {
+                return Err(anyhow!(
+                    "Argument {} of function '{}' must be an integer: {}",
+                    i + 1,
+                    name,
+                    arg
+                ));
+            }
+        }
+
+        Ok(Exp::Call {
+            fun: fun.clone(),
+            args,
+        })
+    }
+}
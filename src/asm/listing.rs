@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use crate::asm::arch::Origins;
+use crate::parser::ast::Exp;
+use crate::parser::program::Instance;
+
+/// Instruction of a compile listing.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Entry {
+    pub mnemonic: String,
+    pub operands: Vec<String>,
+    /// Bytes of the instruction in the compiled code.
+    pub range: Range<usize>,
+    /// Position in preorder of the AST node the instruction was emitted for, `None` for the
+    /// function epilogue.
+    pub node: Option<usize>,
+}
+
+/// What a compiled function consists of, decoded from the emitted bytes and mapped back to the
+/// expression it was compiled from.
+#[derive(Debug)]
+pub struct Listing {
+    entries: Vec<Entry>,
+    /// The compiled expression followed by the bodies of the functions compiled with it.
+    roots: Box<[Exp]>,
+    /// Every node of `roots`, in preorder. The roots are never changed and their nodes don't
+    /// move with the listing, so the pointers stay valid as long as it lives.
+    preorder: Vec<*const Exp>,
+    /// Nodes code was emitted for, by position in preorder.
+    nodes: Vec<usize>,
+}
+
+// The pointers only refer to the listing's own immutable nodes.
+unsafe impl Send for Listing {}
+unsafe impl Sync for Listing {}
+
+impl Listing {
+    /// Attributes decoded instructions to the nodes of `exp` and of the bodies of `funs`
+    /// recorded while they were emitted, so they must be the very expressions that were
+    /// compiled. Other nodes aren't attributed.
+    pub fn new(
+        insns: Vec<(Range<usize>, String)>,
+        origins: Origins,
+        exp: &Exp,
+        funs: &[Instance],
+    ) -> Listing {
+        let roots: Vec<&Exp> = std::iter::once(exp)
+            .chain(funs.iter().map(|fun| &fun.body))
+            .collect();
+        let positions: HashMap<usize, usize> = preorder(&roots)
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| (node as *const Exp as usize, i))
+            .collect();
+        let position = |node: usize| positions.get(&origins.node(node)).copied();
+        let entries = insns
+            .into_iter()
+            .map(|(range, text)| {
+                let (mnemonic, operands) = split(&text);
+                Entry {
+                    mnemonic,
+                    operands,
+                    node: origins.at(range.start).and_then(position),
+                    range,
+                }
+            })
+            .collect();
+        let nodes = (0..origins.len()).filter_map(position).collect();
+        let roots = roots.into_iter().cloned().collect();
+        Listing::indexed(entries, roots, nodes)
+    }
+
+    fn indexed(entries: Vec<Entry>, roots: Box<[Exp]>, nodes: Vec<usize>) -> Listing {
+        let preorder = preorder(&roots.iter().collect::<Vec<_>>())
+            .into_iter()
+            .map(|node| node as *const Exp)
+            .collect();
+        Listing {
+            entries,
+            roots,
+            preorder,
+            nodes,
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// AST node an entry was emitted for.
+    pub fn node(&self, entry: &Entry) -> Option<&Exp> {
+        entry.node.map(|node| self.at(node))
+    }
+
+    /// Every node code was emitted for, in the order compilation entered them.
+    pub fn nodes(&self) -> Vec<&Exp> {
+        self.nodes.iter().map(|node| self.at(*node)).collect()
+    }
+
+    /// The node at `position` in preorder.
+    fn at(&self, position: usize) -> &Exp {
+        // Points into `roots`, which lives as long as `self`.
+        unsafe { &*self.preorder[position] }
+    }
+}
+
+impl Clone for Listing {
+    fn clone(&self) -> Listing {
+        Listing::indexed(self.entries.clone(), self.roots.clone(), self.nodes.clone())
+    }
+}
+
+impl PartialEq for Listing {
+    fn eq(&self, other: &Listing) -> bool {
+        self.entries == other.entries && self.roots == other.roots && self.nodes == other.nodes
+    }
+}
+
+/// `roots` and their subexpressions, one after the other and every node before its children.
+fn preorder<'a>(roots: &[&'a Exp]) -> Vec<&'a Exp> {
+    let (mut nodes, mut pending) = (vec![], roots.iter().rev().copied().collect::<Vec<_>>());
+    while let Some(exp) = pending.pop() {
+        nodes.push(exp);
+        let start = pending.len();
+        match exp {
+            Exp::Val(_) | Exp::Var(_) | Exp::Error(_) => {}
+            Exp::Unary { exp, .. } => pending.push(exp),
+            Exp::Exp { left, right, .. } => pending.extend([left.as_ref(), right.as_ref()]),
+            Exp::If {
+                cond,
+                then,
+                otherwise,
+            } => pending.extend([cond.as_ref(), then.as_ref(), otherwise.as_ref()]),
+            Exp::Call { args, .. } | Exp::Builtin { args, .. } | Exp::Apply { args, .. } => {
+                pending.extend(args)
+            }
+        }
+        pending[start..].reverse();
+    }
+    nodes
+}
+
+impl Display for Listing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            let insn = format!("{} {}", entry.mnemonic, entry.operands.join(", "));
+            match self.node(entry) {
+                Some(node) => writeln!(f, "{:04x}  {:<40}; {}", entry.range.start, insn, node)?,
+                None => writeln!(f, "{:04x}  {}", entry.range.start, insn.trim_end())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits `mnemonic op, op` at the commas outside of memory operands.
+fn split(text: &str) -> (String, Vec<String>) {
+    let (mnemonic, rest) = text.split_once(' ').unwrap_or((text, ""));
+    let mut operands = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in rest.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(rest[start..i].trim().to_owned());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !rest.trim().is_empty() {
+        operands.push(rest[start..].trim().to_owned());
+    }
+    (mnemonic.to_owned(), operands)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asm::aarch64::AArch64;
+    use crate::asm::exec::Rt;
+    use crate::asm::listing::split;
+    use crate::asm::x86_64::X8664;
+    use crate::asm::Fun;
+    use crate::parser::ast::{parse_exp, parse_program, Exp, Val};
+    use crate::parser::lexer::Lexer;
+
+    fn parse(input: &str) -> Exp {
+        let mut lexer = Lexer::new(input);
+        lexer.advance().unwrap();
+        parse_exp(&mut lexer).unwrap()
+    }
+
+    #[test]
+    fn test_listing() {
+        let (fun, listing) = Fun::with_listing(parse("1 + 2"), &[], X8664::default()).unwrap();
+        let entries: Vec<_> = listing
+            .entries()
+            .iter()
+            .map(|entry| {
+                let node = listing.node(entry).map(|node| node.to_string());
+                (
+                    entry.mnemonic.as_str(),
+                    entry.operands.join(", "),
+                    entry.range.clone(),
+                    node,
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("mov", "eax, 0x1".to_owned(), 0..5, Some("1".to_owned())),
+                ("mov", "ecx, 0x2".to_owned(), 5..10, Some("2".to_owned())),
+                (
+                    "add",
+                    "rax, rcx".to_owned(),
+                    10..13,
+                    Some("(1 + 2)".to_owned())
+                ),
+                ("ret", "".to_owned(), 13..14, None),
+            ]
+        );
+        assert_eq!(listing.nodes().len(), 3);
+        assert_eq!(fun.bytecode().len(), 14);
+        assert_eq!(
+            listing.to_string(),
+            "0000  mov eax, 0x1                            ; 1\n\
+             0005  mov ecx, 0x2                            ; 2\n\
+             000a  add rax, rcx                            ; (1 + 2)\n\
+             000d  ret\n"
+        );
+    }
+
+    #[test]
+    fn test_nested() {
+        // The left operand is spilled while the right one is computed, the pop and the division
+        // belong to the root.
+        let exp = parse("(1 + 2) / (3 - 4.5)");
+        let (_, listing) = Fun::with_listing(exp.clone(), &[], X8664::default()).unwrap();
+        let root: Vec<_> = listing
+            .entries()
+            .iter()
+            .filter(|entry| listing.node(entry) == Some(&exp))
+            .map(|entry| entry.mnemonic.as_str())
+            .collect();
+        assert_eq!(
+            root,
+            vec!["cvtsi2sd", "sub", "movsd", "movapd", "movsd", "add", "divsd"]
+        );
+        assert_eq!(*listing.nodes()[0], exp);
+
+        let mut end = 0;
+        for entry in listing.entries() {
+            assert_eq!(entry.range.start, end);
+            end = entry.range.end;
+        }
+    }
+
+    #[test]
+    fn test_program() {
+        // Parameters are read through the frame, and the body of `sq` follows the expression.
+        let (fun, listing) =
+            Fun::with_listing(parse("a * 2"), &[("a", Rt::Int)], X8664::default()).unwrap();
+        assert_eq!(fun.call(&[Val::Int(21)]), Val::Int(42));
+        assert!(listing.nodes().iter().any(|node| node.to_string() == "a"));
+
+        let program = parse_program("sq(x) = x * x; sq(a) + 1").unwrap();
+        let params = [("a", Rt::Float)];
+        let (fun, listing) =
+            Fun::with_program_listing(&program, &params, X8664::default()).unwrap();
+        assert_eq!(fun.call(&[Val::Float(1.5)]), Val::Float(3.25));
+        let nodes: Vec<String> = listing
+            .nodes()
+            .iter()
+            .map(|node| node.to_string())
+            .collect();
+        assert_eq!(
+            nodes,
+            vec!["(sq(a) + 1)", "sq(a)", "a", "1", "(x * x)", "x", "x"]
+        );
+        let other = Fun::program_listing(&program, &params, AArch64::default()).unwrap();
+        assert_eq!(other.nodes().len(), nodes.len());
+        assert_eq!(listing.clone(), listing);
+    }
+
+    #[test]
+    fn test_aarch64() {
+        // The remainder may fail, so the code has a frame holding the status pointer in x19 and
+        // the arguments pointer in x20, and the error exits follow the `ret`.
+        let listing = Fun::listing(parse("7 % 3"), &[], AArch64::default()).unwrap();
+        assert_eq!(
+            listing.to_string(),
+"0000  stp x29, x30, [sp, #-16]!\n\
+             0004  add x29, sp, #0\n\
+             0008  stp x19, x20, [sp, #-16]!\n\
+             000c  mov x19, x0\n\
+             0010  mov x20, x1\n\
+             0014  movz x0, #0x7, lsl #0                   ; 7\n\
+             0018  movz x1, #0x3, lsl #0                   ; 3\n\
+             001c  cbz x1, #48                             ; (7 % 3)\n\
+             0020  cmn x1, #1                              ; (7 % 3)\n\
+             0024  b.ne #16                                ; (7 % 3)\n\
+             0028  movz x16, #0x8000, lsl #48              ; (7 % 3)\n\
+             002c  cmp x0, x16                             ; (7 % 3)\n\
+             0030  b.eq #40                                ; (7 % 3)\n\
+             0034  sdiv x16, x0, x1                        ; (7 % 3)\n\
+             0038  msub x0, x16, x1, x0                    ; (7 % 3)\n\
+             003c  sub sp, x29, #16\n\
+             0040  ldp x19, x20, [sp], #16\n\
+             0044  ldp x29, x30, [sp], #16\n\
+             0048  ret\n\
+             004c  movz x16, #0x1, lsl #0\n\
+             0050  str x16, [x19, #0]\n\
+             0054  b #-24\n\
+             0058  movz x16, #0x2, lsl #0\n\
+             005c  str x16, [x19, #0]\n\
+             0060  b #-36\n"
+        );
+    }
+
+    #[test]
+    fn test_split() {
+        let split = |text| {
+            let (mnemonic, operands) = split(text);
+            (mnemonic, operands.join("|"))
+        };
+        assert_eq!(split("ret"), ("ret".to_owned(), "".to_owned()));
+        assert_eq!(split("push rax"), ("push".to_owned(), "rax".to_owned()));
+        assert_eq!(
+            split("mov qword ptr [r13 + r9*2 + 0x10], rcx"),
+            (
+                "mov".to_owned(),
+                "qword ptr [r13 + r9*2 + 0x10]|rcx".to_owned()
+            )
+        );
+        assert_eq!(
+            split("stp x29, x30, [sp, #-16]!"),
+            ("stp".to_owned(), "x29|x30|[sp, #-16]!".to_owned())
+        );
+    }
+}
@@ -1,44 +1,165 @@
 use std::convert::TryFrom;
+use std::env::consts::ARCH;
 use std::marker::PhantomData;
+use std::sync::Arc;
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 
-use crate::asm::arch::{Arch, Asm, DebugMod, Elf};
+use crate::asm::arch::{Arch, Asm, Elf};
+use crate::asm::listing::Listing;
 use crate::asm::exec::{AsmCode, Rt};
+use crate::asm::host::HostType;
+use crate::interpreter::{EvalError, MAX_DEPTH};
 use crate::parser::ast::{Exp, Val};
+use crate::parser::program::{Instance, Program};
 
+pub mod aarch64;
+#[cfg(unix)]
+pub mod arena;
 pub mod arch;
+pub mod builtin;
+pub mod cache;
 pub mod exec;
+pub mod host;
+pub mod listing;
+pub mod object;
 pub mod x86_64;
 
+/// Arguments are read at offsets of 12 bits on AArch64.
+const MAX_PARAMS: usize = 4096;
+
+/// Compiled expression. `Fun` is `Send` and `Sync`, and clones share the compiled code, so one
+/// function can be called from many threads at once.
+///
+/// Variables of the expression become parameters of the function, so it can be compiled once and
+/// called with many values. Code with parameters or that may fail has a frame (see
+/// `Arch::frame`): the function is passed its arguments in memory and reports errors through a
+/// status word instead of trapping. Other functions ignore both pointers. A function compiled
+/// from a program (see `with_program`) is followed by the functions it calls.
 pub enum Fun<A: Arch> {
     Int {
-        elf: Elf<fn() -> i64>,
-        _a: PhantomData<A>,
+        elf: Elf<extern "C" fn(*mut u64, *const u64) -> i64>,
+        params: Arc<[(String, Rt)]>,
+        _a: PhantomData<fn() -> A>,
     },
     Float {
-        elf: Elf<fn() -> f64>,
-        _a: PhantomData<A>,
+        elf: Elf<extern "C" fn(*mut u64, *const u64) -> f64>,
+        params: Arc<[(String, Rt)]>,
+        _a: PhantomData<fn() -> A>,
     },
 }
 
-impl<A: Arch> Fun<A> {
-    pub fn call(&self) -> Val {
+impl<A: Arch> Clone for Fun<A> {
+    fn clone(&self) -> Self {
         match self {
+            Fun::Int { elf, params, .. } => Fun::Int {
+                elf: elf.clone(),
+                params: params.clone(),
+                _a: PhantomData,
+            },
+            Fun::Float { elf, params, .. } => Fun::Float {
+                elf: elf.clone(),
+                params: params.clone(),
+                _a: PhantomData,
+            },
+        }
+    }
+}
+
+impl<A: Arch> Fun<A> {
+    /// Calls the function, panicking where `try_call` fails.
+    pub fn call(&self, args: &[Val]) -> Val {
+        self.try_call(args).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Calls the function with an argument for every parameter, failing with the same error as
+    /// `Execution::try_exec` on integer division by zero, `i64::MIN / -1` and calls nested deeper
+    /// than `MAX_DEPTH`. Integer arguments are converted for float parameters.
+    ///
+    /// # Panics
+    ///
+    /// If the number of arguments is wrong, a float is passed for an integer parameter or the
+    /// code is for another machine.
+    pub fn try_call(&self, args: &[Val]) -> Result<Val, EvalError> {
+        if let Err(err) = check_native::<A>() {
+            panic!("{}", err);
+        }
+        let params = self.params();
+        assert_eq!(
+            params.len(),
+            args.len(),
+            "Function takes {} arguments but {} were supplied",
+            params.len(),
+            args.len()
+        );
+        let slots: Vec<u64> = params
+            .iter()
+            .zip(args)
+            .map(|((name, rt), arg)| match (rt, arg) {
+                (Rt::Int, Val::Int(val)) => *val as u64,
+                (Rt::Int, Val::Float(_)) => panic!("Parameter '{}' must be an integer", name),
+                (Rt::Float, val) => f64::from_val(*val).to_bits(),
+            })
+            .collect();
+
+        let mut status = [0, MAX_DEPTH as u64];
+        let val = match self {
             Fun::Int { elf, .. } => {
                 let fun = unsafe { elf.func() };
-                Val::Int(fun())
+                Val::Int(fun(status.as_mut_ptr(), slots.as_ptr()))
             }
             Fun::Float { elf, .. } => {
                 let fun = unsafe { elf.func() };
-                Val::Float(fun())
+                Val::Float(fun(status.as_mut_ptr(), slots.as_ptr()))
             }
+        };
+        match EvalError::from_code(status[0]) {
+            Some(err) => Err(err),
+            None => Ok(val),
+        }
+    }
+
+    pub fn result_type(&self) -> Rt {
+        match self {
+            Fun::Int { .. } => Rt::Int,
+            Fun::Float { .. } => Rt::Float,
+        }
+    }
+
+    /// Names and types of the parameters, in argument order.
+    pub fn params(&self) -> &[(String, Rt)] {
+        match self {
+            Fun::Int { params, .. } | Fun::Float { params, .. } => params,
         }
     }
+
+    /// Loads code returning `rt` into executable memory. Only code for this machine is loaded,
+    /// code of other backends can be written out by `Object` instead.
+    fn prepare(asm: &Asm, rt: Rt, params: &[(&str, Rt)]) -> Result<Self, Error> {
+        check_native::<A>()?;
+        let params: Arc<[(String, Rt)]> = params
+            .iter()
+            .map(|(name, rt)| (name.to_string(), *rt))
+            .collect();
+        Ok(if rt == Rt::Int {
+            Fun::Int {
+                elf: asm.prepare()?,
+                params,
+                _a: Default::default(),
+            }
+        } else {
+            Fun::Float {
+                elf: asm.prepare()?,
+                params,
+                _a: Default::default(),
+            }
+        })
+    }
+
     pub fn bytecode(&self) -> Vec<u8> {
         match self {
-            Fun::Int { elf, _a } => elf.bytecode(),
-            Fun::Float { elf, _a } => elf.bytecode(),
+            Fun::Int { elf, .. } => elf.bytecode(),
+            Fun::Float { elf, .. } => elf.bytecode(),
         }
     }
 }
@@ -54,67 +175,714 @@ impl<A> TryFrom<Exp> for Fun<A> where A: Arch + Default + Into<Asm> {
 impl<A> TryFrom<(Exp, A)> for Fun<A> where A: Arch + Into<Asm> {
     type Error = Error;
 
-    fn try_from((exp, mut arch): (Exp, A)) -> Result<Self, Self::Error> {
-        Ok(if exp.result_type() == Rt::Int {
-            exp.to_asm::<A>(&mut arch, A::INT_RET, A::FLOAT_RET);
-            arch.ret();
-            let asm = arch.into();
-            Fun::Int {
-                elf: asm.prepare()?,
-                _a: Default::default(),
-            }
-        } else {
-            exp.to_asm::<A>(&mut arch, A::INT_ACC, A::FLOAT_ACC);
-            arch.ret();
-            let asm = arch.into();
-            Fun::Float {
-                elf: asm.prepare()?,
-                _a: Default::default(),
-            }
-        })
+    fn try_from((exp, arch): (Exp, A)) -> Result<Self, Self::Error> {
+        Ok(Self::compile(exp, &[], arch)?.0)
+    }
+}
+
+impl<A> Fun<A> where A: Arch + Into<Asm> {
+    /// Compiles `exp` into a function taking `params` in this order. Every variable of `exp`
+    /// must be one of them.
+    pub fn with_params(exp: Exp, params: &[(&str, Rt)], arch: A) -> Result<Self, Error> {
+        Ok(Self::compile(exp, params, arch)?.0)
+    }
+
+    /// Compiles `exp` like `with_params` and decodes the emitted code into a listing that maps
+    /// every instruction back to the node it was emitted for.
+    pub fn with_listing(exp: Exp, params: &[(&str, Rt)], arch: A) -> Result<(Self, Listing), Error> {
+        Self::with_program_listing(&Program { defs: vec![], exp }, params, arch)
+    }
+
+    /// The listing of `with_listing` alone. The code is never loaded, so this works for every
+    /// backend on any host.
+    pub fn listing(exp: Exp, params: &[(&str, Rt)], arch: A) -> Result<Listing, Error> {
+        Self::program_listing(&Program { defs: vec![], exp }, params, arch)
+    }
+
+    /// Compiles `program` like `with_program`, with the listing of its expression and of the
+    /// functions of its definitions.
+    pub fn with_program_listing(
+        program: &Program,
+        params: &[(&str, Rt)],
+        arch: A,
+    ) -> Result<(Self, Listing), Error> {
+        let (asm, rt, listing) = Self::listed(program, params, arch)?;
+        Ok((Self::prepare(&asm, rt, params)?, listing))
+    }
+
+    /// The listing of `with_program_listing` alone, which works on any host like `listing`.
+    pub fn program_listing(program: &Program, params: &[(&str, Rt)], arch: A) -> Result<Listing, Error> {
+        Ok(Self::listed(program, params, arch)?.2)
+    }
+
+    fn listed(program: &Program, params: &[(&str, Rt)], mut arch: A) -> Result<(Asm, Rt, Listing), Error> {
+        for def in &program.defs {
+            check_params(def.params.len())?;
+        }
+        check_params(params.len())?;
+        let bound = program.bind(params)?;
+        arch.asm().record_origins();
+        let (mut asm, rt) = Self::assemble(&bound.exp, &bound.funs, arch);
+        asm.finalize()?;
+        let insns = A::disassemble(asm.buffer())?;
+        let origins = asm.take_origins().unwrap_or_default();
+        let listing = Listing::new(insns, origins, &bound.exp, &bound.funs);
+        Ok((asm, rt, listing))
+    }
+
+    /// Compiles the expression of `program` into a function taking `params`, followed by a
+    /// function for every instance of a definition it calls. Calls pass the arguments on the
+    /// stack and share the status word of the outer call.
+    pub fn with_program(program: &Program, params: &[(&str, Rt)], arch: A) -> Result<Self, Error> {
+        for def in &program.defs {
+            check_params(def.params.len())?;
+        }
+        check_params(params.len())?;
+        let bound = program.bind(params)?;
+        let (asm, rt) = Self::assemble(&bound.exp, &bound.funs, arch);
+        Self::prepare(&asm, rt, params)
+    }
+
+    /// Compiles `exp`, returning the buffer the function was prepared from as well.
+    fn compile(exp: Exp, params: &[(&str, Rt)], arch: A) -> Result<(Self, Asm), Error> {
+        check_params(params.len())?;
+        let (asm, rt) = Self::assemble(&exp.bind(params)?, &[], arch);
+        Ok((Self::prepare(&asm, rt, params)?, asm))
+    }
+
+    /// Emits the code of the bound `exp` followed by the functions of `funs`.
+    fn assemble(exp: &Exp, funs: &[Instance], mut arch: A) -> (Asm, Rt) {
+        if exp.needs_frame() {
+            arch.frame();
+        }
+        exp.to_asm::<A>(&mut arch, A::INT_RET, A::FLOAT_RET);
+        arch.ret();
+        for (i, fun) in funs.iter().enumerate() {
+            exec::instance(fun, i, &mut arch);
+        }
+        (arch.into(), exp.result_type())
+    }
+}
+
+/// Fails unless code of `A` runs on this machine.
+fn check_native<A: Arch>() -> Result<(), Error> {
+    if A::NAME != ARCH {
+        return Err(anyhow!("{} code can't run on {}", A::NAME, ARCH));
+    }
+    Ok(())
+}
+
+fn check_params(count: usize) -> Result<(), Error> {
+    if count > MAX_PARAMS {
+        return Err(anyhow!("Functions take at most {} parameters", MAX_PARAMS));
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use std::convert::TryFrom;
+    use std::env::consts::ARCH;
+    use std::sync::Arc;
+    use std::thread;
 
-    use crate::asm::arch::{Asm, DebugMod};
-    use crate::asm::Fun;
+    use crate::asm::aarch64::AArch64;
+    use crate::asm::arch::{Asm, Rel};
+    use crate::asm::exec::Rt;
+    use crate::asm::host::HostFns;
+    use crate::asm::{Fun, MAX_PARAMS};
     use crate::asm::x86_64::X8664;
-    use crate::parser::ast::{parse_exp, Val};
+    use crate::interpreter::{Env, EvalError, Execution, MAX_DEPTH};
+    use crate::parser::ast::{parse_exp, parse_program, Exp, Op, Val};
     use crate::parser::lexer::Lexer;
 
-    fn perform(input: &str, result: Val) {
+    fn parse(input: &str) -> Exp {
         let mut lexer = Lexer::new(input);
         lexer.advance().unwrap();
-        let exp = parse_exp(&mut lexer).unwrap().exp().unwrap();
-        let mut arch = X8664::default();
-        arch.debug_mod(true);
+        parse_exp(&mut lexer).unwrap()
+    }
+
+    fn perform(input: &str, result: Val) {
+        let exp = parse(input);
+        let (fun, listing) = Fun::with_listing(exp, &[], X8664::default()).unwrap();
+        assert_eq!(fun.call(&[]), result);
+
+        // Checked code ends with its error exits, after the `ret`.
+        let entries = listing.entries();
+        assert!(entries.iter().any(|entry| entry.mnemonic == "ret"));
+        assert_eq!(entries.last().unwrap().range.end, fun.bytecode().len());
+    }
+
+    fn compare(input: &str) {
+        compare_exp(parse(input));
+    }
 
-        let fun = Fun::<X8664>::try_from((exp, arch)).unwrap();
-        assert_eq!(fun.call(), result);
+    fn compare_exp(exp: Exp) {
+        let input = exp.to_string();
+        let input = input.as_str();
+        let expected = exp.exec(&Env::new());
+        let fun = Fun::<X8664>::try_from(exp).unwrap();
+        match (fun.call(&[]), expected) {
+            (Val::Float(actual), Val::Float(expected)) if expected.is_nan() => {
+                assert!(actual.is_nan(), "{}: {} != NaN", input, actual)
+            }
+            (actual, expected) => assert_eq!(actual, expected, "{}", input),
+        }
     }
 
     #[test]
     fn test_interpreter() {
-        // perform("13", Val::Int(13));
-        // perform("-1", Val::Int(-1));
-        // perform("-1.0", Val::Float(-1.0));
-        //perform("13 + 13", Val::Int(13 + 13));
-        // perform(" 2 * 13 + 13", Val::Int(2 * 13 + 13));
-        // perform(" 2 * (13 + 13)", Val::Int(2 * (13 + 13)));
+        perform("13", Val::Int(13));
+        perform("-1", Val::Int(-1));
+        perform("-1.0", Val::Float(-1.0));
+        perform("13 + 13", Val::Int(13 + 13));
+        perform(" 2 * 13 + 13", Val::Int(2 * 13 + 13));
+        perform(" 2 * (13 + 13)", Val::Int(2 * (13 + 13)));
         perform(" 2 * (13 + 13) / 2", Val::Int(2 * (13 + 13) / 2));
-        // perform("(2 + 2) * 10 ^ 2", Val::Int((2 + 2) * 10i128.pow(2)));
-        // perform("(2 + 2) * 10.0", Val::Float((2 + 2) as f64 * 10.0));
+        perform("(2 + 2) * 10 ^ 2", Val::Int((2 + 2) * 10i64.pow(2)));
+        perform("(2 + 2) * 10.0", Val::Float((2 + 2) as f64 * 10.0));
+    }
+
+    #[test]
+    fn test_nested() {
+        compare("(1 + 2) * (3 + 4)");
+        compare("(1 - 2) - (3 - 4)");
+        compare("(100 / (7 - 2)) % ((3 + 4) * (1 + 1))");
+        compare("((1 + 2) * (3 + 4)) - ((5 - 6) * (7 + 8))");
+        compare("(1 + 2.5) * (3 + 4)");
+        compare("(1 + 2) * (3.5 + 4)");
+        compare("(1 + 2) / (3 * 1.5) - (10 % 4) * (2 - 0.25)");
+        compare("(1.5 + 2) % ((3 + 4) / (2 - 0.5))");
+        compare("2 - (1 - (2 - (1 - (2 - (1 - 7)))))");
+        compare("((((1 - 2) - 3) - 4) - 5) * (6 - (7 - (8 - 9.5)))");
+    }
+
+    #[test]
+    fn test_deep() {
+        fn balanced(depth: usize, next: &mut i64) -> String {
+            if depth == 0 {
+                *next += 1;
+                next.to_string()
+            } else {
+                let op = ["+", "-", "*"][depth % 3];
+                let left = balanced(depth - 1, next);
+                let right = balanced(depth - 1, next);
+                format!("({} {} {})", left, op, right)
+            }
+        }
+
+        for depth in 1..10 {
+            let exp = balanced(depth, &mut 0);
+            compare(&exp);
+            compare(&format!("{} / 3.5", exp));
+        }
+    }
+
+    #[test]
+    fn test_int() {
+        let operands: [i64; 10] = [0, 1, -1, 2, -2, 7, -7, 13, -13, i64::MAX];
+        for l in operands.iter() {
+            for r in operands.iter() {
+                compare(&format!("{} + {}", l, r));
+                compare(&format!("{} - {}", l, r));
+                compare(&format!("{} * {}", l, r));
+                if *r != 0 {
+                    compare(&format!("{} / {}", l, r));
+                    compare(&format!("{} % {}", l, r));
+                }
+            }
+        }
+        compare("-9223372036854775807 - 2");
+        compare("-9223372036854775807 / 2");
+        compare("-9223372036854775807 % 10");
+    }
+
+    #[test]
+    fn test_pow() {
+        let bases: [i64; 9] = [0, 1, -1, 2, -2, 3, -3, 10, i64::MAX];
+        let exponents: [i64; 13] = [0, 1, 2, 3, 4, 5, 31, 62, 63, 64, -1, -2, -3];
+        for base in bases.iter() {
+            for exp in exponents.iter() {
+                if *base == 0 && *exp < 0 {
+                    continue;
+                }
+                compare(&format!("({}) ^ {}", base, exp));
+            }
+        }
+        compare("(1 + 1) ^ (2 * 5)");
+        compare("2 ^ 3 ^ 2");
+        compare("(2 ^ 62) ^ -1");
+    }
+
+    #[test]
+    fn test_unary() {
+        perform("-(1 + 2)", Val::Int(-3));
+        perform("-2 ^ 2", Val::Int(-4));
+        perform("-(1.5 * 2)", Val::Float(-3.0));
+        perform("+(2 * 3)", Val::Int(6));
+        compare("--3");
+        compare("-(-9223372036854775807 - 1)");
+        compare("-(0.0 * 1)");
+        compare("-(0.0 / 0)");
+        compare("2 ^ -(1 - 3)");
+        compare("-(1 + 2) * -(3.5 - 4) - -(7 % 4)");
+        compare("(1 + 2) - -((3 + 4) * 2)");
+        compare("-(-(-(1.5 + 2)))");
+
+        let params = [("x", Rt::Int), ("y", Rt::Float)];
+        let fun = Fun::with_params(parse("-x * -y + -(x - 1)"), &params, X8664::default()).unwrap();
+        assert_eq!(fun.call(&[Val::Int(3), Val::Float(0.5)]), Val::Float(-0.5));
+        // Only the sign bit is flipped.
+        let fun = Fun::with_params(parse("-y"), &params[1..], X8664::default()).unwrap();
+        match fun.call(&[Val::Float(0.0)]) {
+            Val::Float(val) => assert!(val == 0.0 && val.is_sign_negative()),
+            val => panic!("{}", val),
+        }
+    }
+
+    #[test]
+    fn test_float() {
+        compare("1.5");
+        compare("0.0");
+        compare("-2.25");
+        compare("1.5 + 2");
+        compare("2 * 0.5");
+        compare("1 / 3.0");
+        compare("10.5 / 0.0");
+        compare("0.0 / 0.0");
+        compare("7.5 % 2");
+        compare("-7.5 % 2");
+        compare("7.5 % -2.25");
+        compare("1.0 % 0.0");
+        compare("1000000000000.5 % 0.1");
+        compare("2.0 ^ 0.5");
+        compare("2 ^ -1.5");
+        compare("-8.0 ^ 0.5");
+    }
+
+    extern "C" fn answer() -> i64 {
+        42
+    }
+
+    extern "C" fn sub(a: i64, b: i64) -> i64 {
+        a - b
+    }
+
+    extern "C" fn scale(a: i64, b: f64, c: i64) -> f64 {
+        a as f64 * b + c as f64
+    }
+
+    extern "C" fn mix(a: f64, b: i64, c: f64, d: i64, e: f64, f: i64) -> f64 {
+        a - b as f64 * 2.0 + c * 3.0 - d as f64 * 4.0 + e * 5.0 - f as f64 * 6.0
+    }
+
+    unsafe extern "C" {
+        safe fn hypot(x: f64, y: f64) -> f64;
+    }
+
+    #[test]
+    fn test_host_call() {
+        let mut host = HostFns::new();
+        host.bind("answer", answer as extern "C" fn() -> i64);
+        host.bind("sub", sub as extern "C" fn(i64, i64) -> i64);
+        host.bind("scale", scale as extern "C" fn(i64, f64, i64) -> f64);
+        host.bind("mix", mix as extern "C" fn(f64, i64, f64, i64, f64, i64) -> f64);
+        host.bind("hypot", hypot as extern "C" fn(f64, f64) -> f64);
+
+        let call = |name: &str, args: &[&str]| {
+            host.call(name, args.iter().map(|arg| parse(arg)).collect()).unwrap()
+        };
+
+        compare_exp(call("answer", &[]));
+        compare_exp(call("sub", &["10", "(1 + 2) * 3"]));
+        compare_exp(call("scale", &["3", "0.5", "-7"]));
+        compare_exp(call("scale", &["2 * 2", "1 + 2", "9 / 2"]));
+        compare_exp(call("mix", &["1", "2", "3.5", "4", "5 - 0.5", "(2 + 2) * 2"]));
+        compare_exp(call("hypot", &["3", "4.0"]));
+
+        let nested = call("sub", &["100", "1"]);
+        let nested = host.call("sub", vec![nested, parse("(1 + 1) * 7")]).unwrap();
+        compare_exp(Exp::Exp {
+            op: Op::Mul,
+            left: Box::new(parse("(2 + 3) * 4")),
+            right: Box::new(host.call("scale", vec![nested, parse("1.5"), parse("2")]).unwrap()),
+        });
+        compare_exp(Exp::Exp {
+            op: Op::Sub,
+            left: Box::new(call("hypot", &["6", "8"])),
+            right: Box::new(call("answer", &[])),
+        });
+
+        assert!(host.call("sub", vec![parse("1")]).is_err());
+        assert!(host.call("sub", vec![parse("1"), parse("1.5")]).is_err());
+        assert!(host.call("unknown", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_builtins() {
+        perform("sqrt(16)", Val::Float(4.0));
+        perform("abs(3 - 10)", Val::Int(7));
+        perform("max(2, 3) * min(4, 5)", Val::Int(12));
+        compare("sqrt(2) + sqrt(0.0 - 1)");
+        compare("abs(-9223372036854775807 - 1)");
+        compare("max(abs(-9223372036854775807 - 1), -1) + min(abs(0), abs(-2 * 3))");
+        compare("sin(1) * cos(2.5) - exp(0.5) / ln(10)");
+        compare("min(1.5, 2) + max(-1, 0.5) + abs(-0.25)");
+        compare("min(sqrt(2), abs(-1.5)) ^ max(abs(-3), 2)");
+        compare("ln(0.0) + ln(0.0 - 1)");
+
+        // `sqrt` is an instruction, the others are host calls.
+        let mnemonics = |input| {
+            let (_, listing) = Fun::with_listing(parse(input), &[], X8664::default()).unwrap();
+            listing
+                .entries()
+                .iter()
+                .map(|entry| entry.mnemonic.clone())
+                .collect::<Vec<_>>()
+        };
+        let sqrt = mnemonics("sqrt(2)");
+        assert!(sqrt.contains(&"sqrtsd".to_owned()), "{:?}", sqrt);
+        assert!(!sqrt.contains(&"call".to_owned()), "{:?}", sqrt);
+        assert!(mnemonics("sin(2)").contains(&"call".to_owned()));
+
+        let params = [("x", Rt::Int), ("y", Rt::Float)];
+        let fun = Fun::with_params(
+            parse("max(x, 0) + sqrt(abs(y)) * min(x, y)"),
+            &params,
+            X8664::default(),
+        )
+        .unwrap();
+        assert_eq!(fun.call(&[Val::Int(-3), Val::Float(-2.25)]), Val::Float(-4.5));
+        assert_eq!(fun.call(&[Val::Int(2), Val::Float(4.0)]), Val::Float(6.0));
+    }
+
+    #[test]
+    fn test_errors() {
+        let check = |exp: Exp| {
+            let input = exp.to_string();
+            let expected = exp.try_exec(&Env::new());
+            let fun = Fun::<X8664>::try_from(exp).unwrap();
+            assert_eq!(fun.try_call(&[]), expected, "{}", input);
+            // The frame is restored on the error path, so the function can be called again.
+            assert_eq!(fun.try_call(&[]), expected, "{}", input);
+            expected
+        };
+        let min = "(-9223372036854775807 - 1)";
+
+        for input in [
+            "7 / 0".to_owned(),
+            "7 % (3 - 3)".to_owned(),
+            "0 ^ -1".to_owned(),
+            "(1 - 1) ^ (0 - 3)".to_owned(),
+            format!("{} / -1", min),
+            format!("{} % (0 - 1)", min),
+            "(1 + 2) * ((3 + 4) * (5 / (6 - 6)))".to_owned(),
+            "2.5 * ((1 + 2) * (3 % 0)) + 1.5".to_owned(),
+        ]
+        .iter()
+        {
+            assert!(check(parse(input)).is_err(), "{}", input);
+        }
+
+        // Guards let valid operands through.
+        for input in [
+            "7 / 2".to_owned(),
+            "-7 % -1".to_owned(),
+            "0 ^ 0".to_owned(),
+            "-1 ^ -3".to_owned(),
+            format!("{} / 1", min),
+            format!("{} % 2", min),
+            format!("({} + 1) / -1", min),
+            "7.0 / 0".to_owned(),
+            "1 / 0.0 + 2".to_owned(),
+        ]
+        .iter()
+        {
+            assert!(check(parse(input)).is_ok(), "{}", input);
+        }
+
+        let mut host = HostFns::new();
+        host.bind("sub", sub as extern "C" fn(i64, i64) -> i64);
+        let call = host.call("sub", vec![parse("10"), parse("(1 + 2) / (2 - 2)")]).unwrap();
+        assert_eq!(check(call), Err(EvalError::DivisionByZero));
+        let call = host.call("sub", vec![parse("1"), parse("2")]).unwrap();
+        let exp = Exp::Exp {
+            op: Op::Div,
+            left: Box::new(parse(min)),
+            right: Box::new(call),
+        };
+        assert_eq!(check(exp), Err(EvalError::Overflow));
+    }
+
+    #[test]
+    #[should_panic(expected = "Integer overflow")]
+    fn test_call_panics() {
+        let fun = Fun::<X8664>::try_from(parse("(-9223372036854775807 - 1) / -1")).unwrap();
+        fun.call(&[]);
+    }
+
+    #[test]
+    fn test_params() {
+        let params = [("x", Rt::Int), ("y", Rt::Float)];
+        let mut host = HostFns::new();
+        host.bind("sub", sub as extern "C" fn(i64, i64) -> i64);
+        let call = host.call("sub", vec![parse("x * 3"), parse("x / 2")]).unwrap();
+        let exps = vec![
+            parse("x * 2 + y"),
+            parse("(x + 1) * (y - x) / (x % 3 + y)"),
+            parse("y ^ x + x ^ 2"),
+            parse("x"),
+            Exp::Exp {
+                op: Op::Add,
+                left: Box::new(call),
+                right: Box::new(parse("(y * y) * (x * x)")),
+            },
+        ];
+        for exp in exps {
+            let fun = Fun::with_params(exp.clone(), &params, X8664::default()).unwrap();
+            assert_eq!(fun.params(), &[("x".to_owned(), Rt::Int), ("y".to_owned(), Rt::Float)]);
+            for x in -20..20 {
+                let y = x as f64 * 0.75 + 0.5;
+                let env = Env::new().with("x", Val::Int(x)).with("y", Val::Float(y));
+                let expected = exp.try_exec(&env);
+                assert_eq!(fun.try_call(&[Val::Int(x), Val::Float(y)]), expected, "{}", exp);
+            }
+        }
+
+        // Integers are converted for float parameters.
+        let fun = Fun::with_params(parse("y / 2"), &[("y", Rt::Float)], X8664::default()).unwrap();
+        assert_eq!(fun.call(&[Val::Int(3)]), Val::Float(1.5));
+
+        let err = |input: &str, params: &[(&str, Rt)]| {
+            Fun::with_params(parse(input), params, X8664::default())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(err("x + z", &params), "Unknown variable 'z'");
+        assert_eq!(err("x", &[("x", Rt::Int), ("x", Rt::Float)]), "Duplicate parameter 'x'");
+        assert_eq!(err("x", &[]), "Unknown variable 'x'");
+    }
+
+    #[test]
+    fn test_conditions() {
+        let operands = ["0", "1", "-1", "2.5", "-0.0", "(0.0 / 0)", "(1.0 / 0)"];
+        for l in operands.iter() {
+            for r in operands.iter() {
+                for op in ["<", "<=", ">", ">=", "==", "!="].iter() {
+                    compare(&format!("{} {} {}", l, op, r));
+                }
+                compare(&format!("if {} then {} else 7", l, r));
+            }
+        }
+        compare("(if 1 < 2 then 3 else 4.5) * (if 2 < 1 then 3 else 4)");
+        compare("if (1 + 2) * 3 == 9 then 2 ^ 10 else (0 - 1) ^ 3");
+        compare("1 + (if 0.5 > 0 then 2 else 3) * 4");
+
+        // Only the taken branch is checked.
+        let fun = Fun::<X8664>::try_from(parse("if 1 then 2 else 1 / 0")).unwrap();
+        assert_eq!(fun.try_call(&[]), Ok(Val::Int(2)));
+        let fun = Fun::<X8664>::try_from(parse("if 0 then 2 else 1 / 0")).unwrap();
+        assert_eq!(fun.try_call(&[]), Err(EvalError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_program() {
+        let inputs = [
+            "sq(x) = x * x; sq(x) + sq(y)",
+            "fact(n) = if n <= 1 then 1 else n * fact(n - 1); fact(x) + fact(3.0)",
+            "fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(x) * y",
+            "f(a, b) = if a <= 0 then b else f(a - 1, b / 2); f(x, y) + f(y, x)",
+            "g(n) = 10 / n; h(n) = g(n) + g(n - 1); h(x) + h(y)",
+            "half(n) = if n < 1 then n else half(n / 2.0); half(x) - half(y) ^ 2",
+            "down(n) = if n == 0 then 0 else down(n - 1); down(x * 100)",
+            "p(a, b, c, d, e, f, g, h, i) = a + b * c - d / e + f * g - h % i; \
+             p(x, y, 1, 2, 3, x, 4.5, 5, 6)",
+        ];
+        let params = [("x", Rt::Int), ("y", Rt::Float)];
+        // The interpreter recurses as deep as the calls do.
+        let handle = thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || {
+                for input in inputs.iter() {
+                    let program = parse_program(input).unwrap();
+                    let bound = program.bind(&params).unwrap();
+                    let fun = Fun::with_program(&program, &params, X8664::default()).unwrap();
+                    for x in -2..12 {
+                        let y = x as f64 * 0.75 - 0.5;
+                        let env = Env::new().with("x", Val::Int(x)).with("y", Val::Float(y));
+                        let expected = bound.try_exec(&env);
+                        let args = [Val::Int(x), Val::Float(y)];
+                        assert_eq!(fun.try_call(&args), expected, "{} at {}", input, x);
+                    }
+                }
+            })
+            .unwrap();
+        handle.join().unwrap();
+
+        // Calls can nest `MAX_DEPTH` deep, and the function can be called again after overflowing.
+        let program = parse_program("down(n) = if n == 0 then 0 else down(n - 1); down(x)").unwrap();
+        let fun = Fun::with_program(&program, &[("x", Rt::Int)], X8664::default()).unwrap();
+        let depth = |n: usize| fun.try_call(&[Val::Int(n as i64)]);
+        assert_eq!(depth(MAX_DEPTH - 1), Ok(Val::Int(0)));
+        assert_eq!(depth(MAX_DEPTH), Err(EvalError::StackOverflow));
+        assert_eq!(depth(MAX_DEPTH - 1), Ok(Val::Int(0)));
+        let forever = parse_program("f(n) = f(n + 1) + 1; f(0)").unwrap();
+        let fun = Fun::with_program(&forever, &[], X8664::default()).unwrap();
+        assert_eq!(fun.try_call(&[]), Err(EvalError::StackOverflow));
+
+        let err = |input: &str| {
+            let program = parse_program(input).unwrap();
+            Fun::with_program(&program, &[], X8664::default())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(err("sq(x) = x * x; sq(y)"), "Unknown variable 'y'");
+        let many: Vec<String> = (0..MAX_PARAMS + 1).map(|i| format!("p{}", i)).collect();
+        assert_eq!(
+            err(&format!("f({}) = 1; 2", many.join(", "))),
+            format!("Functions take at most {} parameters", MAX_PARAMS)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Function takes 2 arguments but 1 were supplied")]
+    fn test_call_arity() {
+        let params = [("x", Rt::Int), ("y", Rt::Float)];
+        let fun = Fun::with_params(parse("x + y"), &params, X8664::default()).unwrap();
+        fun.call(&[Val::Int(1)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Parameter 'x' must be an integer")]
+    fn test_call_types() {
+        let fun = Fun::with_params(parse("x * 2"), &[("x", Rt::Int)], X8664::default()).unwrap();
+        fun.call(&[Val::Float(1.5)]);
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut asm = Asm::new();
+        let (repeat, done) = (asm.label(), asm.label());
+        // xor eax, eax
+        asm.put(&[0x31, 0xc0]);
+        // repeat: add eax, 3; cmp eax, 9; jne repeat
+        asm.bind(repeat);
+        asm.put(&[0x83, 0xc0, 0x03, 0x83, 0xf8, 0x09, 0x75]);
+        asm.put_rel(repeat, Rel::Rel8);
+        // jmp done; ud2
+        asm.put(&[0xe9]);
+        asm.put_rel(done, Rel::Rel32);
+        asm.put(&[0x0f, 0x0b]);
+        // done: ret
+        asm.bind(done);
+        asm.put(&[0xc3]);
+
+        let fun = asm.prepare::<extern "C" fn() -> i64>().unwrap();
+        assert_eq!(unsafe { fun.func() }(), 9);
+        assert_eq!(&fun.bytecode()[8..15], &[0x75, 0xf8, 0xe9, 0x02, 0x00, 0x00, 0x00]);
+
+        asm.finalize().unwrap();
+        assert_eq!(asm.buffer(), fun.bytecode().as_slice());
+    }
+
+    #[test]
+    fn test_label_errors() {
+        let mut asm = Asm::new();
+        let label = asm.label();
+        asm.put(&[0xeb]);
+        asm.put_rel(label, Rel::Rel8);
+        assert!(asm.finalize().unwrap_err().to_string().contains("Unresolved label"));
+
+        asm.put(&[0x90; 200]);
+        asm.bind(label);
+        asm.put(&[0xc3]);
+        assert!(asm.prepare::<extern "C" fn()>().unwrap_err().to_string().contains("out of Rel8 range"));
+
+        let mut asm = Asm::new();
+        let label = asm.label();
+        asm.bind(label);
+        asm.put(&[0xc3]);
+        asm.bind(label);
+        assert!(asm.finalize().unwrap_err().to_string().contains("bound twice"));
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "aarch64"))]
+    fn test_foreign() {
+        // A64 code is never jumped to on another machine, though it can still be listed.
+        let err = Fun::<AArch64>::try_from(parse("1 + 2")).err().unwrap();
+        assert_eq!(err.to_string(), format!("aarch64 code can't run on {}", ARCH));
+        let program = parse_program("f(x) = x; f(1)").unwrap();
+        assert!(Fun::with_program(&program, &[], AArch64::default()).is_err());
+        assert!(Fun::listing(parse("1 + 2"), &[], AArch64::default()).is_ok());
+    }
+
+    #[test]
+    fn test_threads() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<Fun<X8664>>();
+        shareable::<Fun<AArch64>>();
+
+        let mut host = HostFns::new();
+        host.bind("sub", sub as extern "C" fn(i64, i64) -> i64);
+        let exp = Exp::Exp {
+            op: Op::Mul,
+            left: Box::new(parse("(1 + 2) * 3 - 4 ^ 2")),
+            right: Box::new(host.call("sub", vec![parse("10"), parse("3")]).unwrap()),
+        };
+        let fun = Arc::new(Fun::<X8664>::try_from(exp).unwrap());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let fun = fun.clone();
+                thread::spawn(move || (0..10_000).all(|_| fun.call(&[]) == Val::Int(-49)))
+            })
+            .collect();
+        // The threads keep the code alive.
+        drop(fun);
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+
+        // So do clones, each on its own.
+        let fun = Fun::<X8664>::try_from(parse("2.5 * 4")).unwrap();
+        let clones: Vec<_> = (0..8).map(|_| fun.clone()).collect();
+        drop(fun);
+        let threads: Vec<_> = clones
+            .into_iter()
+            .map(|fun| thread::spawn(move || fun.call(&[])))
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), Val::Float(10.0));
+        }
+
+        // Compiling from many threads at once.
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                thread::spawn(move || {
+                    let funs: Vec<_> = (0..100)
+                        .map(|j| Fun::<X8664>::try_from(parse(&format!("{} * 1000 + {}", i, j))))
+                        .collect::<Result<_, _>>()
+                        .unwrap();
+                    funs.iter()
+                        .enumerate()
+                        .all(|(j, fun)| fun.call(&[]) == Val::Int(i * 1000 + j as i64))
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
     }
 
     #[test]
-    fn tes() {
+    fn test_prepare() {
+        // mov rcx, i64::MAX; mov rax, rcx; ret
         let mut asm = Asm::new();
         asm.put(&[0x48, 0xB9, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0x48, 0x89, 0xC8, 0xC3]);
-        let fun = asm.prepare::<fn() -> i64>().unwrap();
+        let fun = asm.prepare::<extern "C" fn() -> i64>().unwrap();
         let f = unsafe { fun.func() };
-        dbg!(f());
+        assert_eq!(f(), i64::MAX);
     }
 }
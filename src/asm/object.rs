@@ -0,0 +1,537 @@
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+use anyhow::{anyhow, Error};
+
+use crate::asm::arch::{Arch, Asm, Reloc, RelocKind};
+use crate::asm::exec::Rt;
+use crate::asm::{check_params, Fun};
+use crate::parser::ast::Exp;
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+/// Section header indices, in the order the sections are written.
+const TEXT: u32 = 1;
+const RELA_TEXT: u32 = 2;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const SHSTRTAB: u32 = 5;
+const SECTIONS: usize = 7;
+
+/// Functions start on this boundary.
+const ALIGN: usize = 16;
+
+/// Compiled expressions collected into a relocatable ELF64 object.
+///
+/// Every expression becomes a global function with the calling convention of `Fun`: it takes a
+/// pointer to the status word and one to its arguments, and returns `int64_t` or `double`.
+/// Host functions the code calls are left as undefined symbols for the linker, which may resolve
+/// them in a shared library of a position-independent executable.
+pub struct Object<A: Arch> {
+    text: Vec<u8>,
+    funs: Vec<Symbol>,
+    relocs: Vec<Reloc>,
+    _a: PhantomData<A>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Symbol {
+    name: String,
+    offset: usize,
+    size: usize,
+    params: Vec<(String, Rt)>,
+    ret: Rt,
+}
+
+impl<A> Default for Object<A>
+where
+    A: Arch + Default + Into<Asm>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A> Object<A>
+where
+    A: Arch + Default + Into<Asm>,
+{
+    pub fn new() -> Self {
+        Object {
+            text: vec![],
+            funs: vec![],
+            relocs: vec![],
+            _a: PhantomData,
+        }
+    }
+
+    /// Compiles `exp` into a function named `name` taking `params` in this order, like
+    /// `Fun::with_params`. Failures are reported through the status word, as `Fun` does.
+    pub fn add(&mut self, name: &str, exp: &Exp, params: &[(&str, Rt)]) -> Result<(), Error> {
+        let valid = name
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || i > 0 && c.is_ascii_digit());
+        if name.is_empty() || !valid {
+            return Err(anyhow!("Invalid symbol name '{}'", name));
+        }
+        if self.funs.iter().any(|fun| fun.name == name) {
+            return Err(anyhow!("Symbol '{}' is defined twice", name));
+        }
+
+        check_params(params.len())?;
+        let exp = exp.clone().bind(params)?;
+        let mut arch = A::default();
+        arch.asm().link_calls();
+        let (mut asm, ret) = Fun::<A>::assemble(&exp, &[], arch);
+        asm.finalize()?;
+
+        let offset = self.text.len().div_ceil(ALIGN) * ALIGN;
+        self.text.resize(offset, 0);
+        self.text.extend_from_slice(asm.buffer());
+        for reloc in asm.relocs() {
+            // Calls are relative and their fields hold zeroes, the addend lives in the
+            // relocation entry.
+            self.relocs.push(Reloc {
+                at: offset + reloc.at,
+                symbol: reloc.symbol.clone(),
+                kind: reloc.kind,
+            });
+        }
+        self.funs.push(Symbol {
+            name: name.to_string(),
+            offset,
+            size: asm.len(),
+            params: params
+                .iter()
+                .map(|(name, rt)| (name.to_string(), *rt))
+                .collect(),
+            ret,
+        });
+        Ok(())
+    }
+
+    /// C declarations of the functions, one per line after a comment on how to call them.
+    pub fn header(&self) -> String {
+        let c_type = |rt: Rt| if rt == Rt::Int { "int64_t" } else { "double" };
+        let mut header = String::from(
+            "/* status[0] must be 0 and status[1] the depth calls may nest to. A failed call sets\n   \
+             status[0] to 1 on division by zero, 2 on integer overflow and 3 on calls nested too\n   \
+             deep. args holds the arguments in order, 8 bytes each. */\n",
+        );
+        for fun in &self.funs {
+            header += &format!(
+                "{} {}(uint64_t *status, const void *args);",
+                c_type(fun.ret),
+                fun.name
+            );
+            if !fun.params.is_empty() {
+                let params: Vec<String> = fun
+                    .params
+                    .iter()
+                    .map(|(name, rt)| format!("{} {}", c_type(*rt), name))
+                    .collect();
+                header += &format!(" /* {} */", params.join(", "));
+            }
+            header.push('\n');
+        }
+        header
+    }
+
+    /// Contents of the `.o` file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut strtab = vec![0];
+        let mut name = |name: &str| {
+            let offset = strtab.len() as u32;
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+            offset
+        };
+
+        // Locals come first: the null symbol and `.text`, then the functions and the host
+        // symbols they refer to.
+        let mut symtab = vec![0; SYM_SIZE];
+        put_sym(
+            &mut symtab,
+            0,
+            sym_info(STB_LOCAL, STT_SECTION),
+            TEXT as u16,
+            0,
+            0,
+        );
+        for fun in &self.funs {
+            let info = sym_info(STB_GLOBAL, STT_FUNC);
+            let offset = fun.offset as u64;
+            put_sym(
+                &mut symtab,
+                name(&fun.name),
+                info,
+                TEXT as u16,
+                offset,
+                fun.size as u64,
+            );
+        }
+        let mut undefined = BTreeMap::new();
+        for reloc in &self.relocs {
+            if !undefined.contains_key(reloc.symbol.as_str()) {
+                let index = 2 + self.funs.len() + undefined.len();
+                undefined.insert(reloc.symbol.as_str(), index as u64);
+                let info = sym_info(STB_GLOBAL, STT_NOTYPE);
+                put_sym(&mut symtab, name(&reloc.symbol), info, 0, 0, 0);
+            }
+        }
+
+        let mut rela = vec![];
+        for reloc in &self.relocs {
+            let sym = undefined[reloc.symbol.as_str()];
+            rela.extend_from_slice(&(reloc.at as u64).to_le_bytes());
+            rela.extend_from_slice(&(sym << 32 | elf_reloc_type(reloc.kind)).to_le_bytes());
+            rela.extend_from_slice(&addend(reloc.kind).to_le_bytes());
+        }
+
+        let mut shstrtab = vec![0];
+        let mut section = |name: &str| {
+            let offset = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+            offset
+        };
+        let names = [
+            0,
+            section(".text"),
+            section(".rela.text"),
+            section(".symtab"),
+            section(".strtab"),
+            section(".shstrtab"),
+            section(".note.GNU-stack"),
+        ];
+
+        let mut out = vec![0; EHDR_SIZE];
+        let place = |out: &mut Vec<u8>, data: &[u8], align: usize| {
+            out.resize(out.len().div_ceil(align) * align, 0);
+            let offset = out.len() as u64;
+            out.extend_from_slice(data);
+            offset
+        };
+        let text = place(&mut out, &self.text, ALIGN);
+        let rela_text = place(&mut out, &rela, 8);
+        let sym = place(&mut out, &symtab, 8);
+        let str = place(&mut out, &strtab, 1);
+        let shstr = place(&mut out, &shstrtab, 1);
+        let shoff = place(&mut out, &[], 8);
+
+        let headers = [
+            Shdr::default(),
+            Shdr {
+                name: names[TEXT as usize],
+                kind: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_EXECINSTR,
+                offset: text,
+                size: self.text.len() as u64,
+                align: ALIGN as u64,
+                ..Shdr::default()
+            },
+            Shdr {
+                name: names[RELA_TEXT as usize],
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                offset: rela_text,
+                size: rela.len() as u64,
+                link: SYMTAB,
+                info: TEXT,
+                align: 8,
+                entsize: RELA_SIZE as u64,
+            },
+            Shdr {
+                name: names[SYMTAB as usize],
+                kind: SHT_SYMTAB,
+                offset: sym,
+                size: symtab.len() as u64,
+                link: STRTAB,
+                // Index of the first global symbol.
+                info: 2,
+                align: 8,
+                entsize: SYM_SIZE as u64,
+                ..Shdr::default()
+            },
+            Shdr {
+                name: names[STRTAB as usize],
+                kind: SHT_STRTAB,
+                offset: str,
+                size: strtab.len() as u64,
+                align: 1,
+                ..Shdr::default()
+            },
+            Shdr {
+                name: names[SHSTRTAB as usize],
+                kind: SHT_STRTAB,
+                offset: shstr,
+                size: shstrtab.len() as u64,
+                align: 1,
+                ..Shdr::default()
+            },
+            // Marks the stack as non-executable.
+            Shdr {
+                name: names[6],
+                kind: SHT_PROGBITS,
+                offset: shoff,
+                align: 1,
+                ..Shdr::default()
+            },
+        ];
+        for header in &headers {
+            header.put(&mut out);
+        }
+
+        let ehdr = &mut out[..EHDR_SIZE];
+        ehdr[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        ehdr[16..18].copy_from_slice(&1u16.to_le_bytes());
+        ehdr[18..20].copy_from_slice(&A::ELF_MACHINE.to_le_bytes());
+        ehdr[20..24].copy_from_slice(&1u32.to_le_bytes());
+        ehdr[40..48].copy_from_slice(&shoff.to_le_bytes());
+        ehdr[52..54].copy_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        ehdr[58..60].copy_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+        ehdr[60..62].copy_from_slice(&(SECTIONS as u16).to_le_bytes());
+        ehdr[62..64].copy_from_slice(&(SHSTRTAB as u16).to_le_bytes());
+        out
+    }
+}
+
+#[derive(Default)]
+struct Shdr {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl Shdr {
+    fn put(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.name.to_le_bytes());
+        out.extend_from_slice(&self.kind.to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&self.size.to_le_bytes());
+        out.extend_from_slice(&self.link.to_le_bytes());
+        out.extend_from_slice(&self.info.to_le_bytes());
+        out.extend_from_slice(&self.align.to_le_bytes());
+        out.extend_from_slice(&self.entsize.to_le_bytes());
+    }
+}
+
+fn sym_info(bind: u8, kind: u8) -> u8 {
+    bind << 4 | kind
+}
+
+fn put_sym(symtab: &mut Vec<u8>, name: u32, info: u8, shndx: u16, value: u64, size: u64) {
+    symtab.extend_from_slice(&name.to_le_bytes());
+    symtab.extend_from_slice(&[info, 0]);
+    symtab.extend_from_slice(&shndx.to_le_bytes());
+    symtab.extend_from_slice(&value.to_le_bytes());
+    symtab.extend_from_slice(&size.to_le_bytes());
+}
+
+/// `R_X86_64_*` or `R_AARCH64_*`.
+fn elf_reloc_type(kind: RelocKind) -> u64 {
+    match kind {
+        RelocKind::Abs64 => 1,
+        RelocKind::Plt32 => 4,
+        RelocKind::MovwG0 => 264,
+        RelocKind::MovwG1 => 266,
+        RelocKind::MovwG2 => 268,
+        RelocKind::MovwG3 => 269,
+        RelocKind::Call26 => 283,
+    }
+}
+
+/// The x86 displacement is relative to the end of the field rather than its start.
+fn addend(kind: RelocKind) -> i64 {
+    if kind == RelocKind::Plt32 {
+        -4
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+    use std::process::Command;
+    use std::{env, fs};
+
+    use crate::asm::aarch64::AArch64;
+    use crate::asm::exec::Rt;
+    use crate::asm::object::Object;
+    use crate::asm::x86_64::X8664;
+    use crate::parser::ast::{parse_exp, Exp};
+    use crate::parser::lexer::Lexer;
+
+    fn parse(input: &str) -> Exp {
+        let mut lexer = Lexer::new(input);
+        lexer.advance().unwrap();
+        parse_exp(&mut lexer).unwrap()
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u64_at(bytes: &[u8], at: usize) -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&bytes[at..at + 8]);
+        u64::from_le_bytes(buf)
+    }
+
+    /// Names of the symbols in the object and the section they are defined in.
+    fn symbols(bytes: &[u8]) -> Vec<(String, u16)> {
+        let shoff = u64_at(bytes, 40) as usize;
+        let section = |i: usize| shoff + i * 64;
+        let symtab = section(3);
+        let (offset, size) = (
+            u64_at(bytes, symtab + 24) as usize,
+            u64_at(bytes, symtab + 32) as usize,
+        );
+        let strtab = u64_at(bytes, section(4) + 24) as usize;
+        (offset..offset + size)
+            .step_by(24)
+            .skip(1)
+            .map(|sym| {
+                let start =
+                    strtab + u32::from_le_bytes(bytes[sym..sym + 4].try_into().unwrap()) as usize;
+                let end = start + bytes[start..].iter().position(|b| *b == 0).unwrap();
+                let name = String::from_utf8(bytes[start..end].to_vec()).unwrap();
+                (name, u16_at(bytes, sym + 6))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut object = Object::<X8664>::new();
+        object.add("answer", &parse("6 * 7"), &[]).unwrap();
+        object
+            .add("cube", &parse("x ^ 3"), &[("x", Rt::Float)])
+            .unwrap();
+        let bytes = object.to_bytes();
+
+        assert_eq!(&bytes[..4], b"\x7fELF");
+        assert_eq!(u16_at(&bytes, 16), 1);
+        assert_eq!(u16_at(&bytes, 18), 62);
+        assert_eq!(
+            symbols(&bytes),
+            vec![
+                ("".to_string(), 1),
+                ("answer".to_string(), 1),
+                ("cube".to_string(), 1),
+                ("pow".to_string(), 0),
+            ]
+        );
+        assert!(object.header().ends_with(
+            "int64_t answer(uint64_t *status, const void *args);\n\
+             double cube(uint64_t *status, const void *args); /* double x */\n"
+        ));
+
+        let mut object = Object::<AArch64>::new();
+        object.add("rem", &parse("7.5 % 2"), &[]).unwrap();
+        let bytes = object.to_bytes();
+        assert_eq!(u16_at(&bytes, 18), 183);
+        assert_eq!(symbols(&bytes)[2], ("fmod".to_string(), 0));
+    }
+
+    #[test]
+    fn test_names() {
+        let mut object = Object::<X8664>::new();
+        object.add("_f1", &parse("1"), &[]).unwrap();
+        assert!(object.add("_f1", &parse("2"), &[]).is_err());
+        assert!(object.add("", &parse("2"), &[]).is_err());
+        assert!(object.add("1f", &parse("2"), &[]).is_err());
+        assert!(object.add("a-b", &parse("2"), &[]).is_err());
+
+        let err = object.add("f", &parse("x + 1"), &[]).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown variable 'x'");
+        object.add("f", &parse("x + 1"), &[("x", Rt::Int)]).unwrap();
+        // Failures are only known when the function is called.
+        object.add("g", &parse("1 + 2 / (1 - 1)"), &[]).unwrap();
+    }
+
+    /// Links the object into a C program with the system toolchain.
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn test_link() {
+        let mut object = Object::<X8664>::new();
+        object.add("answer", &parse("6 * 7"), &[]).unwrap();
+        object.add("cube", &parse("1.5 ^ 3"), &[]).unwrap();
+        object.add("rem", &parse("7.5 % 2"), &[]).unwrap();
+        object
+            .add("clamp", &parse("abs(-3) * max(2, min(7, 5))"), &[])
+            .unwrap();
+        let params = [("a", Rt::Int), ("x", Rt::Float)];
+        object.add("area", &parse("a * x / 2"), &params).unwrap();
+        object
+            .add("quot", &parse("a / b"), &[("a", Rt::Int), ("b", Rt::Int)])
+            .unwrap();
+
+        let dir = env::temp_dir().join(format!("neb-object-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("formulas.o"), object.to_bytes()).unwrap();
+        fs::write(
+            dir.join("main.c"),
+            format!(
+                "#include <stdint.h>\n#include <stdio.h>\n{}\
+                 int main(void) {{\n\
+                 uint64_t status[2] = {{0, 1000}};\n\
+                 printf(\"%lld %g %g %lld\\n\", (long long)answer(status, 0), cube(status, 0), \
+                 rem(status, 0), (long long)clamp(status, 0));\n\
+                 union {{ int64_t i; double f; }} args[2] = {{{{.i = 3}}, {{.f = 2.5}}}};\n\
+                 printf(\"%g\\n\", area(status, args));\n\
+                 int64_t ints[2] = {{7, 2}};\n\
+                 printf(\"%lld %llu\\n\", (long long)quot(status, ints), \
+                 (unsigned long long)status[0]);\n\
+                 ints[1] = 0;\n\
+                 quot(status, ints);\n\
+                 printf(\"%llu\\n\", (unsigned long long)status[0]);\n\
+                 }}\n",
+                object.header()
+            ),
+        )
+        .unwrap();
+
+        let status = Command::new("cc")
+            .current_dir(&dir)
+            // `-z text` refuses relocations the loader would have to patch the code for.
+            .args(["-pie", "-Wl,-z,text", "main.c", "formulas.o", "-lm", "-o", "main"])
+            .status()
+            .expect("Linking needs a C compiler named cc");
+        assert!(status.success());
+        let output = Command::new(dir.join("main")).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "42 3.375 1.5 15\n3.75\n3 0\n1\n"
+        );
+    }
+}
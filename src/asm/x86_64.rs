@@ -1,31 +1,113 @@
 use std::fmt::{Display, Formatter};
 use std::fmt;
+use std::ops::Range;
 
-use anyhow::{anyhow, Error, Result};
+use anyhow::Error;
 
-use crate::asm::arch::{Asm, Arch, DebugMod};
+use crate::asm::arch::{Arch, Asm, Label, Rel, RelocKind, Traps};
+use crate::asm::exec::Rt;
+use crate::asm::host::HostFn;
+use crate::asm::Fun;
+use crate::asm::x86_64::encoder::{Alu, Cond, Encoder, Mem, Sse};
+use crate::interpreter::EvalError;
+use crate::parser::ast::Op;
 
+pub mod disasm;
+pub mod encoder;
+pub mod executable;
+
+extern "C" {
+    fn pow(x: f64, y: f64) -> f64;
+}
+
+/// Integer argument registers of the System V calling convention.
+const INT_ARGS: [IntReg; 6] = [
+    IntReg::RDI,
+    IntReg::RSI,
+    IntReg::RDX,
+    IntReg::RCX,
+    IntReg::R8,
+    IntReg::R9,
+];
+
+#[derive(Default)]
 pub struct X8664 {
     asm: Asm,
-    debug: bool,
+    /// Set by `frame`; rbx then holds the status pointer, r12 the arguments and rbp the frame.
+    traps: Option<Traps>,
 }
 
 impl X8664 {
-    fn put(&mut self, bytes: &[u8]) {
-        self.asm.put(bytes);
+    fn enc(&mut self) -> Encoder<'_> {
+        Encoder::new(&mut self.asm)
     }
 
-    fn dbg(&self, f: impl Fn()) {
-        if self.debug {
-            f()
+    /// Jumps to the exit reporting `err` if the last comparison met `cond`.
+    fn trap(&mut self, cond: Cond, err: EvalError) {
+        if let Some(traps) = &mut self.traps {
+            let exit = traps.exit(&mut self.asm, err);
+            self.enc().jump(Some(cond), exit);
         }
     }
+
+    /// Signed rdx:rax / op. The quotient is left in rax and the remainder in rdx,
+    /// both truncated toward zero. Code with a frame leaves early where idiv would trap.
+    fn idiv(&mut self, op: IntReg) {
+        if self.traps.is_some() {
+            let valid = self.asm.label();
+            self.enc().test(op, op);
+            self.trap(Cond::Z, EvalError::DivisionByZero);
+            let mut enc = self.enc();
+            enc.alu_imm(Alu::Cmp, op, -1);
+            enc.jump_rel(Some(Cond::NZ), valid, Rel::Rel8);
+            enc.mov_imm(IntReg::RDX, i64::MIN);
+            enc.alu(Alu::Cmp, IntReg::RAX, IntReg::RDX);
+            self.trap(Cond::Z, EvalError::Overflow);
+            self.asm.bind(valid);
+        }
+        self.enc().cqo();
+        self.enc().idiv(op);
+    }
+
+    /// Pushes a 64-bit constant without touching any register.
+    fn push_imm(&mut self, val: u64) {
+        self.enc().push_imm(val as i32);
+        if val as i64 != val as i32 as i64 {
+            self.enc()
+                .store_imm32(Mem::base(IntReg::RSP).disp(4), (val >> 32) as u32);
+        }
+    }
+
+    /// System V call of `symbol` at an absolute address with the stack aligned to 16 bytes, or
+    /// through the linker if the code `links_calls`. Clobbers all caller-saved registers.
+    fn call_addr(&mut self, symbol: &str, addr: usize) {
+        let mut enc = self.enc();
+        enc.push(IntReg::RBP);
+        enc.mov(IntReg::RBP, IntReg::RSP);
+        enc.alu_imm(Alu::And, IntReg::RSP, -16);
+        if self.asm.links_calls() {
+            self.asm.relocate(self.asm.len() + 1, symbol, RelocKind::Plt32);
+            self.enc().call_extern();
+        } else {
+            self.asm.relocate(self.asm.len() + 2, symbol, RelocKind::Abs64);
+            let mut enc = self.enc();
+            enc.mov_abs(IntReg::RAX, addr as i64);
+            enc.call(IntReg::RAX);
+        }
+        let mut enc = self.enc();
+        enc.mov(IntReg::RSP, IntReg::RBP);
+        enc.pop(IntReg::RBP);
+    }
 }
 
 impl Arch for X8664 {
     type IntReg = IntReg;
     type FloatReg = FloatReg;
 
+    const NAME: &'static str = "x86_64";
+
+    const ELF_MACHINE: u16 = 62;
+
     const INT_RET: Self::IntReg = IntReg::RAX;
     const FLOAT_RET: Self::FloatReg = FloatReg::XMM0;
 
@@ -36,200 +118,510 @@ impl Arch for X8664 {
     const FLOAT_TMP: Self::FloatReg = FloatReg::XMM1;
 
     fn movi(&mut self, from: Self::IntReg, to: Self::IntReg) {
-        self.dbg(|| println!("movi {}, {}", to, from));
+        if from == to {
+            return;
+        }
+        self.enc().mov(to, from);
+    }
 
+    fn movf(&mut self, from: Self::FloatReg, to: Self::FloatReg) {
         if from == to {
             return;
         }
-        self.put(&[0x48, 0x89]);
+        self.enc().movapd(to, from);
+    }
+
+    fn storei(&mut self, reg: Self::IntReg, val: i64) {
+        self.enc().mov_imm(reg, val);
+    }
 
-        if from == IntReg::RAX && to == IntReg::RCX {
-            self.put(&[0xc1]);
+    fn storef(&mut self, reg: Self::FloatReg, val: f64) {
+        let bits = val.to_bits();
+        let mut enc = self.enc();
+        if bits == 0 {
+            enc.xorpd(reg, reg);
             return;
         }
 
-        if from == IntReg::RCX && to == IntReg::RAX {
-            self.put(&[0xc8]);
+        // The constant is assembled in the red zone.
+        let slot = Mem::base(IntReg::RSP).disp(-8);
+        if bits as i64 == bits as i32 as i64 {
+            enc.store_imm(slot, bits as i32);
+        } else {
+            enc.store_imm32(slot, bits as u32);
+            enc.store_imm32(Mem::base(IntReg::RSP).disp(-4), (bits >> 32) as u32);
         }
+        enc.movsd_load(reg, slot);
     }
 
-    fn movf(&mut self, from: Self::FloatReg, to: Self::FloatReg) {
-        self.dbg(|| println!("movf {}, {}", to, from));
-        unimplemented!()
+    fn castf(&mut self, from: Self::IntReg, to: Self::FloatReg) {
+        self.enc().cvtsi2sd(to, from);
     }
 
-    fn storei(&mut self, reg: Self::IntReg, val: i64) {
-        self.dbg(|| println!("movi {}, {}", reg, val));
+    fn negi(&mut self, reg: Self::IntReg) {
+        self.enc().neg(reg);
+    }
 
-        self.put(&[0x48]);
-        match reg {
-            IntReg::RAX => self.put(&[0xb8]),
-            IntReg::RCX => self.put(&[0xb9]),
-        }
-        self.put(&val.to_le_bytes());
+    fn negf(&mut self, reg: Self::FloatReg) {
+        // Only the sign bit is flipped, so -0.0 and NaNs behave as in Rust.
+        let mut enc = self.enc();
+        enc.mov_abs(IntReg::R11, i64::MIN);
+        enc.movq_to_xmm(FloatReg::XMM15, IntReg::R11);
+        enc.xorpd(reg, FloatReg::XMM15);
     }
 
-    fn storef(&mut self, reg: Self::FloatReg, val: f64) {
-        self.dbg(|| println!("movf {}, {}", reg, val));
-        unimplemented!()
+    fn sqrtf(&mut self, reg: Self::FloatReg) {
+        self.enc().sse(Sse::Sqrt, reg, reg);
     }
 
-    fn castf(&mut self, from: Self::IntReg, to: Self::FloatReg) {
-        self.dbg(|| println!("movc {}, {}", to, from));
-        unimplemented!()
+    fn absi(&mut self, reg: Self::IntReg) {
+        // The negation is taken where it isn't negative, which leaves `i64::MIN` alone.
+        let mut enc = self.enc();
+        enc.mov(IntReg::R11, reg);
+        enc.neg(IntReg::R11);
+        enc.cmov(Cond::NS, reg, IntReg::R11);
     }
 
-    fn addi(&mut self, op: Self::IntReg) {
-        self.dbg(|| println!("addi {}, {}", Self::INT_ACC, op));
+    fn mini(&mut self, op: Self::IntReg) {
+        let mut enc = self.enc();
+        enc.alu(Alu::Cmp, Self::INT_ACC, op);
+        enc.cmov(Cond::G, Self::INT_ACC, op);
+    }
 
-        self.put(&[0x48, 0x01]);
-        match op {
-            IntReg::RAX => self.put(&[0xc0]),
-            IntReg::RCX => self.put(&[0xc8]),
-        }
+    fn maxi(&mut self, op: Self::IntReg) {
+        let mut enc = self.enc();
+        enc.alu(Alu::Cmp, Self::INT_ACC, op);
+        enc.cmov(Cond::L, Self::INT_ACC, op);
+    }
+
+    fn addi(&mut self, op: Self::IntReg) {
+        self.enc().alu(Alu::Add, Self::INT_ACC, op);
     }
 
     fn addf(&mut self, op: Self::FloatReg) {
-        self.dbg(|| println!("addi {}, {}", Self::FLOAT_ACC, op));
-        unimplemented!()
+        self.enc().sse(Sse::Add, Self::FLOAT_ACC, op);
     }
 
     fn subi(&mut self, op: Self::IntReg) {
-        self.dbg(|| println!("subi {}, {}", Self::INT_ACC, op));
-
-        unimplemented!()
+        self.enc().alu(Alu::Sub, Self::INT_ACC, op);
     }
 
     fn subf(&mut self, op: Self::FloatReg) {
-        self.dbg(|| println!("subf {}, {}", Self::FLOAT_ACC, op));
-
-        unimplemented!()
+        self.enc().sse(Sse::Sub, Self::FLOAT_ACC, op);
     }
 
     fn muli(&mut self, op: Self::IntReg) {
-        self.dbg(|| println!("muli {}, {}", Self::INT_ACC, op));
-
-        self.put(&[0x48, 0xf7]);
-        match op {
-            IntReg::RAX => self.put(&[0xe8]),
-            IntReg::RCX => self.put(&[0xe9]),
-        }
+        // Two-operand imul keeps the low 64 bits and leaves rdx alone.
+        self.enc().imul(Self::INT_ACC, op);
     }
 
     fn mulf(&mut self, op: Self::FloatReg) {
-        self.dbg(|| println!("mulf {}, {}", Self::FLOAT_ACC, op));
-
-        unimplemented!()
+        self.enc().sse(Sse::Mul, Self::FLOAT_ACC, op);
     }
 
     fn modi(&mut self, op: Self::IntReg) {
-        unimplemented!()
+        self.idiv(op);
+        self.enc().mov(Self::INT_ACC, IntReg::RDX);
     }
 
     fn modf(&mut self, op: Self::FloatReg) {
-        unimplemented!()
+        // fprem keeps the truncated quotient, so the result is exactly what fmod returns.
+        // The operands go through the red zone.
+        let rsp = |disp| Mem::base(IntReg::RSP).disp(disp);
+        let fprem = self.asm.label();
+        let mut enc = self.enc();
+        enc.movsd_store(rsp(-16), op);
+        enc.movsd_store(rsp(-8), Self::FLOAT_ACC);
+        enc.fld(rsp(-16));
+        enc.fld(rsp(-8));
+        // Repeat while C2 (reduction incomplete) is set.
+        enc.asm().bind(fprem);
+        enc.fprem();
+        enc.fnstsw(rsp(-18));
+        enc.test_imm8(rsp(-17), 0x04);
+        enc.jump(Some(Cond::NZ), fprem);
+        enc.fstp_st(1);
+        enc.fstp(rsp(-8));
+        enc.movsd_load(Self::FLOAT_ACC, rsp(-8));
     }
 
     fn divi(&mut self, op: Self::IntReg) {
-        self.dbg(|| println!("divi {}, {}", Self::INT_ACC, op));
-
-        self.put(&[0x48, 0xf7]);
-        match op {
-            IntReg::RAX => self.put(&[0xf8]),
-            IntReg::RCX => self.put(&[0xf9]),
-        }
+        self.idiv(op);
     }
 
     fn divf(&mut self, op: Self::FloatReg) {
-        self.dbg(|| println!("divf {}, {}", Self::INT_ACC, op));
-        unimplemented!()
+        self.enc().sse(Sse::Div, Self::FLOAT_ACC, op);
     }
 
     fn powi(&mut self, op: Self::IntReg) {
-        unimplemented!()
+        if op != IntReg::RCX {
+            self.movi(op, IntReg::RCX);
+        }
+
+        let (pos, repeat, skip, done) = (
+            self.asm.label(),
+            self.asm.label(),
+            self.asm.label(),
+            self.asm.label(),
+        );
+        let (base, exp, result) = (IntReg::RAX, IntReg::RCX, IntReg::RDX);
+
+        let mut enc = self.enc();
+        enc.test(exp, exp);
+        enc.jump_rel(Some(Cond::NS), pos, Rel::Rel8);
+        // A negative exponent follows truncating division: base^-n == (1 / base)^n.
+        enc.neg(exp);
+        enc.mov(IntReg::R8, base);
+        enc.mov_imm(IntReg::RAX, 1);
+        self.idiv(IntReg::R8);
+
+        let mut enc = self.enc();
+        enc.asm().bind(pos);
+        enc.mov_imm(result, 1);
+        enc.asm().bind(repeat);
+        enc.test(exp, exp);
+        enc.jump_rel(Some(Cond::Z), done, Rel::Rel8);
+        enc.test_imm8(exp, 1);
+        enc.jump_rel(Some(Cond::Z), skip, Rel::Rel8);
+        enc.imul(result, base);
+        enc.asm().bind(skip);
+        enc.imul(base, base);
+        enc.shr(exp, 1);
+        enc.jump(None, repeat);
+        enc.asm().bind(done);
+        enc.mov(Self::INT_ACC, result);
     }
 
     fn powf(&mut self, op: Self::FloatReg) {
-        unimplemented!()
+        if op != FloatReg::XMM1 {
+            self.movf(op, FloatReg::XMM1);
+        }
+        self.call_addr("pow", pow as *const () as usize);
     }
 
     fn popi(&mut self, reg: Self::IntReg) {
-        unimplemented!()
+        self.enc().pop(reg);
     }
 
     fn popf(&mut self, reg: Self::FloatReg) {
-        unimplemented!()
+        self.enc().movsd_load(reg, Mem::base(IntReg::RSP));
+        self.enc().alu_imm(Alu::Add, IntReg::RSP, 8);
     }
 
     fn pushli(&mut self, val: i64) {
-        unimplemented!()
+        self.push_imm(val as u64);
     }
 
     fn pushlf(&mut self, val: f64) {
-        unimplemented!()
+        self.push_imm(val.to_bits());
     }
 
     fn pushi(&mut self, reg: Self::IntReg) {
-        unimplemented!()
+        self.enc().push(reg);
     }
 
     fn pushf(&mut self, reg: Self::FloatReg) {
-        unimplemented!()
+        self.enc().alu_imm(Alu::Sub, IntReg::RSP, 8);
+        self.enc().movsd_store(Mem::base(IntReg::RSP), reg);
+    }
+
+    fn call(&mut self, fun: &HostFn) {
+        let rsp = Mem::base(IntReg::RSP);
+        let mut enc = self.enc();
+        enc.push(Self::INT_TMP);
+        enc.alu_imm(Alu::Sub, IntReg::RSP, 8);
+        enc.movsd_store(rsp, Self::FLOAT_TMP);
+
+        let count = fun.args().len();
+        let (mut ints, mut floats) = (0, 0);
+        for (i, rt) in fun.args().iter().enumerate() {
+            let arg = rsp.disp((16 + 8 * (count - 1 - i)) as i32);
+            if *rt == Rt::Int {
+                enc.load(INT_ARGS[ints], arg);
+                ints += 1;
+            } else {
+                enc.movsd_load(FloatReg::from_code(floats), arg);
+                floats += 1;
+            }
+        }
+
+        self.call_addr(fun.name(), fun.addr());
+
+        let mut enc = self.enc();
+        enc.movsd_load(Self::FLOAT_TMP, rsp);
+        enc.alu_imm(Alu::Add, IntReg::RSP, 8);
+        enc.pop(Self::INT_TMP);
+        if count != 0 {
+            enc.alu_imm(Alu::Add, IntReg::RSP, 8 * count as i32);
+        }
+    }
+
+    fn cmpi(&mut self, op: Self::IntReg, cmp: Op) {
+        let cond = match cmp {
+            Op::Lt => Cond::L,
+            Op::Le => Cond::LE,
+            Op::Gt => Cond::G,
+            Op::Ge => Cond::GE,
+            Op::Eq => Cond::Z,
+            Op::Ne => Cond::NZ,
+            op => panic!("{} is not a comparison", op),
+        };
+        let mut enc = self.enc();
+        enc.alu(Alu::Cmp, Self::INT_ACC, op);
+        enc.setcc(cond, Self::INT_ACC);
+        enc.movzx_byte(Self::INT_ACC, Self::INT_ACC);
+    }
+
+    fn cmpf(&mut self, op: Self::FloatReg, cmp: Op) {
+        // cmpsd only has the less-than predicates, so `>` and `>=` swap the operands.
+        let (left, right, pred) = match cmp {
+            Op::Lt => (Self::FLOAT_ACC, op, 1),
+            Op::Le => (Self::FLOAT_ACC, op, 2),
+            Op::Gt => (op, Self::FLOAT_ACC, 1),
+            Op::Ge => (op, Self::FLOAT_ACC, 2),
+            Op::Eq => (Self::FLOAT_ACC, op, 0),
+            Op::Ne => (Self::FLOAT_ACC, op, 4),
+            op => panic!("{} is not a comparison", op),
+        };
+        let mut enc = self.enc();
+        enc.movapd(FloatReg::XMM15, left);
+        enc.cmpsd(FloatReg::XMM15, right, pred);
+        enc.movq_from_xmm(Self::INT_ACC, FloatReg::XMM15);
+        enc.alu_imm(Alu::And, Self::INT_ACC, 1);
+    }
+
+    fn jump(&mut self, label: Label) {
+        self.enc().jump(None, label);
+    }
+
+    fn jump_zero(&mut self, reg: Self::IntReg, label: Label) {
+        let mut enc = self.enc();
+        enc.test(reg, reg);
+        enc.jump(Some(Cond::Z), label);
+    }
+
+    fn reserve(&mut self, slots: usize) {
+        if slots != 0 {
+            self.enc()
+                .alu_imm(Alu::Sub, IntReg::RSP, 8 * slots as i32);
+        }
+    }
+
+    fn setargi(&mut self, reg: Self::IntReg, slot: usize) {
+        self.enc()
+            .store(Mem::base(IntReg::RSP).disp(8 * slot as i32), reg);
+    }
+
+    fn setargf(&mut self, reg: Self::FloatReg, slot: usize) {
+        self.enc()
+            .movsd_store(Mem::base(IntReg::RSP).disp(8 * slot as i32), reg);
+    }
+
+    fn apply(&mut self, target: Label, slots: usize) {
+        let budget = Mem::base(IntReg::RBX).disp(8);
+        self.enc().alu_imm(Alu::Sub, budget, 1);
+        self.trap(Cond::S, EvalError::StackOverflow);
+
+        let mut enc = self.enc();
+        enc.mov(IntReg::RDI, IntReg::RBX);
+        enc.mov(IntReg::RSI, IntReg::RSP);
+        enc.call_label(target);
+        enc.alu_imm(Alu::Add, budget, 1);
+        if slots != 0 {
+            enc.alu_imm(Alu::Add, IntReg::RSP, 8 * slots as i32);
+        }
+        enc.alu_imm(Alu::Cmp, Mem::base(IntReg::RBX), 0);
+        let unwind = self
+            .traps
+            .as_mut()
+            .expect("Calls require a frame")
+            .unwind(&mut self.asm);
+        self.enc().jump(Some(Cond::NZ), unwind);
+    }
+
+    fn frame(&mut self) {
+        let mut enc = self.enc();
+        enc.push(IntReg::RBP);
+        enc.mov(IntReg::RBP, IntReg::RSP);
+        enc.push(IntReg::RBX);
+        enc.push(IntReg::R12);
+        enc.mov(IntReg::RBX, IntReg::RDI);
+        enc.mov(IntReg::R12, IntReg::RSI);
+        self.traps = Some(Traps::default());
+    }
+
+    fn loadi(&mut self, reg: Self::IntReg, slot: usize) {
+        self.enc().load(reg, Mem::base(IntReg::R12).disp(8 * slot as i32));
+    }
+
+    fn loadf(&mut self, reg: Self::FloatReg, slot: usize) {
+        self.enc()
+            .movsd_load(reg, Mem::base(IntReg::R12).disp(8 * slot as i32));
     }
 
     fn ret(&mut self) {
-        self.dbg(|| println!("ret"));
-        self.put(&[0xc3]);
+        let traps = match self.traps.take() {
+            Some(traps) => traps,
+            None => return self.enc().ret(),
+        };
+
+        // Error exits may leave spilled operands behind, the frame drops them.
+        let exit = traps.propagated().unwrap_or_else(|| self.asm.label());
+        let mut enc = self.enc();
+        enc.asm().bind(exit);
+        enc.lea(IntReg::RSP, Mem::base(IntReg::RBP).disp(-16));
+        enc.pop(IntReg::R12);
+        enc.pop(IntReg::RBX);
+        enc.pop(IntReg::RBP);
+        enc.ret();
+        for (err, label) in traps.into_exits() {
+            let code = err.code().expect("Only errors with a code are trapped");
+            enc.asm().bind(label);
+            enc.store_imm(Mem::base(IntReg::RBX), code as i32);
+            enc.jump(None, exit);
+        }
     }
-}
 
-impl DebugMod for X8664 {
-    fn debug_mod(&mut self, debug_mod: bool) {
-        self.debug = debug_mod;
+    fn asm(&mut self) -> &mut Asm {
+        &mut self.asm
     }
-}
 
-impl Default for X8664 {
-    fn default() -> Self {
-        X8664 {
-            asm: Asm::new(),
-            debug: false,
+    fn library(symbol: &str) -> Option<usize> {
+        match symbol {
+            "pow" => Some(pow as *const () as usize),
+            _ => None,
         }
     }
+
+    fn disassemble(code: &[u8]) -> Result<Vec<(Range<usize>, String)>, Error> {
+        Ok(disasm::disassemble(code)?
+            .into_iter()
+            .map(|insn| (insn.offset..insn.offset + insn.len, insn.text))
+            .collect())
+    }
+}
+
+impl From<X8664> for Asm {
+    fn from(arch: X8664) -> Asm {
+        arch.asm
+    }
 }
 
-impl Into<Asm> for X8664 {
-    fn into(self) -> Asm {
-        self.asm
+impl Fun<X8664> {
+    /// Intel syntax listing of the bytes that were actually emitted.
+    pub fn disassemble(&self) -> Result<String, Error> {
+        disasm::listing(&self.bytecode())
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum IntReg {
     RAX,
     RCX,
+    RDX,
+    RBX,
+    RSP,
+    RBP,
+    RSI,
+    RDI,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum FloatReg {
     XMM0,
     XMM1,
+    XMM2,
+    XMM3,
+    XMM4,
+    XMM5,
+    XMM6,
+    XMM7,
+    XMM8,
+    XMM9,
+    XMM10,
+    XMM11,
+    XMM12,
+    XMM13,
+    XMM14,
+    XMM15,
+}
+
+impl IntReg {
+    const ALL: [IntReg; 16] = [
+        IntReg::RAX,
+        IntReg::RCX,
+        IntReg::RDX,
+        IntReg::RBX,
+        IntReg::RSP,
+        IntReg::RBP,
+        IntReg::RSI,
+        IntReg::RDI,
+        IntReg::R8,
+        IntReg::R9,
+        IntReg::R10,
+        IntReg::R11,
+        IntReg::R12,
+        IntReg::R13,
+        IntReg::R14,
+        IntReg::R15,
+    ];
+
+    /// Register number used in ModRM, SIB and REX.
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_code(code: u8) -> IntReg {
+        Self::ALL[code as usize]
+    }
+}
+
+impl FloatReg {
+    const ALL: [FloatReg; 16] = [
+        FloatReg::XMM0,
+        FloatReg::XMM1,
+        FloatReg::XMM2,
+        FloatReg::XMM3,
+        FloatReg::XMM4,
+        FloatReg::XMM5,
+        FloatReg::XMM6,
+        FloatReg::XMM7,
+        FloatReg::XMM8,
+        FloatReg::XMM9,
+        FloatReg::XMM10,
+        FloatReg::XMM11,
+        FloatReg::XMM12,
+        FloatReg::XMM13,
+        FloatReg::XMM14,
+        FloatReg::XMM15,
+    ];
+
+    /// Register number used in ModRM and REX.
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_code(code: u8) -> FloatReg {
+        Self::ALL[code as usize]
+    }
 }
 
 impl Display for IntReg {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            IntReg::RAX => write!(f, "rax"),
-            IntReg::RCX => write!(f, "rcx"),
-        }
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
     }
 }
 
 impl Display for FloatReg {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            FloatReg::XMM0 => write!(f, "xmm0"),
-            FloatReg::XMM1 => write!(f, "xmm1"),
-        }
+        write!(f, "xmm{}", self.code())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,814 @@
+use std::fmt::Write;
+
+use anyhow::{anyhow, Error};
+
+use crate::asm::x86_64::{FloatReg, IntReg};
+
+/// Decoded instruction of a code buffer.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Insn {
+    pub offset: usize,
+    pub len: usize,
+    /// Intel syntax, e.g. `mov qword ptr [rsp - 0x8], rax`.
+    pub text: String,
+}
+
+const CONDS: [&str; 16] = [
+    "o", "no", "b", "ae", "z", "nz", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+/// Predicates of `cmpsd`, by immediate.
+const CMPSD: [&str; 8] = ["eq", "lt", "le", "unord", "neq", "nlt", "nle", "ord"];
+
+const ALUS: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+
+const REGS8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
+/// Operand width, picks the register name or the `ptr` size of a memory operand.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+    Xmm,
+}
+
+/// Register or memory operand of the ModRM byte, the memory operand is already formatted.
+enum Rm {
+    Reg(u8),
+    Mem(String),
+}
+
+struct Reader<'a> {
+    code: &'a [u8],
+    start: usize,
+    pos: usize,
+    /// REX prefix of the current instruction, 0 if there is none.
+    rex: u8,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self
+            .code
+            .get(self.pos)
+            .ok_or_else(|| anyhow!("Truncated instruction at offset {:#x}", self.start))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut bytes = [0; N];
+        for byte in bytes.iter_mut() {
+            *byte = self.byte()?;
+        }
+        Ok(bytes)
+    }
+
+    fn i8(&mut self) -> Result<i64, Error> {
+        Ok(self.byte()? as i8 as i64)
+    }
+
+    fn i32(&mut self) -> Result<i64, Error> {
+        Ok(i32::from_le_bytes(self.bytes()?) as i64)
+    }
+
+    fn w(&self) -> bool {
+        self.rex & 8 != 0
+    }
+
+    /// Decodes ModRM and its SIB byte and displacement, returns the `reg` field and the
+    /// `r/m` operand, both extended by REX.
+    fn modrm(&mut self) -> Result<(u8, Rm), Error> {
+        let modrm = self.byte()?;
+        let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 7, modrm & 7);
+        let reg = reg | (self.rex & 4) << 1;
+        if md == 3 {
+            return Ok((reg, Rm::Reg(rm | (self.rex & 1) << 3)));
+        }
+
+        let mut parts = vec![];
+        // Without a base register the displacement is always 32 bits.
+        let mut disp32 = md == 2;
+        if rm == 4 {
+            let sib = self.byte()?;
+            let (scale, index, base) = (1 << (sib >> 6), (sib >> 3) & 7, sib & 7);
+            let index = index | (self.rex & 2) << 2;
+            if base != 5 || md != 0 {
+                parts.push(IntReg::from_code(base | (self.rex & 1) << 3).to_string());
+            } else {
+                disp32 = true;
+            }
+            if index != 4 {
+                parts.push(format!("{}*{}", IntReg::from_code(index), scale));
+            }
+        } else if rm == 5 && md == 0 {
+            parts.push("rip".to_owned());
+            disp32 = true;
+        } else {
+            parts.push(IntReg::from_code(rm | (self.rex & 1) << 3).to_string());
+        }
+
+        let disp = match md {
+            1 => self.i8()?,
+            _ if disp32 => self.i32()?,
+            _ => 0,
+        };
+        let mut mem = format!("[{}", parts.join(" + "));
+        if disp < 0 {
+            write!(mem, " - {:#x}", -disp).unwrap();
+        } else if disp > 0 || parts.is_empty() {
+            write!(mem, " + {:#x}", disp).unwrap();
+        }
+        mem.push(']');
+        Ok((reg, Rm::Mem(mem)))
+    }
+}
+
+fn reg(code: u8, size: Size, rex: bool) -> String {
+    match size {
+        Size::Byte if rex => REGS8[code as usize].to_owned(),
+        Size::Byte => ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"][code as usize].to_owned(),
+        Size::Word if code >= 8 => format!("{}w", IntReg::from_code(code)),
+        Size::Word => REGS32[code as usize].trim_start_matches('e').to_owned(),
+        Size::Dword => REGS32[code as usize].to_owned(),
+        Size::Qword => IntReg::from_code(code).to_string(),
+        Size::Xmm => FloatReg::from_code(code).to_string(),
+    }
+}
+
+fn operand(rm: &Rm, size: Size, rex: bool) -> String {
+    match rm {
+        Rm::Reg(code) => reg(*code, size, rex),
+        Rm::Mem(mem) => {
+            let ptr = match size {
+                Size::Byte => "byte",
+                Size::Word => "word",
+                Size::Dword => "dword",
+                Size::Qword | Size::Xmm => "qword",
+            };
+            format!("{} ptr {}", ptr, mem)
+        }
+    }
+}
+
+fn imm(val: i64) -> String {
+    if val < 0 {
+        format!("-{:#x}", -(val as i128))
+    } else {
+        format!("{:#x}", val)
+    }
+}
+
+/// Decodes one instruction of the subset the backend emits.
+fn decode(r: &mut Reader<'_>) -> Result<String, Error> {
+    let mut prefix = None;
+    let mut op = r.byte()?;
+    if matches!(op, 0x66 | 0xf2) {
+        prefix = Some(op);
+        op = r.byte()?;
+    }
+    if op & 0xf0 == 0x40 {
+        r.rex = op;
+        op = r.byte()?;
+    }
+    let has_rex = r.rex != 0;
+    let size = if r.w() { Size::Qword } else { Size::Dword };
+    let end = |r: &Reader<'_>, disp: i64| format!("{:#x}", r.pos as i64 + disp);
+
+    Ok(match (prefix, op) {
+        (None, 0x01..=0x3f) if op & 7 == 1 || op & 7 == 3 => {
+            let (reg_, rm) = r.modrm()?;
+            let (a, b) = (operand(&rm, size, has_rex), reg(reg_, size, has_rex));
+            if op & 7 == 1 {
+                format!("{} {}, {}", ALUS[op as usize >> 3], a, b)
+            } else {
+                format!("{} {}, {}", ALUS[op as usize >> 3], b, a)
+            }
+        }
+        (None, 0x50..=0x57) => format!("push {}", IntReg::from_code(op & 7 | (r.rex & 1) << 3)),
+        (None, 0x58..=0x5f) => format!("pop {}", IntReg::from_code(op & 7 | (r.rex & 1) << 3)),
+        (None, 0x68) => format!("push {}", imm(r.i32()?)),
+        (None, 0x6a) => format!("push {}", imm(r.i8()?)),
+        (None, 0x70..=0x7f) => {
+            let disp = r.i8()?;
+            format!("j{} {}", CONDS[op as usize & 15], end(r, disp))
+        }
+        (None, 0x81) | (None, 0x83) => {
+            let (digit, rm) = r.modrm()?;
+            let val = if op == 0x81 { r.i32()? } else { r.i8()? };
+            format!(
+                "{} {}, {}",
+                ALUS[digit as usize & 7],
+                operand(&rm, size, has_rex),
+                imm(val)
+            )
+        }
+        (None, 0x85) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "test {}, {}",
+                operand(&rm, size, has_rex),
+                reg(reg_, size, has_rex)
+            )
+        }
+        (None, 0x88) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "mov {}, {}",
+                operand(&rm, Size::Byte, has_rex),
+                reg(reg_, Size::Byte, has_rex)
+            )
+        }
+        (None, 0x89) | (None, 0x8b) | (None, 0x8d) => {
+            let (reg_, rm) = r.modrm()?;
+            let (a, b) = (operand(&rm, size, has_rex), reg(reg_, size, has_rex));
+            match op {
+                0x89 => format!("mov {}, {}", a, b),
+                0x8b => format!("mov {}, {}", b, a),
+                _ => format!("lea {}, {}", b, a.trim_start_matches("qword ptr ")),
+            }
+        }
+        (None, 0x99) if r.w() => "cqo".to_owned(),
+        (None, 0x99) => "cdq".to_owned(),
+        (None, 0xb8..=0xbf) => {
+            let code = op & 7 | (r.rex & 1) << 3;
+            if r.w() {
+                let val = i64::from_le_bytes(r.bytes()?);
+                format!("movabs {}, {}", IntReg::from_code(code), imm(val))
+            } else {
+                let val = u32::from_le_bytes(r.bytes()?);
+                format!("mov {}, {:#x}", REGS32[code as usize], val)
+            }
+        }
+        (None, 0xc1) | (None, 0xd1) => {
+            let (digit, rm) = r.modrm()?;
+            let name = match digit & 7 {
+                4 => "shl",
+                5 => "shr",
+                7 => "sar",
+                _ => return Err(anyhow!("Unknown shift /{} at offset {:#x}", digit, r.start)),
+            };
+            let count = if op == 0xc1 { r.byte()? } else { 1 };
+            format!("{} {}, {:#x}", name, operand(&rm, size, has_rex), count)
+        }
+        (None, 0xc3) => "ret".to_owned(),
+        (None, 0xc7) => {
+            let (_, rm) = r.modrm()?;
+            let val = r.i32()?;
+            let val = if r.w() {
+                imm(val)
+            } else {
+                format!("{:#x}", val as u32)
+            };
+            format!("mov {}, {}", operand(&rm, size, has_rex), val)
+        }
+        (None, 0xd9) if r.code.get(r.pos) == Some(&0xf8) => {
+            r.byte()?;
+            "fprem".to_owned()
+        }
+        (None, 0xdd) => {
+            let (digit, rm) = r.modrm()?;
+            match (digit & 7, rm) {
+                (3, Rm::Reg(i)) => format!("fstp st({})", i & 7),
+                (0, rm @ Rm::Mem(_)) => format!("fld {}", operand(&rm, Size::Qword, has_rex)),
+                (3, rm) => format!("fstp {}", operand(&rm, Size::Qword, has_rex)),
+                (7, rm @ Rm::Mem(_)) => format!("fnstsw {}", operand(&rm, Size::Word, has_rex)),
+                (digit, _) => {
+                    return Err(anyhow!(
+                        "Unknown x87 opcode /{} at offset {:#x}",
+                        digit,
+                        r.start
+                    ))
+                }
+            }
+        }
+        (None, 0xe8) => {
+            let disp = r.i32()?;
+            format!("call {}", end(r, disp))
+        }
+        (None, 0xe9) => {
+            let disp = r.i32()?;
+            format!("jmp {}", end(r, disp))
+        }
+        (None, 0xeb) => {
+            let disp = r.i8()?;
+            format!("jmp {}", end(r, disp))
+        }
+        (None, 0xf6) => {
+            let (_, rm) = r.modrm()?;
+            let val = r.byte()?;
+            format!("test {}, {:#x}", operand(&rm, Size::Byte, has_rex), val)
+        }
+        (None, 0xf7) => {
+            let (digit, rm) = r.modrm()?;
+            let name = match digit & 7 {
+                2 => "not",
+                3 => "neg",
+                4 => "mul",
+                6 => "div",
+                7 => "idiv",
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown opcode f7 /{} at offset {:#x}",
+                        digit,
+                        r.start
+                    ))
+                }
+            };
+            format!("{} {}", name, operand(&rm, size, has_rex))
+        }
+        (None, 0xff) => {
+            let (digit, rm) = r.modrm()?;
+            match digit & 7 {
+                2 => format!("call {}", operand(&rm, Size::Qword, has_rex)),
+                4 => format!("jmp {}", operand(&rm, Size::Qword, has_rex)),
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown opcode ff /{} at offset {:#x}",
+                        digit,
+                        r.start
+                    ))
+                }
+            }
+        }
+        (_, 0x0f) => decode_0f(r, prefix, size)?,
+        _ => {
+            return Err(anyhow!(
+                "Unknown opcode {:#04x} at offset {:#x}",
+                op,
+                r.start
+            ))
+        }
+    })
+}
+
+/// Two-byte opcodes: `jcc rel32`, `setcc`, `cmovcc`, `imul`, `movzx`, `shrd` and the SSE2
+/// instructions.
+fn decode_0f(r: &mut Reader<'_>, prefix: Option<u8>, size: Size) -> Result<String, Error> {
+    let op = r.byte()?;
+    let has_rex = r.rex != 0;
+    let xmm = |r: &mut Reader<'_>, name: &str| -> Result<String, Error> {
+        let (reg_, rm) = r.modrm()?;
+        Ok(format!(
+            "{} {}, {}",
+            name,
+            reg(reg_, Size::Xmm, true),
+            operand(&rm, Size::Xmm, true)
+        ))
+    };
+
+    Ok(match (prefix, op) {
+        (None, 0x05) => "syscall".to_owned(),
+        (None, 0x80..=0x8f) => {
+            let disp = r.i32()?;
+            format!("j{} {:#x}", CONDS[op as usize & 15], r.pos as i64 + disp)
+        }
+        (None, 0x90..=0x9f) => {
+            let (_, rm) = r.modrm()?;
+            format!(
+                "set{} {}",
+                CONDS[op as usize & 15],
+                operand(&rm, Size::Byte, has_rex)
+            )
+        }
+        (None, 0x40..=0x4f) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "cmov{} {}, {}",
+                CONDS[op as usize & 15],
+                reg(reg_, size, has_rex),
+                operand(&rm, size, has_rex)
+            )
+        }
+        (None, 0xb6) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "movzx {}, {}",
+                reg(reg_, size, has_rex),
+                operand(&rm, Size::Byte, has_rex)
+            )
+        }
+        (None, 0xad) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "shrd {}, {}, cl",
+                operand(&rm, size, has_rex),
+                reg(reg_, size, has_rex)
+            )
+        }
+        (None, 0xaf) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "imul {}, {}",
+                reg(reg_, size, has_rex),
+                operand(&rm, size, has_rex)
+            )
+        }
+        (Some(0xf2), 0x10) => xmm(r, "movsd")?,
+        (Some(0xf2), 0x11) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "movsd {}, {}",
+                operand(&rm, Size::Xmm, true),
+                reg(reg_, Size::Xmm, true)
+            )
+        }
+        (Some(0xf2), 0x2a) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "cvtsi2sd {}, {}",
+                reg(reg_, Size::Xmm, true),
+                operand(&rm, size, has_rex)
+            )
+        }
+        (Some(0xf2), 0x2c) => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "cvttsd2si {}, {}",
+                reg(reg_, size, has_rex),
+                operand(&rm, Size::Xmm, true)
+            )
+        }
+        (Some(0xf2), 0x51) => xmm(r, "sqrtsd")?,
+        (Some(0xf2), 0x58) => xmm(r, "addsd")?,
+        (Some(0xf2), 0x59) => xmm(r, "mulsd")?,
+        (Some(0xf2), 0x5c) => xmm(r, "subsd")?,
+        (Some(0xf2), 0x5e) => xmm(r, "divsd")?,
+        (Some(0xf2), 0xc2) => {
+            let (reg_, rm) = r.modrm()?;
+            let pred = r.byte()?;
+            format!(
+                "cmp{}sd {}, {}",
+                CMPSD[pred as usize & 7],
+                reg(reg_, Size::Xmm, true),
+                operand(&rm, Size::Xmm, true)
+            )
+        }
+        (Some(0x66), 0x28) => xmm(r, "movapd")?,
+        (Some(0x66), 0x57) => xmm(r, "xorpd")?,
+        (Some(0x66), 0x6e) if r.w() => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "movq {}, {}",
+                reg(reg_, Size::Xmm, true),
+                operand(&rm, Size::Qword, true)
+            )
+        }
+        (Some(0x66), 0x7e) if r.w() => {
+            let (reg_, rm) = r.modrm()?;
+            format!(
+                "movq {}, {}",
+                operand(&rm, Size::Qword, true),
+                reg(reg_, Size::Xmm, true)
+            )
+        }
+        _ => {
+            return Err(anyhow!(
+                "Unknown opcode 0x0f {:#04x} at offset {:#x}",
+                op,
+                r.start
+            ))
+        }
+    })
+}
+
+/// Decodes a buffer of x86-64 code produced by the backend.
+pub fn disassemble(code: &[u8]) -> Result<Vec<Insn>, Error> {
+    let mut insns = vec![];
+    let mut pos = 0;
+    while pos < code.len() {
+        let mut reader = Reader {
+            code,
+            start: pos,
+            pos,
+            rex: 0,
+        };
+        let text = decode(&mut reader)?;
+        insns.push(Insn {
+            offset: pos,
+            len: reader.pos - pos,
+            text,
+        });
+        pos = reader.pos;
+    }
+    Ok(insns)
+}
+
+/// Listing with one instruction per line: offset, encoded bytes and Intel syntax.
+pub fn listing(code: &[u8]) -> Result<String, Error> {
+    let mut out = String::new();
+    for insn in disassemble(code)? {
+        let bytes: Vec<String> = code[insn.offset..insn.offset + insn.len]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        writeln!(
+            out,
+            "{:04x}:  {:<30} {}",
+            insn.offset,
+            bytes.join(" "),
+            insn.text
+        )
+        .unwrap();
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use crate::asm::arch::{Asm, Rel};
+    use crate::asm::x86_64::disasm::{disassemble, listing};
+    use crate::asm::x86_64::encoder::{Alu, Cond, Encoder, Mem, Sse};
+    use crate::asm::x86_64::{FloatReg, IntReg, X8664};
+    use crate::asm::Fun;
+    use crate::parser::ast::{parse_exp, Exp};
+    use crate::parser::lexer::Lexer;
+
+    fn perform(expected: &[&str], f: impl FnOnce(&mut Encoder<'_>)) {
+        let mut asm = Asm::new();
+        f(&mut Encoder::new(&mut asm));
+        asm.finalize().unwrap();
+        let insns = disassemble(asm.buffer()).unwrap();
+        let text: Vec<&str> = insns.iter().map(|insn| insn.text.as_str()).collect();
+        assert_eq!(text, expected);
+        assert_eq!(insns.iter().map(|insn| insn.len).sum::<usize>(), asm.len());
+    }
+
+    fn parse(input: &str) -> Exp {
+        let mut lexer = Lexer::new(input);
+        lexer.advance().unwrap();
+        parse_exp(&mut lexer).unwrap()
+    }
+
+    #[test]
+    fn test_int() {
+        perform(
+            &[
+                "mov rax, rcx",
+                "mov r12, r13",
+                "xor eax, eax",
+                "xor r11d, r11d",
+                "mov ecx, 0x1",
+                "mov r14d, 0xffffffff",
+                "mov rdx, -0x1",
+                "movabs rdi, 0x7fffffffffffffff",
+                "add rax, rcx",
+                "sub rax, r11",
+                "cmp r8, rax",
+                "and rsp, -0x10",
+                "sub rsp, 0x100",
+                "cmp qword ptr [rdi], 0x0",
+                "test rcx, rcx",
+                "test sil, 0x1",
+                "test byte ptr [rsp - 0x11], 0x4",
+                "imul r8, r9",
+                "cqo",
+                "idiv rcx",
+                "div rcx",
+                "neg rcx",
+                "shr rcx, 0x1",
+                "shr r10, 0x3",
+                "shl rsi, 0x2",
+                "shrd rax, rdx, cl",
+                "mul r13",
+                "mul qword ptr [rsp + 0x8]",
+                "adc rdx, 0x0",
+            ],
+            |e| {
+                e.mov(IntReg::RAX, IntReg::RCX);
+                e.mov(IntReg::R12, IntReg::R13);
+                e.mov_imm(IntReg::RAX, 0);
+                e.mov_imm(IntReg::R11, 0);
+                e.mov_imm(IntReg::RCX, 1);
+                e.mov_imm(IntReg::R14, 0xffff_ffff);
+                e.mov_imm(IntReg::RDX, -1);
+                e.mov_imm(IntReg::RDI, i64::MAX);
+                e.alu(Alu::Add, IntReg::RAX, IntReg::RCX);
+                e.alu(Alu::Sub, IntReg::RAX, IntReg::R11);
+                e.alu(Alu::Cmp, IntReg::R8, IntReg::RAX);
+                e.alu_imm(Alu::And, IntReg::RSP, -16);
+                e.alu_imm(Alu::Sub, IntReg::RSP, 256);
+                e.alu_imm(Alu::Cmp, Mem::base(IntReg::RDI), 0);
+                e.test(IntReg::RCX, IntReg::RCX);
+                e.test_imm8(IntReg::RSI, 1);
+                e.test_imm8(Mem::base(IntReg::RSP).disp(-17), 4);
+                e.imul(IntReg::R8, IntReg::R9);
+                e.cqo();
+                e.idiv(IntReg::RCX);
+                e.div(IntReg::RCX);
+                e.neg(IntReg::RCX);
+                e.shr(IntReg::RCX, 1);
+                e.shr(IntReg::R10, 3);
+                e.shl(IntReg::RSI, 2);
+                e.shrd_cl(IntReg::RAX, IntReg::RDX);
+                e.mul(IntReg::R13);
+                e.mul(Mem::base(IntReg::RSP).disp(8));
+                e.alu_imm(Alu::Adc, IntReg::RDX, 0);
+            },
+        );
+    }
+
+    #[test]
+    fn test_mem() {
+        perform(
+            &[
+                "mov rax, qword ptr [rsp]",
+                "mov rax, qword ptr [rbp]",
+                "mov r8, qword ptr [r13]",
+                "mov rcx, qword ptr [r12]",
+                "mov rcx, qword ptr [rsp - 0x8]",
+                "mov rdx, qword ptr [rdi + 0x100]",
+                "mov rax, qword ptr [rdi + r8*8]",
+                "mov qword ptr [r13 + r9*2 + 0x10], rcx",
+                "lea rsp, [rbp - 0x8]",
+                "mov dword ptr [rsp - 0x4], 0x12345678",
+                "mov qword ptr [rdi], 0x2",
+                "mov byte ptr [rbx], dl",
+                "mov byte ptr [rbx - 0x1], sil",
+                "lea r9, [rip + 0x1]",
+                "ret",
+            ],
+            |e| {
+                e.load(IntReg::RAX, Mem::base(IntReg::RSP));
+                e.load(IntReg::RAX, Mem::base(IntReg::RBP));
+                e.load(IntReg::R8, Mem::base(IntReg::R13));
+                e.load(IntReg::RCX, Mem::base(IntReg::R12));
+                e.load(IntReg::RCX, Mem::base(IntReg::RSP).disp(-8));
+                e.load(IntReg::RDX, Mem::base(IntReg::RDI).disp(256));
+                e.load(IntReg::RAX, Mem::index(IntReg::RDI, IntReg::R8, 8));
+                e.store(Mem::index(IntReg::R13, IntReg::R9, 2).disp(16), IntReg::RCX);
+                e.lea(IntReg::RSP, Mem::base(IntReg::RBP).disp(-8));
+                e.store_imm32(Mem::base(IntReg::RSP).disp(-4), 0x1234_5678);
+                e.store_imm(Mem::base(IntReg::RDI), 2);
+                e.store_byte(Mem::base(IntReg::RBX), IntReg::RDX);
+                e.store_byte(Mem::base(IntReg::RBX).disp(-1), IntReg::RSI);
+                let data = e.asm().label();
+                e.lea_label(IntReg::R9, data);
+                e.ret();
+                e.asm().bind(data);
+            },
+        );
+    }
+
+    #[test]
+    fn test_control() {
+        perform(
+            &[
+                "push rax",
+                "push r15",
+                "pop rbp",
+                "pop r12",
+                "push -0x1",
+                "push 0x100",
+                "call rax",
+                "call r11",
+                "jnz 0xf",
+                "jmp 0xf",
+                "jge 0x21",
+                "jmp 0xf",
+                "ret",
+                "syscall",
+                "call 0xf",
+                "setl al",
+                "setnz sil",
+                "movzx eax, al",
+                "cmovg rax, rcx",
+                "cmovns r8, r11",
+            ],
+            |e| {
+                e.push(IntReg::RAX);
+                e.push(IntReg::R15);
+                e.pop(IntReg::RBP);
+                e.pop(IntReg::R12);
+                e.push_imm(-1);
+                e.push_imm(256);
+                let (back, forward) = (e.asm().label(), e.asm().label());
+                e.call(IntReg::RAX);
+                e.asm().bind(back);
+                e.call(IntReg::R11);
+                e.jump(Some(Cond::NZ), back);
+                e.jump(None, back);
+                e.jump_rel(Some(Cond::GE), forward, Rel::Rel32);
+                e.jump_rel(None, back, Rel::Rel32);
+                e.asm().bind(forward);
+                e.ret();
+                e.syscall();
+                e.call_label(back);
+                e.setcc(Cond::L, IntReg::RAX);
+                e.setcc(Cond::NZ, IntReg::RSI);
+                e.movzx_byte(IntReg::RAX, IntReg::RAX);
+                e.cmov(Cond::G, IntReg::RAX, IntReg::RCX);
+                e.cmov(Cond::NS, IntReg::R8, IntReg::R11);
+            },
+        );
+    }
+
+    #[test]
+    fn test_float() {
+        perform(
+            &[
+                "movapd xmm1, xmm0",
+                "movapd xmm15, xmm8",
+                "movsd xmm0, qword ptr [rsp - 0x8]",
+                "movsd qword ptr [rsp], xmm9",
+                "addsd xmm0, xmm1",
+                "subsd xmm0, xmm1",
+                "mulsd xmm0, xmm1",
+                "divsd xmm10, xmm3",
+                "sqrtsd xmm0, xmm0",
+                "xorpd xmm0, xmm0",
+                "cvtsi2sd xmm0, rcx",
+                "cvtsi2sd xmm8, r9",
+                "cvttsd2si r14, xmm9",
+                "movq xmm0, rax",
+                "movq rax, xmm1",
+                "cmpltsd xmm15, xmm1",
+                "cmpneqsd xmm0, xmm9",
+                "fld qword ptr [rsp - 0x10]",
+                "fprem",
+                "fnstsw word ptr [rsp - 0x12]",
+                "fstp st(1)",
+                "fstp qword ptr [rsp - 0x8]",
+            ],
+            |e| {
+                e.movapd(FloatReg::XMM1, FloatReg::XMM0);
+                e.movapd(FloatReg::XMM15, FloatReg::XMM8);
+                e.movsd_load(FloatReg::XMM0, Mem::base(IntReg::RSP).disp(-8));
+                e.movsd_store(Mem::base(IntReg::RSP), FloatReg::XMM9);
+                e.sse(Sse::Add, FloatReg::XMM0, FloatReg::XMM1);
+                e.sse(Sse::Sub, FloatReg::XMM0, FloatReg::XMM1);
+                e.sse(Sse::Mul, FloatReg::XMM0, FloatReg::XMM1);
+                e.sse(Sse::Div, FloatReg::XMM10, FloatReg::XMM3);
+                e.sse(Sse::Sqrt, FloatReg::XMM0, FloatReg::XMM0);
+                e.xorpd(FloatReg::XMM0, FloatReg::XMM0);
+                e.cvtsi2sd(FloatReg::XMM0, IntReg::RCX);
+                e.cvtsi2sd(FloatReg::XMM8, IntReg::R9);
+                e.cvttsd2si(IntReg::R14, FloatReg::XMM9);
+                e.movq_to_xmm(FloatReg::XMM0, IntReg::RAX);
+                e.movq_from_xmm(IntReg::RAX, FloatReg::XMM1);
+                e.cmpsd(FloatReg::XMM15, FloatReg::XMM1, 1);
+                e.cmpsd(FloatReg::XMM0, FloatReg::XMM9, 4);
+                e.fld(Mem::base(IntReg::RSP).disp(-16));
+                e.fprem();
+                e.fnstsw(Mem::base(IntReg::RSP).disp(-18));
+                e.fstp_st(1);
+                e.fstp(Mem::base(IntReg::RSP).disp(-8));
+            },
+        );
+    }
+
+    #[test]
+    fn test_fun() {
+        let fun = Fun::<X8664>::try_from(parse("1 + 2")).unwrap();
+        assert_eq!(
+            fun.disassemble().unwrap(),
+            "0000:  b8 01 00 00 00                 mov eax, 0x1\n\
+             0005:  b9 02 00 00 00                 mov ecx, 0x2\n\
+             000a:  48 01 c8                       add rax, rcx\n\
+             000d:  c3                             ret\n"
+        );
+
+        // Every operation of the backend decodes.
+        for input in [
+            "(1 + 2) * (3 - 4) / (5 % 6)",
+            "(1.5 + 2) * (3 - 4.25) / (5 % 6.5)",
+            "3 ^ -2 + 2.5 ^ (1 + 1)",
+            "0.0 - 9223372036854775807",
+        ]
+        .iter()
+        {
+            let fun = Fun::<X8664>::try_from(parse(input)).unwrap();
+            let code = fun.bytecode();
+            let insns = disassemble(&code).unwrap();
+            assert!(insns.iter().any(|insn| insn.text == "ret"), "{}", input);
+            assert_eq!(insns.iter().map(|insn| insn.len).sum::<usize>(), code.len());
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = listing(&[0x48, 0x89]).unwrap_err().to_string();
+        assert!(
+            err.contains("Truncated instruction at offset 0x0"),
+            "{}",
+            err
+        );
+        let err = listing(&[0xc3, 0x0e]).unwrap_err().to_string();
+        assert!(err.contains("Unknown opcode 0x0e at offset 0x1"), "{}", err);
+        let err = listing(&[0x0f, 0x0b]).unwrap_err().to_string();
+        assert!(err.contains("Unknown opcode 0x0f 0x0b"), "{}", err);
+        assert_eq!(listing(&[]).unwrap(), "");
+    }
+}
@@ -0,0 +1,749 @@
+use std::convert::TryFrom;
+
+use crate::asm::arch::{Asm, Label, Rel};
+use crate::asm::x86_64::{FloatReg, IntReg};
+
+/// Memory operand `[base + index * scale + disp]`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Mem {
+    base: IntReg,
+    index: Option<(IntReg, u8)>,
+    disp: i32,
+}
+
+impl Mem {
+    pub fn base(base: IntReg) -> Mem {
+        Mem {
+            base,
+            index: None,
+            disp: 0,
+        }
+    }
+
+    /// `[base + index * scale]`. `scale` is one of 1, 2, 4 or 8 and `rsp` can't be an index.
+    pub fn index(base: IntReg, index: IntReg, scale: u8) -> Mem {
+        assert!(index != IntReg::RSP, "rsp can't be used as an index");
+        assert!(
+            matches!(scale, 1 | 2 | 4 | 8),
+            "Invalid scale: {}",
+            scale
+        );
+        Mem {
+            base,
+            index: Some((index, scale)),
+            disp: 0,
+        }
+    }
+
+    pub fn disp(mut self, disp: i32) -> Mem {
+        self.disp = disp;
+        self
+    }
+}
+
+/// Register or memory operand of the ModRM byte.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Rm {
+    Reg(u8),
+    Mem(Mem),
+}
+
+impl From<IntReg> for Rm {
+    fn from(reg: IntReg) -> Self {
+        Rm::Reg(reg.code())
+    }
+}
+
+impl From<FloatReg> for Rm {
+    fn from(reg: FloatReg) -> Self {
+        Rm::Reg(reg.code())
+    }
+}
+
+impl From<Mem> for Rm {
+    fn from(mem: Mem) -> Self {
+        Rm::Mem(mem)
+    }
+}
+
+/// Condition code of `jcc` and `setcc`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Cond {
+    O = 0x0,
+    Z = 0x4,
+    NZ = 0x5,
+    S = 0x8,
+    NS = 0x9,
+    L = 0xc,
+    GE = 0xd,
+    LE = 0xe,
+    G = 0xf,
+}
+
+/// Integer ALU operation, the value is the `/digit` of the immediate forms.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Alu {
+    Add = 0,
+    Or = 1,
+    Adc = 2,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+/// SSE2 scalar double operation `op xmm, xmm/m64`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Sse {
+    Sqrt = 0x51,
+    Add = 0x58,
+    Mul = 0x59,
+    Sub = 0x5c,
+    Div = 0x5e,
+}
+
+/// x86-64 instruction encoder. Picks REX prefixes, SIB bytes and the most compact displacement
+/// and immediate forms on its own.
+pub struct Encoder<'a> {
+    asm: &'a mut Asm,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(asm: &'a mut Asm) -> Encoder<'a> {
+        Encoder { asm }
+    }
+
+    /// Underlying buffer, for labels.
+    pub fn asm(&mut self) -> &mut Asm {
+        self.asm
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        self.asm.put(bytes);
+    }
+
+    /// Emits `[prefix] [REX] opcode ModRM [SIB] [disp]`.
+    fn emit(&mut self, prefix: Option<u8>, w: bool, opcode: &[u8], reg: u8, rm: Rm) {
+        self.emit_rex(prefix, w, false, opcode, reg, rm);
+    }
+
+    /// Same as `emit`, `byte` forces a REX prefix so that registers 4-7 mean spl..dil.
+    fn emit_rex(&mut self, prefix: Option<u8>, w: bool, byte: bool, opcode: &[u8], reg: u8, rm: Rm) {
+        if let Some(prefix) = prefix {
+            self.put(&[prefix]);
+        }
+
+        let (x, b) = match rm {
+            Rm::Reg(code) => (0, code >> 3),
+            Rm::Mem(mem) => (
+                mem.index.map(|(index, _)| index.code() >> 3).unwrap_or(0),
+                mem.base.code() >> 3,
+            ),
+        };
+        let rex = (w as u8) << 3 | (reg >> 3) << 2 | x << 1 | b;
+        let byte_reg = byte && (matches!(rm, Rm::Reg(4..=7)) || (4..=7).contains(&reg));
+        if rex != 0 || byte_reg {
+            self.put(&[0x40 | rex]);
+        }
+
+        self.put(opcode);
+        self.modrm(reg & 7, rm);
+    }
+
+    fn modrm(&mut self, reg: u8, rm: Rm) {
+        let mem = match rm {
+            Rm::Reg(code) => {
+                self.put(&[0xc0 | reg << 3 | (code & 7)]);
+                return;
+            }
+            Rm::Mem(mem) => mem,
+        };
+
+        let base = mem.base.code() & 7;
+        // rbp and r13 can't be encoded without a displacement.
+        let md = if mem.disp == 0 && base != 5 {
+            0
+        } else if i8::try_from(mem.disp).is_ok() {
+            1
+        } else {
+            2
+        };
+
+        match mem.index {
+            None if base != 4 => self.put(&[md << 6 | reg << 3 | base]),
+            index => {
+                // rsp and r12 as a base always need a SIB byte.
+                let (index, scale) = index
+                    .map(|(index, scale)| (index.code() & 7, scale.trailing_zeros() as u8))
+                    .unwrap_or((4, 0));
+                self.put(&[md << 6 | reg << 3 | 4, scale << 6 | index << 3 | base]);
+            }
+        }
+
+        match md {
+            1 => self.put(&[mem.disp as u8]),
+            2 => self.put(&mem.disp.to_le_bytes()),
+            _ => {}
+        }
+    }
+
+    /// `mov dst, src`
+    pub fn mov(&mut self, dst: IntReg, src: IntReg) {
+        self.emit(None, true, &[0x89], src.code(), dst.into());
+    }
+
+    /// `mov dst, imm` in the shortest form: `xor`, `mov r32, imm32`, sign extended
+    /// `mov r/m64, imm32` or `movabs`.
+    pub fn mov_imm(&mut self, dst: IntReg, imm: i64) {
+        let code = dst.code();
+        if imm == 0 {
+            self.emit(None, false, &[0x31], code, dst.into());
+        } else if u32::try_from(imm).is_ok() {
+            if code >= 8 {
+                self.put(&[0x41]);
+            }
+            self.put(&[0xb8 | (code & 7)]);
+            self.put(&(imm as u32).to_le_bytes());
+        } else if i32::try_from(imm).is_ok() {
+            self.emit(None, true, &[0xc7], 0, dst.into());
+            self.put(&(imm as i32).to_le_bytes());
+        } else {
+            self.mov_abs(dst, imm);
+        }
+    }
+
+    /// `movabs dst, imm64`, always ten bytes with the immediate at offset 2.
+    pub fn mov_abs(&mut self, dst: IntReg, imm: i64) {
+        let code = dst.code();
+        self.put(&[0x48 | code >> 3, 0xb8 | (code & 7)]);
+        self.put(&imm.to_le_bytes());
+    }
+
+    /// `mov dst, qword [mem]`
+    pub fn load(&mut self, dst: IntReg, mem: Mem) {
+        self.emit(None, true, &[0x8b], dst.code(), mem.into());
+    }
+
+    /// `mov qword [mem], src`
+    pub fn store(&mut self, mem: Mem, src: IntReg) {
+        self.emit(None, true, &[0x89], src.code(), mem.into());
+    }
+
+    /// `mov byte [mem], src`: stores the low byte of `src`.
+    pub fn store_byte(&mut self, mem: Mem, src: IntReg) {
+        self.emit_rex(None, false, true, &[0x88], src.code(), mem.into());
+    }
+
+    /// `mov dword [mem], imm`
+    pub fn store_imm32(&mut self, mem: Mem, imm: u32) {
+        self.emit(None, false, &[0xc7], 0, mem.into());
+        self.put(&imm.to_le_bytes());
+    }
+
+    /// `mov qword [mem], imm` with the immediate sign extended from 32 bits.
+    pub fn store_imm(&mut self, mem: Mem, imm: i32) {
+        self.emit(None, true, &[0xc7], 0, mem.into());
+        self.put(&imm.to_le_bytes());
+    }
+
+    /// `lea dst, [mem]`
+    pub fn lea(&mut self, dst: IntReg, mem: Mem) {
+        self.emit(None, true, &[0x8d], dst.code(), mem.into());
+    }
+
+    /// `lea dst, [rip + disp]`: the address of `label`.
+    pub fn lea_label(&mut self, dst: IntReg, label: Label) {
+        let code = dst.code();
+        self.put(&[0x48 | (code >> 3) << 2, 0x8d, 0x05 | (code & 7) << 3]);
+        self.asm.put_rel(label, Rel::Rel32);
+    }
+
+    /// `op dst, src`
+    pub fn alu(&mut self, op: Alu, dst: IntReg, src: IntReg) {
+        self.emit(None, true, &[(op as u8) << 3 | 1], src.code(), dst.into());
+    }
+
+    /// `op qword dst, imm`
+    pub fn alu_imm(&mut self, op: Alu, dst: impl Into<Rm>, imm: i32) {
+        if let Ok(imm) = i8::try_from(imm) {
+            self.emit(None, true, &[0x83], op as u8, dst.into());
+            self.put(&[imm as u8]);
+        } else {
+            self.emit(None, true, &[0x81], op as u8, dst.into());
+            self.put(&imm.to_le_bytes());
+        }
+    }
+
+    /// `test a, b`
+    pub fn test(&mut self, a: IntReg, b: IntReg) {
+        self.emit(None, true, &[0x85], b.code(), a.into());
+    }
+
+    /// `test` of the low byte of a register or memory operand.
+    pub fn test_imm8(&mut self, rm: impl Into<Rm>, imm: u8) {
+        self.emit_rex(None, false, true, &[0xf6], 0, rm.into());
+        self.put(&[imm]);
+    }
+
+    /// `imul dst, src`
+    pub fn imul(&mut self, dst: IntReg, src: IntReg) {
+        self.emit(None, true, &[0x0f, 0xaf], dst.code(), src.into());
+    }
+
+    /// `cqo`: sign extends rax into rdx.
+    pub fn cqo(&mut self) {
+        self.put(&[0x48, 0x99]);
+    }
+
+    /// `idiv src`: signed rdx:rax / src.
+    pub fn idiv(&mut self, src: IntReg) {
+        self.emit(None, true, &[0xf7], 7, src.into());
+    }
+
+    /// `div src`: unsigned rdx:rax / src.
+    pub fn div(&mut self, src: IntReg) {
+        self.emit(None, true, &[0xf7], 6, src.into());
+    }
+
+    /// `mul src`: the unsigned 128-bit product rax * src in rdx:rax.
+    pub fn mul(&mut self, src: impl Into<Rm>) {
+        self.emit(None, true, &[0xf7], 4, src.into());
+    }
+
+    /// `neg reg`
+    pub fn neg(&mut self, reg: IntReg) {
+        self.emit(None, true, &[0xf7], 3, reg.into());
+    }
+
+    /// `shr reg, imm`
+    pub fn shr(&mut self, reg: IntReg, imm: u8) {
+        self.shift(5, reg, imm);
+    }
+
+    /// `shl reg, imm`
+    pub fn shl(&mut self, reg: IntReg, imm: u8) {
+        self.shift(4, reg, imm);
+    }
+
+    fn shift(&mut self, digit: u8, reg: IntReg, imm: u8) {
+        if imm == 1 {
+            self.emit(None, true, &[0xd1], digit, reg.into());
+        } else {
+            self.emit(None, true, &[0xc1], digit, reg.into());
+            self.put(&[imm]);
+        }
+    }
+
+    /// `shrd dst, src, cl`: shifts `dst` right, filling it from the low bits of `src`.
+    pub fn shrd_cl(&mut self, dst: IntReg, src: IntReg) {
+        self.emit(None, true, &[0x0f, 0xad], src.code(), dst.into());
+    }
+
+    /// `push reg`
+    pub fn push(&mut self, reg: IntReg) {
+        let code = reg.code();
+        if code >= 8 {
+            self.put(&[0x41]);
+        }
+        self.put(&[0x50 | (code & 7)]);
+    }
+
+    /// `pop reg`
+    pub fn pop(&mut self, reg: IntReg) {
+        let code = reg.code();
+        if code >= 8 {
+            self.put(&[0x41]);
+        }
+        self.put(&[0x58 | (code & 7)]);
+    }
+
+    /// `push imm` with the immediate sign extended to 64 bits.
+    pub fn push_imm(&mut self, imm: i32) {
+        if let Ok(imm) = i8::try_from(imm) {
+            self.put(&[0x6a, imm as u8]);
+        } else {
+            self.put(&[0x68]);
+            self.put(&imm.to_le_bytes());
+        }
+    }
+
+    /// `call reg`
+    pub fn call(&mut self, reg: IntReg) {
+        self.emit(None, false, &[0xff], 2, reg.into());
+    }
+
+    /// `call label`, always with a rel32 displacement.
+    pub fn call_label(&mut self, label: Label) {
+        self.put(&[0xe8]);
+        self.asm.put_rel(label, Rel::Rel32);
+    }
+
+    /// `setcc reg`: sets the low byte of `reg` to 1 if `cond` holds and to 0 otherwise.
+    pub fn setcc(&mut self, cond: Cond, reg: IntReg) {
+        self.emit_rex(None, false, true, &[0x0f, 0x90 | cond as u8], 0, reg.into());
+    }
+
+    /// `call rel32` with a zero displacement, for a linker to fill in.
+    pub fn call_extern(&mut self) {
+        self.put(&[0xe8, 0, 0, 0, 0]);
+    }
+
+    /// `cmovcc dst, src`
+    pub fn cmov(&mut self, cond: Cond, dst: IntReg, src: IntReg) {
+        self.emit(None, true, &[0x0f, 0x40 | cond as u8], dst.code(), src.into());
+    }
+
+    /// `movzx dst, src` of the low byte of `src`, clearing the rest of `dst`.
+    pub fn movzx_byte(&mut self, dst: IntReg, src: IntReg) {
+        self.emit_rex(None, false, true, &[0x0f, 0xb6], dst.code(), src.into());
+    }
+
+    /// `ret`
+    pub fn ret(&mut self) {
+        self.put(&[0xc3]);
+    }
+
+    /// `syscall`
+    pub fn syscall(&mut self) {
+        self.put(&[0x0f, 0x05]);
+    }
+
+    /// Jumps to `label`, unconditionally when `cond` is `None`. A bound label gets the short
+    /// form when it is in reach, an unbound one always gets rel32.
+    pub fn jump(&mut self, cond: Option<Cond>, label: Label) {
+        let short = self
+            .asm
+            .offset(label)
+            .map(|target| target as i64 - (self.asm.len() + 2) as i64)
+            .map(|disp| i8::try_from(disp).is_ok())
+            .unwrap_or(false);
+        self.jump_rel(cond, label, if short { Rel::Rel8 } else { Rel::Rel32 });
+    }
+
+    /// Jumps to `label` with the given displacement size. Out of range displacements are
+    /// reported when the buffer is finalized.
+    pub fn jump_rel(&mut self, cond: Option<Cond>, label: Label, rel: Rel) {
+        match (cond, rel) {
+            (None, Rel::Rel8) => self.put(&[0xeb]),
+            (None, Rel::Rel32) => self.put(&[0xe9]),
+            (Some(cond), Rel::Rel8) => self.put(&[0x70 | cond as u8]),
+            (Some(cond), Rel::Rel32) => self.put(&[0x0f, 0x80 | cond as u8]),
+            (_, rel) => panic!("{:?} is not an x86 displacement", rel),
+        }
+        self.asm.put_rel(label, rel);
+    }
+
+    /// `movapd dst, src`
+    pub fn movapd(&mut self, dst: FloatReg, src: FloatReg) {
+        self.emit(Some(0x66), false, &[0x0f, 0x28], dst.code(), src.into());
+    }
+
+    /// `movsd dst, qword [mem]`
+    pub fn movsd_load(&mut self, dst: FloatReg, mem: Mem) {
+        self.emit(Some(0xf2), false, &[0x0f, 0x10], dst.code(), mem.into());
+    }
+
+    /// `movsd qword [mem], src`
+    pub fn movsd_store(&mut self, mem: Mem, src: FloatReg) {
+        self.emit(Some(0xf2), false, &[0x0f, 0x11], src.code(), mem.into());
+    }
+
+    /// `op dst, src`
+    pub fn sse(&mut self, op: Sse, dst: FloatReg, src: FloatReg) {
+        self.emit(Some(0xf2), false, &[0x0f, op as u8], dst.code(), src.into());
+    }
+
+    /// `cmpsd dst, src, pred`: sets `dst` to all ones if the comparison holds and to zero
+    /// otherwise. Predicate 0 is `==`, 1 `<`, 2 `<=` and 4 `!=`, the only one holding for NaN.
+    pub fn cmpsd(&mut self, dst: FloatReg, src: FloatReg, pred: u8) {
+        self.emit(Some(0xf2), false, &[0x0f, 0xc2], dst.code(), src.into());
+        self.put(&[pred]);
+    }
+
+    /// `xorpd dst, src`
+    pub fn xorpd(&mut self, dst: FloatReg, src: FloatReg) {
+        self.emit(Some(0x66), false, &[0x0f, 0x57], dst.code(), src.into());
+    }
+
+    /// `cvtsi2sd dst, src`
+    pub fn cvtsi2sd(&mut self, dst: FloatReg, src: IntReg) {
+        self.emit(Some(0xf2), true, &[0x0f, 0x2a], dst.code(), src.into());
+    }
+
+    /// `cvttsd2si dst, src`: converts to a 64-bit integer, truncating toward zero.
+    pub fn cvttsd2si(&mut self, dst: IntReg, src: FloatReg) {
+        self.emit(Some(0xf2), true, &[0x0f, 0x2c], dst.code(), src.into());
+    }
+
+    /// `movq dst, src`: moves the bits of a general purpose register into an xmm register.
+    pub fn movq_to_xmm(&mut self, dst: FloatReg, src: IntReg) {
+        self.emit(Some(0x66), true, &[0x0f, 0x6e], dst.code(), src.into());
+    }
+
+    /// `movq dst, src`: moves the bits of an xmm register into a general purpose register.
+    pub fn movq_from_xmm(&mut self, dst: IntReg, src: FloatReg) {
+        self.emit(Some(0x66), true, &[0x0f, 0x7e], src.code(), dst.into());
+    }
+
+    /// `fld qword [mem]`
+    pub fn fld(&mut self, mem: Mem) {
+        self.emit(None, false, &[0xdd], 0, mem.into());
+    }
+
+    /// `fstp qword [mem]`
+    pub fn fstp(&mut self, mem: Mem) {
+        self.emit(None, false, &[0xdd], 3, mem.into());
+    }
+
+    /// `fstp st(i)`
+    pub fn fstp_st(&mut self, i: u8) {
+        self.put(&[0xdd, 0xd8 | (i & 7)]);
+    }
+
+    /// `fprem`
+    pub fn fprem(&mut self) {
+        self.put(&[0xd9, 0xf8]);
+    }
+
+    /// `fnstsw word [mem]`
+    pub fn fnstsw(&mut self, mem: Mem) {
+        self.emit(None, false, &[0xdd], 7, mem.into());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asm::arch::{Asm, Rel};
+    use crate::asm::x86_64::encoder::{Alu, Cond, Encoder, Mem, Sse};
+    use crate::asm::x86_64::{FloatReg, IntReg};
+
+    fn perform(expected: &[u8], f: impl FnOnce(&mut Encoder)) {
+        let mut asm = Asm::new();
+        f(&mut Encoder::new(&mut asm));
+        asm.finalize().unwrap();
+        assert_eq!(asm.buffer(), expected);
+    }
+
+    #[test]
+    fn test_mov() {
+        perform(&[0x48, 0x89, 0xc8], |e| e.mov(IntReg::RAX, IntReg::RCX));
+        perform(&[0x4c, 0x89, 0xc0], |e| e.mov(IntReg::RAX, IntReg::R8));
+        perform(&[0x49, 0x89, 0xc7], |e| e.mov(IntReg::R15, IntReg::RAX));
+        perform(&[0x4d, 0x89, 0xec], |e| e.mov(IntReg::R12, IntReg::R13));
+        perform(&[0x48, 0x89, 0xe5], |e| e.mov(IntReg::RBP, IntReg::RSP));
+    }
+
+    #[test]
+    fn test_mov_imm() {
+        perform(&[0x31, 0xc0], |e| e.mov_imm(IntReg::RAX, 0));
+        perform(&[0x45, 0x31, 0xdb], |e| e.mov_imm(IntReg::R11, 0));
+        perform(&[0xb9, 0x01, 0x00, 0x00, 0x00], |e| e.mov_imm(IntReg::RCX, 1));
+        perform(&[0x41, 0xbe, 0xff, 0xff, 0xff, 0xff], |e| {
+            e.mov_imm(IntReg::R14, 0xffff_ffff)
+        });
+        perform(&[0x48, 0xc7, 0xc2, 0xff, 0xff, 0xff, 0xff], |e| {
+            e.mov_imm(IntReg::RDX, -1)
+        });
+        perform(&[0x49, 0xc7, 0xc1, 0x00, 0x00, 0x00, 0x80], |e| {
+            e.mov_imm(IntReg::R9, i32::MIN as i64)
+        });
+        perform(
+            &[0x48, 0xbf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+            |e| e.mov_imm(IntReg::RDI, i64::MAX),
+        );
+        perform(
+            &[0x49, 0xba, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+            |e| e.mov_imm(IntReg::R10, 1 << 32),
+        );
+    }
+
+    #[test]
+    fn test_mem() {
+        perform(&[0x48, 0x8b, 0x00], |e| e.load(IntReg::RAX, Mem::base(IntReg::RAX)));
+        perform(&[0x48, 0x8b, 0x04, 0x24], |e| {
+            e.load(IntReg::RAX, Mem::base(IntReg::RSP))
+        });
+        perform(&[0x48, 0x8b, 0x45, 0x00], |e| {
+            e.load(IntReg::RAX, Mem::base(IntReg::RBP))
+        });
+        perform(&[0x4d, 0x8b, 0x45, 0x00], |e| {
+            e.load(IntReg::R8, Mem::base(IntReg::R13))
+        });
+        perform(&[0x49, 0x8b, 0x0c, 0x24], |e| {
+            e.load(IntReg::RCX, Mem::base(IntReg::R12))
+        });
+        perform(&[0x48, 0x8b, 0x4c, 0x24, 0xf8], |e| {
+            e.load(IntReg::RCX, Mem::base(IntReg::RSP).disp(-8))
+        });
+        perform(&[0x48, 0x8b, 0x97, 0x00, 0x01, 0x00, 0x00], |e| {
+            e.load(IntReg::RDX, Mem::base(IntReg::RDI).disp(256))
+        });
+        perform(&[0x4a, 0x8b, 0x04, 0xc7], |e| {
+            e.load(IntReg::RAX, Mem::index(IntReg::RDI, IntReg::R8, 8))
+        });
+        perform(&[0x4b, 0x89, 0x4c, 0x4d, 0x10], |e| {
+            e.store(Mem::index(IntReg::R13, IntReg::R9, 2).disp(16), IntReg::RCX)
+        });
+        perform(&[0x48, 0x8d, 0x65, 0xf8], |e| {
+            e.lea(IntReg::RSP, Mem::base(IntReg::RBP).disp(-8))
+        });
+        perform(&[0xc7, 0x44, 0x24, 0xfc, 0x78, 0x56, 0x34, 0x12], |e| {
+            e.store_imm32(Mem::base(IntReg::RSP).disp(-4), 0x1234_5678)
+        });
+        perform(&[0x48, 0xc7, 0x07, 0x02, 0x00, 0x00, 0x00], |e| {
+            e.store_imm(Mem::base(IntReg::RDI), 2)
+        });
+        perform(&[0x88, 0x13], |e| e.store_byte(Mem::base(IntReg::RBX), IntReg::RDX));
+        perform(&[0x40, 0x88, 0x73, 0xff], |e| {
+            e.store_byte(Mem::base(IntReg::RBX).disp(-1), IntReg::RSI)
+        });
+        perform(&[0x41, 0x88, 0x04, 0x24], |e| {
+            e.store_byte(Mem::base(IntReg::R12), IntReg::RAX)
+        });
+        perform(&[0x4c, 0x8d, 0x0d, 0x01, 0x00, 0x00, 0x00, 0xc3], |e| {
+            let data = e.asm().label();
+            e.lea_label(IntReg::R9, data);
+            e.ret();
+            e.asm().bind(data);
+        });
+    }
+
+    #[test]
+    fn test_alu() {
+        perform(&[0x48, 0x01, 0xc8], |e| e.alu(Alu::Add, IntReg::RAX, IntReg::RCX));
+        perform(&[0x4c, 0x29, 0xd8], |e| e.alu(Alu::Sub, IntReg::RAX, IntReg::R11));
+        perform(&[0x48, 0x31, 0xd2], |e| e.alu(Alu::Xor, IntReg::RDX, IntReg::RDX));
+        perform(&[0x49, 0x39, 0xc0], |e| e.alu(Alu::Cmp, IntReg::R8, IntReg::RAX));
+        perform(&[0x48, 0x83, 0xe4, 0xf0], |e| e.alu_imm(Alu::And, IntReg::RSP, -16));
+        perform(&[0x48, 0x83, 0xc4, 0x08], |e| e.alu_imm(Alu::Add, IntReg::RSP, 8));
+        perform(&[0x48, 0x81, 0xec, 0x00, 0x01, 0x00, 0x00], |e| {
+            e.alu_imm(Alu::Sub, IntReg::RSP, 256)
+        });
+        perform(&[0x49, 0x83, 0xff, 0xff], |e| e.alu_imm(Alu::Cmp, IntReg::R15, -1));
+        perform(&[0x48, 0x83, 0x3f, 0x00], |e| {
+            e.alu_imm(Alu::Cmp, Mem::base(IntReg::RDI), 0)
+        });
+        perform(&[0x48, 0x85, 0xc9], |e| e.test(IntReg::RCX, IntReg::RCX));
+        perform(&[0xf6, 0xc1, 0x01], |e| e.test_imm8(IntReg::RCX, 1));
+        perform(&[0x40, 0xf6, 0xc6, 0x01], |e| e.test_imm8(IntReg::RSI, 1));
+        perform(&[0x41, 0xf6, 0xc0, 0x01], |e| e.test_imm8(IntReg::R8, 1));
+        perform(&[0xf6, 0x44, 0x24, 0xef, 0x04], |e| {
+            e.test_imm8(Mem::base(IntReg::RSP).disp(-17), 4)
+        });
+        perform(&[0x48, 0x0f, 0xaf, 0xc1], |e| e.imul(IntReg::RAX, IntReg::RCX));
+        perform(&[0x4d, 0x0f, 0xaf, 0xc1], |e| e.imul(IntReg::R8, IntReg::R9));
+        perform(&[0x48, 0x99], |e| e.cqo());
+        perform(&[0x48, 0xf7, 0xf9], |e| e.idiv(IntReg::RCX));
+        perform(&[0x49, 0xf7, 0xf8], |e| e.idiv(IntReg::R8));
+        perform(&[0x48, 0xf7, 0xf1], |e| e.div(IntReg::RCX));
+        perform(&[0x48, 0xf7, 0xd9], |e| e.neg(IntReg::RCX));
+        perform(&[0x48, 0xd1, 0xe9], |e| e.shr(IntReg::RCX, 1));
+        perform(&[0x49, 0xc1, 0xea, 0x03], |e| e.shr(IntReg::R10, 3));
+        perform(&[0x48, 0xc1, 0xe6, 0x02], |e| e.shl(IntReg::RSI, 2));
+        perform(&[0x48, 0x0f, 0xad, 0xd0], |e| e.shrd_cl(IntReg::RAX, IntReg::RDX));
+        perform(&[0x49, 0xf7, 0xe5], |e| e.mul(IntReg::R13));
+        perform(&[0x48, 0xf7, 0x64, 0x24, 0x08], |e| {
+            e.mul(Mem::base(IntReg::RSP).disp(8))
+        });
+        perform(&[0x48, 0x83, 0xd2, 0x00], |e| e.alu_imm(Alu::Adc, IntReg::RDX, 0));
+    }
+
+    #[test]
+    fn test_stack() {
+        perform(&[0x50, 0x41, 0x57, 0x5d, 0x41, 0x5c], |e| {
+            e.push(IntReg::RAX);
+            e.push(IntReg::R15);
+            e.pop(IntReg::RBP);
+            e.pop(IntReg::R12);
+        });
+        perform(&[0x6a, 0xff, 0x68, 0x00, 0x01, 0x00, 0x00], |e| {
+            e.push_imm(-1);
+            e.push_imm(256);
+        });
+        perform(&[0xff, 0xd0, 0x41, 0xff, 0xd3, 0xc3], |e| {
+            e.call(IntReg::RAX);
+            e.call(IntReg::R11);
+            e.ret();
+        });
+        perform(&[0x0f, 0x05], |e| e.syscall());
+    }
+
+    #[test]
+    fn test_setcc() {
+        perform(&[0x0f, 0x9c, 0xc0], |e| e.setcc(Cond::L, IntReg::RAX));
+        perform(&[0x40, 0x0f, 0x94, 0xc6], |e| e.setcc(Cond::Z, IntReg::RSI));
+        perform(&[0x41, 0x0f, 0x9f, 0xc3], |e| e.setcc(Cond::G, IntReg::R11));
+        perform(&[0x0f, 0xb6, 0xc0], |e| e.movzx_byte(IntReg::RAX, IntReg::RAX));
+        perform(&[0x44, 0x0f, 0xb6, 0xc7], |e| e.movzx_byte(IntReg::R8, IntReg::RDI));
+        perform(&[0xf2, 0x0f, 0xc2, 0xc1, 0x01], |e| {
+            e.cmpsd(FloatReg::XMM0, FloatReg::XMM1, 1)
+        });
+        perform(&[0xf2, 0x44, 0x0f, 0xc2, 0xf8, 0x04], |e| {
+            e.cmpsd(FloatReg::XMM15, FloatReg::XMM0, 4)
+        });
+    }
+
+    #[test]
+    fn test_jump() {
+        let mut asm = Asm::new();
+        let mut e = Encoder::new(&mut asm);
+        let (back, forward) = (e.asm().label(), e.asm().label());
+        e.asm().bind(back);
+        e.jump(Some(Cond::NZ), back);
+        e.jump(None, back);
+        e.jump(Some(Cond::L), forward);
+        e.jump_rel(Some(Cond::Z), forward, Rel::Rel8);
+        e.jump(None, forward);
+        e.asm().bind(forward);
+        e.call_label(back);
+        asm.finalize().unwrap();
+        assert_eq!(
+            asm.buffer(),
+            &[
+                0x75, 0xfe, 0xeb, 0xfc, 0x0f, 0x8c, 0x07, 0x00, 0x00, 0x00, 0x74, 0x05, 0xe9,
+                0x00, 0x00, 0x00, 0x00, 0xe8, 0xea, 0xff, 0xff, 0xff
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sse() {
+        perform(&[0x66, 0x0f, 0x28, 0xc1], |e| e.movapd(FloatReg::XMM0, FloatReg::XMM1));
+        perform(&[0x66, 0x45, 0x0f, 0x28, 0xfe], |e| {
+            e.movapd(FloatReg::XMM15, FloatReg::XMM14)
+        });
+        perform(&[0xf2, 0x0f, 0x58, 0xc1], |e| e.sse(Sse::Add, FloatReg::XMM0, FloatReg::XMM1));
+        perform(&[0xf2, 0x41, 0x0f, 0x5e, 0xc0], |e| {
+            e.sse(Sse::Div, FloatReg::XMM0, FloatReg::XMM8)
+        });
+        perform(&[0xf2, 0x44, 0x0f, 0x51, 0xd2], |e| {
+            e.sse(Sse::Sqrt, FloatReg::XMM10, FloatReg::XMM2)
+        });
+        perform(&[0xf2, 0x0f, 0x10, 0x44, 0x24, 0xf8], |e| {
+            e.movsd_load(FloatReg::XMM0, Mem::base(IntReg::RSP).disp(-8))
+        });
+        perform(&[0xf2, 0x44, 0x0f, 0x11, 0x0c, 0x24], |e| {
+            e.movsd_store(Mem::base(IntReg::RSP), FloatReg::XMM9)
+        });
+        perform(&[0x66, 0x0f, 0x57, 0xc9], |e| e.xorpd(FloatReg::XMM1, FloatReg::XMM1));
+        perform(&[0xf2, 0x48, 0x0f, 0x2a, 0xc1], |e| e.cvtsi2sd(FloatReg::XMM0, IntReg::RCX));
+        perform(&[0xf2, 0x4d, 0x0f, 0x2a, 0xdc], |e| e.cvtsi2sd(FloatReg::XMM11, IntReg::R12));
+        perform(&[0xf2, 0x48, 0x0f, 0x2c, 0xc0], |e| e.cvttsd2si(IntReg::RAX, FloatReg::XMM0));
+        perform(&[0xf2, 0x4d, 0x0f, 0x2c, 0xf1], |e| {
+            e.cvttsd2si(IntReg::R14, FloatReg::XMM9)
+        });
+        perform(&[0x66, 0x48, 0x0f, 0x6e, 0xc0], |e| e.movq_to_xmm(FloatReg::XMM0, IntReg::RAX));
+        perform(&[0x66, 0x49, 0x0f, 0x7e, 0xcb], |e| {
+            e.movq_from_xmm(IntReg::R11, FloatReg::XMM1)
+        });
+    }
+
+    #[test]
+    fn test_x87() {
+        perform(&[0xdd, 0x44, 0x24, 0xf0], |e| e.fld(Mem::base(IntReg::RSP).disp(-16)));
+        perform(&[0xdd, 0x5c, 0x24, 0xf8], |e| e.fstp(Mem::base(IntReg::RSP).disp(-8)));
+        perform(&[0xdd, 0xd9], |e| e.fstp_st(1));
+        perform(&[0xd9, 0xf8], |e| e.fprem());
+        perform(&[0xdd, 0x7c, 0x24, 0xee], |e| e.fnstsw(Mem::base(IntReg::RSP).disp(-18)));
+    }
+}
@@ -0,0 +1,873 @@
+use anyhow::{anyhow, Error};
+
+use crate::asm::arch::{Arch, Label};
+use crate::asm::exec::Rt;
+use crate::asm::x86_64::encoder::{Alu, Cond, Encoder, Mem};
+use crate::asm::x86_64::{FloatReg, IntReg, X8664};
+use crate::asm::Fun;
+use crate::interpreter::{EvalError, MAX_DEPTH};
+use crate::parser::program::Program;
+
+/// Address the executable is loaded at.
+const BASE: u64 = 0x40_0000;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const PHDRS: usize = 2;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SYS_WRITE: i64 = 1;
+const SYS_EXIT: i64 = 60;
+
+/// Output buffer on the stack, enough for the longest double.
+const BUFFER: i32 = 512;
+
+/// Compiles `program` into a static x86-64 Linux executable that prints its value and exits,
+/// or prints its error to stderr and exits with status 1.
+///
+/// The executable has no dependencies or inputs, so programs that call host functions
+/// (including `pow` for float powers and the float builtins but `sqrt`) or read variables are
+/// rejected. Values are printed the way `Val` displays them.
+pub fn executable(program: &Program) -> Result<Vec<u8>, Error> {
+    let bound = program.bind(&[])?;
+    let mut arch = X8664::default();
+    let main = arch.asm.label();
+    arch.asm.bind(main);
+    let (mut asm, rt) = Fun::<X8664>::assemble(&bound.exp, &bound.funs, arch);
+    if let Some(reloc) = asm.relocs().first() {
+        return Err(anyhow!(
+            "Host function '{}' can't be called from a standalone executable",
+            reloc.symbol
+        ));
+    }
+    let mut enc = Encoder::new(&mut asm);
+    let start = enc.asm().label();
+    enc.asm().bind(start);
+    Stub { enc }.start(main, rt);
+    asm.finalize()?;
+
+    let code = asm.buffer();
+    let offset = (EHDR_SIZE + PHDRS * PHDR_SIZE) as u64;
+    let size = offset + code.len() as u64;
+    let entry = BASE + offset + asm.offset(start).unwrap() as u64;
+
+    let mut out = vec![0; EHDR_SIZE];
+    out[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    out[16..18].copy_from_slice(&2u16.to_le_bytes());
+    out[18..20].copy_from_slice(&X8664::ELF_MACHINE.to_le_bytes());
+    out[20..24].copy_from_slice(&1u32.to_le_bytes());
+    out[24..32].copy_from_slice(&entry.to_le_bytes());
+    out[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+    out[52..54].copy_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    out[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    out[56..58].copy_from_slice(&(PHDRS as u16).to_le_bytes());
+
+    // The whole file is mapped as one read-execute segment.
+    put_phdr(&mut out, PT_LOAD, PF_R | PF_X, BASE, size, 0x1000);
+    put_phdr(&mut out, PT_GNU_STACK, PF_R | PF_W, 0, 0, 16);
+    out.extend_from_slice(code);
+    Ok(out)
+}
+
+fn put_phdr(out: &mut Vec<u8>, kind: u32, flags: u32, vaddr: u64, size: u64, align: u64) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&vaddr.to_le_bytes());
+    out.extend_from_slice(&vaddr.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&align.to_le_bytes());
+}
+
+/// Bits of the multipliers of `Stub::shortest`, scaled powers of five.
+const POW5_BITS: u64 = 125;
+/// Entries of the multiplier tables, enough for the exponents of every double.
+const POW5_INV_ENTRIES: u64 = 291;
+const POW5_ENTRIES: u64 = 326;
+
+/// Variables of `Stub::shortest`, on the stack below its return address.
+const VM_ZEROS: i32 = 0;
+const EVEN: i32 = 8;
+const MM_SHIFT: i32 = 16;
+const SLOTS: i32 = 24;
+
+/// Entry stub that calls the program with a status word, then formats the result in rax or
+/// xmm0 or the error in the status, writes it out and exits.
+///
+/// The text is assembled backwards from the end of a stack buffer, `rbx` pointing at the first
+/// character so far. `r12` is set for negative numbers.
+struct Stub<'a> {
+    enc: Encoder<'a>,
+}
+
+impl Stub<'_> {
+    fn start(mut self, main: Label, rt: Rt) {
+        let (error, format) = (self.label(), self.label());
+        // The status word, as `Fun::try_call` passes it.
+        let e = &mut self.enc;
+        e.push_imm(MAX_DEPTH as i32);
+        e.push_imm(0);
+        e.mov(IntReg::RDI, IntReg::RSP);
+        e.mov(IntReg::RSI, IntReg::RSP);
+        e.call_label(main);
+        e.mov(IntReg::RBX, IntReg::RSP);
+        e.alu_imm(Alu::Sub, IntReg::RSP, BUFFER);
+        e.alu_imm(Alu::Cmp, Mem::base(IntReg::RBX), 0);
+        e.jump(Some(Cond::NZ), error);
+        self.put_str(b"\n");
+        match rt {
+            Rt::Int => self.int(),
+            Rt::Float => self.enc.call_label(format),
+        }
+        self.write_exit(1, 0);
+
+        self.enc.asm().bind(error);
+        let written = self.label();
+        self.enc.load(IntReg::RAX, Mem::base(IntReg::RBX));
+        let mut code = 1;
+        while let Some(err) = EvalError::from_code(code) {
+            let next = self.label();
+            self.enc.alu_imm(Alu::Cmp, IntReg::RAX, code as i32);
+            self.enc.jump(Some(Cond::NZ), next);
+            self.put_str(format!("{}\n", err).as_bytes());
+            self.enc.jump(None, written);
+            self.enc.asm().bind(next);
+            code += 1;
+        }
+        self.enc.asm().bind(written);
+        self.write_exit(2, 1);
+
+        if rt == Rt::Float {
+            self.float(format);
+        }
+    }
+
+    fn label(&mut self) -> Label {
+        self.enc.asm().label()
+    }
+
+    /// Writes the text to `fd` and exits with `status`.
+    fn write_exit(&mut self, fd: i64, status: i64) {
+        let e = &mut self.enc;
+        e.mov_imm(IntReg::RAX, SYS_WRITE);
+        e.mov_imm(IntReg::RDI, fd);
+        e.mov(IntReg::RSI, IntReg::RBX);
+        e.lea(IntReg::RDX, Mem::base(IntReg::RSP).disp(BUFFER));
+        e.alu(Alu::Sub, IntReg::RDX, IntReg::RBX);
+        e.syscall();
+        e.mov_imm(IntReg::RAX, SYS_EXIT);
+        e.mov_imm(IntReg::RDI, status);
+        e.syscall();
+    }
+
+    /// Prepends a constant string.
+    fn put_str(&mut self, text: &[u8]) {
+        for byte in text.iter().rev() {
+            self.enc.mov_imm(IntReg::RDX, *byte as i64);
+            self.put_byte();
+        }
+    }
+
+    /// Prepends the low byte of rdx.
+    fn put_byte(&mut self) {
+        self.enc.alu_imm(Alu::Sub, IntReg::RBX, 1);
+        self.enc.store_byte(Mem::base(IntReg::RBX), IntReg::RDX);
+    }
+
+    /// Prepends the digits of the unsigned rax: all of them, or exactly r8 with leading zeros.
+    fn put_digits(&mut self, fixed: bool) {
+        let repeat = self.label();
+        let e = &mut self.enc;
+        e.mov_imm(IntReg::RCX, 10);
+        e.asm().bind(repeat);
+        e.mov_imm(IntReg::RDX, 0);
+        e.div(IntReg::RCX);
+        e.alu_imm(Alu::Add, IntReg::RDX, b'0' as i32);
+        self.put_byte();
+        let e = &mut self.enc;
+        if fixed {
+            e.alu_imm(Alu::Sub, IntReg::R8, 1);
+        } else {
+            e.test(IntReg::RAX, IntReg::RAX);
+        }
+        e.jump(Some(Cond::NZ), repeat);
+    }
+
+    /// Prepends a minus if r12 is set.
+    fn put_sign(&mut self) {
+        let positive = self.label();
+        self.enc.test(IntReg::R12, IntReg::R12);
+        self.enc.jump(Some(Cond::Z), positive);
+        self.put_str(b"-");
+        self.enc.asm().bind(positive);
+    }
+
+    fn int(&mut self) {
+        let positive = self.label();
+        let e = &mut self.enc;
+        e.mov_imm(IntReg::R12, 0);
+        e.test(IntReg::RAX, IntReg::RAX);
+        e.jump(Some(Cond::NS), positive);
+        // `i64::MIN` stays negative, but is right as an unsigned number.
+        e.mov_imm(IntReg::R12, 1);
+        e.neg(IntReg::RAX);
+        e.asm().bind(positive);
+        self.put_digits(false);
+        self.put_sign();
+    }
+
+    /// Emits the function at `format` that prepends xmm0 the way `Val` displays it, followed
+    /// by the tables it reads. It keeps `rbx` and clobbers the other registers.
+    fn float(&mut self, format: Label) {
+        let (positive, finite, infinite, nonzero, signed, done) = (
+            self.label(),
+            self.label(),
+            self.label(),
+            self.label(),
+            self.label(),
+            self.label(),
+        );
+        let (inv, pow5) = (self.label(), self.label());
+
+        // Sign and magnitude, from the bits. Positive doubles order like their bits.
+        let e = &mut self.enc;
+        e.asm().bind(format);
+        e.alu_imm(Alu::Sub, IntReg::RSP, SLOTS);
+        e.movq_from_xmm(IntReg::RAX, FloatReg::XMM0);
+        e.mov_imm(IntReg::R12, 0);
+        e.test(IntReg::RAX, IntReg::RAX);
+        e.jump(Some(Cond::NS), positive);
+        e.mov_imm(IntReg::R12, 1);
+        e.mov_imm(IntReg::RCX, i64::MAX);
+        e.alu(Alu::And, IntReg::RAX, IntReg::RCX);
+        e.asm().bind(positive);
+
+        e.mov_imm(IntReg::RCX, f64::INFINITY.to_bits() as i64);
+        e.alu(Alu::Cmp, IntReg::RAX, IntReg::RCX);
+        e.jump(Some(Cond::L), finite);
+        e.jump(Some(Cond::Z), infinite);
+        self.put_str(b"NaN");
+        self.enc.jump(None, done);
+        self.enc.asm().bind(infinite);
+        self.put_str(b"inf");
+        self.enc.jump(None, signed);
+
+        self.enc.asm().bind(finite);
+        self.enc.test(IntReg::RAX, IntReg::RAX);
+        self.enc.jump(Some(Cond::NZ), nonzero);
+        self.put_str(b"0");
+        self.enc.jump(None, signed);
+
+        // The digits without an exponent: zeros after them, or a point among them and zeros
+        // before them.
+        let (fraction, zeros, integer) = (self.label(), self.label(), self.label());
+        self.enc.asm().bind(nonzero);
+        self.shortest(inv, pow5);
+        let e = &mut self.enc;
+        e.test(IntReg::RBP, IntReg::RBP);
+        e.jump(Some(Cond::S), fraction);
+        e.asm().bind(zeros);
+        e.test(IntReg::RBP, IntReg::RBP);
+        e.jump(Some(Cond::Z), integer);
+        self.put_str(b"0");
+        self.enc.alu_imm(Alu::Sub, IntReg::RBP, 1);
+        self.enc.jump(None, zeros);
+        self.enc.asm().bind(fraction);
+        self.enc.mov(IntReg::R8, IntReg::RBP);
+        self.enc.neg(IntReg::R8);
+        self.enc.mov(IntReg::RAX, IntReg::R13);
+        self.put_digits(true);
+        self.put_str(b".");
+        self.enc.mov(IntReg::R13, IntReg::RAX);
+        self.enc.asm().bind(integer);
+        self.enc.mov(IntReg::RAX, IntReg::R13);
+        self.put_digits(false);
+
+        self.enc.asm().bind(signed);
+        self.put_sign();
+        let e = &mut self.enc;
+        e.asm().bind(done);
+        e.alu_imm(Alu::Add, IntReg::RSP, SLOTS);
+        e.ret();
+
+        let (inv_table, pow5_table) = tables();
+        e.asm().bind(inv);
+        e.asm().put(&inv_table);
+        e.asm().bind(pow5);
+        e.asm().put(&pow5_table);
+    }
+
+    /// Computes the shortest digits that read back as the positive, finite double with the
+    /// bits in rax, closest to it among those, as Ryu does: r13 gets them as an integer and
+    /// rbp the power of ten they are scaled by. Unlike Ryu, exact halves round up.
+    ///
+    /// The bounds of the doubles rounding to it are multiplied by a power of ten, which makes
+    /// them integers of about 17 digits, and digits are removed while the bounds still differ.
+    /// r13, r14 and r15 hold the scaled value and its upper and lower bound.
+    fn shortest(&mut self, inv: Label, pow5: Label) {
+        let slot = |offset| Mem::base(IntReg::RSP).disp(offset);
+        let (shifted, subnormal, decoded, negative, computed) = (
+            self.label(),
+            self.label(),
+            self.label(),
+            self.label(),
+            self.label(),
+        );
+
+        // r8 = e2, r9 = m2: the double is m2 * 2^e2, the exponent lowered by 2 for the bounds
+        // at mv - 2 and mv + 2 around rsi = mv = 4 * m2. The lower bound is closer by half when
+        // the mantissa is a power of two.
+        let e = &mut self.enc;
+        e.mov(IntReg::R9, IntReg::RAX);
+        e.mov_imm(IntReg::RCX, (1 << 52) - 1);
+        e.alu(Alu::And, IntReg::R9, IntReg::RCX);
+        e.mov(IntReg::R8, IntReg::RAX);
+        e.shr(IntReg::R8, 52);
+        e.store_imm(slot(MM_SHIFT), 1);
+        e.test(IntReg::R9, IntReg::R9);
+        e.jump(Some(Cond::NZ), shifted);
+        e.alu_imm(Alu::Cmp, IntReg::R8, 1);
+        e.jump(Some(Cond::LE), shifted);
+        e.store_imm(slot(MM_SHIFT), 0);
+        e.asm().bind(shifted);
+        e.test(IntReg::R8, IntReg::R8);
+        e.jump(Some(Cond::Z), subnormal);
+        e.mov_imm(IntReg::RCX, 1 << 52);
+        e.alu(Alu::Or, IntReg::R9, IntReg::RCX);
+        e.alu_imm(Alu::Sub, IntReg::R8, 1023 + 52 + 2);
+        e.jump(None, decoded);
+        e.asm().bind(subnormal);
+        e.mov_imm(IntReg::R8, 1 - 1023 - 52 - 2);
+        e.asm().bind(decoded);
+
+        // Bounds are inclusive for even mantissas, which win ties when reading.
+        e.mov(IntReg::RAX, IntReg::R9);
+        e.alu_imm(Alu::And, IntReg::RAX, 1);
+        e.alu_imm(Alu::Xor, IntReg::RAX, 1);
+        e.store(slot(EVEN), IntReg::RAX);
+        e.mov(IntReg::RSI, IntReg::R9);
+        e.shl(IntReg::RSI, 2);
+        e.store_imm(slot(VM_ZEROS), 0);
+        e.test(IntReg::R8, IntReg::R8);
+        e.jump(Some(Cond::S), negative);
+
+        // e2 >= 0: r10 = q = log10(2^e2), minus one above 2^3, multiplies by 2^e2 / 10^q. For
+        // small q the product is exact, and a bound divisible by 5^q has only zeros to drop: the
+        // lower one then stays in reach, the upper one is excluded unless inclusive.
+        e.mov(IntReg::R10, IntReg::R8);
+        e.mov_imm(IntReg::RCX, 78913);
+        e.imul(IntReg::R10, IntReg::RCX);
+        e.shr(IntReg::R10, 18);
+        e.alu_imm(Alu::Cmp, IntReg::R8, 3);
+        e.setcc(Cond::G, IntReg::RAX);
+        e.movzx_byte(IntReg::RAX, IntReg::RAX);
+        e.alu(Alu::Sub, IntReg::R10, IntReg::RAX);
+        e.mov(IntReg::RBP, IntReg::R10);
+        e.mov(IntReg::RCX, IntReg::R10);
+        e.mov_imm(IntReg::RAX, 1_217_359);
+        e.imul(IntReg::RCX, IntReg::RAX);
+        e.shr(IntReg::RCX, 19);
+        e.alu(Alu::Add, IntReg::RCX, IntReg::R10);
+        e.alu(Alu::Sub, IntReg::RCX, IntReg::R8);
+        e.alu_imm(Alu::Add, IntReg::RCX, POW5_BITS as i32 - 64);
+        e.lea_label(IntReg::RDI, inv);
+        e.mov(IntReg::RAX, IntReg::R10);
+        e.shl(IntReg::RAX, 4);
+        e.alu(Alu::Add, IntReg::RDI, IntReg::RAX);
+        self.mul_shift_all();
+
+        let odd = self.label();
+        let e = &mut self.enc;
+        e.alu_imm(Alu::Cmp, IntReg::R10, 21);
+        e.jump(Some(Cond::G), computed);
+        e.mov(IntReg::RAX, IntReg::RSI);
+        e.mov_imm(IntReg::RDX, 0);
+        e.mov_imm(IntReg::R11, 5);
+        e.div(IntReg::R11);
+        e.test(IntReg::RDX, IntReg::RDX);
+        e.jump(Some(Cond::Z), computed);
+        e.alu_imm(Alu::Cmp, slot(EVEN), 0);
+        e.jump(Some(Cond::Z), odd);
+        e.mov(IntReg::RAX, IntReg::RSI);
+        e.alu_imm(Alu::Sub, IntReg::RAX, 1);
+        e.load(IntReg::R11, slot(MM_SHIFT));
+        e.alu(Alu::Sub, IntReg::RAX, IntReg::R11);
+        self.multiple_of_pow5();
+        let e = &mut self.enc;
+        e.store(slot(VM_ZEROS), IntReg::RAX);
+        e.jump(None, computed);
+        e.asm().bind(odd);
+        e.mov(IntReg::RAX, IntReg::RSI);
+        e.alu_imm(Alu::Add, IntReg::RAX, 2);
+        self.multiple_of_pow5();
+        self.enc.alu(Alu::Sub, IntReg::R14, IntReg::RAX);
+        self.enc.jump(None, computed);
+
+        // e2 < 0: q = log10(5^-e2), minus one above 5^1, multiplies by 5^(-e2 - q) / 2^q.
+        let odd = self.label();
+        let e = &mut self.enc;
+        e.asm().bind(negative);
+        e.mov(IntReg::R11, IntReg::R8);
+        e.neg(IntReg::R11);
+        e.mov(IntReg::R10, IntReg::R11);
+        e.mov_imm(IntReg::RCX, 732_923);
+        e.imul(IntReg::R10, IntReg::RCX);
+        e.shr(IntReg::R10, 20);
+        e.alu_imm(Alu::Cmp, IntReg::R11, 1);
+        e.setcc(Cond::G, IntReg::RAX);
+        e.movzx_byte(IntReg::RAX, IntReg::RAX);
+        e.alu(Alu::Sub, IntReg::R10, IntReg::RAX);
+        e.mov(IntReg::RBP, IntReg::R10);
+        e.alu(Alu::Add, IntReg::RBP, IntReg::R8);
+        e.alu(Alu::Sub, IntReg::R11, IntReg::R10);
+        e.lea_label(IntReg::RDI, pow5);
+        e.mov(IntReg::RAX, IntReg::R11);
+        e.shl(IntReg::RAX, 4);
+        e.alu(Alu::Add, IntReg::RDI, IntReg::RAX);
+        e.mov(IntReg::RAX, IntReg::R11);
+        e.mov_imm(IntReg::RCX, 1_217_359);
+        e.imul(IntReg::RAX, IntReg::RCX);
+        e.shr(IntReg::RAX, 19);
+        e.mov(IntReg::RCX, IntReg::R10);
+        e.alu(Alu::Sub, IntReg::RCX, IntReg::RAX);
+        e.alu_imm(Alu::Add, IntReg::RCX, POW5_BITS as i32 - 1 - 64);
+        self.mul_shift_all();
+
+        let e = &mut self.enc;
+        e.alu_imm(Alu::Cmp, IntReg::R10, 1);
+        e.jump(Some(Cond::G), computed);
+        e.alu_imm(Alu::Cmp, slot(EVEN), 0);
+        e.jump(Some(Cond::Z), odd);
+        e.load(IntReg::RAX, slot(MM_SHIFT));
+        e.store(slot(VM_ZEROS), IntReg::RAX);
+        e.jump(None, computed);
+        e.asm().bind(odd);
+        e.alu_imm(Alu::Sub, IntReg::R14, 1);
+
+        // Digits are removed while the bounds differ, then while the lower bound ends in a
+        // zero if it is exact. r9 counts them and r10 holds the last one of the value.
+        let (shorten, trimmed, exact, round, up) = (
+            self.label(),
+            self.label(),
+            self.label(),
+            self.label(),
+            self.label(),
+        );
+        let vm_zero = self.label();
+        let e = &mut self.enc;
+        e.asm().bind(computed);
+        e.mov_imm(IntReg::R9, 0);
+        e.mov_imm(IntReg::R10, 0);
+        e.mov_imm(IntReg::RCX, 10);
+        e.asm().bind(shorten);
+        e.mov(IntReg::RAX, IntReg::R14);
+        e.mov_imm(IntReg::RDX, 0);
+        e.div(IntReg::RCX);
+        e.mov(IntReg::R11, IntReg::RAX);
+        e.mov(IntReg::RAX, IntReg::R15);
+        e.mov_imm(IntReg::RDX, 0);
+        e.div(IntReg::RCX);
+        e.alu(Alu::Cmp, IntReg::R11, IntReg::RAX);
+        e.jump(Some(Cond::LE), trimmed);
+        e.test(IntReg::RDX, IntReg::RDX);
+        e.jump(Some(Cond::Z), vm_zero);
+        e.store_imm(slot(VM_ZEROS), 0);
+        e.asm().bind(vm_zero);
+        e.mov(IntReg::R15, IntReg::RAX);
+        e.mov(IntReg::R14, IntReg::R11);
+        self.remove_digit();
+        self.enc.jump(None, shorten);
+
+        let e = &mut self.enc;
+        e.asm().bind(trimmed);
+        e.alu_imm(Alu::Cmp, slot(VM_ZEROS), 0);
+        e.jump(Some(Cond::Z), round);
+        e.asm().bind(exact);
+        e.mov(IntReg::RAX, IntReg::R15);
+        e.mov_imm(IntReg::RDX, 0);
+        e.div(IntReg::RCX);
+        e.test(IntReg::RDX, IntReg::RDX);
+        e.jump(Some(Cond::NZ), round);
+        e.mov(IntReg::R15, IntReg::RAX);
+        e.mov(IntReg::RAX, IntReg::R14);
+        e.mov_imm(IntReg::RDX, 0);
+        e.div(IntReg::RCX);
+        e.mov(IntReg::R14, IntReg::RAX);
+        self.remove_digit();
+        self.enc.jump(None, exact);
+
+        // Rounds up from a half, as `Display` does, or if the value would fall out of the
+        // bounds.
+        let kept = self.label();
+        let e = &mut self.enc;
+        e.asm().bind(round);
+        e.alu_imm(Alu::Cmp, IntReg::R10, 5);
+        e.jump(Some(Cond::GE), up);
+        e.alu(Alu::Cmp, IntReg::R13, IntReg::R15);
+        e.jump(Some(Cond::NZ), kept);
+        e.alu_imm(Alu::Cmp, slot(EVEN), 0);
+        e.jump(Some(Cond::Z), up);
+        e.alu_imm(Alu::Cmp, slot(VM_ZEROS), 0);
+        e.jump(Some(Cond::Z), up);
+        e.jump(None, kept);
+        e.asm().bind(up);
+        e.alu_imm(Alu::Add, IntReg::R13, 1);
+        e.asm().bind(kept);
+        e.alu(Alu::Add, IntReg::RBP, IntReg::R9);
+    }
+
+    /// Sets r13, r14 and r15 to rsi, rsi + 2 and the lower bound of rsi, multiplied by the
+    /// 128-bit table entry at rdi and shifted right by cl + 64.
+    fn mul_shift_all(&mut self) {
+        let slot = |offset| Mem::base(IntReg::RSP).disp(offset);
+        for dst in [IntReg::R13, IntReg::R14, IntReg::R15] {
+            let e = &mut self.enc;
+            e.mov(IntReg::R9, IntReg::RSI);
+            if dst == IntReg::R14 {
+                e.alu_imm(Alu::Add, IntReg::R9, 2);
+            } else if dst == IntReg::R15 {
+                e.alu_imm(Alu::Sub, IntReg::R9, 1);
+                e.load(IntReg::RAX, slot(MM_SHIFT));
+                e.alu(Alu::Sub, IntReg::R9, IntReg::RAX);
+            }
+            e.mov(IntReg::RAX, IntReg::R9);
+            e.mul(Mem::base(IntReg::RDI));
+            e.mov(IntReg::R11, IntReg::RDX);
+            e.mov(IntReg::RAX, IntReg::R9);
+            e.mul(Mem::base(IntReg::RDI).disp(8));
+            e.alu(Alu::Add, IntReg::RAX, IntReg::R11);
+            e.alu_imm(Alu::Adc, IntReg::RDX, 0);
+            e.shrd_cl(IntReg::RAX, IntReg::RDX);
+            e.mov(dst, IntReg::RAX);
+        }
+    }
+
+    /// Sets rax to 1 if 5^r10 divides rax and to 0 otherwise. rax must not be zero.
+    fn multiple_of_pow5(&mut self) {
+        let (repeat, done) = (self.label(), self.label());
+        let e = &mut self.enc;
+        e.mov_imm(IntReg::RCX, 0);
+        e.mov_imm(IntReg::R11, 5);
+        e.asm().bind(repeat);
+        e.mov_imm(IntReg::RDX, 0);
+        e.div(IntReg::R11);
+        e.test(IntReg::RDX, IntReg::RDX);
+        e.jump(Some(Cond::NZ), done);
+        e.alu_imm(Alu::Add, IntReg::RCX, 1);
+        e.jump(None, repeat);
+        e.asm().bind(done);
+        e.alu(Alu::Cmp, IntReg::RCX, IntReg::R10);
+        e.setcc(Cond::GE, IntReg::RAX);
+        e.movzx_byte(IntReg::RAX, IntReg::RAX);
+    }
+
+    /// Removes the last digit of r13 into r10, counting it in r9. rcx holds 10.
+    fn remove_digit(&mut self) {
+        let e = &mut self.enc;
+        e.mov(IntReg::RAX, IntReg::R13);
+        e.mov_imm(IntReg::RDX, 0);
+        e.div(IntReg::RCX);
+        e.mov(IntReg::R13, IntReg::RAX);
+        e.mov(IntReg::R10, IntReg::RDX);
+        e.alu_imm(Alu::Add, IntReg::R9, 1);
+    }
+}
+
+/// Multipliers of `Stub::shortest` as pairs of little-endian words: `2^k / 5^q` rounded up for
+/// positive binary exponents and `5^i` for negative ones, both scaled to `POW5_BITS` bits.
+fn tables() -> (Vec<u8>, Vec<u8>) {
+    let (mut inv, mut pow5) = (vec![], vec![]);
+    let mut power = vec![1u64];
+    for e in 0..POW5_INV_ENTRIES.max(POW5_ENTRIES) {
+        let bits = pow5bits(e);
+        if e < POW5_INV_ENTRIES {
+            let entry = divide_pow2(&power, bits - 1 + POW5_BITS) + 1;
+            inv.extend_from_slice(&entry.to_le_bytes());
+        }
+        if e < POW5_ENTRIES {
+            let shift = bits as i64 - POW5_BITS as i64;
+            let entry = (0..128).fold(0u128, |entry, i| {
+                entry | (bit(&power, shift + i) as u128) << i
+            });
+            pow5.extend_from_slice(&entry.to_le_bytes());
+        }
+        let mut carry = 0;
+        for limb in power.iter_mut() {
+            let product = *limb as u128 * 5 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 {
+            power.push(carry as u64);
+        }
+    }
+    (inv, pow5)
+}
+
+/// Number of bits of `5^e`, the formula `Stub::shortest` computes it with.
+fn pow5bits(e: u64) -> u64 {
+    ((e * 1_217_359) >> 19) + 1
+}
+
+/// Bit `pos` of the little-endian number `n`, zero below the first.
+fn bit(n: &[u64], pos: i64) -> bool {
+    pos >= 0
+        && n.get(pos as usize / 64)
+            .is_some_and(|limb| limb >> (pos % 64) & 1 == 1)
+}
+
+/// `2^k / d`, rounded down. The quotient must fit 128 bits.
+fn divide_pow2(d: &[u64], k: u64) -> u128 {
+    let mut rem = vec![0u64; d.len() + 1];
+    let mut quotient = 0;
+    for pos in (0..=k).rev() {
+        let mut carry = (pos == k) as u64;
+        for limb in rem.iter_mut() {
+            let next = *limb >> 63;
+            *limb = *limb << 1 | carry;
+            carry = next;
+        }
+        let less = (0..rem.len())
+            .rev()
+            .map(|i| (rem[i], d.get(i).copied().unwrap_or(0)))
+            .find(|(a, b)| a != b)
+            .is_some_and(|(a, b)| a < b);
+        if !less {
+            let mut borrow = 0;
+            for (i, limb) in rem.iter_mut().enumerate() {
+                let (diff, under) = limb.overflowing_sub(d.get(i).copied().unwrap_or(0));
+                let (diff, under_borrow) = diff.overflowing_sub(borrow);
+                *limb = diff;
+                borrow = (under || under_borrow) as u64;
+            }
+            quotient |= 1u128 << pos;
+        }
+    }
+    quotient
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::asm::arch::{Asm, Elf};
+    use crate::asm::x86_64::encoder::Encoder;
+    use crate::asm::x86_64::executable::{executable, Stub};
+    use crate::asm::x86_64::IntReg;
+    use crate::interpreter::{Env, Execution};
+    use crate::parser::ast::parse_program;
+    use crate::parser::program::Program;
+
+    fn parse(input: &str) -> Program {
+        parse_program(input).unwrap()
+    }
+
+    /// Runs the executable of `input`, returning its exit status and output.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn run(input: &str) -> (i32, String, String) {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "neb-formula-{}-{}",
+            std::process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, executable(&parse(input)).unwrap()).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let output = Command::new(&path).output();
+        fs::remove_file(&path).unwrap();
+        let output = output.unwrap();
+        (
+            output.status.code().unwrap(),
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn test_executable() {
+        let perform = |input: &str, expected: &str| {
+            assert_eq!(
+                run(input),
+                (0, format!("{}\n", expected), String::new()),
+                "{}",
+                input
+            );
+            let val = parse(input)
+                .bind(&[])
+                .unwrap()
+                .try_exec(&Env::new())
+                .unwrap();
+            assert_eq!(val.to_string(), expected, "{}", input);
+        };
+
+        perform("6 * 7", "42");
+        perform("0", "0");
+        perform("3 - 10 * 5", "-47");
+        perform("0 - 9223372036854775807 - 1", "-9223372036854775808");
+        perform("2 ^ 62 + (2 ^ 62 - 1)", "9223372036854775807");
+        perform("1.5 * 2.25", "3.375");
+        perform("0.0 - 2.5 / 2", "-1.25");
+        perform("1 / 3.0", "0.3333333333333333");
+        perform("2 / 3.0", "0.6666666666666666");
+        perform("1.9999999", "1.9999999");
+        perform("4.0 - 4", "0");
+        perform("0.0 - 0.0 * 1", "0");
+        perform("0.0 * (0 - 1)", "-0");
+        perform("0.000001", "0.000001");
+        perform("0.1 + 0.2", "0.30000000000000004");
+        perform(
+            "1000000000000.0 * 1000000000000.0",
+            "1000000000000000000000000",
+        );
+        perform("1.0 / 0", "inf");
+        perform("0.0 - 1.0 / 0", "-inf");
+        perform("0.0 / 0", "NaN");
+
+        // Builtins computed inline, and programs with their definitions.
+        perform(
+            "sqrt(2) * sqrt(2) + abs(0 - 3) * max(1, 2) - min(4, 5)",
+            "4",
+        );
+        perform("sq(x) = x * x; sq(3) + sq(0.5)", "9.25");
+        perform(
+            "fact(n) = if n < 2 then 1 else n * fact(n - 1); fact(20)",
+            "2432902008176640000",
+        );
+        perform("h(x) = sqrt(x) / x; h(2) * h(3)", "0.408248290463863");
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn test_errors() {
+        assert_eq!(
+            run("1 / 0"),
+            (1, String::new(), "Division by zero\n".to_string())
+        );
+        assert_eq!(
+            run("f(n) = (0 - 9223372036854775807 - 1) / n; 2.0 + f(0 - 1)"),
+            (1, String::new(), "Integer overflow\n".to_string())
+        );
+        assert_eq!(
+            run("f(n) = f(n + 1) + 1; f(0)"),
+            (
+                1,
+                String::new(),
+                "Calls nested deeper than 1000 levels\n".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_rejected() {
+        for (input, fun) in [
+            ("2.0 ^ 0.5", "pow"),
+            ("sin(1)", "sin"),
+            ("f(x) = ln(x) + 1; f(2)", "log"),
+            ("abs(0 - 2.5)", "fabs"),
+        ] {
+            let err = executable(&parse(input)).unwrap_err();
+            assert!(err.to_string().contains(&format!("'{}'", fun)), "{}", err);
+        }
+
+        let err = executable(&parse("x + 1")).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown variable 'x'");
+    }
+
+    type Format = extern "C" fn(*mut u8, f64) -> *mut u8;
+
+    /// The function of `Stub::float`, called with the end of a buffer in rdi and returning the
+    /// start of the text.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn formatter() -> Elf<Format> {
+        let mut asm = Asm::new();
+        let mut enc = Encoder::new(&mut asm);
+        let format = enc.asm().label();
+        let saved = [
+            IntReg::RBX,
+            IntReg::RBP,
+            IntReg::R12,
+            IntReg::R13,
+            IntReg::R14,
+            IntReg::R15,
+        ];
+        for reg in saved.iter() {
+            enc.push(*reg);
+        }
+        enc.mov(IntReg::RBX, IntReg::RDI);
+        enc.call_label(format);
+        enc.mov(IntReg::RAX, IntReg::RBX);
+        for reg in saved.iter().rev() {
+            enc.pop(*reg);
+        }
+        enc.ret();
+        Stub { enc }.float(format);
+        asm.finalize().unwrap();
+        asm.prepare().unwrap()
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn test_format() {
+        let mut vals = vec![
+            0.0,
+            -0.0,
+            1.0,
+            0.1,
+            5e-324,
+            -1e-323,
+            f64::MIN_POSITIVE,
+            f64::MIN_POSITIVE - 5e-324,
+            f64::MAX,
+            f64::EPSILON,
+            9007199254740993.0,
+            1e22,
+            1e23,
+            123456789012345680.0,
+            f64::NAN,
+            f64::NEG_INFINITY,
+        ];
+        for e in -1074..1024 {
+            vals.push(2.0f64.powi(e));
+        }
+        // Short mantissas are often exactly between two candidates with the fewest digits.
+        for m in (1..64).step_by(2) {
+            for e in -1074..972 {
+                vals.push(m as f64 * 2.0f64.powi(e));
+            }
+        }
+        for e in -324..309 {
+            vals.push(format!("1e{}", e).parse().unwrap());
+            vals.push(format!("9.999999999999999e{}", e).parse().unwrap());
+        }
+        // A xorshift generator, over all bit patterns and over small integers.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..20000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            vals.push(f64::from_bits(state));
+            vals.push((state % 100_000) as f64 / 1000.0);
+        }
+
+        let elf = formatter();
+        let format = unsafe { elf.func() };
+        for val in vals {
+            let mut buffer = [0u8; 512];
+            let end = unsafe { buffer.as_mut_ptr().add(buffer.len()) };
+            let start = format(end, val) as usize - buffer.as_ptr() as usize;
+            let text = String::from_utf8(buffer[start..].to_vec()).unwrap();
+            assert_eq!(text, val.to_string(), "{:e}", val);
+        }
+    }
+}
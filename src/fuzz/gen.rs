@@ -0,0 +1,357 @@
+use crate::asm::exec::Rt;
+use crate::asm::host::HostFn;
+use crate::parser::ast::{Builtin, Exp, Op, UnOp, Val, Var};
+use crate::parser::program::{Def, Program};
+
+const OPS: [Op; 6] = [Op::Add, Op::Sub, Op::Mul, Op::Mod, Op::Div, Op::Pow];
+
+const COMPARISONS: [Op; 6] = [Op::Lt, Op::Le, Op::Gt, Op::Ge, Op::Eq, Op::Ne];
+
+/// Operands around the edges of the integer operations: overflow, truncation and the shifts of
+/// the power loop.
+const INTS: [i64; 14] = [
+    0,
+    1,
+    -1,
+    2,
+    -2,
+    3,
+    7,
+    -7,
+    10,
+    63,
+    64,
+    -64,
+    i64::MAX,
+    i64::MIN,
+];
+
+const FLOATS: [f64; 12] = [
+    0.0,
+    -0.0,
+    0.5,
+    1.5,
+    -2.25,
+    3.0,
+    1e-9,
+    1e18,
+    -1e300,
+    f64::INFINITY,
+    f64::NEG_INFINITY,
+    f64::NAN,
+];
+
+/// xorshift64* generator. Deterministic for a seed, which is all the tests need.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // The state must not be zero; the odd constant also spreads small seeds.
+        Rng {
+            state: (seed ^ 0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `0..n`, `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Random well-typed expressions and programs, with operands biased toward edge cases.
+#[derive(Clone, Debug)]
+pub struct Gen {
+    rng: Rng,
+    depth: usize,
+    hosts: Vec<HostFn>,
+    params: Vec<(String, Rt)>,
+    defs: usize,
+    /// Names and arities of the definitions generated expressions can call.
+    funs: Vec<(String, usize)>,
+}
+
+impl Gen {
+    pub fn new(seed: u64) -> Gen {
+        Gen {
+            rng: Rng::new(seed),
+            depth: 6,
+            hosts: Vec::new(),
+            params: Vec::new(),
+            defs: 0,
+            funs: Vec::new(),
+        }
+    }
+
+    /// Limits the nesting of generated expressions.
+    pub fn depth(mut self, depth: usize) -> Gen {
+        self.depth = depth;
+        self
+    }
+
+    /// Lets generated expressions call `hosts`.
+    pub fn hosts(mut self, hosts: Vec<HostFn>) -> Gen {
+        self.hosts = hosts;
+        self
+    }
+
+    /// Lets generated expressions read the variables `params`, bound to their slots.
+    pub fn params(mut self, params: &[(&str, Rt)]) -> Gen {
+        self.params = params
+            .iter()
+            .map(|(name, rt)| (name.to_string(), *rt))
+            .collect();
+        self
+    }
+
+    /// Makes `program` define `defs` functions for its expression to call.
+    pub fn defs(mut self, defs: usize) -> Gen {
+        self.defs = defs;
+        self
+    }
+
+    /// The variables set with `params`, in slot order.
+    pub fn vars(&self) -> &[(String, Rt)] {
+        &self.params
+    }
+
+    /// A value for every parameter.
+    pub fn args(&mut self) -> Vec<Val> {
+        let rts: Vec<Rt> = self.params.iter().map(|(_, rt)| *rt).collect();
+        rts.into_iter().map(|rt| self.val(rt)).collect()
+    }
+
+    pub fn exp(&mut self) -> Exp {
+        let rt = *self.rng.pick(&[Rt::Int, Rt::Float]);
+        self.typed(rt, self.depth)
+    }
+
+    /// A program with the definitions set with `defs`, each calling the ones before it, and an
+    /// expression calling them. Calls are unbound, as the parser leaves them.
+    pub fn program(&mut self) -> Program {
+        let defs = (0..self.defs)
+            .map(|i| {
+                let def = self.def(format!("f{}", i));
+                self.funs.push((def.name.clone(), def.params.len()));
+                def
+            })
+            .collect();
+        let exp = self.exp();
+        self.funs.clear();
+        Program { defs, exp }
+    }
+
+    /// A definition reading its own parameters. Half of them recurse on the first parameter,
+    /// counting it down to a base case; arguments far from it reach the depth limit instead.
+    fn def(&mut self, name: String) -> Def {
+        let arity = 1 + self.rng.below(3);
+        let params: Vec<(String, Rt)> = (0..arity)
+            .map(|i| (format!("x{}", i), *self.rng.pick(&[Rt::Int, Rt::Float])))
+            .collect();
+        let outer = std::mem::replace(&mut self.params, params);
+        let rt = *self.rng.pick(&[Rt::Int, Rt::Float]);
+        let depth = self.depth.clamp(1, 3);
+        let body = if self.rng.below(2) == 0 {
+            // Only the recursive call, so the number of calls stays linear in the argument.
+            let funs = std::mem::take(&mut self.funs);
+            let counter = || Box::new(Exp::Var(Var::new("x0")));
+            let mut args = vec![Exp::Exp {
+                op: Op::Sub,
+                left: counter(),
+                right: Box::new(Exp::Val(Val::Int(1))),
+            }];
+            for _ in 1..arity {
+                let rt = *self.rng.pick(&[Rt::Int, Rt::Float]);
+                args.push(self.typed(rt, depth - 1));
+            }
+            let call = Exp::Apply {
+                name: name.clone(),
+                args,
+                rt: Rt::Int,
+                instance: 0,
+            };
+            let body = Exp::If {
+                cond: Box::new(Exp::Exp {
+                    op: Op::Lt,
+                    left: counter(),
+                    right: Box::new(Exp::Val(Val::Int(1))),
+                }),
+                then: Box::new(self.typed(rt, depth)),
+                otherwise: Box::new(Exp::Exp {
+                    op: *self.rng.pick(&OPS),
+                    left: Box::new(call),
+                    right: Box::new(self.typed(rt, depth - 1)),
+                }),
+            };
+            self.funs = funs;
+            body
+        } else {
+            self.typed(rt, depth)
+        };
+        let params = std::mem::replace(&mut self.params, outer);
+        Def {
+            name,
+            params: params.into_iter().map(|(name, _)| name).collect(),
+            body,
+        }
+    }
+
+    /// An expression of type `rt`, nested at most `depth` levels.
+    pub fn typed(&mut self, rt: Rt, depth: usize) -> Exp {
+        if depth == 0 || self.rng.below(4) == 0 {
+            let slots: Vec<usize> = (0..self.params.len())
+                .filter(|slot| self.params[*slot].1 == rt)
+                .collect();
+            if !slots.is_empty() && self.rng.below(2) == 0 {
+                let slot = *self.rng.pick(&slots);
+                let name = self.params[slot].0.clone();
+                return Exp::Var(Var { name, rt, slot });
+            }
+            return Exp::Val(self.val(rt));
+        }
+
+        // A call of a definition has the type binding gives it, whatever `rt` is, and host
+        // calls need arguments of their exact types, so programs with definitions don't make
+        // host calls.
+        if !self.funs.is_empty() && self.rng.below(5) == 0 {
+            let (name, arity) = self.rng.pick(&self.funs).clone();
+            let args = (0..arity)
+                .map(|_| {
+                    let rt = *self.rng.pick(&[Rt::Int, Rt::Float]);
+                    self.typed(rt, depth - 1)
+                })
+                .collect();
+            return Exp::Apply {
+                name,
+                args,
+                rt: Rt::Int,
+                instance: 0,
+            };
+        }
+
+        let hosts: Vec<HostFn> = self
+            .hosts
+            .iter()
+            .filter(|fun| fun.ret() == rt && self.funs.is_empty())
+            .cloned()
+            .collect();
+        if !hosts.is_empty() && self.rng.below(6) == 0 {
+            let fun = self.rng.pick(&hosts).clone();
+            let args = fun
+                .args()
+                .iter()
+                .map(|arg| self.typed(*arg, depth - 1))
+                .collect();
+            return Exp::Call { fun, args };
+        }
+
+        if self.rng.below(8) == 0 {
+            return self.builtin(rt, depth - 1);
+        }
+
+        if self.rng.below(8) == 0 {
+            return Exp::Unary {
+                op: *self.rng.pick(&[UnOp::Neg, UnOp::Plus]),
+                exp: Box::new(self.typed(rt, depth - 1)),
+            };
+        }
+
+        if self.rng.below(8) == 0 {
+            let cond = self.cond(depth - 1);
+            let (then, otherwise) = self.operands(rt);
+            return Exp::If {
+                cond: Box::new(cond),
+                then: Box::new(self.typed(then, depth - 1)),
+                otherwise: Box::new(self.typed(otherwise, depth - 1)),
+            };
+        }
+
+        if rt == Rt::Int && self.rng.below(4) == 0 {
+            return self.comparison(depth);
+        }
+
+        let (left, right) = self.operands(rt);
+        Exp::Exp {
+            op: *self.rng.pick(&OPS),
+            left: Box::new(self.typed(left, depth - 1)),
+            right: Box::new(self.typed(right, depth - 1)),
+        }
+    }
+
+    /// Types of two operands giving a result of type `rt`. A float result needs one float
+    /// operand, the other one is converted.
+    fn operands(&mut self, rt: Rt) -> (Rt, Rt) {
+        match (rt, self.rng.below(3)) {
+            (Rt::Int, _) => (Rt::Int, Rt::Int),
+            (Rt::Float, 0) => (Rt::Int, Rt::Float),
+            (Rt::Float, 1) => (Rt::Float, Rt::Int),
+            (Rt::Float, _) => (Rt::Float, Rt::Float),
+        }
+    }
+
+    /// A condition: mostly a comparison, otherwise any value, which holds when it isn't zero.
+    fn cond(&mut self, depth: usize) -> Exp {
+        if depth == 0 || self.rng.below(4) == 0 {
+            let rt = *self.rng.pick(&[Rt::Int, Rt::Float]);
+            self.typed(rt, depth)
+        } else {
+            self.comparison(depth)
+        }
+    }
+
+    /// A comparison of operands of any types, nested at most `depth` levels, which must not be
+    /// zero.
+    fn comparison(&mut self, depth: usize) -> Exp {
+        let left = *self.rng.pick(&[Rt::Int, Rt::Float]);
+        let right = *self.rng.pick(&[Rt::Int, Rt::Float]);
+        Exp::Exp {
+            op: *self.rng.pick(&COMPARISONS),
+            left: Box::new(self.typed(left, depth - 1)),
+            right: Box::new(self.typed(right, depth - 1)),
+        }
+    }
+
+    /// A builtin call of type `rt`. Integers come from `abs`, `min` and `max` of integers; for a
+    /// float one of them gets a float first argument.
+    fn builtin(&mut self, rt: Rt, depth: usize) -> Exp {
+        let fun = match rt {
+            Rt::Int => *self.rng.pick(&[Builtin::Abs, Builtin::Min, Builtin::Max]),
+            Rt::Float => *self.rng.pick(&Builtin::ALL),
+        };
+        let args = (0..fun.arity())
+            .map(|i| {
+                let arg = match rt {
+                    Rt::Int => Rt::Int,
+                    Rt::Float if i == 0 && fun.ret(&[Rt::Int; 2]) == Rt::Int => Rt::Float,
+                    Rt::Float => *self.rng.pick(&[Rt::Int, Rt::Float]),
+                };
+                self.typed(arg, depth)
+            })
+            .collect();
+        Exp::Builtin { fun, args }
+    }
+
+    fn val(&mut self, rt: Rt) -> Val {
+        match (rt, self.rng.below(3)) {
+            (Rt::Int, 0) => Val::Int(self.rng.next_u64() as i64),
+            (Rt::Int, 1) => Val::Int(self.rng.below(201) as i64 - 100),
+            (Rt::Int, _) => Val::Int(*self.rng.pick(&INTS)),
+            (Rt::Float, 0) => Val::Float(f64::from_bits(self.rng.next_u64())),
+            (Rt::Float, 1) => Val::Float((self.rng.below(801) as f64 - 400.0) / 8.0),
+            (Rt::Float, _) => Val::Float(*self.rng.pick(&FLOATS)),
+        }
+    }
+}
@@ -0,0 +1,466 @@
+pub mod gen;
+
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::Error;
+
+use crate::asm::arch::{Arch, Asm};
+use crate::asm::exec::{AsmCode, Rt};
+use crate::asm::Fun;
+use crate::fuzz::gen::Gen;
+use crate::interpreter::{Env, EvalError, Execution};
+use crate::parser::ast::{Exp, Val};
+use crate::parser::program::Program;
+
+/// What an evaluator made of an expression.
+pub type Outcome = Result<Val, EvalError>;
+
+/// A program the interpreter and the compiled code disagree on, with the values of its
+/// variables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub program: Program,
+    pub env: Env,
+    pub interpreted: Outcome,
+    pub compiled: Outcome,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        if self.env != Env::new() {
+            write!(f, " with {}", self.env)?;
+        }
+        write!(
+            f,
+            ": interpreter gives {:?}, compiled code gives {:?}",
+            self.interpreted, self.compiled
+        )
+    }
+}
+
+/// NaNs are the same outcome whatever their payload.
+fn same(a: &Outcome, b: &Outcome) -> bool {
+    match (a, b) {
+        (Ok(Val::Float(a)), Ok(Val::Float(b))) if a.is_nan() => b.is_nan(),
+        _ => a == b,
+    }
+}
+
+/// Evaluates `program` with the interpreter and with code compiled for `A`, which must be the
+/// host architecture, passing `args` for `params`.
+pub fn compare<A>(
+    program: &Program,
+    params: &[(&str, Rt)],
+    args: &[Val],
+) -> Result<Option<Divergence>, Error>
+where
+    A: Arch + Default + Into<Asm>,
+{
+    let env = params
+        .iter()
+        .zip(args)
+        .fold(Env::new(), |env, ((name, _), arg)| env.with(name, *arg));
+    let interpreted = program.bind(params)?.try_exec(&env);
+    let compiled = Fun::<A>::with_program(program, params, A::default())?.try_call(args);
+    Ok(if same(&interpreted, &compiled) {
+        None
+    } else {
+        Some(Divergence {
+            program: program.clone(),
+            env,
+            interpreted,
+            compiled,
+        })
+    })
+}
+
+/// Compares `count` programs from `gen`, each called with fresh arguments, returning the first
+/// divergence shrunk to a minimal counterexample.
+pub fn run<A>(gen: &mut Gen, count: usize) -> Result<Option<Divergence>, Error>
+where
+    A: Arch + Default + Into<Asm>,
+{
+    let params = gen.vars().to_vec();
+    let params: Vec<(&str, Rt)> = params.iter().map(|(name, rt)| (name.as_str(), *rt)).collect();
+    for _ in 0..count {
+        let program = gen.program();
+        let args = gen.args();
+        if let Some(divergence) = compare::<A>(&program, &params, &args)? {
+            let diverges =
+                |program: &Program| matches!(compare::<A>(program, &params, &args), Ok(Some(_)));
+            let program = shrink_program(divergence.program, diverges);
+            return compare::<A>(&program, &params, &args);
+        }
+    }
+    Ok(None)
+}
+
+/// Shrinks the expression of `program` like `shrink`, then the body of every definition.
+pub fn shrink_program(mut program: Program, mut keep: impl FnMut(&Program) -> bool) -> Program {
+    let exp = program.exp.clone();
+    program.exp = shrink(exp, |exp| {
+        keep(&Program {
+            exp: exp.clone(),
+            ..program.clone()
+        })
+    });
+    for i in 0..program.defs.len() {
+        let body = program.defs[i].body.clone();
+        program.defs[i].body = shrink(body, |body| {
+            let mut program = program.clone();
+            program.defs[i].body = body.clone();
+            keep(&program)
+        });
+    }
+    program
+}
+
+/// Greedily simplifies `exp` while `keep` holds: subtrees are replaced by zero, by one of their
+/// operands or by their value, constants move toward zero and variables become zero. Arguments of calls keep
+/// their types, and every step makes the expression strictly simpler, so shrinking terminates.
+pub fn shrink(mut exp: Exp, mut keep: impl FnMut(&Exp) -> bool) -> Exp {
+    while let Some(simpler) = candidates(&exp).into_iter().find(|exp| keep(exp)) {
+        exp = simpler;
+    }
+    exp
+}
+
+fn candidates(exp: &Exp) -> Vec<Exp> {
+    let rt = exp.result_type();
+    let zero = Exp::Val(match rt {
+        Rt::Int => Val::Int(0),
+        Rt::Float => Val::Float(0.0),
+    });
+    let folded = exp.try_exec(&Env::new()).ok().map(Exp::Val);
+    match exp {
+        Exp::Var(_) | Exp::Error(_) => vec![zero],
+        Exp::Unary { op, exp } => {
+            let mut exps = vec![zero, exp.as_ref().clone()];
+            exps.extend(folded);
+            exps.extend(candidates(exp).into_iter().map(|exp| Exp::Unary {
+                op: *op,
+                exp: Box::new(exp),
+            }));
+            exps
+        }
+        Exp::Val(Val::Int(val)) => {
+            let mut vals = vec![0, val / 2];
+            vals.extend(val.checked_neg().filter(|_| *val < 0));
+            vals.into_iter()
+                .filter(|v| v != val)
+                .map(|v| Exp::Val(Val::Int(v)))
+                .collect()
+        }
+        Exp::Val(Val::Float(val)) => {
+            let mut vals = vec![0.0, 1.0];
+            if val.is_finite() && val.trunc() != *val {
+                vals.push(val.trunc());
+            }
+            // 0 < 1 < other integers < anything else; NaN compares unequal to all of them.
+            let rank = |v: f64| {
+                if v == 0.0 {
+                    0
+                } else if v == 1.0 {
+                    1
+                } else if v.is_finite() && v.trunc() == v {
+                    2
+                } else {
+                    3
+                }
+            };
+            vals.into_iter()
+                .filter(|v| rank(*v) < rank(*val))
+                .map(|v| Exp::Val(Val::Float(v)))
+                .collect()
+        }
+        Exp::Exp { op, left, right } => {
+            let mut exps = vec![zero, left.as_ref().clone(), right.as_ref().clone()];
+            exps.extend(folded);
+            exps.extend(candidates(left).into_iter().map(|left| Exp::Exp {
+                op: *op,
+                left: Box::new(left),
+                right: right.clone(),
+            }));
+            exps.extend(candidates(right).into_iter().map(|right| Exp::Exp {
+                op: *op,
+                left: left.clone(),
+                right: Box::new(right),
+            }));
+            exps
+        }
+        Exp::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            let mut exps = vec![
+                zero,
+                then.as_ref().clone(),
+                otherwise.as_ref().clone(),
+                cond.as_ref().clone(),
+            ];
+            exps.extend(folded);
+            let with = |cond: &Exp, then: &Exp, otherwise: &Exp| Exp::If {
+                cond: Box::new(cond.clone()),
+                then: Box::new(then.clone()),
+                otherwise: Box::new(otherwise.clone()),
+            };
+            exps.extend(candidates(cond).iter().map(|cond| with(cond, then, otherwise)));
+            exps.extend(candidates(then).iter().map(|then| with(cond, then, otherwise)));
+            exps.extend(candidates(otherwise).iter().map(|otherwise| with(cond, then, otherwise)));
+            exps
+        }
+        Exp::Call { args, .. } | Exp::Builtin { args, .. } | Exp::Apply { args, .. } => {
+            let mut exps = vec![zero];
+            exps.extend(args.iter().cloned());
+            exps.extend(folded);
+            for (i, arg) in args.iter().enumerate() {
+                let rt = arg.result_type();
+                for simpler in candidates(arg)
+                    .into_iter()
+                    .filter(|arg| arg.result_type() == rt)
+                {
+                    let mut args = args.clone();
+                    args[i] = simpler;
+                    exps.push(with_args(exp, args));
+                }
+            }
+            exps
+        }
+    }
+}
+
+/// The call `exp` with other arguments.
+fn with_args(exp: &Exp, args: Vec<Exp>) -> Exp {
+    match exp {
+        Exp::Call { fun, .. } => Exp::Call {
+            fun: fun.clone(),
+            args,
+        },
+        Exp::Builtin { fun, .. } => Exp::Builtin { fun: *fun, args },
+        Exp::Apply {
+            name, rt, instance, ..
+        } => Exp::Apply {
+            name: name.clone(),
+            args,
+            rt: *rt,
+            instance: *instance,
+        },
+        _ => unreachable!("not a call: {}", exp),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+    use std::thread;
+
+    use crate::asm::exec::{AsmCode, Rt};
+    use crate::asm::host::{HostFn, HostFns};
+    use crate::asm::x86_64::X8664;
+    use crate::asm::Fun;
+    use crate::fuzz::gen::Gen;
+    use crate::fuzz::{run, shrink};
+    use crate::interpreter::{Env, EvalError, Execution};
+    use crate::parser::ast::{Builtin, Exp, Val};
+
+    extern "C" fn sub(a: i64, b: i64) -> i64 {
+        a.wrapping_sub(b)
+    }
+
+    extern "C" fn scale(a: i64, b: f64, c: i64) -> f64 {
+        a as f64 * b + c as f64
+    }
+
+    extern "C" fn answer() -> i64 {
+        42
+    }
+
+    fn hosts() -> Vec<HostFn> {
+        let mut fns = HostFns::new();
+        fns.bind("sub", sub as extern "C" fn(i64, i64) -> i64);
+        fns.bind("scale", scale as extern "C" fn(i64, f64, i64) -> f64);
+        fns.bind("answer", answer as extern "C" fn() -> i64);
+        ["sub", "scale", "answer"]
+            .iter()
+            .map(|name| fns.get(name).unwrap().clone())
+            .collect()
+    }
+
+    fn depth(exp: &Exp) -> usize {
+        match exp {
+            Exp::Val(_) | Exp::Var(_) | Exp::Error(_) => 0,
+            Exp::Unary { exp, .. } => 1 + depth(exp),
+            Exp::Exp { left, right, .. } => 1 + depth(left).max(depth(right)),
+            Exp::If {
+                cond,
+                then,
+                otherwise,
+            } => 1 + depth(cond).max(depth(then)).max(depth(otherwise)),
+            Exp::Call { args, .. } | Exp::Builtin { args, .. } | Exp::Apply { args, .. } => {
+                1 + args.iter().map(depth).max().unwrap_or(0)
+            }
+        }
+    }
+
+    fn size(exp: &Exp) -> usize {
+        match exp {
+            Exp::Val(_) | Exp::Var(_) | Exp::Error(_) => 1,
+            Exp::Unary { exp, .. } => 1 + size(exp),
+            Exp::Exp { left, right, .. } => 1 + size(left) + size(right),
+            Exp::If {
+                cond,
+                then,
+                otherwise,
+            } => 1 + size(cond) + size(then) + size(otherwise),
+            Exp::Call { args, .. } | Exp::Builtin { args, .. } | Exp::Apply { args, .. } => {
+                1 + args.iter().map(size).sum::<usize>()
+            }
+        }
+    }
+
+    #[test]
+    fn test_differential() {
+        for seed in 0..8 {
+            let mut gen = Gen::new(seed).hosts(hosts());
+            if let Some(divergence) = run::<X8664>(&mut gen, 300).unwrap() {
+                panic!("seed {}: {}", seed, divergence);
+            }
+        }
+
+        let params = [("a", Rt::Int), ("b", Rt::Float), ("c", Rt::Int)];
+        for seed in 0..4 {
+            let mut gen = Gen::new(seed).hosts(hosts()).params(&params);
+            if let Some(divergence) = run::<X8664>(&mut gen, 300).unwrap() {
+                panic!("seed {}: {}", seed, divergence);
+            }
+        }
+
+        // The interpreter recurses as deep as the calls do.
+        let handle = thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || {
+                for seed in 0..4 {
+                    let mut gen = Gen::new(seed).depth(4).params(&params).defs(3);
+                    if let Some(divergence) = run::<X8664>(&mut gen, 200).unwrap() {
+                        panic!("seed {}: {}", seed, divergence);
+                    }
+                }
+            })
+            .unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_gen() {
+        let exps = |seed| {
+            let mut gen = Gen::new(seed).depth(4).hosts(hosts());
+            (0..200).map(|_| gen.exp()).collect::<Vec<_>>()
+        };
+        // NaN operands are never equal, their renderings are.
+        let render = |seed| format!("{:?}", exps(seed));
+        assert!(render(1) == render(1));
+        assert!(render(1) != render(2));
+
+        let mut seen = HashSet::new();
+        for exp in exps(3) {
+            assert!(depth(&exp) <= 4, "{}", exp);
+            seen.insert(format!("{:?}", exp.result_type()));
+            match &exp {
+                Exp::Call { fun, args } => {
+                    seen.insert(fun.name().to_owned());
+                    let types: Vec<Rt> = args.iter().map(|arg| arg.result_type()).collect();
+                    assert_eq!(types, fun.args(), "{}", exp);
+                }
+                Exp::Builtin { fun, .. } => {
+                    seen.insert(fun.name().to_owned());
+                }
+                _ => {}
+            }
+            // Host calls are well-typed, so everything compiles.
+            assert!(Fun::<X8664>::try_from(exp).is_ok());
+        }
+        assert!(seen.contains("Int") && seen.contains("Float"));
+        assert!(seen.contains("sub") && seen.contains("scale"));
+        assert!(seen.iter().any(|name| Builtin::from_name(name).is_some()));
+
+        // Variables are read at their slots, with their types.
+        let params = [("a", Rt::Int), ("b", Rt::Float)];
+        let mut gen = Gen::new(5).depth(3).params(&params);
+        let exps: Vec<Exp> = (0..100).map(|_| gen.exp()).collect();
+        assert!(exps.iter().any(|exp| exp.to_string().contains('a')));
+        assert!(exps.iter().any(|exp| exp.to_string().contains('b')));
+        for exp in exps {
+            let bound = exp.clone().bind(&params).unwrap();
+            assert_eq!(format!("{:?}", bound), format!("{:?}", exp));
+        }
+        assert_eq!(gen.args().len(), 2);
+
+        // Programs call their definitions, which recurse down to a base case or to the depth
+        // limit, and always bind.
+        let seen = thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || programs(Gen::new(6).depth(4).params(&params).defs(3)))
+            .unwrap()
+            .join()
+            .unwrap();
+        for name in ["recursion", "if", "call", "value", "depth"] {
+            assert!(seen.contains(name), "{}", name);
+        }
+    }
+
+    /// Kinds of programs from `gen`, checking they bind and compile.
+    fn programs(mut gen: Gen) -> HashSet<&'static str> {
+        let params = [("a", Rt::Int), ("b", Rt::Float)];
+        let mut seen = HashSet::new();
+        for _ in 0..100 {
+            let program = gen.program();
+            assert_eq!(program.defs.len(), 3);
+            let text = program.to_string();
+            for (name, found) in [("recursion", "f0((x0 - 1)"), ("if", "if "), ("call", "f2(")] {
+                if text.contains(found) {
+                    seen.insert(name);
+                }
+            }
+            let args = gen.args();
+            let env = Env::new().with("a", args[0]).with("b", args[1]);
+            match program.bind(&params).unwrap().try_exec(&env) {
+                Ok(_) => seen.insert("value"),
+                Err(EvalError::StackOverflow) => seen.insert("depth"),
+                Err(_) => false,
+            };
+            assert!(Fun::<X8664>::with_program(&program, &params, X8664::default()).is_ok());
+        }
+        seen
+    }
+
+    #[test]
+    fn test_shrink() {
+        let mut gen = Gen::new(4).hosts(hosts());
+        let fails = |exp: &Exp| exp.try_exec(&Env::new()) == Err(EvalError::DivisionByZero);
+        let mut shrunk = 0;
+        for _ in 0..500 {
+            let exp = gen.exp();
+            if !fails(&exp) {
+                continue;
+            }
+            let small = shrink(exp.clone(), fails);
+            assert!(fails(&small), "{}", small);
+            // The division and its two operands.
+            assert_eq!(size(&small), 3, "{} from {}", small, exp);
+            shrunk += 1;
+        }
+        assert!(shrunk > 10);
+
+        let big = |exp: &Exp| matches!(exp.try_exec(&Env::new()), Ok(Val::Float(val)) if val > 100.0);
+        let exp = (0..100)
+            .map(|_| gen.typed(Rt::Float, 6))
+            .find(|exp| size(exp) > 3 && big(exp))
+            .unwrap();
+        let small = shrink(exp, big);
+        assert!(big(&small));
+        assert!(size(&small) <= 3, "{}", small);
+    }
+}
@@ -1,53 +1,213 @@
-use crate::interpreter::Execution;
-use crate::parser::ast::{Exp, Op, Val};
+use std::cmp::Ordering;
+
+use crate::asm::exec::{AsmCode, Rt};
+use crate::interpreter::{Env, EvalError, Execution, MAX_DEPTH};
+use crate::parser::ast::{Exp, Op, UnOp, Val};
+use crate::parser::program::Bound;
 
 impl Execution for Val {
-    fn exec(&self) -> Val {
-        *self
+    fn try_exec(&self, _env: &Env) -> Result<Val, EvalError> {
+        Ok(*self)
     }
 }
 
 impl Execution for Exp {
-    fn exec(&self) -> Val {
-        match self {
+    fn try_exec(&self, env: &Env) -> Result<Val, EvalError> {
+        Ok(match self {
             Exp::Val(val) => *val,
+            Exp::Var(var) => env
+                .get(&var.name)
+                .ok_or_else(|| EvalError::UnboundVariable(var.name.clone()))?,
+            Exp::Unary { op, exp } => match (op, exp.try_exec(env)?) {
+                (UnOp::Plus, val) => val,
+                (UnOp::Neg, Val::Int(val)) => Val::Int(val.wrapping_neg()),
+                (UnOp::Neg, Val::Float(val)) => Val::Float(-val),
+            },
             Exp::Exp { op, left, right } => {
-                let (left, right) = unify_types(left.exec(), right.exec());
+                let (left, right) = unify_types(left.try_exec(env)?, right.try_exec(env)?);
                 match op {
                     Op::Add => match (left, right) {
                         (Val::Float(l), Val::Float(r)) => Val::Float(l + r),
-                        (Val::Int(l), Val::Int(r)) => Val::Int(l + r),
+                        (Val::Int(l), Val::Int(r)) => Val::Int(l.wrapping_add(r)),
                         _ => panic!("invalid invariant"),
                     },
                     Op::Sub => match (left, right) {
                         (Val::Float(l), Val::Float(r)) => Val::Float(l - r),
-                        (Val::Int(l), Val::Int(r)) => Val::Int(l - r),
+                        (Val::Int(l), Val::Int(r)) => Val::Int(l.wrapping_sub(r)),
                         _ => panic!("invalid invariant"),
                     },
                     Op::Mul => match (left, right) {
                         (Val::Float(l), Val::Float(r)) => Val::Float(l * r),
-                        (Val::Int(l), Val::Int(r)) => Val::Int(l * r),
+                        (Val::Int(l), Val::Int(r)) => Val::Int(l.wrapping_mul(r)),
                         _ => panic!("invalid invariant"),
                     },
                     Op::Mod => match (left, right) {
                         (Val::Float(l), Val::Float(r)) => Val::Float(l % r),
-                        (Val::Int(l), Val::Int(r)) => Val::Int(l % r),
+                        (Val::Int(l), Val::Int(r)) => Val::Int(modi(l, r)?),
                         _ => panic!("invalid invariant"),
                     },
                     Op::Div => match (left, right) {
                         (Val::Float(l), Val::Float(r)) => Val::Float(l / r),
-                        (Val::Int(l), Val::Int(r)) => Val::Int(l / r),
+                        (Val::Int(l), Val::Int(r)) => Val::Int(divi(l, r)?),
                         _ => panic!("invalid invariant"),
                     },
                     Op::Pow => match (left, right) {
                         (Val::Float(l), Val::Float(r)) => Val::Float(l.powf(r)),
-                        (Val::Int(l), Val::Int(r)) => Val::Int(l.pow(r as u32)),
+                        (Val::Int(l), Val::Int(r)) => Val::Int(powi(l, r)?),
                         _ => panic!("invalid invariant"),
                     },
+                    Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::Eq | Op::Ne => {
+                        Val::Int(compare(*op, left, right) as i64)
+                    }
+                }
+            }
+            Exp::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let holds = match cond.try_exec(env)? {
+                    Val::Int(val) => val != 0,
+                    Val::Float(val) => val != 0.0,
+                };
+                let val = if holds { then } else { otherwise }.try_exec(env)?;
+                // The result is a float if the other branch is, as in compiled code.
+                if type_in(self, env) == Rt::Float {
+                    val.into_float()
+                } else {
+                    val
                 }
             }
+            Exp::Apply {
+                name,
+                args,
+                rt,
+                instance,
+            } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.try_exec(env))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let fun = env
+                    .funs
+                    .get(*instance)
+                    .filter(|fun| fun.name == *name)
+                    .ok_or_else(|| EvalError::UnboundFunction(name.clone()))?;
+                if env.depth == MAX_DEPTH {
+                    return Err(EvalError::StackOverflow);
+                }
+                let scope = Env {
+                    vars: fun.params.iter().map(|(param, _)| param.clone()).zip(args).collect(),
+                    funs: env.funs.clone(),
+                    depth: env.depth + 1,
+                };
+                let val = fun.body.try_exec(&scope)?;
+                if *rt == Rt::Float {
+                    val.into_float()
+                } else {
+                    val
+                }
+            }
+            Exp::Call { fun, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.try_exec(env))
+                    .collect::<Result<Vec<_>, _>>()?;
+                fun.invoke(&args)
+            }
+            Exp::Builtin { fun, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.try_exec(env))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let rts: Vec<Rt> = args.iter().map(Val::result_type).collect();
+                fun.host(&rts).invoke(&args)
+            }
+            Exp::Error(loc) => return Err(EvalError::SyntaxError(loc.clone())),
+        })
+    }
+}
+
+impl Execution for Bound {
+    fn try_exec(&self, env: &Env) -> Result<Val, EvalError> {
+        let env = Env {
+            funs: self.funs.clone(),
+            ..env.clone()
+        };
+        self.exp.try_exec(&env)
+    }
+}
+
+/// Type of the value of `exp` with the variables of `env`. Calls have the type their binding
+/// gave them.
+fn type_in(exp: &Exp, env: &Env) -> Rt {
+    let either = |left: &Exp, right: &Exp| type_in(left, env).max(type_in(right, env));
+    match exp {
+        Exp::Var(var) => env.get(&var.name).map_or(var.rt, |val| val.result_type()),
+        Exp::Unary { exp, .. } => type_in(exp, env),
+        Exp::Exp { op, .. } if op.is_comparison() => Rt::Int,
+        Exp::Exp { left, right, .. } => either(left, right),
+        Exp::If {
+            then, otherwise, ..
+        } => either(then, otherwise),
+        Exp::Builtin { fun, args } => {
+            let rts: Vec<Rt> = args.iter().map(|arg| type_in(arg, env)).collect();
+            fun.ret(&rts)
+        }
+        exp => exp.result_type(),
+    }
+}
+
+/// Whether the comparison `cmp` holds for operands of the same type. Only `!=` holds for NaN.
+fn compare(cmp: Op, left: Val, right: Val) -> bool {
+    let ord = match (left, right) {
+        (Val::Float(l), Val::Float(r)) => l.partial_cmp(&r),
+        (Val::Int(l), Val::Int(r)) => Some(l.cmp(&r)),
+        _ => panic!("invalid invariant"),
+    };
+    match cmp {
+        Op::Lt => ord == Some(Ordering::Less),
+        Op::Le => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => ord == Some(Ordering::Greater),
+        Op::Ge => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+        Op::Eq => ord == Some(Ordering::Equal),
+        Op::Ne => ord != Some(Ordering::Equal),
+        op => panic!("{} is not a comparison", op),
+    }
+}
+
+/// Truncating division, failing where the hardware `idiv` would trap.
+pub fn divi(l: i64, r: i64) -> Result<i64, EvalError> {
+    match (l, r) {
+        (_, 0) => Err(EvalError::DivisionByZero),
+        (i64::MIN, -1) => Err(EvalError::Overflow),
+        _ => Ok(l / r),
+    }
+}
+
+/// Remainder of the truncating division, failing exactly where `divi` does.
+pub fn modi(l: i64, r: i64) -> Result<i64, EvalError> {
+    divi(l, r).map(|_| l % r)
+}
+
+/// Exponentiation by squaring with wrapping multiplication. A negative exponent follows the
+/// truncating division, so `base^-n` is `(1 / base)^n` and fails for a zero base.
+pub fn powi(base: i64, exp: i64) -> Result<i64, EvalError> {
+    let (mut base, mut exp) = if exp < 0 {
+        (divi(1, base)?, exp.unsigned_abs())
+    } else {
+        (base, exp as u64)
+    };
+
+    let mut result = 1i64;
+    while exp != 0 {
+        if exp & 1 == 1 {
+            result = result.wrapping_mul(base);
         }
+        base = base.wrapping_mul(base);
+        exp >>= 1;
     }
+    Ok(result)
 }
 
 fn unify_types(left: Val, right: Val) -> (Val, Val) {
@@ -1,22 +1,140 @@
 pub mod exec;
 
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+
 use crate::parser::ast::Val;
+use crate::parser::lexer::Loc;
+use crate::parser::program::Instance;
+
+/// How deeply calls of defined functions can nest, in the interpreter and in compiled code.
+pub const MAX_DEPTH: usize = 1000;
 
 pub trait Execution {
-    fn exec(&self) -> Val;
+    /// Evaluates the expression with the variables of `env`, failing on integer division by
+    /// zero, `i64::MIN / -1`, variables `env` lacks and calls nested deeper than `MAX_DEPTH`.
+    fn try_exec(&self, env: &Env) -> Result<Val, EvalError>;
+
+    /// Evaluates the expression, panicking where `try_exec` fails.
+    fn exec(&self, env: &Env) -> Val {
+        self.try_exec(env).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+/// Values of the variables an expression is evaluated with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Env {
+    vars: BTreeMap<String, Val>,
+    /// Functions of the program being evaluated, see `Bound`.
+    funs: Arc<[Instance]>,
+    /// Calls of those functions the evaluation is nested in.
+    depth: usize,
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env::default()
+    }
+
+    pub fn set(&mut self, name: &str, val: Val) {
+        self.vars.insert(name.to_owned(), val);
+    }
+
+    pub fn with(mut self, name: &str, val: Val) -> Env {
+        self.set(name, val);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<Val> {
+        self.vars.get(name).copied()
+    }
+}
+
+impl Display for Env {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, (name, val)) in self.vars.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} = {}", name, val)?;
+        }
+        Ok(())
+    }
+}
+
+/// An expression without a value. Compiled code reports the arithmetic errors through a status
+/// word, see `EvalError::code`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError {
+    DivisionByZero,
+    Overflow,
+    UnboundVariable(String),
+    /// A call of a function without the program defining it.
+    UnboundFunction(String),
+    /// Calls nested deeper than `MAX_DEPTH`.
+    StackOverflow,
+    /// An error node left by `parse_recovering`.
+    SyntaxError(Loc),
+}
+
+impl EvalError {
+    /// The status word compiled code stores for this error. Zero means success; variables and
+    /// functions are bound before code is compiled, so unbound ones have no code.
+    pub fn code(&self) -> Option<u64> {
+        match self {
+            EvalError::DivisionByZero => Some(1),
+            EvalError::Overflow => Some(2),
+            EvalError::StackOverflow => Some(3),
+            EvalError::UnboundVariable(_)
+            | EvalError::UnboundFunction(_)
+            | EvalError::SyntaxError(_) => None,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Option<EvalError> {
+        match code {
+            1 => Some(EvalError::DivisionByZero),
+            2 => Some(EvalError::Overflow),
+            3 => Some(EvalError::StackOverflow),
+            _ => None,
+        }
+    }
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::DivisionByZero => write!(f, "Division by zero"),
+            EvalError::Overflow => write!(f, "Integer overflow"),
+            EvalError::UnboundVariable(name) => write!(f, "Unbound variable '{}'", name),
+            EvalError::UnboundFunction(name) => write!(f, "Unbound function '{}'", name),
+            EvalError::StackOverflow => {
+                write!(f, "Calls nested deeper than {} levels", MAX_DEPTH)
+            }
+            EvalError::SyntaxError(loc) => write!(f, "Syntax error at {}", loc),
+        }
+    }
 }
 
+impl std::error::Error for EvalError {}
 #[cfg(test)]
 mod test {
-    use crate::interpreter::Execution;
-    use crate::parser::ast::{parse_exp, Val};
+    use std::thread;
+
+    use crate::asm::exec::Rt;
+    use crate::interpreter::{Env, EvalError, Execution, MAX_DEPTH};
+    use crate::parser::ast::{parse_exp, parse_program, Exp, Val};
     use crate::parser::lexer::Lexer;
 
-    fn perform(input: &str, result: Val) {
+    fn parse(input: &str) -> Exp {
         let mut lexer = Lexer::new(input);
         lexer.advance().unwrap();
-        let exp = parse_exp(&mut lexer).unwrap().exp().unwrap();
-        assert_eq!(exp.exec(), result);
+        parse_exp(&mut lexer).unwrap()
+    }
+
+    fn perform(input: &str, result: Val) {
+        assert_eq!(parse(input).exec(&Env::new()), result);
     }
 
     #[test]
@@ -30,5 +148,178 @@ mod test {
         perform(" 2 * (13 + 13) / 2", Val::Int(2 * (13 + 13) / 2));
         perform("(2 + 2) * 10 ^ 2", Val::Int((2 + 2) * 10i64.pow(2)));
         perform("(2 + 2) * 10.0", Val::Float((2 + 2) as f64 * 10.0));
+        perform("2 ^ 0", Val::Int(1));
+        perform("2 ^ -1", Val::Int(0));
+        perform("-1 ^ -3", Val::Int(-1));
+        perform("2 ^ 64", Val::Int(0));
+        perform("2 ^ 3 ^ 2", Val::Int(512));
+        perform("2 ^ 2 ^ -1", Val::Int(1));
+    }
+
+    #[test]
+    fn test_unary() {
+        perform("-2 ^ 2", Val::Int(-4));
+        perform("(-2) ^ 2", Val::Int(4));
+        perform("2 ^ -1 ^ 2", Val::Int(0));
+        perform("-(1 + 2)", Val::Int(-3));
+        perform("--3", Val::Int(3));
+        perform("+5", Val::Int(5));
+        perform("-+-(2 * 1.5)", Val::Float(3.0));
+        perform("2 * -3 + 1", Val::Int(-5));
+        perform("-(-9223372036854775807 - 1)", Val::Int(i64::MIN));
+
+        let env = Env::new().with("x", Val::Int(3)).with("y", Val::Float(-0.0));
+        assert_eq!(parse("-x ^ 2").exec(&env), Val::Int(-9));
+        assert_eq!(parse("(-x) ^ 2").exec(&env), Val::Int(9));
+        match parse("-y").exec(&env) {
+            Val::Float(val) => assert!(val == 0.0 && val.is_sign_positive()),
+            val => panic!("{}", val),
+        }
+    }
+
+    #[test]
+    fn test_builtins() {
+        perform("sqrt(16)", Val::Float(4.0));
+        perform("abs(-3) + abs(2)", Val::Int(5));
+        perform("abs(-2.5)", Val::Float(2.5));
+        perform("min(2, 7) * max(2, 7)", Val::Int(14));
+        perform("min(2, 7.5)", Val::Float(2.0));
+        perform("max(-1, (0,5))", Val::Float(0.5));
+        perform("max(2,5)", Val::Int(5));
+        perform("max(2.5, 3) + 2,5", Val::Float(5.5));
+        perform("2,5 + 1", Val::Float(3.5));
+        perform("ln(exp(0))", Val::Float(0.0));
+        perform("sin(0) + cos(0)", Val::Float(1.0));
+        perform("abs(-9223372036854775807 - 1)", Val::Int(i64::MIN));
+
+        let env = Env::new().with("x", Val::Int(-4)).with("y", Val::Float(9.0));
+        assert_eq!(parse("abs(x) * sqrt(y)").exec(&env), Val::Float(12.0));
+        assert_eq!(parse("max(x, -5)").exec(&env), Val::Int(-4));
+    }
+
+    #[test]
+    fn test_errors() {
+        let env = Env::new();
+        let fail = |input| parse(input).try_exec(&env).unwrap_err();
+        assert_eq!(fail("1 / 0"), EvalError::DivisionByZero);
+        assert_eq!(fail("1 % (2 - 2)"), EvalError::DivisionByZero);
+        assert_eq!(fail("0 ^ -1"), EvalError::DivisionByZero);
+        assert_eq!(fail("(-9223372036854775807 - 1) / -1"), EvalError::Overflow);
+        assert_eq!(fail("(-9223372036854775807 - 1) % -1"), EvalError::Overflow);
+        assert_eq!(fail("2 * (1 / 0) + 0.5"), EvalError::DivisionByZero);
+
+        assert_eq!(fail("x + 1"), EvalError::UnboundVariable("x".to_owned()));
+
+        assert_eq!(parse("1.0 / 0").try_exec(&env), Ok(Val::Float(f64::INFINITY)));
+        assert_eq!(parse("0 ^ 0").try_exec(&env), Ok(Val::Int(1)));
+        assert_eq!(parse("-7 % -1").try_exec(&env), Ok(Val::Int(0)));
+        assert_eq!(EvalError::from_code(0), None);
+        for err in [EvalError::DivisionByZero, EvalError::Overflow, EvalError::StackOverflow] {
+            assert_eq!(EvalError::from_code(err.code().unwrap()), Some(err));
+        }
+        assert_eq!(EvalError::UnboundVariable("x".to_owned()).code(), None);
+    }
+
+    #[test]
+    fn test_conditions() {
+        perform("1 < 2", Val::Int(1));
+        perform("2 <= 1", Val::Int(0));
+        perform("1 == 1.0", Val::Int(1));
+        perform("0.0 / 0 != 0.0 / 0", Val::Int(1));
+        perform("0.0 / 0 >= 0.0 / 0", Val::Int(0));
+        perform("-0.0 == 0", Val::Int(1));
+        perform("1 + 2 > 2 * 1.5", Val::Int(0));
+        perform("if 2 > 1 then 10 else 20", Val::Int(10));
+        perform("if 0 then 10 else 2.5", Val::Float(2.5));
+        // Both branches have the type of the wider one.
+        perform("if 1 then 10 else 2.5", Val::Float(10.0));
+        perform("if 0.0 / 0 then 1 else 2", Val::Int(1));
+        perform("if -0.0 then 1 else 2", Val::Int(2));
+        // Only the taken branch is evaluated.
+        perform("if 1 then 1 else 1 / 0", Val::Int(1));
+        assert_eq!(
+            parse("if 1 / 0 then 1 else 2").try_exec(&Env::new()),
+            Err(EvalError::DivisionByZero)
+        );
+    }
+
+    fn run(input: &str, env: &Env) -> Result<Val, EvalError> {
+        let params: Vec<(&str, Rt)> = ["x", "y"]
+            .iter()
+            .filter_map(|name| match env.get(name)? {
+                Val::Int(_) => Some((*name, Rt::Int)),
+                Val::Float(_) => Some((*name, Rt::Float)),
+            })
+            .collect();
+        parse_program(input)
+            .unwrap()
+            .bind(&params)
+            .unwrap()
+            .try_exec(env)
+    }
+
+    #[test]
+    fn test_program() {
+        let env = Env::new().with("x", Val::Int(10));
+        let fact = "fact(n) = if n <= 1 then 1 else n * fact(n - 1); ";
+        assert_eq!(run(&format!("{}fact(x)", fact), &env), Ok(Val::Int(3_628_800)));
+        assert_eq!(run("sq(x) = x * x; sq(x) + sq(0.5)", &env), Ok(Val::Float(100.25)));
+        assert_eq!(
+            run("fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(x)", &env),
+            Ok(Val::Int(55))
+        );
+        // Parameters shadow the variables of the expression.
+        assert_eq!(run("f(x) = x + 1; f(2) * x", &env), Ok(Val::Int(30)));
+        assert_eq!(
+            run("half(n) = if n < 1 then n else half(n / 2.0); half(x)", &env),
+            Ok(Val::Float(0.625))
+        );
+        assert_eq!(
+            run("inv(n) = 1 / n; inv(x) + inv(x - 10)", &env),
+            Err(EvalError::DivisionByZero)
+        );
+
+        // Without the program the calls have nothing to call.
+        let program = parse_program("f(n) = n; f(1)").unwrap();
+        assert_eq!(
+            program.exp.try_exec(&Env::new()),
+            Err(EvalError::UnboundFunction("f".to_owned()))
+        );
+
+        // The interpreter recurses as deep as the calls do.
+        let handle = thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(|| {
+                let down = "down(n) = if n == 0 then 0 else down(n - 1); ";
+                let depth = |n: usize| {
+                    let env = Env::new().with("x", Val::Int(n as i64));
+                    run(&format!("{}down(x)", down), &env)
+                };
+                (depth(MAX_DEPTH - 1), depth(MAX_DEPTH))
+            })
+            .unwrap();
+        let (deepest, overflow) = handle.join().unwrap();
+        assert_eq!(deepest, Ok(Val::Int(0)));
+        assert_eq!(overflow, Err(EvalError::StackOverflow));
+    }
+
+    #[test]
+    #[should_panic(expected = "Division by zero")]
+    fn test_exec_panics() {
+        parse("7 / 0").exec(&Env::new());
+    }
+
+    #[test]
+    fn test_env() {
+        let env = Env::new().with("x", Val::Int(7)).with("rate", Val::Float(0.5));
+        assert_eq!(parse("x * 2 + 1").exec(&env), Val::Int(15));
+        assert_eq!(parse("x * rate").exec(&env), Val::Float(3.5));
+        assert_eq!(parse("x / (x - 7)").try_exec(&env), Err(EvalError::DivisionByZero));
+        assert_eq!(env.to_string(), "rate = 0.5, x = 7");
+
+        let mut env = env;
+        env.set("x", Val::Float(1.5));
+        assert_eq!(env.get("x"), Some(Val::Float(1.5)));
+        assert_eq!(parse("x * 2").exec(&env), Val::Float(3.0));
     }
 }
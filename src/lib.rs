@@ -1,3 +1,4 @@
 pub mod asm;
+pub mod fuzz;
 pub mod interpreter;
 pub mod parser;
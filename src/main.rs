@@ -1,3 +1,47 @@
+use std::env;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::process;
+
+use anyhow::{anyhow, Error};
+
+use neb::asm::x86_64::executable::executable;
+use neb::interpreter::{Env, Execution};
+use neb::parser::ast::parse_program_recovering;
+use neb::parser::program::Program;
+
+const USAGE: &str = "usage: neb PROGRAM\n       neb -o OUTPUT PROGRAM";
+
+/// Parses `input`, reporting every syntax error rather than only the first.
+fn parse(input: &str) -> Result<Program, Error> {
+    let (program, errors) = parse_program_recovering(input);
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        let rendered: Vec<String> = errors.iter().map(|err| err.render(input)).collect();
+        Err(anyhow!("{}", rendered.join("\n")))
+    }
+}
+
+/// Prints the value of the program, or compiles it into an executable printing it with `-o`.
+fn run(args: &[String]) -> Result<(), Error> {
+    match args {
+        [input] => println!("{}", parse(input)?.bind(&[])?.try_exec(&Env::new())?),
+        [flag, output, input] if flag == "-o" => {
+            fs::write(output, executable(&parse(input)?)?)?;
+            #[cfg(unix)]
+            fs::set_permissions(output, fs::Permissions::from_mode(0o755))?;
+        }
+        _ => return Err(anyhow!("{}", USAGE)),
+    }
+    Ok(())
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 }
@@ -1,32 +1,213 @@
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::ops::Range;
 
 use anyhow::{anyhow, Error};
 
-use crate::parser::lexer::{Lexer, Token};
+use crate::asm::exec::{AsmCode, Rt};
+use crate::asm::host::HostFn;
+use crate::parser::error::ParseError;
+use crate::parser::lexer::{Lexer, Loc, Token};
+use crate::parser::program::{Def, Program};
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub enum Exp {
     Val(Val),
+    Var(Var),
+    Unary {
+        op: UnOp,
+        exp: Box<Exp>,
+    },
     Exp {
         op: Op,
         left: Box<Exp>,
         right: Box<Exp>,
     },
+    Call {
+        fun: HostFn,
+        args: Vec<Exp>,
+    },
+    Builtin {
+        fun: Builtin,
+        args: Vec<Exp>,
+    },
+    /// `if cond then then else otherwise`. A condition holds when it isn't zero; the result is a
+    /// float when either branch is, whichever is taken.
+    If {
+        cond: Box<Exp>,
+        then: Box<Exp>,
+        otherwise: Box<Exp>,
+    },
+    /// A call of a function the program defines. Like a variable's, its result type and the
+    /// index of the instance it calls are set by `Program::bind`; the parser leaves an integer
+    /// and instance 0.
+    Apply {
+        name: String,
+        args: Vec<Exp>,
+        rt: Rt,
+        instance: usize,
+    },
+    /// Stands in for input `parse_recovering` couldn't parse. Such expressions can't be bound,
+    /// so they are never compiled.
+    Error(Loc),
 }
 
 impl Display for Exp {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Exp::Val(val) => val.fmt(f),
+            Exp::Var(var) => write!(f, "{}", var.name),
+            // A signed operand is parenthesised, so `-(-1)` doesn't read as a decrement and
+            // `(-2) ^ 2` doesn't read as `-(2 ^ 2)`.
+            Exp::Unary { op, exp } if exp.is_signed() => write!(f, "{}({})", op, exp),
+            Exp::Unary { op, exp } => write!(f, "{}{}", op, exp),
+            Exp::Exp {
+                op: Op::Pow,
+                left,
+                right,
+            } if left.is_signed() => write!(f, "(({}) ^ {})", left, right),
             Exp::Exp { op, left, right } => {
                 write!(f, "({} {} {})", left, op, right)
             }
+            Exp::Call { fun, args } => write_call(f, fun.name(), args),
+            Exp::Builtin { fun, args } => write_call(f, fun.name(), args),
+            Exp::If {
+                cond,
+                then,
+                otherwise,
+            } => write!(f, "(if {} then {} else {})", cond, then, otherwise),
+            Exp::Apply { name, args, .. } => write_call(f, name, args),
+            Exp::Error(_) => write!(f, "<error>"),
         }
     }
 }
 
+/// Renders `name(arg, ...)`.
+fn write_call(f: &mut Formatter<'_>, name: &str, args: &[Exp]) -> fmt::Result {
+    write!(f, "{}(", name)?;
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        arg.fmt(f)?;
+    }
+    write!(f, ")")
+}
+
+/// A named value supplied when the expression is evaluated.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Var {
+    pub name: String,
+    /// Type of the value and its index among the parameters of a compiled function. Both are
+    /// set by `Exp::bind`; the parser leaves an integer in slot 0.
+    pub rt: Rt,
+    pub slot: usize,
+}
+
+impl Var {
+    pub fn new(name: &str) -> Var {
+        Var {
+            name: name.to_owned(),
+            rt: Rt::Int,
+            slot: 0,
+        }
+    }
+}
+
+impl Exp {
+    /// Whether the rendering starts with a sign.
+    fn is_signed(&self) -> bool {
+        match self {
+            Exp::Val(Val::Int(val)) => *val < 0,
+            Exp::Val(Val::Float(val)) => val.is_sign_negative() && !val.is_nan(),
+            Exp::Unary { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Resolves every variable to one of `params`, which are the parameters of a compiled
+    /// function in argument order.
+    pub fn bind(self, params: &[(&str, Rt)]) -> Result<Exp, Error> {
+        self.bind_with(params, &mut |name, _| {
+            Err(anyhow!("Function '{}' is only defined in its program", name))
+        })
+    }
+
+    /// Same as `bind`, resolving calls of defined functions with `resolve`, which gets the name
+    /// and argument types and returns the instance and its result type.
+    pub(crate) fn bind_with(self, params: &[(&str, Rt)], resolve: &mut Resolve) -> Result<Exp, Error> {
+        for (i, (name, _)) in params.iter().enumerate() {
+            if params[..i].iter().any(|(other, _)| other == name) {
+                return Err(anyhow!("Duplicate parameter '{}'", name));
+            }
+        }
+        self.bind_vars(params, resolve)
+    }
+
+    fn bind_vars(self, params: &[(&str, Rt)], resolve: &mut Resolve) -> Result<Exp, Error> {
+        Ok(match self {
+            Exp::Val(val) => Exp::Val(val),
+            Exp::Var(var) => {
+                let slot = params
+                    .iter()
+                    .position(|(name, _)| *name == var.name)
+                    .ok_or_else(|| anyhow!("Unknown variable '{}'", var.name))?;
+                Exp::Var(Var {
+                    rt: params[slot].1,
+                    slot,
+                    ..var
+                })
+            }
+            Exp::Unary { op, exp } => Exp::Unary {
+                op,
+                exp: Box::new(exp.bind_vars(params, resolve)?),
+            },
+            Exp::Exp { op, left, right } => Exp::Exp {
+                op,
+                left: Box::new(left.bind_vars(params, resolve)?),
+                right: Box::new(right.bind_vars(params, resolve)?),
+            },
+            Exp::Call { fun, args } => Exp::Call {
+                fun,
+                args: bind_args(args, params, resolve)?,
+            },
+            Exp::Builtin { fun, args } => Exp::Builtin {
+                fun,
+                args: bind_args(args, params, resolve)?,
+            },
+            Exp::If {
+                cond,
+                then,
+                otherwise,
+            } => Exp::If {
+                cond: Box::new(cond.bind_vars(params, resolve)?),
+                then: Box::new(then.bind_vars(params, resolve)?),
+                otherwise: Box::new(otherwise.bind_vars(params, resolve)?),
+            },
+            Exp::Apply { name, args, .. } => {
+                let args = bind_args(args, params, resolve)?;
+                let rts: Vec<Rt> = args.iter().map(Exp::result_type).collect();
+                let (instance, rt) = resolve(&name, &rts)?;
+                Exp::Apply {
+                    name,
+                    args,
+                    rt,
+                    instance,
+                }
+            }
+            Exp::Error(loc) => return Err(anyhow!("Syntax error at {}", loc)),
+        })
+    }
+}
+
+/// Resolves a call of a defined function, see `Exp::bind_with`.
+pub(crate) type Resolve<'a> = dyn FnMut(&str, &[Rt]) -> Result<(usize, Rt), Error> + 'a;
+
+fn bind_args(args: Vec<Exp>, params: &[(&str, Rt)], resolve: &mut Resolve) -> Result<Vec<Exp>, Error> {
+    args.into_iter()
+        .map(|arg| arg.bind_vars(params, resolve))
+        .collect()
+}
+
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub enum Op {
     // +
@@ -41,6 +222,18 @@ pub enum Op {
     Div,
     // ^
     Pow,
+    // <
+    Lt,
+    // <=
+    Le,
+    // >
+    Gt,
+    // >=
+    Ge,
+    // ==
+    Eq,
+    // !=
+    Ne,
 }
 
 impl Display for Op {
@@ -49,28 +242,127 @@ impl Display for Op {
             f,
             "{}",
             match self {
-                Op::Add => '+',
-                Op::Sub => '-',
-                Op::Mul => '*',
-                Op::Mod => '%',
-                Op::Div => '/',
-                Op::Pow => '^',
+                Op::Add => "+",
+                Op::Sub => "-",
+                Op::Mul => "*",
+                Op::Mod => "%",
+                Op::Div => "/",
+                Op::Pow => "^",
+                Op::Lt => "<",
+                Op::Le => "<=",
+                Op::Gt => ">",
+                Op::Ge => ">=",
+                Op::Eq => "==",
+                Op::Ne => "!=",
             }
         )
     }
 }
 
 impl Op {
-    pub fn order(&self) -> u8 {
+    /// How tightly the operator holds its left and right operands. The higher side wins, so
+    /// `^` is right-associative and the others are left-associative. Comparisons bind loosest.
+    pub fn binding_power(&self) -> (u8, u8) {
         match self {
-            Op::Add => 3,
-            Op::Sub => 3,
-            Op::Mul => 2,
-            Op::Mod => 2,
-            Op::Div => 2,
-            Op::Pow => 1,
+            Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::Eq | Op::Ne => (1, 2),
+            Op::Add => (3, 4),
+            Op::Sub => (3, 4),
+            Op::Mul => (5, 6),
+            Op::Mod => (5, 6),
+            Op::Div => (5, 6),
+            Op::Pow => (10, 9),
         }
     }
+
+    /// Whether the operator compares its operands, giving 1 when the comparison holds and 0
+    /// otherwise. Comparisons involving NaN only hold for `!=`.
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::Eq | Op::Ne)
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum UnOp {
+    // +
+    Plus,
+    // -
+    Neg,
+}
+
+impl Display for UnOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                UnOp::Plus => '+',
+                UnOp::Neg => '-',
+            }
+        )
+    }
+}
+
+impl UnOp {
+    /// Unary operators bind tighter than `*` and looser than `^`, so `-2 ^ 2` is `-(2 ^ 2)`
+    /// and `2 ^ -1 ^ 2` is `2 ^ -(1 ^ 2)`.
+    pub const BINDING_POWER: u8 = 7;
+}
+
+/// Functions every expression can call by name. `abs`, `min` and `max` keep integers integral;
+/// the others compute on floats.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Builtin {
+    Sqrt,
+    Abs,
+    Sin,
+    Cos,
+    Exp,
+    Ln,
+    Min,
+    Max,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 8] = [
+        Builtin::Sqrt,
+        Builtin::Abs,
+        Builtin::Sin,
+        Builtin::Cos,
+        Builtin::Exp,
+        Builtin::Ln,
+        Builtin::Min,
+        Builtin::Max,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        Builtin::ALL.iter().copied().find(|fun| fun.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Sqrt => "sqrt",
+            Builtin::Abs => "abs",
+            Builtin::Sin => "sin",
+            Builtin::Cos => "cos",
+            Builtin::Exp => "exp",
+            Builtin::Ln => "ln",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Builtin::Min | Builtin::Max => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl Display for Builtin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
@@ -106,270 +398,478 @@ impl Display for Val {
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
-pub enum Sequence {
-    Exp(Exp),
-    Op(Op),
-    Operand(Val),
+/// Tokens an operand can start with.
+const OPERAND: [Token; 7] = [
+    Token::IntNumber,
+    Token::FloatNumber,
+    Token::Ident,
+    Token::LParen,
+    Token::Plus,
+    Token::Minus,
+    Token::If,
+];
+
+/// Tokens of binary operators.
+const OPERATORS: [Token; 12] = [
+    Token::Plus,
+    Token::Minus,
+    Token::Star,
+    Token::Slash,
+    Token::Caret,
+    Token::Percent,
+    Token::Less,
+    Token::LessEq,
+    Token::Greater,
+    Token::GreaterEq,
+    Token::EqEq,
+    Token::NotEq,
+];
+
+/// Tokens that end an operand where they belong, and are skipped anywhere else.
+const SEPARATORS: [Token; 6] = [
+    Token::RParen,
+    Token::Comma,
+    Token::Then,
+    Token::Else,
+    Token::Assign,
+    Token::Semicolon,
+];
+
+/// Parses the whole input into an expression, starting at the current token.
+pub fn parse_exp(lexer: &mut Lexer) -> Result<Exp, ParseError> {
+    Parser::new(lexer, None).parse_operand(0, &[Token::EOF])
 }
 
-impl Display for Sequence {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            Sequence::Exp(exp) => exp.fmt(f),
-            Sequence::Op(op) => op.fmt(f),
-            Sequence::Operand(op) => op.fmt(f),
+/// Parses `input` without stopping at the first error. Every error is reported, in order, and
+/// the returned expression holds an `Exp::Error` node wherever an operand couldn't be parsed.
+/// Parsing resynchronises at the next operator or parenthesis.
+pub fn parse_recovering(input: &str) -> (Exp, Vec<ParseError>) {
+    let mut lexer = Lexer::new(input);
+    let mut parser = Parser::new(&mut lexer, Some(vec![]));
+    let exp = parser
+        .advance()
+        .and_then(|_| parser.parse_operand(0, &[Token::EOF]))
+        .expect("recovering parser failed");
+    (exp, parser.errors.unwrap_or_default())
+}
+
+/// Parses `input` as definitions, each followed by `;`, and the expression using them, as in
+/// `sq(x) = x * x; sq(3)`.
+pub fn parse_program(input: &str) -> Result<Program, ParseError> {
+    let mut lexer = Lexer::new(input);
+    let mut parser = Parser::new(&mut lexer, None);
+    parser.advance()?;
+    parser.parse_program()
+}
+
+/// Same as `parse_recovering` for a program. A definition whose head can't be parsed is
+/// skipped up to the next `;`.
+pub fn parse_program_recovering(input: &str) -> (Program, Vec<ParseError>) {
+    let mut lexer = Lexer::new(input);
+    let mut parser = Parser::new(&mut lexer, Some(vec![]));
+    let program = parser
+        .advance()
+        .and_then(|_| parser.parse_program())
+        .expect("recovering parser failed");
+    (program, parser.errors.unwrap_or_default())
+}
+
+struct Parser<'a, 'input> {
+    lexer: &'a mut Lexer<'input>,
+    /// Errors seen so far when recovering; `None` stops at the first one.
+    errors: Option<Vec<ParseError>>,
+    /// Names and arities of the functions defined so far.
+    funs: Vec<(String, usize)>,
+    /// Parameters of the definition being parsed, the only variables its body can read.
+    scope: Option<Vec<String>>,
+}
+
+impl<'a, 'input> Parser<'a, 'input> {
+    fn new(lexer: &'a mut Lexer<'input>, errors: Option<Vec<ParseError>>) -> Self {
+        Parser {
+            lexer,
+            errors,
+            funs: vec![],
+            scope: None,
         }
     }
-}
 
-impl Sequence {
-    pub fn op(&self) -> Option<Op> {
-        match self {
-            Sequence::Op(op) => Some(*op),
-            _ => None,
+    /// Fails with `err`, or records it when recovering.
+    fn report(&mut self, err: ParseError) -> Result<(), ParseError> {
+        match &mut self.errors {
+            Some(errors) => {
+                errors.push(err);
+                Ok(())
+            }
+            None => Err(err),
         }
     }
 
-    pub fn val(&self) -> Option<Val> {
-        match self {
-            Sequence::Operand(val) => Some(*val),
-            _ => None,
+    /// Moves to the next token, skipping invalid characters when recovering.
+    fn advance(&mut self) -> Result<(), ParseError> {
+        while let Err(err) = self.lexer.advance() {
+            self.report(err)?;
         }
+        Ok(())
     }
 
-    pub fn exp(self) -> Option<Exp> {
-        match self {
-            Sequence::Exp(exp) => Some(exp),
-            Sequence::Op(_) => None,
-            Sequence::Operand(val) => Some(Exp::Val(val)),
+    /// Reports the current token, which isn't one of `expected`, and returns the error node that
+    /// takes its place. The token isn't consumed.
+    fn unexpected(&mut self, expected: &[Token]) -> Result<Exp, ParseError> {
+        let loc = self.lexer.loc();
+        self.report(ParseError::Unexpected {
+            loc: loc.clone(),
+            found: self.lexer.token(),
+            expected: expected.iter().copied().collect(),
+        })?;
+        Ok(Exp::Error(loc))
+    }
+
+    /// Consumes `token`, which ends the operand before it at one of `ends`: the `)` closing a
+    /// group or an argument list, or the `then` and `else` of a condition. Recovery carries on
+    /// as if it was there, which it may not be at the end of input.
+    fn expect(&mut self, token: Token, ends: &[Token]) -> Result<(), ParseError> {
+        if self.lexer.token() == token {
+            self.advance()
+        } else {
+            let mut expected = OPERATORS.to_vec();
+            expected.extend_from_slice(ends);
+            self.unexpected(&expected).map(|_| ())
         }
     }
-}
 
-pub fn parse_exp(lexer: &mut Lexer) -> Result<Sequence, Error> {
-    let mut last: Option<Token> = None;
-    let mut seq = vec![];
-    loop {
-        match lexer.token() {
-            Token::LParen => {
-                if let Some(last) = &last {
-                    if !last.is_sign() {
-                        return Err(anyhow!("Unexpected '(' token. Position: {}", lexer.loc()));
-                    }
-                }
+    /// Definitions as long as the statement ahead is one, then the expression.
+    fn parse_program(&mut self) -> Result<Program, ParseError> {
+        let mut defs = vec![];
+        while self.at_definition() {
+            if let Some(def) = self.parse_def()? {
+                defs.push(def);
+            }
+        }
+        let exp = self.parse_operand(0, &[Token::EOF])?;
+        Ok(Program { defs, exp })
+    }
 
-                lexer.advance()?;
-                seq.push(Some(parse_exp(lexer)?));
-                if lexer.token() != Token::RParen {
-                    return Err(anyhow!(
-                        "Unexpected end of input. Expected ')' token. Position: {}",
-                        lexer.loc()
-                    ));
+    /// Whether a `=` comes before the next `;`, which makes the statement a definition.
+    fn at_definition(&self) -> bool {
+        let mut lexer = self.lexer.clone();
+        loop {
+            match lexer.token() {
+                Token::Assign => return true,
+                Token::Semicolon | Token::EOF => return false,
+                _ => {
+                    // Invalid characters are reported when the statement is parsed.
+                    let _ = lexer.advance();
                 }
             }
-            Token::EOF => {
-                if let Some(last) = &last {
-                    if !(last.is_number() || *last == Token::EOF || *last == Token::RParen) {
-                        return Err(anyhow!(
-                            "Unexpected end of input. Position: {}",
-                            lexer.loc()
-                        ));
-                    }
+        }
+    }
+
+    /// Parses `name(params) = body;`. The function can call itself, so it's known before its
+    /// body is parsed. Returns `None` when recovering from a head that isn't a definition or
+    /// from a redefinition.
+    fn parse_def(&mut self) -> Result<Option<Def>, ParseError> {
+        let loc = self.lexer.loc();
+        let name = self.lexer.content().to_owned();
+        if self.lexer.token() != Token::Ident {
+            return self.skip_statement(&[Token::Ident]);
+        }
+        self.advance()?;
+        if self.lexer.token() != Token::LParen {
+            return self.skip_statement(&[Token::LParen]);
+        }
+        self.advance()?;
+
+        let mut params: Vec<String> = vec![];
+        if self.lexer.token() != Token::RParen {
+            loop {
+                if self.lexer.token() != Token::Ident {
+                    return self.skip_statement(&[Token::Ident]);
                 }
-                break;
+                let param = self.lexer.content().to_owned();
+                if params.contains(&param) {
+                    self.report(ParseError::Redefined {
+                        loc: self.lexer.loc(),
+                        name: param.clone(),
+                    })?;
+                }
+                params.push(param);
+                self.advance()?;
+                if self.lexer.token() != Token::Comma {
+                    break;
+                }
+                self.advance()?;
             }
-            Token::RParen => {
+        }
+        if self.lexer.token() != Token::RParen {
+            return self.skip_statement(&[Token::Comma, Token::RParen]);
+        }
+        self.advance()?;
+        if self.lexer.token() != Token::Assign {
+            return self.skip_statement(&[Token::Assign]);
+        }
+        self.advance()?;
+
+        let defined = Builtin::from_name(&name).is_some()
+            || self.funs.iter().any(|(other, _)| *other == name);
+        if defined {
+            self.report(ParseError::Redefined {
+                loc,
+                name: name.clone(),
+            })?;
+        } else {
+            self.funs.push((name.clone(), params.len()));
+        }
+        self.scope = Some(params);
+        let body = self.parse_operand(0, &[Token::Semicolon]);
+        let params = self.scope.take().unwrap_or_default();
+        let body = body?;
+        self.expect(Token::Semicolon, &[Token::Semicolon])?;
+        // A redefinition is checked but dropped, calls go to the first definition.
+        Ok(Some(Def { name, params, body }).filter(|_| !defined))
+    }
+
+    /// Reports the current token, which isn't one of `expected`, and moves past the next `;`.
+    fn skip_statement(&mut self, expected: &[Token]) -> Result<Option<Def>, ParseError> {
+        self.unexpected(expected)?;
+        while self.lexer.token() != Token::EOF {
+            let token = self.lexer.token();
+            self.advance()?;
+            if token == Token::Semicolon {
                 break;
             }
+        }
+        Ok(None)
+    }
+
+    /// Precedence climbing: parses an operand followed by every operator that binds at least as
+    /// tightly as `min`. The operand ends at one of `ends`: `)` inside parentheses, `,` or `)`
+    /// in an argument list, `then` or `else` in a condition and `;` after a definition. Each
+    /// token is looked at once, so parsing is linear in the input.
+    fn parse_operand(&mut self, min: u8, ends: &[Token]) -> Result<Exp, ParseError> {
+        let mut exp = match self.lexer.token() {
             Token::IntNumber | Token::FloatNumber => {
-                if let Some(last) = &last {
-                    if !last.is_sign() {
-                        return Err(anyhow!(
-                            "Unexpected number '{}' token. Position: {}",
-                            lexer.content(),
-                            lexer.loc()
-                        ));
+                let exp = match parse_number(self.lexer) {
+                    Ok(val) => Exp::Val(val),
+                    Err(err) => {
+                        let loc = err.loc().clone();
+                        self.report(err)?;
+                        Exp::Error(loc)
                     }
-                }
-
-                seq.push(Some(Sequence::Operand(parse_number(false, lexer)?)));
+                };
+                self.advance()?;
+                exp
             }
-            Token::Plus => {
-                if let Some(last) = &last {
-                    if last.is_sign() {
-                        return Err(anyhow!("Unexpected '+' token. Position: {}", lexer.loc()));
-                    }
-                }
-                seq.push(Some(Sequence::Op(Op::Add)));
-            }
-            Token::Minus => {
-                if let Some(last) = &last {
-                    if last.is_sign() {
-                        lexer.advance()?;
-                        seq.push(Some(Sequence::Operand(parse_number(true, lexer)?)));
-                    } else {
-                        seq.push(Some(Sequence::Op(Op::Sub)));
-                    }
+            Token::Ident => {
+                let name = self.lexer.content().to_owned();
+                let loc = self.lexer.loc();
+                self.advance()?;
+                if self.lexer.token() == Token::LParen {
+                    self.parse_call(&name, loc)?
+                } else if self.scope.as_ref().is_some_and(|scope| !scope.contains(&name)) {
+                    self.report(ParseError::UnknownVariable { loc: loc.clone(), name })?;
+                    Exp::Error(loc)
                 } else {
-                    lexer.advance()?;
-                    seq.push(Some(Sequence::Operand(parse_number(true, lexer)?)));
+                    Exp::Var(Var::new(&name))
                 }
             }
-            Token::Star => {
-                if let Some(last) = &last {
-                    if last.is_sign() {
-                        return Err(anyhow!("Unexpected '*' token. Position: {}", lexer.loc()));
-                    }
-                }
-                seq.push(Some(Sequence::Op(Op::Mul)));
+            Token::LParen => {
+                self.advance()?;
+                let exp = self.parse_operand(0, &[Token::RParen])?;
+                self.expect(Token::RParen, &[Token::RParen])?;
+                exp
             }
-            Token::Slash => {
-                if let Some(last) = &last {
-                    if last.is_sign() {
-                        return Err(anyhow!("Unexpected '/' token. Position: {}", lexer.loc()));
-                    }
-                }
-                seq.push(Some(Sequence::Op(Op::Div)));
+            Token::Plus | Token::Minus => {
+                let op = if self.lexer.token() == Token::Plus {
+                    UnOp::Plus
+                } else {
+                    UnOp::Neg
+                };
+                self.advance()?;
+                make_unary(op, self.parse_operand(UnOp::BINDING_POWER, ends)?)
             }
-            Token::Caret => {
-                if let Some(last) = &last {
-                    if last.is_sign() {
-                        return Err(anyhow!("Unexpected '^' token. Position: {}", lexer.loc()));
-                    }
+            // The else branch takes every operator after it, like a unary operator that binds
+            // looser than all of them.
+            Token::If => {
+                self.advance()?;
+                let mut inner = ends.to_vec();
+                inner.push(Token::Then);
+                let cond = self.parse_operand(0, &inner)?;
+                self.expect(Token::Then, &[Token::Then])?;
+                *inner.last_mut().unwrap() = Token::Else;
+                let then = self.parse_operand(0, &inner)?;
+                self.expect(Token::Else, &[Token::Else])?;
+                let otherwise = self.parse_operand(0, ends)?;
+                Exp::If {
+                    cond: Box::new(cond),
+                    then: Box::new(then),
+                    otherwise: Box::new(otherwise),
                 }
-                seq.push(Some(Sequence::Op(Op::Pow)));
             }
-            Token::Percent => {
-                if let Some(last) = &last {
-                    if last.is_sign() {
-                        return Err(anyhow!("Unexpected '%' token. Position: {}", lexer.loc()));
-                    }
+            // A ')' that closes nothing, a ',' outside an argument list and the like are skipped.
+            token if SEPARATORS.contains(&token) && !ends.contains(&token) => {
+                self.unexpected(&OPERAND)?;
+                self.advance()?;
+                return self.parse_operand(min, ends);
+            }
+            // An operator is left for the loop below, which applies it to the error node.
+            _ => self.unexpected(&OPERAND)?,
+        };
+
+        let mut expected = OPERATORS.to_vec();
+        expected.extend_from_slice(ends);
+        loop {
+            let op = match self.lexer.token() {
+                Token::Plus => Op::Add,
+                Token::Minus => Op::Sub,
+                Token::Star => Op::Mul,
+                Token::Slash => Op::Div,
+                Token::Percent => Op::Mod,
+                Token::Caret => Op::Pow,
+                Token::Less => Op::Lt,
+                Token::LessEq => Op::Le,
+                Token::Greater => Op::Gt,
+                Token::GreaterEq => Op::Ge,
+                Token::EqEq => Op::Eq,
+                Token::NotEq => Op::Ne,
+                token if ends.contains(&token) => break,
+                // A missing ')', reported where the '(' is parsed.
+                Token::EOF => break,
+                token if SEPARATORS.contains(&token) => {
+                    self.unexpected(&expected)?;
+                    self.advance()?;
+                    continue;
                 }
-                seq.push(Some(Sequence::Op(Op::Mod)));
+                // A missing operator: the operand that follows is parsed for its errors and
+                // dropped.
+                _ => {
+                    self.unexpected(&expected)?;
+                    self.parse_operand(UnOp::BINDING_POWER, ends)?;
+                    continue;
+                }
+            };
+            let (left, right) = op.binding_power();
+            if left < min {
+                break;
             }
+            self.advance()?;
+            exp = Exp::Exp {
+                op,
+                left: Box::new(exp),
+                right: Box::new(self.parse_operand(right, ends)?),
+            };
         }
-
-        last = Some(lexer.token());
-        lexer.advance()?;
-    }
-
-    if seq.is_empty() {
-        return Err(anyhow!("Empty expression"));
+        Ok(exp)
     }
 
-    if seq.len() == 1 {
-        Ok(seq.remove(0).ok_or_else(|| anyhow!("Empty expression"))?)
-    } else {
-        Ok(Sequence::Exp(make_exp(seq)?))
-    }
-}
-
-fn parse_number(negative: bool, lexer: &mut Lexer) -> Result<Val, Error> {
-    match lexer.token() {
-        Token::IntNumber => {
-            let mut val = lexer.content().parse().map_err(|err| {
-                anyhow!("{:?}. '{}' Position: {}", err, lexer.content(), lexer.loc())
+    /// Parses the arguments of a call of the function `name`, found at `loc`. The current token
+    /// is the `(`. Builtins and the functions defined so far can be called.
+    fn parse_call(&mut self, name: &str, loc: Loc) -> Result<Exp, ParseError> {
+        let fun = Builtin::from_name(name);
+        let arity = fun.map(Builtin::arity).or_else(|| {
+            self.funs
+                .iter()
+                .find(|(other, _)| other == name)
+                .map(|(_, arity)| *arity)
+        });
+        if arity.is_none() {
+            self.report(ParseError::UnknownFunction {
+                loc: loc.clone(),
+                name: name.to_owned(),
             })?;
-            if negative {
-                val *= -1;
+        }
+
+        let ends = [Token::Comma, Token::RParen];
+        let mut args = vec![];
+        self.advance()?;
+        if self.lexer.token() != Token::RParen {
+            loop {
+                args.push(self.parse_operand(0, &ends)?);
+                if self.lexer.token() != Token::Comma {
+                    break;
+                }
+                self.advance()?;
             }
-            Ok(Val::Int(val))
         }
-        Token::FloatNumber => {
-            let mut val = lexer.content().replace(",", ".").parse().map_err(|err| {
-                anyhow!("{:?}. '{}' Position: {}", err, lexer.content(), lexer.loc())
-            })?;
-            if negative {
-                val *= -1.0;
+        self.expect(Token::RParen, &ends)?;
+
+        let loc = Loc {
+            start: loc.start,
+            end: self.lexer.previous_end_loc(),
+        };
+        match (fun, arity) {
+            (Some(fun), Some(arity)) if arity == args.len() => Ok(Exp::Builtin { fun, args }),
+            (None, Some(arity)) if arity == args.len() => Ok(Exp::Apply {
+                name: name.to_owned(),
+                args,
+                rt: Rt::Int,
+                instance: 0,
+            }),
+            (_, Some(arity)) => {
+                self.report(ParseError::Arity {
+                    loc: loc.clone(),
+                    name: name.to_owned(),
+                    arity,
+                    found: args.len(),
+                })?;
+                Ok(Exp::Error(loc))
             }
-            Ok(Val::Float(val))
+            (_, None) => Ok(Exp::Error(loc)),
         }
-        _ => Err(anyhow!(
-            "Invalid number. '{}' Position: {}",
-            lexer.content(),
-            lexer.loc()
-        )),
     }
 }
 
-fn make_exp(mut seq: Vec<Option<Sequence>>) -> Result<Exp, Error> {
-    let mut operator_order = seq
-        .iter()
-        .flatten()
-        .enumerate()
-        .filter_map(|(i, sq)| {
-            if let Some(op) = sq.op() {
-                Some((op.order(), i, op))
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
-
-    operator_order.sort_by(|(l_order, l_index, _), (r_order, r_index, _)| {
-        (*l_order, *l_index).cmp(&(*r_order, *r_index))
-    });
-
-    let mut buffer: Vec<(Range<usize>, Exp)> = Vec::new();
-
-    fn find_in_buffer(
-        buffer: &mut Vec<(Range<usize>, Exp)>,
-        index: usize,
-    ) -> Option<(Range<usize>, Exp)> {
-        if let Some(index) = buffer.iter().enumerate().find_map(|(i, (range, _))| {
-            if range.start <= index && range.end >= index {
-                Some(i)
-            } else {
-                None
-            }
-        }) {
-            Some(buffer.remove(index))
-        } else {
-            None
-        }
-    }
-
-    fn find_exp(
-        buffer: &mut Vec<(Range<usize>, Exp)>,
-        seq: &mut Vec<Option<Sequence>>,
-        index: usize,
-    ) -> Result<(Range<usize>, Exp), Error> {
-        find_in_buffer(buffer, index)
-            .or_else(|| {
-                seq[index]
-                    .take()
-                    .and_then(|sq| sq.exp())
-                    .map(|ex| (((index..index), ex)))
-            })
-            .ok_or_else(|| anyhow!("Invalid expiration"))
-    }
-
-    for (_, index, operator) in operator_order {
-        // index may not be 0.
-        let (l_range, l_exp) = find_exp(&mut buffer, &mut seq, index - 1)?;
-        let (r_range, r_exp) = find_exp(&mut buffer, &mut seq, index + 1)?;
-
-        let exp = Exp::Exp {
-            op: operator,
-            left: Box::new(l_exp),
-            right: Box::new(r_exp),
-        };
-
-        buffer.push(((l_range.start..r_range.end), exp))
+fn parse_number(lexer: &mut Lexer) -> Result<Val, ParseError> {
+    let invalid = || ParseError::InvalidNumber {
+        loc: lexer.loc(),
+        found: lexer.token(),
+    };
+    match lexer.token() {
+        Token::IntNumber => lexer.content().parse().map(Val::Int).map_err(|_| invalid()),
+        Token::FloatNumber => lexer
+            .content()
+            .replace(",", ".")
+            .parse()
+            .map(Val::Float)
+            .map_err(|_| invalid()),
+        _ => Err(ParseError::Unexpected {
+            loc: lexer.loc(),
+            found: lexer.token(),
+            expected: [Token::IntNumber, Token::FloatNumber]
+                .iter()
+                .copied()
+                .collect(),
+        }),
     }
+}
 
-    if buffer.len() != 1 {
-        return Err(anyhow!("Invalid expiration"));
+/// Negation of a literal is folded into it, as in `-1`.
+fn make_unary(op: UnOp, exp: Exp) -> Exp {
+    match (op, exp) {
+        (UnOp::Neg, Exp::Val(Val::Int(val))) => Exp::Val(Val::Int(val.wrapping_neg())),
+        (UnOp::Neg, Exp::Val(Val::Float(val))) => Exp::Val(Val::Float(-val)),
+        (op, exp) => Exp::Unary {
+            op,
+            exp: Box::new(exp),
+        },
     }
-
-    Ok(buffer.remove(0).1)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::parser::ast::parse_exp;
-    use crate::parser::lexer::Lexer;
+    use std::thread;
+    use std::time::Instant;
+
+    use anyhow::Error;
+
+    use crate::asm::exec::Rt;
+    use crate::interpreter::{Env, EvalError, Execution};
+    use crate::parser::ast::{
+        parse_exp, parse_program, parse_program_recovering, parse_recovering, Builtin, Exp, Op,
+        UnOp, Val, Var, OPERAND, OPERATORS,
+    };
+    use crate::parser::error::ParseError;
+    use crate::parser::lexer::{Lexer, Loc, Token};
 
     fn perform_test(input: &str, ir_foot_print: &str) {
         let mut lexer = Lexer::new(input);
@@ -394,4 +894,516 @@ mod test {
             "(((1 + 2) * 10) * (3 - (10 ^ 2)))",
         );
     }
+
+    #[test]
+    fn test_associativity() {
+        perform_test("1 - 2 - 3", "((1 - 2) - 3)");
+        perform_test("8 / 4 / 2 % 3", "(((8 / 4) / 2) % 3)");
+        perform_test("2 ^ 3 ^ 2", "(2 ^ (3 ^ 2))");
+        perform_test("(2 ^ 3) ^ 2", "((2 ^ 3) ^ 2)");
+        perform_test("2 * 3 ^ 2 ^ x * 4", "((2 * (3 ^ (2 ^ x))) * 4)");
+        perform_test("1 + 2 * 3 ^ 4 - 5", "((1 + (2 * (3 ^ 4))) - 5)");
+
+        for input in ["", "()", "(1", "1)", "1 2", "(1 + 2)(3)", "1 + * 2", "1 ^"].iter() {
+            assert!(parse(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_linear() {
+        // Quadrupling the input quadruples the time of a linear parser and multiplies that of a
+        // quadratic one by 16. The best of a few runs keeps the ratio clear of scheduling noise.
+        // The tree is as deep as the chain of additions, so it is dropped on a thread with room
+        // for that.
+        let time = |terms: usize| {
+            let mut input = String::from("0");
+            for i in 0..terms {
+                input.push_str(&format!(" + {} * (x ^ 2) - 1", i % 7));
+            }
+            let handle = thread::Builder::new()
+                .stack_size(256 << 20)
+                .spawn(move || {
+                    (0..3)
+                        .map(|_| {
+                            let start = Instant::now();
+                            let exp = parse(&input).unwrap();
+                            let elapsed = start.elapsed();
+                            assert!(matches!(exp, Exp::Exp { op: Op::Sub, .. }));
+                            elapsed
+                        })
+                        .min()
+                        .unwrap()
+                })
+                .unwrap();
+            handle.join().unwrap()
+        };
+        let (small, large) = (time(5_000), time(20_000));
+        assert!(large < small * 8, "{:?} for 4 times {:?}", large, small);
+    }
+
+    fn error(input: &str) -> ParseError {
+        let mut lexer = Lexer::new(input);
+        lexer
+            .advance()
+            .and_then(|_| parse_exp(&mut lexer))
+            .unwrap_err()
+    }
+
+    fn unexpected(start: usize, end: usize, found: Token, expected: &[&[Token]]) -> ParseError {
+        ParseError::Unexpected {
+            loc: Loc { start, end },
+            found,
+            expected: expected.concat().into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("1 + * 2"), unexpected(4, 5, Token::Star, &[&OPERAND]));
+        assert_eq!(error(""), unexpected(0, 0, Token::EOF, &[&OPERAND]));
+        assert_eq!(error("()"), unexpected(1, 2, Token::RParen, &[&OPERAND]));
+        assert_eq!(
+            error("(1 + 2"),
+            unexpected(6, 6, Token::EOF, &[&OPERATORS, &[Token::RParen]])
+        );
+        assert_eq!(
+            error("(1 + 2) * 3)"),
+            unexpected(11, 12, Token::RParen, &[&OPERATORS, &[Token::EOF]])
+        );
+        assert_eq!(
+            error("x y"),
+            unexpected(2, 3, Token::Ident, &[&OPERATORS, &[Token::EOF]])
+        );
+        assert_eq!(
+            error("-(x 2)"),
+            unexpected(4, 5, Token::IntNumber, &[&OPERATORS, &[Token::RParen]])
+        );
+        assert_eq!(
+            error("1 + 99999999999999999999"),
+            ParseError::InvalidNumber {
+                loc: Loc { start: 4, end: 24 },
+                found: Token::IntNumber,
+            }
+        );
+        assert_eq!(
+            error("1.2.3"),
+            ParseError::InvalidNumber {
+                loc: Loc { start: 0, end: 5 },
+                found: Token::FloatNumber,
+            }
+        );
+        assert_eq!(
+            error("2 * $"),
+            ParseError::InvalidCharacter {
+                loc: Loc { start: 4, end: 5 },
+                found: '$',
+            }
+        );
+
+        assert_eq!(
+            error("2 * (3 +)").render("2 * (3 +)"),
+            "Unexpected ')', expected integer, float, variable, '(', '+', '-' or 'if'\n\
+             2 * (3 +)\n        ^"
+        );
+    }
+
+    #[test]
+    fn test_recovering() {
+        let recover = |input| {
+            let (exp, errors) = parse_recovering(input);
+            (exp.to_string(), errors)
+        };
+        assert_eq!(
+            recover("1 + * 2"),
+            (
+                "(1 + (<error> * 2))".to_owned(),
+                vec![unexpected(4, 5, Token::Star, &[&OPERAND])]
+            )
+        );
+        assert_eq!(
+            recover("(1 + ) * $ 3)"),
+            (
+                "((1 + <error>) * 3)".to_owned(),
+                vec![
+                    unexpected(5, 6, Token::RParen, &[&OPERAND]),
+                    ParseError::InvalidCharacter {
+                        loc: Loc { start: 9, end: 10 },
+                        found: '$',
+                    },
+                    unexpected(12, 13, Token::RParen, &[&OPERATORS, &[Token::EOF]]),
+                ]
+            )
+        );
+        assert_eq!(
+            recover("x y ^ 2 + 1.2.3"),
+            (
+                "(x + <error>)".to_owned(),
+                vec![
+                    unexpected(2, 3, Token::Ident, &[&OPERATORS, &[Token::EOF]]),
+                    ParseError::InvalidNumber {
+                        loc: Loc { start: 10, end: 15 },
+                        found: Token::FloatNumber,
+                    },
+                ]
+            )
+        );
+        assert_eq!(
+            recover("-(2 * x"),
+            (
+                "-(2 * x)".to_owned(),
+                vec![unexpected(7, 7, Token::EOF, &[&OPERATORS, &[Token::RParen]])]
+            )
+        );
+        assert_eq!(
+            recover(""),
+            (
+                "<error>".to_owned(),
+                vec![unexpected(0, 0, Token::EOF, &[&OPERAND])]
+            )
+        );
+        assert_eq!(recover("(x + 1) * 2"), ("((x + 1) * 2)".to_owned(), vec![]));
+
+        // The first error is the one the strict parser stops at.
+        for input in [
+            "1 + * 2", "", "()", "(1 + 2", "(1 + 2) * 3)", "x y", "-(x 2)", "1.2.3", "2 * $",
+        ]
+        .iter()
+        {
+            assert_eq!(parse_recovering(input).1[0], error(input), "{}", input);
+        }
+
+        // Error nodes can't be compiled or evaluated.
+        let (exp, _) = parse_recovering("1 + * 2");
+        assert_eq!(
+            exp.clone().bind(&[]).unwrap_err().to_string(),
+            "Syntax error at [4:5]"
+        );
+        assert_eq!(
+            exp.try_exec(&Env::new()),
+            Err(EvalError::SyntaxError(Loc { start: 4, end: 5 }))
+        );
+    }
+
+    fn parse(input: &str) -> Result<Exp, Error> {
+        let mut lexer = Lexer::new(input);
+        lexer.advance()?;
+        Ok(parse_exp(&mut lexer)?)
+    }
+
+    #[test]
+    fn test_vars() {
+        perform_test("x", "x");
+        perform_test("x * 2 + y", "((x * 2) + y)");
+        perform_test("(rate - 1) ^ n_2", "((rate - 1) ^ n_2)");
+        assert_eq!(parse("x").unwrap(), Exp::Var(Var::new("x")));
+
+        for input in ["x y", "2 x", "x 2", "x (1)", "(x) y"].iter() {
+            assert!(parse(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_calls() {
+        perform_test("sqrt(x) + min(1, 2)", "(sqrt(x) + min(1, 2))");
+        perform_test("max(1, -2 ^ 2) * 3", "(max(1, -(2 ^ 2)) * 3)");
+        perform_test("-abs(x - 1)", "-abs((x - 1))");
+        perform_test("ln(exp(sin(cos(0))))", "ln(exp(sin(cos(0))))");
+        perform_test("max(1,2)", "max(1, 2)");
+        // Commas are decimal separators outside argument lists only.
+        perform_test("2,5 + 1", "(2.5 + 1)");
+        perform_test("max(2.5, 3)", "max(2.5, 3)");
+        perform_test("max((2,5), 3)", "max(2.5, 3)");
+        // A function name without arguments is a variable.
+        perform_test("sin * 2", "(sin * 2)");
+        assert_eq!(
+            parse("abs(x)").unwrap(),
+            Exp::Builtin {
+                fun: Builtin::Abs,
+                args: vec![Exp::Var(Var::new("x"))],
+            }
+        );
+
+        assert_eq!(
+            error("1 + foo(2)"),
+            ParseError::UnknownFunction {
+                loc: Loc { start: 4, end: 7 },
+                name: "foo".to_owned(),
+            }
+        );
+        assert_eq!(
+            error("2 * min(1)"),
+            ParseError::Arity {
+                loc: Loc { start: 4, end: 10 },
+                name: "min".to_owned(),
+                arity: 2,
+                found: 1,
+            }
+        );
+        assert_eq!(
+            error("max(1,2,3)"),
+            ParseError::Arity {
+                loc: Loc { start: 0, end: 10 },
+                name: "max".to_owned(),
+                arity: 2,
+                found: 3,
+            }
+        );
+        assert_eq!(
+            error("max(2,5, 3)"),
+            ParseError::Arity {
+                loc: Loc { start: 0, end: 11 },
+                name: "max".to_owned(),
+                arity: 2,
+                found: 3,
+            }
+        );
+        assert_eq!(
+            error("sqrt()"),
+            ParseError::Arity {
+                loc: Loc { start: 0, end: 6 },
+                name: "sqrt".to_owned(),
+                arity: 1,
+                found: 0,
+            }
+        );
+        let in_args: &[Token] = &[Token::Comma, Token::RParen];
+        assert_eq!(
+            error("min(1 2)"),
+            unexpected(6, 7, Token::IntNumber, &[&OPERATORS, in_args])
+        );
+        assert_eq!(
+            error("min(1, 2"),
+            unexpected(8, 8, Token::EOF, &[&OPERATORS, in_args])
+        );
+        assert_eq!(
+            error("max(, 1)"),
+            unexpected(4, 5, Token::Comma, &[&OPERAND])
+        );
+        assert_eq!(
+            error("(1, 2)"),
+            unexpected(2, 3, Token::Comma, &[&OPERATORS, &[Token::RParen]])
+        );
+
+        let (exp, errors) = parse_recovering("foo(1 +) + min(,2, 3)");
+        assert_eq!(exp.to_string(), "(<error> + <error>)");
+        assert_eq!(
+            errors,
+            vec![
+                ParseError::UnknownFunction {
+                    loc: Loc { start: 0, end: 3 },
+                    name: "foo".to_owned(),
+                },
+                unexpected(7, 8, Token::RParen, &[&OPERAND]),
+                unexpected(15, 16, Token::Comma, &[&OPERAND]),
+                ParseError::Arity {
+                    loc: Loc { start: 11, end: 21 },
+                    name: "min".to_owned(),
+                    arity: 2,
+                    found: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unary() {
+        perform_test("-(1 + 2)", "-(1 + 2)");
+        perform_test("-x", "-x");
+        perform_test("--x", "-(-x)");
+        perform_test("+5", "+5");
+        perform_test("--3", "3");
+        perform_test("-2 ^ 2", "-(2 ^ 2)");
+        perform_test("(-2) ^ 2", "((-2) ^ 2)");
+        perform_test("-x ^ 2 * 3", "(-(x ^ 2) * 3)");
+        perform_test("2 ^ -x", "(2 ^ -x)");
+        perform_test("2 ^ -1 ^ 2", "(2 ^ -(1 ^ 2))");
+        perform_test("2 * -(3 + x)", "(2 * -(3 + x))");
+        perform_test("1 - -x", "(1 - -x)");
+        perform_test("(x) - +(y)", "(x - +y)");
+
+        for input in ["-", "2 -", "- * 2", "2 * + / 3", "-()"].iter() {
+            assert!(parse(input).is_err(), "{}", input);
+        }
+
+        // Rendering and parsing again gives the same tree.
+        let neg = |exp| Exp::Unary {
+            op: UnOp::Neg,
+            exp: Box::new(exp),
+        };
+        let pow = |left, right| Exp::Exp {
+            op: Op::Pow,
+            left: Box::new(left),
+            right: Box::new(right),
+        };
+        let x = || Exp::Var(Var::new("x"));
+        for exp in [
+            neg(pow(x(), Exp::Val(Val::Int(2)))),
+            pow(neg(x()), Exp::Val(Val::Int(2))),
+            pow(Exp::Val(Val::Int(-2)), Exp::Val(Val::Float(-0.5))),
+            neg(neg(x())),
+            Exp::Unary {
+                op: UnOp::Plus,
+                exp: Box::new(neg(x())),
+            },
+        ] {
+            let text = exp.to_string();
+            assert_eq!(parse(&text).unwrap(), exp, "{}", text);
+        }
+        // Negated literals are folded.
+        assert_eq!(parse("-(-1.5)").unwrap(), Exp::Val(Val::Float(1.5)));
+    }
+
+    #[test]
+    fn test_conditions() {
+        perform_test("1 + 2 < 3 * 4", "((1 + 2) < (3 * 4))");
+        perform_test("x == y != 0", "((x == y) != 0)");
+        perform_test("-x >= 2 ^ -1", "(-x >= (2 ^ -1))");
+        perform_test("if x <= 1 then 1 else 2", "(if (x <= 1) then 1 else 2)");
+        perform_test(
+            "1 + if x > 0 then x else -x * 2",
+            "(1 + (if (x > 0) then x else (-x * 2)))",
+        );
+        perform_test(
+            "if if a then b else c then (if d then e else f) else g",
+            "(if (if a then b else c) then (if d then e else f) else g)",
+        );
+        perform_test(
+            "max(if x then 1 else 2, 3)",
+            "max((if x then 1 else 2), 3)",
+        );
+
+        assert_eq!(
+            error("if x then 1"),
+            unexpected(11, 11, Token::EOF, &[&OPERATORS, &[Token::Else]])
+        );
+        assert_eq!(
+            error("if x 1 else 2"),
+            unexpected(5, 6, Token::IntNumber, &[&OPERATORS, &[Token::EOF, Token::Then]])
+        );
+        assert_eq!(error("1 < < 2"), unexpected(4, 5, Token::Less, &[&OPERAND]));
+        assert_eq!(error("then 1"), unexpected(0, 4, Token::Then, &[&OPERAND]));
+        assert_eq!(
+            error("x = 1"),
+            unexpected(2, 3, Token::Assign, &[&OPERATORS, &[Token::EOF]])
+        );
+    }
+
+    #[test]
+    fn test_program() {
+        let program = parse_program("sq(x) = x * x; fact(n) = if n <= 1 then 1 else n * fact(n - 1); sq(fact(3))").unwrap();
+        assert_eq!(
+            program.to_string(),
+            "sq(x) = (x * x); fact(n) = (if (n <= 1) then 1 else (n * fact((n - 1)))); \
+             sq(fact(3))"
+        );
+        assert_eq!(program.defs[1].params, vec!["n".to_owned()]);
+        assert_eq!(parse_program("1 + 2").unwrap().defs, vec![]);
+        assert_eq!(
+            parse_program("two() = 2; one() = two() - 1; one()").unwrap().to_string(),
+            "two() = 2; one() = (two() - 1); one()"
+        );
+        // A function can call itself and the ones before it, but not the ones after it.
+        assert_eq!(
+            parse_program("f() = g(); g() = 1; f()").unwrap_err(),
+            ParseError::UnknownFunction {
+                loc: Loc { start: 6, end: 7 },
+                name: "g".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse_program("f(x) = x + y; f(1)").unwrap_err(),
+            ParseError::UnknownVariable {
+                loc: Loc { start: 11, end: 12 },
+                name: "y".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse_program("f(x) = x; f(1, 2)").unwrap_err(),
+            ParseError::Arity {
+                loc: Loc { start: 10, end: 17 },
+                name: "f".to_owned(),
+                arity: 1,
+                found: 2,
+            }
+        );
+        assert_eq!(
+            parse_program("sin(x) = x; 1").unwrap_err(),
+            ParseError::Redefined {
+                loc: Loc { start: 0, end: 3 },
+                name: "sin".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse_program("f(x, x) = x; 1").unwrap_err(),
+            ParseError::Redefined {
+                loc: Loc { start: 5, end: 6 },
+                name: "x".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse_program("f(x) = x f(1)").unwrap_err(),
+            unexpected(9, 10, Token::Ident, &[&OPERATORS, &[Token::Semicolon]])
+        );
+        assert_eq!(
+            parse_program("f(1) = 1; 2").unwrap_err(),
+            unexpected(2, 3, Token::IntNumber, &[&[Token::Ident]])
+        );
+
+        let (program, errors) =
+            parse_program_recovering("f(x) = x +; f(x) = 1; g(2) = 2; f(y) * g(1)");
+        assert_eq!(program.to_string(), "f(x) = (x + <error>); (f(y) * <error>)");
+        assert_eq!(
+            errors,
+            vec![
+                unexpected(10, 11, Token::Semicolon, &[&OPERAND]),
+                ParseError::Redefined {
+                    loc: Loc { start: 12, end: 13 },
+                    name: "f".to_owned(),
+                },
+                unexpected(24, 25, Token::IntNumber, &[&[Token::Ident]]),
+                ParseError::UnknownFunction {
+                    loc: Loc { start: 39, end: 40 },
+                    name: "g".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bind() {
+        let params = [("x", Rt::Float), ("y", Rt::Int)];
+        let exp = parse("x * 2 + y * y").unwrap().bind(&params).unwrap();
+        let var = |name: &str, rt, slot| {
+            Box::new(Exp::Var(Var {
+                name: name.to_owned(),
+                rt,
+                slot,
+            }))
+        };
+        let y = || var("y", Rt::Int, 1);
+        assert_eq!(
+            exp,
+            Exp::Exp {
+                op: Op::Add,
+                left: Box::new(Exp::Exp {
+                    op: Op::Mul,
+                    left: var("x", Rt::Float, 0),
+                    right: Box::new(Exp::Val(Val::Int(2))),
+                }),
+                right: Box::new(Exp::Exp {
+                    op: Op::Mul,
+                    left: y(),
+                    right: y(),
+                }),
+            }
+        );
+        assert_eq!(exp.to_string(), "((x * 2) + (y * y))");
+
+        // Unused parameters are fine, unknown variables and duplicates are not.
+        assert!(parse("y").unwrap().bind(&params).is_ok());
+        let err = parse("x + z").unwrap().bind(&params).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown variable 'z'");
+        let err = parse("x").unwrap().bind(&[("x", Rt::Int), ("x", Rt::Float)]);
+        assert_eq!(err.unwrap_err().to_string(), "Duplicate parameter 'x'");
+    }
 }
@@ -0,0 +1,224 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::parser::lexer::{Loc, Token};
+
+/// Why the input isn't an expression, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A character no token starts with.
+    InvalidCharacter { loc: Loc, found: char },
+    /// A number token that doesn't hold a valid number, like `1.2.3` or an integer past
+    /// `i64::MAX`.
+    InvalidNumber { loc: Loc, found: Token },
+    /// A token that can't appear where it is. `found` is `Token::EOF` when the input ends early.
+    Unexpected {
+        loc: Loc,
+        found: Token,
+        expected: BTreeSet<Token>,
+    },
+    /// A call of a name that isn't a function.
+    UnknownFunction { loc: Loc, name: String },
+    /// A call of the function `name`, which takes `arity` arguments, with the wrong number of
+    /// them. `loc` spans the whole call.
+    Arity {
+        loc: Loc,
+        name: String,
+        arity: usize,
+        found: usize,
+    },
+    /// A variable in a definition that isn't one of its parameters.
+    UnknownVariable { loc: Loc, name: String },
+    /// A definition of a function that already exists, or a parameter listed twice.
+    Redefined { loc: Loc, name: String },
+}
+
+impl ParseError {
+    pub fn loc(&self) -> &Loc {
+        match self {
+            ParseError::InvalidCharacter { loc, .. }
+            | ParseError::InvalidNumber { loc, .. }
+            | ParseError::Unexpected { loc, .. }
+            | ParseError::UnknownFunction { loc, .. }
+            | ParseError::Arity { loc, .. }
+            | ParseError::UnknownVariable { loc, .. }
+            | ParseError::Redefined { loc, .. } => loc,
+        }
+    }
+
+    /// The message followed by the line of `source` with the problem, underlined with carets.
+    /// The end of input is pointed at just past the last character.
+    pub fn render(&self, source: &str) -> String {
+        let loc = self.loc();
+        let start = loc.start.min(source.len());
+        let end = loc.end.clamp(start, source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        // An underline stops at the end of the line.
+        let end = end.min(line_end);
+
+        let line = &source[line_start..line_end];
+        let column = source[line_start..start].chars().count();
+        let width = source[start..end].chars().count().max(1);
+        format!(
+            "{}\n{}\n{}{}",
+            Message(self),
+            line,
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+/// The description without the position, which `render` shows by pointing at it.
+struct Message<'a>(&'a ParseError);
+
+impl Display for Message<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            ParseError::InvalidCharacter { found, .. } => {
+                write!(f, "Invalid character: '{}'", found)
+            }
+            ParseError::InvalidNumber { found, .. } => write!(f, "Invalid {}", found),
+            ParseError::Unexpected {
+                found, expected, ..
+            } => {
+                write!(f, "Unexpected {}", found)?;
+                for (i, token) in expected.iter().enumerate() {
+                    let sep = match i {
+                        0 => ", expected ",
+                        _ if i + 1 == expected.len() => " or ",
+                        _ => ", ",
+                    };
+                    write!(f, "{}{}", sep, token)?;
+                }
+                Ok(())
+            }
+            ParseError::UnknownFunction { name, .. } => write!(f, "Unknown function '{}'", name),
+            ParseError::Arity {
+                name, arity, found, ..
+            } => {
+                let plural = |n| if n == 1 { "" } else { "s" };
+                write!(
+                    f,
+                    "Function '{}' takes {} argument{}, found {}",
+                    name,
+                    arity,
+                    plural(*arity),
+                    found
+                )
+            }
+            ParseError::UnknownVariable { name, .. } => write!(f, "Unknown variable '{}'", name),
+            ParseError::Redefined { name, .. } => write!(f, "'{}' is already defined", name),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}. Position: {}", Message(self), self.loc())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use crate::parser::error::ParseError;
+    use crate::parser::lexer::{Loc, Token};
+
+    fn unexpected(start: usize, end: usize, found: Token, expected: &[Token]) -> ParseError {
+        ParseError::Unexpected {
+            loc: Loc { start, end },
+            found,
+            expected: expected.iter().copied().collect::<BTreeSet<_>>(),
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let err = unexpected(
+            4,
+            5,
+            Token::Star,
+            &[Token::IntNumber, Token::LParen, Token::Minus],
+        );
+        assert_eq!(
+            err.to_string(),
+            "Unexpected '*', expected integer, '(' or '-'. Position: [4:5]"
+        );
+        let err = unexpected(3, 3, Token::EOF, &[Token::RParen]);
+        assert_eq!(
+            err.to_string(),
+            "Unexpected end of input, expected ')'. Position: [3:3]"
+        );
+        let err = ParseError::InvalidCharacter {
+            loc: Loc { start: 0, end: 1 },
+            found: '$',
+        };
+        assert_eq!(err.to_string(), "Invalid character: '$'. Position: [0:1]");
+        let err = ParseError::Arity {
+            loc: Loc { start: 0, end: 9 },
+            name: "sqrt".to_owned(),
+            arity: 1,
+            found: 2,
+        };
+        assert_eq!(
+            err.to_string(),
+            "Function 'sqrt' takes 1 argument, found 2. Position: [0:9]"
+        );
+        let err = ParseError::UnknownFunction {
+            loc: Loc { start: 2, end: 5 },
+            name: "foo".to_owned(),
+        };
+        assert_eq!(
+            err.render("1+foo(2)"),
+            "Unknown function 'foo'\n1+foo(2)\n  ^^^"
+        );
+        let err = ParseError::Redefined {
+            loc: Loc { start: 0, end: 2 },
+            name: "sq".to_owned(),
+        };
+        assert_eq!(err.to_string(), "'sq' is already defined. Position: [0:2]");
+    }
+
+    #[test]
+    fn test_render() {
+        let err = unexpected(4, 5, Token::Star, &[Token::Ident]);
+        assert_eq!(
+            err.render("1 + * 2"),
+            "Unexpected '*', expected variable\n1 + * 2\n    ^"
+        );
+
+        let err = ParseError::InvalidNumber {
+            loc: Loc { start: 8, end: 13 },
+            found: Token::FloatNumber,
+        };
+        assert_eq!(
+            err.render("2 * (\n  1.2.3)"),
+            "Invalid float\n  1.2.3)\n  ^^^^^"
+        );
+
+        // The end of input is past the last character.
+        let err = unexpected(5, 5, Token::EOF, &[Token::RParen]);
+        assert_eq!(
+            err.render("(1 + "),
+            "Unexpected end of input, expected ')'\n(1 + \n     ^"
+        );
+
+        // Columns count characters, not bytes.
+        let err = ParseError::InvalidCharacter {
+            loc: Loc { start: 5, end: 7 },
+            found: 'é',
+        };
+        assert_eq!(
+            err.render("x + (é)"),
+            "Invalid character: 'é'\nx + (é)\n     ^"
+        );
+    }
+}
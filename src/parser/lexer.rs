@@ -1,56 +1,121 @@
-use anyhow::Error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
+use crate::parser::error::ParseError;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Token {
     EOF,
     IntNumber,
     FloatNumber,
+    Ident,
     LParen,
     RParen,
+    Comma,
     Plus,
     Minus,
     Star,
     Slash,
     Caret,
     Percent,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    EqEq,
+    NotEq,
+    Assign,
+    Semicolon,
+    If,
+    Then,
+    Else,
 }
 
 impl Token {
     pub fn is_number(&self) -> bool {
-        match self {
-            Token::IntNumber | Token::FloatNumber => true,
-            _ => false,
-        }
+        matches!(self, Token::IntNumber | Token::FloatNumber)
+    }
+
+    /// A number or a variable.
+    pub fn is_operand(&self) -> bool {
+        self.is_number() || *self == Token::Ident
     }
 
     pub fn is_paren(&self) -> bool {
-        match self {
-            Token::LParen | Token::RParen => true,
-            _ => false,
-        }
+        matches!(self, Token::LParen | Token::RParen)
     }
 
     pub fn is_sign(&self) -> bool {
+        matches!(
+            self,
+            Token::Plus | Token::Minus | Token::Star | Token::Slash | Token::Caret | Token::Percent
+        )
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Token::Less
+                | Token::LessEq
+                | Token::Greater
+                | Token::GreaterEq
+                | Token::EqEq
+                | Token::NotEq
+        )
+    }
+
+    /// A word that can't name a variable or a function.
+    pub fn is_keyword(&self) -> bool {
+        matches!(self, Token::If | Token::Then | Token::Else)
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Token::Plus
-            | Token::Minus
-            | Token::Star
-            | Token::Slash
-            | Token::Caret
-            | Token::Percent => true,
-            _ => false,
+            Token::EOF => write!(f, "end of input"),
+            Token::IntNumber => write!(f, "integer"),
+            Token::FloatNumber => write!(f, "float"),
+            Token::Ident => write!(f, "variable"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::Comma => write!(f, "','"),
+            Token::Plus => write!(f, "'+'"),
+            Token::Minus => write!(f, "'-'"),
+            Token::Star => write!(f, "'*'"),
+            Token::Slash => write!(f, "'/'"),
+            Token::Caret => write!(f, "'^'"),
+            Token::Percent => write!(f, "'%'"),
+            Token::Less => write!(f, "'<'"),
+            Token::LessEq => write!(f, "'<='"),
+            Token::Greater => write!(f, "'>'"),
+            Token::GreaterEq => write!(f, "'>='"),
+            Token::EqEq => write!(f, "'=='"),
+            Token::NotEq => write!(f, "'!='"),
+            Token::Assign => write!(f, "'='"),
+            Token::Semicolon => write!(f, "';'"),
+            Token::If => write!(f, "'if'"),
+            Token::Then => write!(f, "'then'"),
+            Token::Else => write!(f, "'else'"),
         }
     }
 }
 
+/// Splits the input into tokens.
+///
+/// Numbers take a decimal point or a decimal comma, `2.5 + 1` and `2,5 + 1` are the same sum.
+/// Directly inside an argument list a comma always separates arguments instead: `max(2,5)` is
+/// the larger of 2 and 5, and `max(2,5, 3)` has three arguments. Decimal arguments are written
+/// `max(2.5, 3)`, or `max((2,5), 3)` as parentheses group again.
+#[derive(Clone)]
 pub struct Lexer<'input> {
     text: &'input str,
     prev_end: usize,
     cur_start: usize,
     cur_end: usize,
     token: Token,
+    /// Open parentheses, `true` for the ones opening an argument list.
+    parens: Vec<bool>,
 }
 
 impl<'input> Lexer<'input> {
@@ -61,6 +126,7 @@ impl<'input> Lexer<'input> {
             cur_start: 0,
             cur_end: 0,
             token: Token::EOF,
+            parens: vec![],
         }
     }
 
@@ -83,17 +149,41 @@ impl<'input> Lexer<'input> {
         self.prev_end
     }
 
-    pub fn advance(&mut self) -> Result<(), Error> {
+    /// Moves to the next token. An invalid character is moved past as well, so advancing again
+    /// carries on after it.
+    pub fn advance(&mut self) -> Result<(), ParseError> {
         self.prev_end = self.cur_end;
         let text = self.text[self.cur_end..].trim_start();
         self.cur_start = self.text.len() - text.len();
-        let (token, len) = Self::find_token(text)?;
+        let arguments = self.parens.last() == Some(&true);
+        let (token, len) = match Self::find_token(text, !arguments) {
+            Ok(found) => found,
+            Err(found) => {
+                self.cur_end = self.cur_start + found.len_utf8();
+                return Err(ParseError::InvalidCharacter {
+                    loc: Loc {
+                        start: self.cur_start,
+                        end: self.cur_end,
+                    },
+                    found,
+                });
+            }
+        };
         self.cur_end = self.cur_start + len;
+        match token {
+            Token::LParen => self.parens.push(self.token == Token::Ident),
+            Token::RParen => {
+                self.parens.pop();
+            }
+            _ => {}
+        }
         self.token = token;
         Ok(())
     }
 
-    fn find_token(text: &str) -> Result<(Token, usize), Error> {
+    /// The token `text` starts with and its length, or the character no token starts with.
+    /// Commas in numbers are decimal separators if `decimal_comma` is set.
+    fn find_token(text: &str, decimal_comma: bool) -> Result<(Token, usize), char> {
         let c: char = match text.chars().next() {
             Some(next_char) => next_char,
             None => {
@@ -103,13 +193,18 @@ impl<'input> Lexer<'input> {
 
         Ok(match c {
             '0'..='9' => {
-                let len = text
-                    .chars()
-                    .position(|c| match c {
-                        '0'..='9' | '.' | ',' => false,
+                // A comma followed by a digit is a decimal separator, so `1,5` is a number and
+                // `1, 5` two arguments. See `Lexer` for argument lists.
+                let bytes = text.as_bytes();
+                let len = (0..bytes.len())
+                    .find(|&i| match bytes[i] {
+                        b'0'..=b'9' | b'.' => false,
+                        b',' => {
+                            !decimal_comma || !bytes.get(i + 1).is_some_and(u8::is_ascii_digit)
+                        }
                         _ => true,
                     })
-                    .unwrap_or_else(|| text.len());
+                    .unwrap_or(bytes.len());
                 let tkn = &text[..len];
                 if tkn.contains('.') || tkn.contains(',') {
                     (Token::FloatNumber, len)
@@ -117,20 +212,47 @@ impl<'input> Lexer<'input> {
                     (Token::IntNumber, len)
                 }
             }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let len = text
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(text.len());
+                let token = match &text[..len] {
+                    "if" => Token::If,
+                    "then" => Token::Then,
+                    "else" => Token::Else,
+                    _ => Token::Ident,
+                };
+                (token, len)
+            }
             '%' => (Token::Percent, 1),
             '(' => (Token::LParen, 1),
             ')' => (Token::RParen, 1),
+            ',' => (Token::Comma, 1),
             '*' => (Token::Star, 1),
             '+' => (Token::Plus, 1),
             '-' => (Token::Minus, 1),
             '/' => (Token::Slash, 1),
             '^' => (Token::Caret, 1),
-            _ => return Err(Error::msg(format!("Invalid character: '{}'", c))),
+            ';' => (Token::Semicolon, 1),
+            '<' | '>' | '=' | '!' => {
+                let eq = text[1..].starts_with('=');
+                match (c, eq) {
+                    ('<', false) => (Token::Less, 1),
+                    ('<', true) => (Token::LessEq, 2),
+                    ('>', false) => (Token::Greater, 1),
+                    ('>', true) => (Token::GreaterEq, 2),
+                    ('=', false) => (Token::Assign, 1),
+                    ('=', true) => (Token::EqEq, 2),
+                    ('!', true) => (Token::NotEq, 2),
+                    _ => return Err(c),
+                }
+            }
+            _ => return Err(c),
         })
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone)]
 pub struct Loc {
     pub start: usize,
     pub end: usize,
@@ -144,7 +266,8 @@ impl Display for Loc {
 
 #[cfg(test)]
 mod test {
-    use crate::parser::lexer::{Lexer, Token};
+    use crate::parser::error::ParseError;
+    use crate::parser::lexer::{Lexer, Loc, Token};
 
     fn perform(tokens: &[(Token, &str)], input: &str) {
         let mut lexer = Lexer::new(input);
@@ -172,15 +295,38 @@ mod test {
     }
 
     #[test]
-    #[should_panic(expected = "Invalid character: 'd'")]
     pub fn test_invalid_input() {
+        let mut lexer = Lexer::new("10 * $");
+        lexer.advance().unwrap();
+        lexer.advance().unwrap();
+        assert_eq!(lexer.token(), Token::Star);
+        assert_eq!(
+            lexer.advance(),
+            Err(ParseError::InvalidCharacter {
+                loc: Loc { start: 5, end: 6 },
+                found: '$',
+            })
+        );
+        // The invalid character is skipped by the next advance.
+        lexer.advance().unwrap();
+        assert_eq!(lexer.token(), Token::EOF);
+        assert_eq!(lexer.loc(), Loc { start: 6, end: 6 });
+    }
+
+    #[test]
+    pub fn test_ident() {
+        perform(&[(Token::Ident, "x"), (Token::EOF, "")], "x");
         perform(
             &[
-                (Token::IntNumber, "10"),
+                (Token::Ident, "rate_2"),
                 (Token::Star, "*"),
+                (Token::Ident, "_Total"),
+                (Token::Minus, "-"),
+                (Token::IntNumber, "2"),
+                (Token::Ident, "x"),
                 (Token::EOF, ""),
             ],
-            "10 * d",
+            "rate_2*_Total - 2x",
         );
     }
 
@@ -193,6 +339,43 @@ mod test {
             "123.123",
         );
         perform(&[(Token::FloatNumber, "0,1"), (Token::EOF, "")], "0,1");
+        // A comma without a digit after it separates arguments.
+        perform(
+            &[
+                (Token::IntNumber, "1"),
+                (Token::Comma, ","),
+                (Token::FloatNumber, "2,5"),
+                (Token::Comma, ","),
+                (Token::Ident, "x"),
+                (Token::EOF, ""),
+            ],
+            "1, 2,5,x",
+        );
+    }
+
+    #[test]
+    pub fn test_arguments() {
+        // Parentheses nested in an argument list group again.
+        perform(
+            &[
+                (Token::Ident, "max"),
+                (Token::LParen, "("),
+                (Token::IntNumber, "1"),
+                (Token::Comma, ","),
+                (Token::IntNumber, "2"),
+                (Token::Comma, ","),
+                (Token::LParen, "("),
+                (Token::FloatNumber, "1,5"),
+                (Token::RParen, ")"),
+                (Token::Comma, ","),
+                (Token::FloatNumber, "0.5"),
+                (Token::RParen, ")"),
+                (Token::Star, "*"),
+                (Token::FloatNumber, "2,5"),
+                (Token::EOF, ""),
+            ],
+            "max(1,2,(1,5),0.5)*2,5",
+        );
     }
 
     #[test]
@@ -207,14 +390,60 @@ mod test {
                 (Token::Percent, "%"),
                 (Token::LParen, "("),
                 (Token::RParen, ")"),
+                (Token::Comma, ","),
+                (Token::EOF, ""),
+            ],
+            "+-/*^%(),",
+        );
+    }
+
+    #[test]
+    pub fn test_comparison() {
+        perform(
+            &[
+                (Token::Less, "<"),
+                (Token::LessEq, "<="),
+                (Token::Greater, ">"),
+                (Token::GreaterEq, ">="),
+                (Token::EqEq, "=="),
+                (Token::NotEq, "!="),
+                (Token::Assign, "="),
+                (Token::LessEq, "<="),
+                (Token::Assign, "="),
+                (Token::Semicolon, ";"),
+                (Token::EOF, ""),
+            ],
+            "< <= > >= == != = <== ;",
+        );
+        let mut lexer = Lexer::new("x ! y");
+        lexer.advance().unwrap();
+        assert_eq!(
+            lexer.advance(),
+            Err(ParseError::InvalidCharacter {
+                loc: Loc { start: 2, end: 3 },
+                found: '!',
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_keywords() {
+        perform(
+            &[
+                (Token::If, "if"),
+                (Token::Ident, "x"),
+                (Token::Then, "then"),
+                (Token::Ident, "iffy"),
+                (Token::Else, "else"),
+                (Token::Ident, "else_"),
                 (Token::EOF, ""),
             ],
-            "+-/*^%()",
+            "if x then iffy else else_",
         );
     }
 
     #[test]
-    pub fn test_expiration() {
+    pub fn test_expression() {
         perform(
             &[
                 (Token::LParen, "("),
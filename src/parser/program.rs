@@ -0,0 +1,206 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+
+use crate::asm::exec::{AsmCode, Rt};
+use crate::parser::ast::Exp;
+
+/// Definitions followed by the expression using them, as in `sq(x) = x * x; sq(3)`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+    pub defs: Vec<Def>,
+    pub exp: Exp,
+}
+
+/// `name(params) = body`. The body reads only the parameters, and calls builtins, the
+/// definitions before it and itself.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Def {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Exp,
+}
+
+/// A definition for one combination of argument types, with its body bound to them.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instance {
+    pub name: String,
+    pub params: Vec<(String, Rt)>,
+    pub body: Exp,
+    pub rt: Rt,
+}
+
+/// A program bound by `Program::bind`. Calls in the expression and the bodies refer to `funs`
+/// by index.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Bound {
+    pub exp: Exp,
+    pub funs: Arc<[Instance]>,
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for def in &self.defs {
+            write!(f, "{}; ", def)?;
+        }
+        self.exp.fmt(f)
+    }
+}
+
+impl Display for Def {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}({}) = {}",
+            self.name,
+            self.params.join(", "),
+            self.body
+        )
+    }
+}
+
+/// Name, argument types and result type of an instance.
+type Signature = (String, Vec<Rt>, Rt);
+
+impl Program {
+    /// Binds the expression to `params` like `Exp::bind`, and instantiates every definition
+    /// for each combination of argument types it's called with. The result type of a
+    /// recursive function depends on itself, so it starts as an integer and the bodies are
+    /// bound again until no result type widens to a float.
+    pub fn bind(&self, params: &[(&str, Rt)]) -> Result<Bound, Error> {
+        let mut sigs: Vec<Signature> = vec![];
+        loop {
+            let before = sigs.clone();
+            let exp = self.exp.clone().bind_with(params, &mut |name, args| {
+                self.resolve(&mut sigs, name, args)
+            })?;
+
+            // Bodies can add instances, which are bound in the same pass.
+            let mut bodies = vec![];
+            while bodies.len() < sigs.len() {
+                let (name, args, rt) = sigs[bodies.len()].clone();
+                let def = self.def(&name)?;
+                let typed: Vec<(&str, Rt)> = def
+                    .params
+                    .iter()
+                    .map(String::as_str)
+                    .zip(args.iter().copied())
+                    .collect();
+                let body = def.body.clone().bind_with(&typed, &mut |name, args| {
+                    self.resolve(&mut sigs, name, args)
+                })?;
+                sigs[bodies.len()].2 = rt.max(body.result_type());
+                bodies.push(body);
+            }
+
+            if sigs == before {
+                let funs = sigs
+                    .into_iter()
+                    .zip(bodies)
+                    .map(|((name, args, rt), body)| {
+                        Ok(Instance {
+                            params: self.def(&name)?.params.iter().cloned().zip(args).collect(),
+                            name,
+                            body,
+                            rt,
+                        })
+                    })
+                    .collect::<Result<_, Error>>()?;
+                return Ok(Bound { exp, funs });
+            }
+        }
+    }
+
+    fn def(&self, name: &str) -> Result<&Def, Error> {
+        self.defs
+            .iter()
+            .find(|def| def.name == name)
+            .ok_or_else(|| anyhow!("Unknown function '{}'", name))
+    }
+
+    /// The instance of `name` for arguments of types `args` and its result type so far, added
+    /// to `sigs` on the first call.
+    fn resolve(
+        &self,
+        sigs: &mut Vec<Signature>,
+        name: &str,
+        args: &[Rt],
+    ) -> Result<(usize, Rt), Error> {
+        let def = self.def(name)?;
+        if def.params.len() != args.len() {
+            return Err(anyhow!(
+                "Function '{}' takes {} arguments, found {}",
+                name,
+                def.params.len(),
+                args.len()
+            ));
+        }
+        let found = sigs
+            .iter()
+            .position(|(other, types, _)| other == name && types[..] == *args);
+        Ok(match found {
+            Some(i) => (i, sigs[i].2),
+            None => {
+                sigs.push((name.to_owned(), args.to_vec(), Rt::Int));
+                (sigs.len() - 1, Rt::Int)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asm::exec::Rt;
+    use crate::parser::ast::{parse_program, Exp};
+
+    #[test]
+    fn test_bind() {
+        let program =
+            parse_program("sq(x) = x * x; half(x) = x / 2.0; sq(a) + sq(half(b))").unwrap();
+        assert_eq!(
+            program.to_string(),
+            "sq(x) = (x * x); half(x) = (x / 2); (sq(a) + sq(half(b)))"
+        );
+        let bound = program.bind(&[("a", Rt::Int), ("b", Rt::Int)]).unwrap();
+        let sigs: Vec<_> = bound
+            .funs
+            .iter()
+            .map(|fun| (fun.name.as_str(), fun.params[0].1, fun.rt))
+            .collect();
+        assert_eq!(
+            sigs,
+            vec![
+                ("sq", Rt::Int, Rt::Int),
+                ("half", Rt::Int, Rt::Float),
+                ("sq", Rt::Float, Rt::Float),
+            ]
+        );
+        match bound.exp {
+            Exp::Exp { right, .. } => assert!(matches!(
+                *right,
+                Exp::Apply {
+                    rt: Rt::Float,
+                    instance: 2,
+                    ..
+                }
+            )),
+            exp => panic!("{}", exp),
+        }
+
+        // The result type of a recursive function widens until it's stable.
+        let program = parse_program("f(n) = if n < 1 then 0 else f(n - 1) + 0.5; f(3)").unwrap();
+        let bound = program.bind(&[]).unwrap();
+        assert_eq!(bound.funs.len(), 1);
+        assert_eq!(bound.funs[0].rt, Rt::Float);
+        assert!(matches!(bound.exp, Exp::Apply { rt: Rt::Float, .. }));
+
+        let program = parse_program("add(a,b) = a + b; add(1,2)").unwrap();
+        assert_eq!(program.to_string(), "add(a, b) = (a + b); add(1, 2)");
+
+        let program = parse_program("sq(x) = x * x; sq(y)").unwrap();
+        assert!(program.bind(&[]).is_err());
+        assert!(program.exp.bind(&[("y", Rt::Int)]).is_err());
+    }
+}